@@ -0,0 +1,29 @@
+-- Sessions for auth keys
+-- NOTES:
+-- every auth key now has its own id so that it can be listed and revoked
+-- individually. existing keys are carried over as unnamed sessions.
+CREATE TABLE IF NOT EXISTS auth_keys_new (
+    session BLOB PRIMARY KEY NOT NULL CHECK (length(session) == 16),
+    id BLOB NOT NULL CHECK (length(id) == 16),
+    expiry INTEGER NOT NULL,
+    secret BLOB NOT NULL,
+    -- user supplied name of the device
+    name TEXT NULL,
+    created INTEGER NOT NULL,
+    last_used INTEGER NOT NULL,
+    FOREIGN KEY(id) REFERENCES user(id)
+) STRICT;
+INSERT INTO auth_keys_new (session, id, expiry, secret, name, created, last_used)
+SELECT randomblob(16),
+    id,
+    expiry,
+    secret,
+    NULL,
+    CAST(strftime('%s', 'now') AS INTEGER),
+    CAST(strftime('%s', 'now') AS INTEGER)
+FROM auth_keys;
+DROP INDEX IF EXISTS auth_keys_id;
+DROP TABLE auth_keys;
+ALTER TABLE auth_keys_new
+    RENAME TO auth_keys;
+CREATE INDEX IF NOT EXISTS auth_keys_id ON auth_keys (id);
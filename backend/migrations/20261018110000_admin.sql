@@ -0,0 +1,14 @@
+-- User administration
+-- NOTES:
+-- admin and disabled are booleans, as STRICT tables do not allow BOOLEAN
+ALTER TABLE user
+ADD COLUMN admin INTEGER NOT NULL DEFAULT FALSE CHECK (admin IN (FALSE, TRUE));
+ALTER TABLE user
+ADD COLUMN disabled INTEGER NOT NULL DEFAULT FALSE CHECK (disabled IN (FALSE, TRUE));
+-- the first user on an existing server becomes the admin
+UPDATE user
+SET admin = TRUE
+WHERE rowid = (
+        SELECT MIN(rowid)
+        FROM user
+    );
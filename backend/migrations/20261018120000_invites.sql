@@ -0,0 +1,16 @@
+-- Invite codes for signing up
+-- NOTES:
+-- creator is nulled out when the creating user is deleted, so that accounts made
+-- from the invite keep track of it
+CREATE TABLE IF NOT EXISTS invite (
+    id BLOB PRIMARY KEY NOT NULL CHECK (length(id) == 16),
+    code TEXT UNIQUE NOT NULL,
+    creator BLOB NULL,
+    uses_left INTEGER NOT NULL CHECK (uses_left >= 0),
+    expiry INTEGER NOT NULL,
+    created INTEGER NOT NULL,
+    FOREIGN KEY(creator) REFERENCES user(id)
+) STRICT;
+-- invite used to create the account, if any
+ALTER TABLE user
+ADD COLUMN invite BLOB NULL REFERENCES invite(id);
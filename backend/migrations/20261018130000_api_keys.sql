@@ -0,0 +1,15 @@
+-- Long lived api keys for integrations
+-- NOTES:
+-- only the sha256 hash of the key is stored. scopes is a bitset, see
+-- `apikey::scope_bit` for which bit is which.
+CREATE TABLE IF NOT EXISTS api_key (
+    id BLOB PRIMARY KEY NOT NULL CHECK (length(id) == 16),
+    owner BLOB NOT NULL CHECK (length(owner) == 16),
+    name TEXT NOT NULL,
+    hash BLOB UNIQUE NOT NULL CHECK (length(hash) == 32),
+    scopes INTEGER NOT NULL,
+    created INTEGER NOT NULL,
+    last_used INTEGER NULL,
+    FOREIGN KEY(owner) REFERENCES user(id)
+) STRICT;
+CREATE INDEX IF NOT EXISTS api_key_owner ON api_key (owner);
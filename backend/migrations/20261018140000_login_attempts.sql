@@ -0,0 +1,12 @@
+-- Failed login attempts for brute force protection
+-- NOTES:
+-- kind is what the attempt is counted against, either an ip address or a username.
+-- rows are forgotten about after a while, see `ratelimit.rs`.
+CREATE TABLE IF NOT EXISTS login_attempt (
+    kind TEXT NOT NULL CHECK (kind IN ('ip', 'username')),
+    key TEXT NOT NULL,
+    failures INTEGER NOT NULL CHECK (failures >= 0),
+    last_failure INTEGER NOT NULL,
+    locked_until INTEGER NOT NULL,
+    PRIMARY KEY(kind, key)
+) STRICT;
@@ -0,0 +1,10 @@
+-- Server side keys for signing things that are handed out to clients
+-- NOTES:
+-- "stream" signs stream links. to invalidate every stream link, set its secret
+-- to a new randomblob(64).
+CREATE TABLE IF NOT EXISTS signing_key (
+    name TEXT PRIMARY KEY NOT NULL,
+    secret BLOB NOT NULL CHECK (length(secret) >= 32)
+) STRICT;
+INSERT INTO signing_key (name, secret)
+VALUES ('stream', randomblob(64));
@@ -0,0 +1,7 @@
+-- Per user storage quotas
+-- NOTES:
+-- NULL means that there is no limit
+ALTER TABLE user
+ADD COLUMN quota_bytes INTEGER NULL CHECK (quota_bytes >= 0);
+ALTER TABLE user
+ADD COLUMN quota_tracks INTEGER NULL CHECK (quota_tracks >= 0);
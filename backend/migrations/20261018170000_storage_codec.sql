@@ -0,0 +1,10 @@
+-- Codec that tracks are stored in
+-- NOTES:
+-- bitrate is in kbps, and is NULL for lossless codecs. container is the file
+-- extension of the stored file, which for "original" comes from the upload.
+ALTER TABLE track
+ADD COLUMN codec TEXT NOT NULL DEFAULT 'qoa' CHECK (codec IN ('original', 'flac', 'opus', 'qoa'));
+ALTER TABLE track
+ADD COLUMN bitrate INTEGER NULL CHECK (bitrate > 0);
+ALTER TABLE track
+ADD COLUMN container TEXT NOT NULL DEFAULT 'qoa';
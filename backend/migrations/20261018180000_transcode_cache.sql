@@ -0,0 +1,13 @@
+-- Transcoded streams that are kept on disk
+-- NOTES:
+-- bitrate is 0 for lossless codecs so that it can be part of the key. files live
+-- in DATA_DIR/cache, see `cache.rs`. rows must be removed before their track is.
+CREATE TABLE IF NOT EXISTS transcode_cache (
+    track BLOB NOT NULL,
+    codec TEXT NOT NULL CHECK (codec IN ('flac', 'opus')),
+    bitrate INTEGER NOT NULL CHECK (bitrate >= 0),
+    size INTEGER NOT NULL CHECK (size >= 0),
+    last_used INTEGER NOT NULL,
+    PRIMARY KEY(track, codec, bitrate),
+    FOREIGN KEY(track) REFERENCES track(id)
+) STRICT;
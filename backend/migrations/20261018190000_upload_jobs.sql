@@ -0,0 +1,19 @@
+-- Queue of uploads waiting to be processed
+-- NOTES:
+-- the id is also the id of the track that gets created, and the uploaded file sits
+-- at DATA_DIR/owner/dir/id until then. jobs that were running when the server
+-- stopped are queued again on startup.
+CREATE TABLE IF NOT EXISTS upload_job (
+    id BLOB PRIMARY KEY NOT NULL,
+    owner BLOB NOT NULL,
+    state TEXT NOT NULL CHECK (state IN ('queued', 'decoding', 'embedding', 'done', 'failed')),
+    error TEXT NULL,
+    dir TEXT NOT NULL,
+    orig_fname TEXT NOT NULL,
+    codec TEXT NOT NULL CHECK (codec IN ('original', 'flac', 'opus', 'qoa')),
+    bitrate INTEGER NULL CHECK (bitrate > 0),
+    container TEXT NOT NULL,
+    created INTEGER NOT NULL,
+    updated INTEGER NOT NULL,
+    FOREIGN KEY(owner) REFERENCES user(id)
+) STRICT;
@@ -0,0 +1,19 @@
+-- Resumable uploads that are still being sent
+-- NOTES:
+-- the id becomes the track id, and the partial file sits at DATA_DIR/owner/dir/id
+-- just like a finished upload. sessions that haven't been touched in a while are
+-- removed along with their file, see `endpoints/upload.rs`.
+CREATE TABLE IF NOT EXISTS upload_session (
+    id BLOB PRIMARY KEY NOT NULL,
+    owner BLOB NOT NULL,
+    dir TEXT NOT NULL,
+    orig_fname TEXT NOT NULL,
+    codec TEXT NOT NULL CHECK (codec IN ('original', 'flac', 'opus', 'qoa')),
+    bitrate INTEGER NULL CHECK (bitrate > 0),
+    container TEXT NOT NULL,
+    size INTEGER NULL CHECK (size >= 0),
+    received INTEGER NOT NULL CHECK (received >= 0),
+    created INTEGER NOT NULL,
+    updated INTEGER NOT NULL,
+    FOREIGN KEY(owner) REFERENCES user(id)
+) STRICT;
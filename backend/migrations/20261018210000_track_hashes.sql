@@ -0,0 +1,18 @@
+-- Hashes for finding duplicate tracks
+-- NOTES:
+-- file_hash is the sha256 of the file as it was uploaded, and audio_hash is the
+-- sha256 of the decoded pcm. both are NULL for tracks uploaded before this. an
+-- upload_job's track is the track the upload ended up as, which is an existing
+-- track if the upload was skipped as a duplicate.
+ALTER TABLE track
+ADD COLUMN file_hash BLOB NULL CHECK (length(file_hash) == 32);
+ALTER TABLE track
+ADD COLUMN audio_hash BLOB NULL CHECK (length(audio_hash) == 32);
+CREATE INDEX IF NOT EXISTS track_file_hash ON track (owner, file_hash);
+CREATE INDEX IF NOT EXISTS track_audio_hash ON track (owner, audio_hash);
+ALTER TABLE upload_job
+ADD COLUMN duplicates TEXT NOT NULL DEFAULT 'allow' CHECK (duplicates IN ('reject', 'skip', 'allow'));
+ALTER TABLE upload_job
+ADD COLUMN track BLOB NULL;
+ALTER TABLE upload_session
+ADD COLUMN duplicates TEXT NOT NULL DEFAULT 'allow' CHECK (duplicates IN ('reject', 'skip', 'allow'));
@@ -0,0 +1,9 @@
+-- Originals of transcoded uploads
+-- NOTES:
+-- when KEEP_ORIGINALS is set, the upload is kept at DATA_DIR/owner/path/id.orig
+-- next to the storage copy. tracks stored as "original" are their own original. the
+-- hash of the original is file_hash. both are NULL when there's no original.
+ALTER TABLE track
+ADD COLUMN orig_size INTEGER NULL CHECK (orig_size >= 0);
+ALTER TABLE track
+ADD COLUMN orig_mime TEXT NULL;
@@ -0,0 +1,32 @@
+-- Imports of music that is already on the server
+-- NOTES:
+-- an import walks source, a folder on the server, and queues every audio file in it
+-- as an upload_job for owner, with the folders under source mirrored under dir.
+-- total is NULL until source has been walked. every file the import has gotten to
+-- is in import_file, by its path under source, so that an import that was
+-- interrupted picks up where it left off. job is NULL when the file couldn't be
+-- queued, with error saying why.
+CREATE TABLE IF NOT EXISTS import (
+    id BLOB PRIMARY KEY NOT NULL,
+    owner BLOB NOT NULL,
+    source TEXT NOT NULL,
+    dir TEXT NOT NULL,
+    codec TEXT NOT NULL CHECK (codec IN ('original', 'flac', 'opus', 'qoa')),
+    bitrate INTEGER NULL CHECK (bitrate > 0),
+    duplicates TEXT NOT NULL CHECK (duplicates IN ('reject', 'skip', 'allow')),
+    state TEXT NOT NULL CHECK (state IN ('scanning', 'done', 'failed')),
+    error TEXT NULL,
+    total INTEGER NULL CHECK (total >= 0),
+    created INTEGER NOT NULL,
+    updated INTEGER NOT NULL,
+    FOREIGN KEY(owner) REFERENCES user(id)
+) STRICT;
+
+CREATE TABLE IF NOT EXISTS import_file (
+    import BLOB NOT NULL,
+    path TEXT NOT NULL,
+    job BLOB NULL,
+    error TEXT NULL,
+    PRIMARY KEY(import, path),
+    FOREIGN KEY(import) REFERENCES import(id)
+) STRICT;
@@ -0,0 +1,8 @@
+-- Uploads from inbox folders
+-- NOTES:
+-- inbox is where the upload was under DATA_DIR/inbox/owner when it was dropped in,
+-- and is NULL for anything that was uploaded some other way. when an upload from an
+-- inbox fails, it's moved to the same place under DATA_DIR/quarantine/owner rather
+-- than being removed.
+ALTER TABLE upload_job
+ADD COLUMN inbox TEXT NULL;
@@ -0,0 +1,37 @@
+-- Track artists
+-- NOTES:
+-- everyone credited on a track is in JOIN_track_artist along with what they did,
+-- ordered by position. track.artist stays as the first primary artist.
+-- album_artist is who the track's album is credited to.
+-- album artists and composers used to be kept with the extra tags, so they're
+-- moved out of them.
+CREATE TABLE IF NOT EXISTS JOIN_track_artist (
+    track BLOB NOT NULL,
+    artist BLOB NOT NULL,
+    role TEXT NOT NULL CHECK (role IN ('primary', 'featured', 'remixer', 'composer')),
+    position INTEGER NOT NULL,
+    PRIMARY KEY (track, artist, role),
+    FOREIGN KEY(track) REFERENCES track(id),
+    FOREIGN KEY(artist) REFERENCES artist(id)
+) STRICT;
+CREATE INDEX IF NOT EXISTS JOIN_track_artist_artist ON JOIN_track_artist (artist);
+ALTER TABLE track ADD COLUMN album_artist BLOB NULL REFERENCES artist(id);
+INSERT INTO JOIN_track_artist (track, artist, role, position)
+SELECT id, artist, 'primary', 0 FROM track WHERE artist IS NOT NULL;
+
+INSERT OR IGNORE INTO artist (id, artist_name, sort_name)
+SELECT randomblob(16), json_extract(tags, '$."album-artist"'), NULL FROM track
+WHERE json_extract(tags, '$."album-artist"') IS NOT NULL;
+UPDATE track SET album_artist = (
+    SELECT id FROM artist WHERE artist_name = json_extract(track.tags, '$."album-artist"')
+), tags = json_remove(tags, '$."album-artist"')
+WHERE json_extract(tags, '$."album-artist"') IS NOT NULL;
+
+INSERT OR IGNORE INTO artist (id, artist_name, sort_name)
+SELECT randomblob(16), json_extract(tags, '$.composer'), NULL FROM track
+WHERE json_extract(tags, '$.composer') IS NOT NULL;
+INSERT OR IGNORE INTO JOIN_track_artist (track, artist, role, position)
+SELECT track.id, artist.id, 'composer', 1 FROM track
+JOIN artist ON artist.artist_name = json_extract(track.tags, '$.composer');
+UPDATE track SET tags = json_remove(tags, '$.composer')
+WHERE json_extract(tags, '$.composer') IS NOT NULL;
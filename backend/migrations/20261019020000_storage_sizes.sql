@@ -0,0 +1,10 @@
+-- Sizes of stored files
+-- NOTES:
+-- size is how many bytes the stored copy of a track takes up, so that quotas don't
+-- need to look through the disk. tracks from before this are NULL until their size
+-- is filled in on startup. upload jobs record how big the upload waiting to be
+-- processed is.
+ALTER TABLE track
+ADD COLUMN size INTEGER NULL CHECK (size >= 0);
+ALTER TABLE upload_job
+ADD COLUMN size INTEGER NOT NULL DEFAULT 0 CHECK (size >= 0);
@@ -0,0 +1,7 @@
+-- Linked imports
+-- NOTES:
+-- imports copy files into the library unless link is set, where they're hard linked
+-- to the source instead. a linked file is the same file as the source, so anything
+-- that changes the source afterwards changes the library too.
+ALTER TABLE import
+ADD COLUMN link INTEGER NOT NULL DEFAULT FALSE CHECK (link IN (FALSE, TRUE));
@@ -0,0 +1,689 @@
+use crate::db::{uuid_serialize, write_transaction};
+use crate::endpoints::check_dir_in_data_dir;
+use crate::endpoints::track_manage::upload_format;
+use crate::quota;
+use crate::subtasks::import;
+use crate::user::{create_user, hash_password, RequireAdmin};
+use crate::{MioInnerError, MioState};
+use anyhow::anyhow;
+use axum::extract::{Query, State};
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use axum::routing::*;
+use axum::{middleware, Extension, Json, Router};
+use chrono::Utc;
+#[allow(unused)]
+use log::*;
+use mio_protocol::*;
+use std::path::PathBuf;
+use uuid::Uuid;
+
+const INVITE_CODE_LEN: usize = 16;
+
+pub fn routes(state: MioState) -> Router<MioState> {
+    Router::new()
+        .route("/users", get(list_users).post(add_user).patch(update_user))
+        .route("/users/password", patch(reset_password))
+        .route("/users/storage", get(storage_usage))
+        .route("/users/quota", patch(set_quota))
+        .route("/cache", get(cache_stats))
+        .route("/import", get(list_imports).post(start_import))
+        .route("/import/status", get(import_status))
+        .route(
+            "/invites",
+            get(list_invites).post(create_invite).delete(revoke_invite),
+        )
+        .route_layer(middleware::from_extractor_with_state::<RequireAdmin, _>(
+            state,
+        ))
+}
+
+#[tracing::instrument]
+async fn list_users(State(state): State<MioState>) -> Result<impl IntoResponse, MioInnerError> {
+    let mut conn = state.db.acquire().await?;
+    let users =
+        sqlx::query!("SELECT id, username, admin, disabled, invite FROM user ORDER BY username;")
+            .fetch_all(&mut *conn)
+            .await?
+            .into_iter()
+            .map(|x| {
+                Ok(retstructs::User {
+                    id: uuid_serialize(&x.id)?,
+                    username: x.username,
+                    admin: x.admin != 0,
+                    disabled: x.disabled != 0,
+                    invite: x.invite.map(|x| uuid_serialize(&x)).transpose()?,
+                })
+            })
+            .collect::<Result<_, MioInnerError>>()?;
+    Ok((StatusCode::OK, Json(retstructs::Users { users })))
+}
+
+// create a user, regardless of if signup is enabled
+#[tracing::instrument]
+async fn add_user(
+    State(state): State<MioState>,
+    Json(msgstructs::AdminUserCreate {
+        username,
+        password,
+        admin,
+    }): Json<msgstructs::AdminUserCreate>,
+) -> Result<impl IntoResponse, MioInnerError> {
+    let phc_string = hash_password(password);
+    let mut conn = state.db.acquire().await?;
+    write_transaction(&mut conn, |txn| {
+        Box::pin(async move {
+            let id = create_user(&mut *txn, username.clone(), phc_string, admin, None).await?;
+            info!("POST /admin/users created user {username} ({id})");
+            Ok((
+                StatusCode::OK,
+                Json(retstructs::User {
+                    id,
+                    username,
+                    admin,
+                    disabled: false,
+                    invite: None,
+                }),
+            ))
+        })
+    })
+    .await
+}
+
+// make sure the admin set at startup exists. someone who already has the username is
+// made an admin and let back in instead, and their password is left alone.
+pub(crate) async fn bootstrap(
+    state: &MioState,
+    username: String,
+    password: String,
+) -> Result<(), MioInnerError> {
+    let mut conn = state.db.acquire().await?;
+    let existing = sqlx::query!("SELECT id FROM user WHERE username = ?;", username)
+        .fetch_optional(&mut *conn)
+        .await?;
+    let phc_string = existing.is_none().then(|| hash_password(password));
+    write_transaction(&mut conn, |txn| {
+        Box::pin(async move {
+            match phc_string {
+                Some(phc_string) => {
+                    let id =
+                        create_user(&mut *txn, username.clone(), phc_string, true, None).await?;
+                    info!("BOOTSTRAP created admin {username} ({id})");
+                }
+                None => {
+                    sqlx::query!(
+                        "UPDATE user SET admin = TRUE, disabled = FALSE WHERE username = ?;",
+                        username
+                    )
+                    .execute(&mut *txn)
+                    .await?;
+                    info!("BOOTSTRAP made {username} an admin");
+                }
+            }
+            Ok(())
+        })
+    })
+    .await
+}
+
+// promote, demote, disable, or enable a user
+#[tracing::instrument]
+async fn update_user(
+    State(state): State<MioState>,
+    Extension(auth::JWTInner { userid, .. }): Extension<auth::JWTInner>,
+    Json(msgstructs::AdminUserUpdate {
+        id,
+        admin,
+        disabled,
+    }): Json<msgstructs::AdminUserUpdate>,
+) -> Result<impl IntoResponse, MioInnerError> {
+    // this stops the server from ending up with no admins by accident
+    if id == userid && (admin == Some(false) || disabled == Some(true)) {
+        return Err(MioInnerError::Conflict(anyhow!(
+            "admins cannot demote or disable themselves"
+        )));
+    }
+    let mut conn = state.db.acquire().await?;
+    write_transaction(&mut conn, |txn| {
+        Box::pin(async move {
+            if sqlx::query!("SELECT id FROM user WHERE id = ?;", id)
+                .fetch_optional(&mut *txn)
+                .await?
+                .is_none()
+            {
+                return Err(MioInnerError::NotFound(anyhow!("user {id} does not exist")));
+            }
+            if let Some(admin) = admin {
+                sqlx::query!("UPDATE user SET admin = ? WHERE id = ?;", admin, id)
+                    .execute(&mut *txn)
+                    .await?;
+            }
+            if let Some(disabled) = disabled {
+                sqlx::query!("UPDATE user SET disabled = ? WHERE id = ?;", disabled, id)
+                    .execute(&mut *txn)
+                    .await?;
+
+                // a disabled user should be logged out everywhere
+                if disabled {
+                    sqlx::query!("DELETE FROM auth_keys WHERE id = ?;", id)
+                        .execute(&mut *txn)
+                        .await?;
+                }
+            }
+            info!("PATCH /admin/users updated {id}: admin {admin:?}, disabled {disabled:?}");
+            Ok(StatusCode::OK)
+        })
+    })
+    .await
+}
+
+// force a new password onto a user, logging them out everywhere
+#[tracing::instrument]
+async fn reset_password(
+    State(state): State<MioState>,
+    Json(msgstructs::AdminPasswordReset { id, new_password }): Json<msgstructs::AdminPasswordReset>,
+) -> Result<impl IntoResponse, MioInnerError> {
+    let phc_string = hash_password(new_password);
+    let mut conn = state.db.acquire().await?;
+    write_transaction(&mut conn, |txn| {
+        Box::pin(async move {
+            let phc_string = phc_string.await??;
+            if sqlx::query!("UPDATE user SET password = ? WHERE id = ?;", phc_string, id)
+                .execute(&mut *txn)
+                .await?
+                .rows_affected()
+                == 0
+            {
+                return Err(MioInnerError::NotFound(anyhow!("user {id} does not exist")));
+            }
+            sqlx::query!("DELETE FROM auth_keys WHERE id = ?;", id)
+                .execute(&mut *txn)
+                .await?;
+            info!("PATCH /admin/users/password reset password for {id}");
+            Ok(StatusCode::OK)
+        })
+    })
+    .await
+}
+
+#[tracing::instrument]
+async fn storage_usage(
+    State(state): State<MioState>,
+    Query(msgstructs::IdInfoQuery { id }): Query<msgstructs::IdInfoQuery>,
+) -> Result<impl IntoResponse, MioInnerError> {
+    let mut conn = state.db.acquire().await?;
+    Ok((StatusCode::OK, Json(quota::usage(&mut conn, id).await?)))
+}
+
+#[tracing::instrument]
+async fn cache_stats(State(state): State<MioState>) -> Result<impl IntoResponse, MioInnerError> {
+    let mut conn = state.db.acquire().await?;
+    Ok((StatusCode::OK, Json(crate::cache::stats(&mut conn).await?)))
+}
+
+#[tracing::instrument]
+async fn set_quota(
+    State(state): State<MioState>,
+    Json(msgstructs::AdminQuotaSet { id, bytes, tracks }): Json<msgstructs::AdminQuotaSet>,
+) -> Result<impl IntoResponse, MioInnerError> {
+    let bytes = bytes
+        .map(i64::try_from)
+        .transpose()
+        .map_err(|_| MioInnerError::Conflict(anyhow!("byte quota is too large")))?;
+    if tracks.is_some_and(|x| x < 0) {
+        return Err(MioInnerError::Conflict(anyhow!(
+            "track quota cannot be negative"
+        )));
+    }
+    let mut conn = state.db.acquire().await?;
+    if sqlx::query!(
+        "UPDATE user SET quota_bytes = ?, quota_tracks = ? WHERE id = ?;",
+        bytes,
+        tracks,
+        id
+    )
+    .execute(&mut *conn)
+    .await?
+    .rows_affected()
+        == 0
+    {
+        return Err(MioInnerError::NotFound(anyhow!("user {id} does not exist")));
+    }
+    info!("PATCH /admin/users/quota set quota of {id} to {bytes:?} bytes, {tracks:?} tracks");
+    Ok(StatusCode::OK)
+}
+
+// import a folder on the server into a user's library. this only starts the import,
+// which is run in the background.
+#[tracing::instrument]
+async fn start_import(
+    State(state): State<MioState>,
+    Json(msgstructs::AdminImport {
+        source,
+        user,
+        dir,
+        codec,
+        bitrate,
+        duplicates,
+        link,
+    }): Json<msgstructs::AdminImport>,
+) -> Result<impl IntoResponse, MioInnerError> {
+    let format = upload_format(codec, bitrate, None)?;
+    let source = PathBuf::from(source);
+    if !source.is_absolute() {
+        return Err(MioInnerError::ExternalIoError(
+            anyhow!("{source:?} is not an absolute path"),
+            StatusCode::BAD_REQUEST,
+        ));
+    }
+    if !tokio::fs::metadata(&source).await.is_ok_and(|x| x.is_dir()) {
+        return Err(MioInnerError::NotFound(anyhow!(
+            "{source:?} is not a folder"
+        )));
+    }
+    check_dir_in_data_dir(&dir, user)?;
+    let mut conn = state.db.acquire().await?;
+    if sqlx::query!("SELECT id FROM user WHERE id = ?;", user)
+        .fetch_optional(&mut *conn)
+        .await?
+        .is_none()
+    {
+        return Err(MioInnerError::NotFound(anyhow!(
+            "user {user} does not exist"
+        )));
+    }
+    let id = Uuid::new_v4();
+    info!("POST /admin/import importing {source:?} into {dir:?} for {user} as {id}");
+    import::start(
+        &mut conn,
+        import::NewImport {
+            id,
+            owner: user,
+            source,
+            dir,
+            codec: format.codec,
+            bitrate: format.bitrate,
+            duplicates: duplicates.unwrap_or_default(),
+            link: link.unwrap_or(false),
+        },
+    )
+    .await?;
+    Ok((
+        StatusCode::ACCEPTED,
+        Json(import::report(&mut conn, id).await?),
+    ))
+}
+
+#[tracing::instrument]
+async fn import_status(
+    State(state): State<MioState>,
+    Query(msgstructs::IdInfoQuery { id }): Query<msgstructs::IdInfoQuery>,
+) -> Result<impl IntoResponse, MioInnerError> {
+    let mut conn = state.db.acquire().await?;
+    Ok((StatusCode::OK, Json(import::report(&mut conn, id).await?)))
+}
+
+#[tracing::instrument]
+async fn list_imports(State(state): State<MioState>) -> Result<impl IntoResponse, MioInnerError> {
+    let mut conn = state.db.acquire().await?;
+    let ids = sqlx::query!("SELECT id FROM import ORDER BY created;")
+        .fetch_all(&mut *conn)
+        .await?;
+    let mut imports = vec![];
+    for x in ids {
+        imports.push(import::report(&mut conn, uuid_serialize(&x.id)?).await?);
+    }
+    Ok((StatusCode::OK, Json(retstructs::Imports { imports })))
+}
+
+#[tracing::instrument]
+async fn list_invites(State(state): State<MioState>) -> Result<impl IntoResponse, MioInnerError> {
+    let mut conn = state.db.acquire().await?;
+    let invites = sqlx::query!(
+        "SELECT id, code, creator, uses_left, expiry, created FROM invite ORDER BY created;"
+    )
+    .fetch_all(&mut *conn)
+    .await?
+    .into_iter()
+    .map(|x| {
+        Ok(retstructs::Invite {
+            id: uuid_serialize(&x.id)?,
+            code: x.code,
+            creator: x.creator.map(|x| uuid_serialize(&x)).transpose()?,
+            uses_left: x.uses_left,
+            expiry: x.expiry,
+            created: x.created,
+        })
+    })
+    .collect::<Result<_, MioInnerError>>()?;
+    Ok((StatusCode::OK, Json(retstructs::Invites { invites })))
+}
+
+#[tracing::instrument]
+async fn create_invite(
+    State(state): State<MioState>,
+    Extension(auth::JWTInner { userid, .. }): Extension<auth::JWTInner>,
+    Json(msgstructs::InviteCreate { uses, lifetime }): Json<msgstructs::InviteCreate>,
+) -> Result<impl IntoResponse, MioInnerError> {
+    if uses <= 0 || lifetime <= 0 {
+        return Err(MioInnerError::UserCreationFail(
+            anyhow!("an invite needs at least one use and a lifetime"),
+            StatusCode::BAD_REQUEST,
+        ));
+    }
+    let id = Uuid::new_v4();
+    let code = {
+        use rand::distributions::Alphanumeric;
+        use rand::Rng;
+
+        rand::thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(INVITE_CODE_LEN)
+            .map(char::from)
+            .collect::<String>()
+    };
+    let created = Utc::now().timestamp();
+    let expiry = created.saturating_add(lifetime);
+    let mut conn = state.db.acquire().await?;
+    sqlx::query!(
+        "INSERT INTO invite (id, code, creator, uses_left, expiry, created) 
+        VALUES (?,?,?,?,?,?);",
+        id,
+        code,
+        userid,
+        uses,
+        expiry,
+        created
+    )
+    .execute(&mut *conn)
+    .await?;
+    info!("POST /admin/invites {userid} created invite {id} with {uses} uses");
+    Ok((
+        StatusCode::OK,
+        Json(retstructs::Invite {
+            id,
+            code,
+            creator: Some(userid),
+            uses_left: uses,
+            expiry,
+            created,
+        }),
+    ))
+}
+
+// invites are kept around after being revoked, as accounts remember which invite
+// created them
+#[tracing::instrument]
+async fn revoke_invite(
+    State(state): State<MioState>,
+    Query(msgstructs::DeleteQuery { id }): Query<msgstructs::DeleteQuery>,
+) -> Result<impl IntoResponse, MioInnerError> {
+    let mut conn = state.db.acquire().await?;
+    if sqlx::query!("UPDATE invite SET uses_left = 0 WHERE id = ?;", id)
+        .execute(&mut *conn)
+        .await?
+        .rows_affected()
+        == 0
+    {
+        return Err(MioInnerError::NotFound(anyhow!(
+            "invite {id} does not exist"
+        )));
+    }
+    info!("DELETE /admin/invites revoked invite {id}");
+    Ok(StatusCode::OK)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::test::*;
+    use axum::http::{HeaderName, Method};
+    use axum_extra::headers::{authorization::Credentials, Authorization};
+    use mio_protocol::*;
+
+    #[tokio::test]
+    async fn admin_bad_not_admin() {
+        let cli = client().await;
+
+        let jwt = gen_user(&cli, "admin_bad_not_admin").await;
+        jwt_header(&cli, Method::GET, "/admin/users", &jwt)
+            .expect_failure()
+            .await;
+    }
+
+    #[tokio::test]
+    async fn admin_bootstrap_good() {
+        let cli = client().await;
+        let login = |username: &'static str, password: &'static str| {
+            cli.get("/user/login").add_header(
+                HeaderName::from_static("authorization"),
+                Authorization::basic(username, password).0.encode(),
+            )
+        };
+
+        // a new admin
+        super::bootstrap(
+            &STATE,
+            "admin_bootstrap_good".to_owned(),
+            "secret".to_owned(),
+        )
+        .await
+        .unwrap();
+        let jwt = login("admin_bootstrap_good", "secret")
+            .await
+            .json::<auth::JWT>();
+        jwt_header(&cli, Method::GET, "/admin/users", &jwt).await;
+
+        // signing up doesn't make anyone an admin
+        let user_jwt = gen_user(&cli, "admin_bootstrap_good_2").await;
+        jwt_header(&cli, Method::GET, "/admin/users", &user_jwt)
+            .expect_failure()
+            .await;
+
+        // an existing user keeps their password
+        super::bootstrap(
+            &STATE,
+            "admin_bootstrap_good_2".to_owned(),
+            "other".to_owned(),
+        )
+        .await
+        .unwrap();
+        login("admin_bootstrap_good_2", "other")
+            .expect_failure()
+            .await;
+        jwt_header(&cli, Method::GET, "/admin/users", &user_jwt).await;
+    }
+
+    #[tokio::test]
+    async fn admin_create_user_good() {
+        let cli = client().await;
+        let jwt = gen_admin(&cli, "admin_create_user_good").await;
+        let user = jwt_header(&cli, Method::POST, "/admin/users", &jwt)
+            .json(&msgstructs::AdminUserCreate {
+                username: "admin_create_user_good_2".to_owned(),
+                password: "password".to_owned(),
+                admin: false,
+            })
+            .await
+            .json::<retstructs::User>();
+        assert!(jwt_header(&cli, Method::GET, "/admin/users", &jwt)
+            .await
+            .json::<retstructs::Users>()
+            .users
+            .contains(&user));
+        cli.get("/user/login")
+            .add_header(
+                HeaderName::from_static("authorization"),
+                Authorization::basic("admin_create_user_good_2", "password")
+                    .0
+                    .encode(),
+            )
+            .await;
+    }
+
+    #[tokio::test]
+    async fn admin_disable_user_good() {
+        let cli = client().await;
+        let jwt = gen_admin(&cli, "admin_disable_user_good").await;
+        let user_jwt = gen_user(&cli, "admin_disable_user_good_2").await;
+        let id = user_jwt.whois().unwrap().userid;
+        let update = |disabled| msgstructs::AdminUserUpdate {
+            id,
+            admin: None,
+            disabled: Some(disabled),
+        };
+        jwt_header(&cli, Method::PATCH, "/admin/users", &jwt)
+            .json(&update(true))
+            .await;
+        jwt_header(&cli, Method::GET, "/api/auth_test", &user_jwt)
+            .expect_failure()
+            .await;
+        let login = || {
+            cli.get("/user/login").add_header(
+                HeaderName::from_static("authorization"),
+                Authorization::basic("admin_disable_user_good_2", "password")
+                    .0
+                    .encode(),
+            )
+        };
+        login().expect_failure().await;
+        jwt_header(&cli, Method::PATCH, "/admin/users", &jwt)
+            .json(&update(false))
+            .await;
+        login().await;
+    }
+
+    #[tokio::test]
+    async fn admin_disable_user_bad_self() {
+        let cli = client().await;
+        let jwt = gen_admin(&cli, "admin_disable_user_bad_self").await;
+        jwt_header(&cli, Method::PATCH, "/admin/users", &jwt)
+            .json(&msgstructs::AdminUserUpdate {
+                id: jwt.whois().unwrap().userid,
+                admin: None,
+                disabled: Some(true),
+            })
+            .expect_failure()
+            .await;
+    }
+
+    #[tokio::test]
+    async fn admin_reset_password_good() {
+        let cli = client().await;
+        let jwt = gen_admin(&cli, "admin_reset_password_good").await;
+        let user_jwt = gen_user(&cli, "admin_reset_password_good_2").await;
+        jwt_header(&cli, Method::PATCH, "/admin/users/password", &jwt)
+            .json(&msgstructs::AdminPasswordReset {
+                id: user_jwt.whois().unwrap().userid,
+                new_password: "hunter2".to_owned(),
+            })
+            .await;
+        jwt_header(&cli, Method::GET, "/api/auth_test", &user_jwt)
+            .expect_failure()
+            .await;
+        cli.get("/user/login")
+            .add_header(
+                HeaderName::from_static("authorization"),
+                Authorization::basic("admin_reset_password_good_2", "hunter2")
+                    .0
+                    .encode(),
+            )
+            .await;
+    }
+
+    // util function to sign up with an invite
+    async fn signup_invite(
+        cli: &axum_test::TestServer,
+        username: &str,
+        code: &str,
+    ) -> axum_test::TestResponse {
+        cli.post(&format!("/user/signup?invite={code}"))
+            .add_header(
+                HeaderName::from_static("authorization"),
+                Authorization::basic(username, "password").0.encode(),
+            )
+            .expect_success()
+            .await
+    }
+
+    #[tokio::test]
+    async fn admin_invite_good() {
+        let cli = client().await;
+        let jwt = gen_admin(&cli, "admin_invite_good").await;
+        let invite = jwt_header(&cli, Method::POST, "/admin/invites", &jwt)
+            .json(&msgstructs::InviteCreate {
+                uses: 1,
+                lifetime: 60,
+            })
+            .await
+            .json::<retstructs::Invite>();
+        signup_invite(&cli, "admin_invite_good_2", &invite.code).await;
+
+        // the account remembers the invite, and the invite is used up
+        let users = jwt_header(&cli, Method::GET, "/admin/users", &jwt)
+            .await
+            .json::<retstructs::Users>()
+            .users;
+        assert_eq!(
+            users
+                .iter()
+                .find(|x| x.username == "admin_invite_good_2")
+                .unwrap()
+                .invite,
+            Some(invite.id)
+        );
+        cli.post(&format!("/user/signup?invite={}", invite.code))
+            .add_header(
+                HeaderName::from_static("authorization"),
+                Authorization::basic("admin_invite_good_3", "password")
+                    .0
+                    .encode(),
+            )
+            .expect_failure()
+            .await;
+    }
+
+    #[tokio::test]
+    async fn admin_invite_bad_revoked() {
+        let cli = client().await;
+        let jwt = gen_admin(&cli, "admin_invite_bad_revoked").await;
+        let invite = jwt_header(&cli, Method::POST, "/admin/invites", &jwt)
+            .json(&msgstructs::InviteCreate {
+                uses: 5,
+                lifetime: 60,
+            })
+            .await
+            .json::<retstructs::Invite>();
+        jwt_header(
+            &cli,
+            Method::DELETE,
+            &format!("/admin/invites?id={}", invite.id),
+            &jwt,
+        )
+        .await;
+        cli.post(&format!("/user/signup?invite={}", invite.code))
+            .add_header(
+                HeaderName::from_static("authorization"),
+                Authorization::basic("admin_invite_bad_revoked_2", "password")
+                    .0
+                    .encode(),
+            )
+            .expect_failure()
+            .await;
+    }
+
+    #[tokio::test]
+    async fn admin_storage_good() {
+        let cli = client().await;
+        let jwt = gen_admin(&cli, "admin_storage_good").await;
+        let id = jwt.whois().unwrap().userid;
+        let usage = jwt_header(
+            &cli,
+            Method::GET,
+            &format!("/admin/users/storage?id={id}"),
+            &jwt,
+        )
+        .await
+        .json::<retstructs::StorageUsage>();
+        assert_eq!(usage.tracks, 0);
+    }
+}
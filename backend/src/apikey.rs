@@ -0,0 +1,368 @@
+use crate::db::{uuid_serialize, write_transaction};
+use crate::{MioInnerError, MioState};
+use anyhow::anyhow;
+use axum::extract::{Query, Request, State};
+use axum::http::StatusCode;
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use axum::{Extension, Json};
+use chrono::Utc;
+#[allow(unused)]
+use log::*;
+use mio_protocol::*;
+use sha2::{Digest, Sha256};
+use sqlx::SqliteConnection;
+use uuid::Uuid;
+
+// all api keys start with this, which is how they are told apart from JWTs
+pub(crate) const KEY_PREFIX: &str = "mio_";
+const KEY_LEN: usize = 48;
+
+// the api key that authenticated the request. not present for password sessions.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct CurrentApiKey {
+    pub id: Uuid,
+    pub scopes: Vec<auth::Scope>,
+}
+
+// these are stored in the db, so do not reorder them
+fn scope_bit(scope: auth::Scope) -> i64 {
+    match scope {
+        auth::Scope::ReadLibrary => 1 << 0,
+        auth::Scope::Stream => 1 << 1,
+        auth::Scope::Upload => 1 << 2,
+        auth::Scope::ManageFolders => 1 << 3,
+    }
+}
+
+fn scopes_from_bits(bits: i64) -> Vec<auth::Scope> {
+    auth::Scope::ALL
+        .into_iter()
+        .filter(|x| bits & scope_bit(*x) != 0)
+        .collect()
+}
+
+fn hash_key(key: &str) -> Vec<u8> {
+    Sha256::digest(key.as_bytes()).to_vec()
+}
+
+// look up the owner of a api key. disabled users cannot use their keys.
+pub(crate) async fn authenticate(
+    conn: &mut SqliteConnection,
+    raw_key: &str,
+) -> Result<(Uuid, CurrentApiKey), MioInnerError> {
+    let hash = hash_key(raw_key);
+    let key = sqlx::query!(
+        "SELECT api_key.id, owner, scopes, last_used
+        FROM api_key
+        JOIN user ON user.id = api_key.owner
+        WHERE hash = ? AND user.disabled = FALSE;",
+        hash
+    )
+    .fetch_optional(&mut *conn)
+    .await?
+    .ok_or_else(|| {
+        MioInnerError::UserChallengedFail(anyhow!("Invalid api key"), StatusCode::UNAUTHORIZED)
+    })?;
+
+    // like sessions, usage only needs to be roughly right
+    let now = Utc::now().timestamp();
+    if key.last_used.is_none_or(|x| x < now - 60) {
+        let id = key.id.clone();
+        write_transaction(conn, |txn| {
+            Box::pin(async move {
+                sqlx::query!("UPDATE api_key SET last_used = ? WHERE id = ?;", now, id)
+                    .execute(&mut *txn)
+                    .await?;
+                Ok(())
+            })
+        })
+        .await?;
+    }
+    Ok((
+        uuid_serialize(&key.owner)?,
+        CurrentApiKey {
+            id: uuid_serialize(&key.id)?,
+            scopes: scopes_from_bits(key.scopes),
+        },
+    ))
+}
+
+// middleware that rejects api keys without the scope in the state. password
+// sessions are always let through.
+pub(crate) async fn check_scope(
+    State(scope): State<auth::Scope>,
+    req: Request,
+    next: Next,
+) -> Result<Response, MioInnerError> {
+    if let Some(key) = req.extensions().get::<CurrentApiKey>() {
+        if !key.scopes.contains(&scope) {
+            debug!("SCOPE_CHK api key {} is missing {scope:?}", key.id);
+            return Err(MioInnerError::UserChallengedFail(
+                anyhow!("This api key does not have the {scope:?} scope"),
+                StatusCode::FORBIDDEN,
+            ));
+        }
+    }
+    Ok(next.run(req).await)
+}
+
+// middleware that rejects api keys entirely, for account management
+pub(crate) async fn require_session(req: Request, next: Next) -> Result<Response, MioInnerError> {
+    if let Some(key) = req.extensions().get::<CurrentApiKey>() {
+        debug!(
+            "SCOPE_CHK api key {} tried to access a session only route",
+            key.id
+        );
+        return Err(MioInnerError::UserChallengedFail(
+            anyhow!("This action cannot be done with an api key"),
+            StatusCode::FORBIDDEN,
+        ));
+    }
+    Ok(next.run(req).await)
+}
+
+#[tracing::instrument]
+pub async fn list_keys(
+    State(state): State<MioState>,
+    Extension(auth::JWTInner { userid, .. }): Extension<auth::JWTInner>,
+) -> Result<impl IntoResponse, MioInnerError> {
+    let mut conn = state.db.acquire().await?;
+    let keys = sqlx::query!(
+        "SELECT id, name, scopes, created, last_used
+        FROM api_key
+        WHERE owner = ?
+        ORDER BY created;",
+        userid
+    )
+    .fetch_all(&mut *conn)
+    .await?
+    .into_iter()
+    .map(|x| {
+        Ok(retstructs::ApiKey {
+            id: uuid_serialize(&x.id)?,
+            name: x.name,
+            scopes: scopes_from_bits(x.scopes),
+            created: x.created,
+            last_used: x.last_used,
+        })
+    })
+    .collect::<Result<_, MioInnerError>>()?;
+    Ok((StatusCode::OK, Json(retstructs::ApiKeys { keys })))
+}
+
+#[tracing::instrument]
+pub async fn create_key(
+    State(state): State<MioState>,
+    Extension(auth::JWTInner { userid, .. }): Extension<auth::JWTInner>,
+    Json(msgstructs::ApiKeyCreate { name, scopes }): Json<msgstructs::ApiKeyCreate>,
+) -> Result<impl IntoResponse, MioInnerError> {
+    if scopes.is_empty() {
+        return Err(MioInnerError::UserChallengedFail(
+            anyhow!("an api key needs at least one scope"),
+            StatusCode::BAD_REQUEST,
+        ));
+    }
+    let bits = scopes.iter().fold(0, |acc, x| acc | scope_bit(*x));
+    let key = {
+        use rand::distributions::Alphanumeric;
+        use rand::Rng;
+
+        let mut key = KEY_PREFIX.to_owned();
+        key.extend(
+            rand::thread_rng()
+                .sample_iter(&Alphanumeric)
+                .take(KEY_LEN)
+                .map(char::from),
+        );
+        key
+    };
+    let hash = hash_key(&key);
+    let id = Uuid::new_v4();
+    let created = Utc::now().timestamp();
+    let mut conn = state.db.acquire().await?;
+    sqlx::query!(
+        "INSERT INTO api_key (id, owner, name, hash, scopes, created)
+        VALUES (?,?,?,?,?,?);",
+        id,
+        userid,
+        name,
+        hash,
+        bits,
+        created
+    )
+    .execute(&mut *conn)
+    .await?;
+    info!("POST /user/keys created api key {id} for {userid}");
+    Ok((
+        StatusCode::OK,
+        Json(retstructs::NewApiKey {
+            info: retstructs::ApiKey {
+                id,
+                name,
+                scopes: scopes_from_bits(bits),
+                created,
+                last_used: None,
+            },
+            key,
+        }),
+    ))
+}
+
+#[tracing::instrument]
+pub async fn revoke_key(
+    State(state): State<MioState>,
+    Extension(auth::JWTInner { userid, .. }): Extension<auth::JWTInner>,
+    Query(msgstructs::DeleteQuery { id }): Query<msgstructs::DeleteQuery>,
+) -> Result<impl IntoResponse, MioInnerError> {
+    let mut conn = state.db.acquire().await?;
+    if sqlx::query!(
+        "DELETE FROM api_key WHERE id = ? AND owner = ?;",
+        id,
+        userid
+    )
+    .execute(&mut *conn)
+    .await?
+    .rows_affected()
+        == 0
+    {
+        return Err(MioInnerError::NotFound(anyhow!(
+            "api key {id} does not exist for {userid}"
+        )));
+    }
+    info!("DELETE /user/keys revoked api key {id} for {userid}");
+    Ok(StatusCode::OK)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::test::*;
+    use axum::http::Method;
+    use mio_protocol::*;
+
+    async fn gen_key(
+        cli: &axum_test::TestServer,
+        jwt: &auth::JWT,
+        scopes: Vec<auth::Scope>,
+    ) -> retstructs::NewApiKey {
+        jwt_header(cli, Method::POST, "/user/keys", jwt)
+            .json(&msgstructs::ApiKeyCreate {
+                name: "test key".to_owned(),
+                scopes,
+            })
+            .await
+            .json::<retstructs::NewApiKey>()
+    }
+
+    #[tokio::test]
+    async fn apikey_good() {
+        let cli = client().await;
+        let jwt = gen_user(&cli, "apikey_good").await;
+        let key = gen_key(&cli, &jwt, vec![auth::Scope::ReadLibrary]).await;
+        assert!(key.key.starts_with(super::KEY_PREFIX));
+        let key_auth = auth::JWT::from_raw(key.key);
+
+        // keys can be used like tokens
+        jwt_header(&cli, Method::GET, "/api/auth_test", &key_auth).await;
+        jwt_header(&cli, Method::GET, "/api/load/albums", &key_auth).await;
+        let keys = jwt_header(&cli, Method::GET, "/user/keys", &jwt)
+            .await
+            .json::<retstructs::ApiKeys>()
+            .keys;
+        assert_eq!(keys.len(), 1);
+        assert_eq!(keys[0].id, key.info.id);
+        assert!(keys[0].last_used.is_some());
+
+        // and revoked
+        jwt_header(
+            &cli,
+            Method::DELETE,
+            &format!("/user/keys?id={}", key.info.id),
+            &jwt,
+        )
+        .await;
+        jwt_header(&cli, Method::GET, "/api/auth_test", &key_auth)
+            .expect_failure()
+            .await;
+    }
+
+    #[tokio::test]
+    async fn apikey_bad_scope() {
+        let cli = client().await;
+        let jwt = gen_user(&cli, "apikey_bad_scope").await;
+        let key_auth =
+            auth::JWT::from_raw(gen_key(&cli, &jwt, vec![auth::Scope::Stream]).await.key);
+        jwt_header(&cli, Method::GET, "/api/load/albums", &key_auth)
+            .expect_failure()
+            .await;
+        jwt_header(&cli, Method::GET, "/api/folder", &key_auth)
+            .expect_failure()
+            .await;
+    }
+
+    #[tokio::test]
+    async fn apikey_bad_scope_track_manage() {
+        let cli = client().await;
+        let jwt = gen_user(&cli, "apikey_bad_scope_track_manage").await;
+        let id = gen_track(jwt.whois().unwrap().userid, b"not really audio").await;
+        let key_auth =
+            auth::JWT::from_raw(gen_key(&cli, &jwt, vec![auth::Scope::Upload]).await.key);
+
+        // uploading does not allow removing what is already there
+        jwt_header(
+            &cli,
+            Method::DELETE,
+            &format!("/api/track?id={id}"),
+            &key_auth,
+        )
+        .expect_failure()
+        .await
+        .assert_status(axum::http::StatusCode::FORBIDDEN);
+
+        let key_auth = auth::JWT::from_raw(
+            gen_key(&cli, &jwt, vec![auth::Scope::ManageFolders])
+                .await
+                .key,
+        );
+        jwt_header(
+            &cli,
+            Method::DELETE,
+            &format!("/api/track?id={id}"),
+            &key_auth,
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn apikey_bad_account_management() {
+        let cli = client().await;
+        let jwt = gen_user(&cli, "apikey_bad_account_management").await;
+        let key_auth =
+            auth::JWT::from_raw(gen_key(&cli, &jwt, auth::Scope::ALL.to_vec()).await.key);
+
+        // keys cannot make more keys or touch sessions
+        jwt_header(&cli, Method::POST, "/user/keys", &key_auth)
+            .json(&msgstructs::ApiKeyCreate {
+                name: "escalated".to_owned(),
+                scopes: auth::Scope::ALL.to_vec(),
+            })
+            .expect_failure()
+            .await;
+        jwt_header(&cli, Method::GET, "/user/sessions", &key_auth)
+            .expect_failure()
+            .await;
+    }
+
+    #[tokio::test]
+    async fn apikey_bad_invalid() {
+        let cli = client().await;
+        jwt_header(
+            &cli,
+            Method::GET,
+            "/api/auth_test",
+            &auth::JWT::from_raw(format!("{}notakey", super::KEY_PREFIX)),
+        )
+        .expect_failure()
+        .await;
+    }
+}
@@ -0,0 +1,351 @@
+use crate::db::{uuid_serialize, write_transaction};
+use crate::subtasks::transcode::StorageFormat;
+use crate::{MioInnerError, MioState};
+use axum::body::Bytes;
+use chrono::Utc;
+#[allow(unused)]
+use log::*;
+use mio_protocol::*;
+use sqlx::SqliteConnection;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::mpsc;
+use uuid::Uuid;
+
+// counters since the server started
+static HITS: AtomicU64 = AtomicU64::new(0);
+static MISSES: AtomicU64 = AtomicU64::new(0);
+
+fn cache_dir() -> PathBuf {
+    crate::DATA_DIR.get().unwrap().join("cache")
+}
+
+fn cache_path(track: Uuid, codec: &str, bitrate: i64) -> PathBuf {
+    cache_dir().join(format!("{track}.{codec}.{bitrate}"))
+}
+
+// lossless codecs don't have a bitrate, but it's part of the key
+fn key_bitrate(format: &StorageFormat) -> i64 {
+    format.bitrate.unwrap_or(0) as i64
+}
+
+// get a transcode out of the cache, if it's there
+pub(crate) async fn fetch(
+    state: &MioState,
+    track: Uuid,
+    format: &StorageFormat,
+) -> Result<Option<Vec<u8>>, MioInnerError> {
+    let codec = format.codec.as_str();
+    let bitrate = key_bitrate(format);
+    let mut conn = state.db.acquire().await?;
+    let now = Utc::now().timestamp();
+    let found = sqlx::query!(
+        "UPDATE transcode_cache SET last_used = ?
+        WHERE track = ? AND codec = ? AND bitrate = ?;",
+        now,
+        track,
+        codec,
+        bitrate
+    )
+    .execute(&mut *conn)
+    .await?
+    .rows_affected()
+        > 0;
+    if found {
+        match tokio::fs::read(cache_path(track, codec, bitrate)).await {
+            Ok(data) => {
+                HITS.fetch_add(1, Ordering::Relaxed);
+                return Ok(Some(data));
+            }
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                // evicted out from under us, or the file went missing
+                debug!("CACHE {track} {codec} {bitrate} was in the db but not on disk");
+                sqlx::query!(
+                    "DELETE FROM transcode_cache WHERE track = ? AND codec = ? AND bitrate = ?;",
+                    track,
+                    codec,
+                    bitrate
+                )
+                .execute(&mut *conn)
+                .await?;
+            }
+            Err(err) => return Err(err.into()),
+        }
+    }
+    MISSES.fetch_add(1, Ordering::Relaxed);
+    Ok(None)
+}
+
+// pass a transcode through while saving it into the cache. if anything goes wrong,
+// including the receiver going away, nothing is cached.
+pub(crate) fn tee(
+    state: MioState,
+    track: Uuid,
+    format: StorageFormat,
+    mut rx: mpsc::Receiver<anyhow::Result<Bytes>>,
+) -> mpsc::Receiver<anyhow::Result<Bytes>> {
+    let (tx, out) = mpsc::channel(1);
+    tokio::spawn(async move {
+        let partial = cache_dir().join(format!("{}.partial", Uuid::new_v4()));
+        let mut file = match open_partial(&partial).await {
+            Ok(file) => Some(file),
+            Err(err) => {
+                warn!("CACHE could not create {partial:?}: {err}");
+                None
+            }
+        };
+        let mut complete = true;
+        while let Some(chunk) = rx.recv().await {
+            match (&chunk, file.as_mut()) {
+                (Ok(data), Some(opened)) => {
+                    if let Err(err) = opened.write_all(data).await {
+                        warn!("CACHE failed to write to {partial:?}: {err}");
+                        file = None;
+                    }
+                }
+                (Err(_), _) => complete = false,
+                _ => (),
+            }
+            if tx.send(chunk).await.is_err() {
+                complete = false;
+                break;
+            }
+        }
+        let ret = match file {
+            Some(mut file) if complete => match file.shutdown().await {
+                Ok(()) => {
+                    drop(file);
+                    commit(&state, track, &format, partial.clone()).await
+                }
+                Err(err) => Err(err.into()),
+            },
+            _ => Ok(()),
+        };
+        if let Err(err) = ret {
+            warn!("CACHE failed to save {track}: {err}");
+        }
+
+        // this only exists if the transcode didn't make it into the cache
+        drop(tokio::fs::remove_file(partial).await);
+    });
+    out
+}
+
+async fn open_partial(partial: &Path) -> std::io::Result<tokio::fs::File> {
+    tokio::fs::create_dir_all(cache_dir()).await?;
+    tokio::fs::File::create(partial).await
+}
+
+// move a finished transcode into place, and make room for it
+pub(crate) async fn commit(
+    state: &MioState,
+    track: Uuid,
+    format: &StorageFormat,
+    partial: PathBuf,
+) -> Result<(), MioInnerError> {
+    let codec = format.codec.as_str();
+    let bitrate = key_bitrate(format);
+    let size = tokio::fs::metadata(&partial).await?.len() as i64;
+    let now = Utc::now().timestamp();
+    let mut conn = state.db.acquire().await?;
+    write_transaction(&mut conn, |txn| {
+        Box::pin(async move {
+            // the track could have been deleted while transcoding
+            if sqlx::query!("SELECT id FROM track WHERE id = ?;", track)
+                .fetch_optional(&mut *txn)
+                .await?
+                .is_none()
+            {
+                return Ok(());
+            }
+            sqlx::query!(
+                "INSERT INTO transcode_cache (track, codec, bitrate, size, last_used)
+                VALUES (?, ?, ?, ?, ?)
+                ON CONFLICT(track, codec, bitrate) DO UPDATE SET
+                size = excluded.size, last_used = excluded.last_used;",
+                track,
+                codec,
+                bitrate,
+                size,
+                now
+            )
+            .execute(&mut *txn)
+            .await?;
+            tokio::fs::rename(&partial, cache_path(track, codec, bitrate)).await?;
+            trace!("CACHE saved {track} {codec} {bitrate}, {size} bytes");
+            Ok(())
+        })
+    })
+    .await?;
+    evict(&mut conn, *crate::TRANSCODE_CACHE_SIZE.get().unwrap()).await
+}
+
+// remove the least recently used transcodes until the cache fits in the limit
+pub(crate) async fn evict(conn: &mut SqliteConnection, limit: u64) -> Result<(), MioInnerError> {
+    let removed = write_transaction(conn, |txn| {
+        Box::pin(async move {
+            let entries = sqlx::query!(
+                "SELECT track, codec, bitrate, size FROM transcode_cache
+                ORDER BY last_used ASC;"
+            )
+            .fetch_all(&mut *txn)
+            .await?;
+            let mut total = entries.iter().map(|x| x.size as u64).sum::<u64>();
+            let mut removed = vec![];
+            for entry in entries {
+                if total <= limit {
+                    break;
+                }
+                sqlx::query!(
+                    "DELETE FROM transcode_cache WHERE track = ? AND codec = ? AND bitrate = ?;",
+                    entry.track,
+                    entry.codec,
+                    entry.bitrate
+                )
+                .execute(&mut *txn)
+                .await?;
+                total -= entry.size as u64;
+                removed.push(cache_path(
+                    uuid_serialize(&entry.track)?,
+                    &entry.codec,
+                    entry.bitrate,
+                ));
+            }
+            Ok(removed)
+        })
+    })
+    .await?;
+    if !removed.is_empty() {
+        debug!("CACHE evicting {} transcodes", removed.len());
+    }
+    remove_files(removed).await
+}
+
+// drop everything cached for a track. this has to be called before the track is
+// deleted or its file is replaced.
+pub(crate) async fn invalidate(
+    conn: &mut SqliteConnection,
+    track: Uuid,
+) -> Result<(), MioInnerError> {
+    let removed = sqlx::query!(
+        "DELETE FROM transcode_cache WHERE track = ? RETURNING codec, bitrate;",
+        track
+    )
+    .fetch_all(&mut *conn)
+    .await?
+    .into_iter()
+    .map(|x| cache_path(track, &x.codec, x.bitrate))
+    .collect();
+    remove_files(removed).await
+}
+
+// same as `invalidate`, but for all of a user's tracks
+pub(crate) async fn invalidate_owner(
+    conn: &mut SqliteConnection,
+    owner: Uuid,
+) -> Result<(), MioInnerError> {
+    let tracks = sqlx::query!(
+        "SELECT DISTINCT transcode_cache.track FROM transcode_cache
+        JOIN track ON track.id = transcode_cache.track
+        WHERE track.owner = ?;",
+        owner
+    )
+    .fetch_all(&mut *conn)
+    .await?;
+    for track in tracks {
+        invalidate(&mut *conn, uuid_serialize(&track.track)?).await?;
+    }
+    Ok(())
+}
+
+async fn remove_files(paths: Vec<PathBuf>) -> Result<(), MioInnerError> {
+    for path in paths {
+        if let Err(err) = tokio::fs::remove_file(&path).await {
+            if err.kind() != std::io::ErrorKind::NotFound {
+                return Err(err.into());
+            }
+        }
+    }
+    Ok(())
+}
+
+pub(crate) async fn stats(
+    conn: &mut SqliteConnection,
+) -> Result<retstructs::CacheStats, MioInnerError> {
+    let totals = sqlx::query!(
+        "SELECT COUNT(*) AS \"entries: i64\", COALESCE(SUM(size), 0) AS \"bytes: i64\"
+        FROM transcode_cache;"
+    )
+    .fetch_one(&mut *conn)
+    .await?;
+    Ok(retstructs::CacheStats {
+        hits: HITS.load(Ordering::Relaxed),
+        misses: MISSES.load(Ordering::Relaxed),
+        entries: totals.entries,
+        bytes: totals.bytes as u64,
+        limit: *crate::TRANSCODE_CACHE_SIZE.get().unwrap(),
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use crate::subtasks::transcode::StorageFormat;
+    use crate::test::*;
+    use axum::http::{Method, StatusCode};
+    use mio_protocol::*;
+
+    #[tokio::test]
+    async fn cache_good() {
+        let cli = client().await;
+        let jwt = gen_user(&cli, "cache_good").await;
+        let admin = gen_admin(&cli, "cache_good_admin").await;
+        let id = gen_track(jwt.whois().unwrap().userid, b"not really audio").await;
+        let url = format!("/api/track?id={id}&format=opus&bitrate=96");
+        let opus = StorageFormat::new(Codec::Opus, Some(96), "").unwrap();
+        let flac = StorageFormat::new(Codec::Flac, None, "").unwrap();
+
+        // pretend that both of these were transcoded already
+        for (format, data) in [(&opus, &b"cached opus"[..]), (&flac, &b"cached flac"[..])] {
+            let partial = super::cache_dir().join(format!("{}.partial", uuid::Uuid::new_v4()));
+            tokio::fs::create_dir_all(super::cache_dir()).await.unwrap();
+            tokio::fs::write(&partial, data).await.unwrap();
+            super::commit(&STATE, id, format, partial).await.unwrap();
+        }
+        let before = jwt_header(&cli, Method::GET, "/admin/cache", &admin)
+            .await
+            .json::<retstructs::CacheStats>();
+        let resp = jwt_header(&cli, Method::GET, &url, &jwt).await;
+        assert_eq!(resp.as_bytes().as_ref(), b"cached opus");
+        assert_eq!(
+            resp.headers()[axum::http::header::CONTENT_TYPE],
+            "audio/ogg"
+        );
+        let after = jwt_header(&cli, Method::GET, "/admin/cache", &admin)
+            .await
+            .json::<retstructs::CacheStats>();
+        assert!(after.hits > before.hits);
+
+        // the flac was used least recently, so it goes first
+        let mut conn = STATE.db.acquire().await.unwrap();
+        sqlx::query!(
+            "UPDATE transcode_cache SET last_used = 0 WHERE track = ? AND codec = 'flac';",
+            id
+        )
+        .execute(&mut *conn)
+        .await
+        .unwrap();
+        super::evict(&mut conn, after.bytes - "cached flac".len() as u64)
+            .await
+            .unwrap();
+        assert!(!super::cache_path(id, "flac", 0).exists());
+        assert!(super::cache_path(id, "opus", 96).exists());
+        drop(conn);
+
+        // deleting the track removes what's left
+        jwt_header(&cli, Method::DELETE, &format!("/api/track?id={id}"), &jwt)
+            .await
+            .assert_status(StatusCode::OK);
+        assert!(!super::cache_path(id, "opus", 96).exists());
+    }
+}
@@ -0,0 +1,525 @@
+use crate::endpoints::check_dir_in_data_dir;
+use crate::endpoints::track_manage::{
+    create_upload_file, sanitize_fname, upload_format, CHUNK_TIMEOUT,
+};
+use crate::error::MioInnerError;
+use crate::{MioState, DATA_DIR};
+use anyhow::anyhow;
+use axum::body::Body;
+use axum::extract::*;
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use axum::routing::*;
+use futures::StreamExt;
+#[allow(unused)]
+use log::*;
+use mio_protocol::*;
+use std::io::Read;
+use std::path::{Component, Path, PathBuf};
+use tokio::fs::{create_dir_all, remove_dir_all, remove_file, rename, File};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::time::timeout;
+use uuid::Uuid;
+
+pub fn routes() -> Router<MioState> {
+    Router::new().route("/", post(archive_upload))
+}
+
+// files with these extensions are taken out of an archive, everything else is skipped
+const AUDIO_EXTENSIONS: &[&str] = &[
+    "aac", "aif", "aiff", "alac", "ape", "flac", "m4a", "mka", "mp3", "mp4", "oga", "ogg", "opus",
+    "qoa", "wav", "webm", "wma", "wv",
+];
+
+#[derive(Debug, Clone, Copy)]
+enum Kind {
+    Zip,
+    Tar,
+}
+
+// where archives are worked on, each in their own folder
+fn scratch_dir() -> PathBuf {
+    DATA_DIR.get().unwrap().join("tmp")
+}
+
+// remove whatever was left behind by archives that were being worked on when the
+// server stopped. nothing else can be using the folder yet, so it's all taken.
+pub(crate) async fn sweep_scratch() -> Result<(), MioInnerError> {
+    match remove_dir_all(scratch_dir()).await {
+        Ok(()) => info!("ARCHIVE cleaned up what was left in {:?}", scratch_dir()),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => (),
+        Err(err) => return Err(err.into()),
+    }
+    Ok(())
+}
+
+// an audio file that was taken out of the archive, and is waiting to be queued
+#[derive(Debug)]
+struct Extracted {
+    // folder under the target dir, already sanitized
+    dir: PathBuf,
+    fname: String,
+    temp: PathBuf,
+}
+
+#[tracing::instrument(skip(payload))]
+async fn archive_upload(
+    State(state): State<MioState>,
+    Extension(auth::JWTInner { userid, .. }): Extension<auth::JWTInner>,
+    Query(msgstructs::ArchiveUploadQuery {
+        dir,
+        codec,
+        bitrate,
+        duplicates,
+    }): Query<msgstructs::ArchiveUploadQuery>,
+    payload: Body,
+) -> Result<impl IntoResponse, MioInnerError> {
+    // catch a bad format before taking anything
+    upload_format(codec, bitrate, None)?;
+    trace!("POST /archive acquiring directory lock");
+    let _hold = state.lock_files.read().await;
+    check_dir_in_data_dir(&dir, userid)?;
+    let base = DATA_DIR.get().unwrap().join(format!("{userid}")).join(&dir);
+    if !tokio::fs::metadata(&base).await.is_ok_and(|x| x.is_dir()) {
+        return Err(MioInnerError::NotFound(anyhow!("{dir:?}")));
+    }
+    let mut conn = state.db.acquire().await?;
+    let usage = crate::quota::check_new_upload(&mut conn, userid, 0, 1).await?;
+    drop(conn);
+
+    // everything is done in a scratch folder, which is always cleaned up after
+    let scratch = scratch_dir().join(format!("{}", Uuid::new_v4()));
+    create_dir_all(&scratch).await?;
+    let archive = scratch.join("archive");
+    let ret = async {
+        receive(payload, &archive, &usage).await?;
+        let kind = sniff(&archive).await?;
+        debug!("POST /archive extracting {kind:?} archive into {dir:?}");
+        let extracted = tokio::task::spawn_blocking({
+            let scratch = scratch.clone();
+            move || extract(&archive, scratch, kind, usage)
+        })
+        .await??;
+
+        // queue up everything that made it out
+        let mut files = vec![];
+        for (path, ret) in extracted {
+            let ret = match ret {
+                Ok(extracted) => {
+                    queue(&state, userid, &dir, extracted, codec, bitrate, duplicates).await
+                }
+                Err(err) => Err(err),
+            };
+            files.push(match ret {
+                Ok(uuid) => retstructs::ArchiveFile {
+                    path,
+                    uuid: Some(uuid),
+                    error: None,
+                },
+                Err(err) => {
+                    debug!("POST /archive could not take {path:?}: {err}");
+                    retstructs::ArchiveFile {
+                        path,
+                        uuid: None,
+                        error: Some(err.msg()),
+                    }
+                }
+            });
+        }
+        Ok::<_, MioInnerError>(retstructs::ArchiveReport { files })
+    }
+    .await;
+    if let Err(err) = remove_dir_all(&scratch).await {
+        warn!("POST /archive failed to clean up {scratch:?}: {err}");
+    }
+    Ok((StatusCode::ACCEPTED, Json(ret?)))
+}
+
+// write the archive out to disk, as neither format can be read as it streams in. it
+// takes up space like anything else the user sends, so it's kept under their quota.
+async fn receive(
+    payload: Body,
+    path: &Path,
+    usage: &retstructs::StorageUsage,
+) -> Result<(), MioInnerError> {
+    let max_size = *crate::MAX_ARCHIVE_SIZE.get().unwrap();
+    let mut payload = payload.into_data_stream();
+    let mut file = File::create(path).await?;
+    let mut written = 0;
+    loop {
+        match timeout(CHUNK_TIMEOUT, payload.next()).await {
+            Ok(Some(Ok(chunk))) => {
+                written += chunk.len() as u64;
+                if written > max_size {
+                    return Err(MioInnerError::TrackProcessingError(
+                        anyhow!("archive is larger than the max of {max_size} bytes"),
+                        StatusCode::PAYLOAD_TOO_LARGE,
+                    ));
+                }
+                crate::quota::check(usage, written, 0)?;
+                file.write_all(&chunk).await?;
+            }
+            Ok(None) => break,
+            Ok(Some(Err(err))) => {
+                return Err(MioInnerError::TrackProcessingError(
+                    anyhow!("failed to stream chunk: {err}"),
+                    StatusCode::BAD_REQUEST,
+                ))
+            }
+            Err(_) => {
+                return Err(MioInnerError::TrackProcessingError(
+                    anyhow!("upload timeout hit"),
+                    StatusCode::REQUEST_TIMEOUT,
+                ))
+            }
+        }
+    }
+    file.shutdown().await?;
+    Ok(())
+}
+
+// figure out what kind of archive it is from the first few bytes
+async fn sniff(path: &Path) -> Result<Kind, MioInnerError> {
+    let mut head = vec![];
+    File::open(path)
+        .await?
+        .take(512)
+        .read_to_end(&mut head)
+        .await?;
+    if head.starts_with(b"PK\x03\x04") || head.starts_with(b"PK\x05\x06") {
+        Ok(Kind::Zip)
+    } else if head.get(257..262) == Some(b"ustar") {
+        Ok(Kind::Tar)
+    } else {
+        Err(MioInnerError::ExternalIoError(
+            anyhow!("upload is not a zip or tar archive"),
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+        ))
+    }
+}
+
+// where an entry in the archive goes, as a folder and filename. none is returned for
+// anything that isn't audio. imports go through this as well.
+pub(crate) fn audio_target(name: &Path) -> Option<Result<(PathBuf, String), MioInnerError>> {
+    let ext = name.extension()?.to_str()?.to_ascii_lowercase();
+    if !AUDIO_EXTENSIONS.contains(&ext.as_str()) {
+        return None;
+    }
+    let mut parts = vec![];
+    for part in name.components() {
+        match part {
+            Component::Normal(part) => {
+                let part = part.to_string_lossy();
+
+                // hidden files and mac metadata are never tracks
+                if part.starts_with('.') || part == "__MACOSX" {
+                    return None;
+                }
+                parts.push(part.into_owned());
+            }
+            Component::CurDir => (),
+            _ => {
+                return Some(Err(MioInnerError::ExternalIoError(
+                    anyhow!("{name:?} points outside of the archive"),
+                    StatusCode::BAD_REQUEST,
+                )))
+            }
+        }
+    }
+
+    // folders follow the same rules as the folder api
+    let fname = parts.pop()?;
+    let dir = parts
+        .into_iter()
+        .map(sanitize_filename::sanitize)
+        .filter(|x| !x.is_empty())
+        .collect();
+    Some(Ok((dir, fname)))
+}
+
+// pulls audio out of an archive into the scratch folder, keeping track of how much has
+// been taken
+struct Extractor {
+    scratch: PathBuf,
+    usage: retstructs::StorageUsage,
+    tracks: i64,
+    total: u64,
+    files: Vec<(String, Result<Extracted, MioInnerError>)>,
+}
+
+impl Extractor {
+    fn take(&mut self, name: &Path, reader: &mut dyn Read) {
+        let Some(target) = audio_target(name) else {
+            trace!("POST /archive skipping {name:?}");
+            return;
+        };
+        let ret = target.and_then(|(dir, fname)| {
+            self.write(reader)
+                .map(|temp| Extracted { dir, fname, temp })
+        });
+        self.files.push((name.to_string_lossy().into_owned(), ret));
+    }
+
+    fn write(&mut self, reader: &mut dyn Read) -> Result<PathBuf, MioInnerError> {
+        crate::quota::check(&self.usage, self.total, self.tracks + 1)?;
+
+        // an archive can hold far more than it takes up, so what comes out of it is
+        // limited as well
+        let max_file = *crate::MAX_UPLOAD_SIZE.get().unwrap();
+        let max_archive = *crate::MAX_ARCHIVE_SIZE.get().unwrap();
+        let limit = max_file.min(max_archive.saturating_sub(self.total));
+        let temp = self.scratch.join(format!("{}", self.files.len()));
+        let mut file = std::fs::File::create(&temp)?;
+        let written = std::io::copy(&mut reader.take(limit + 1), &mut file)?;
+        drop(file);
+        let ret = if written > limit {
+            Err(MioInnerError::TrackProcessingError(
+                if limit == max_file {
+                    anyhow!("file is larger than the max of {max_file} bytes")
+                } else {
+                    anyhow!("archive contents are larger than the max of {max_archive} bytes")
+                },
+                StatusCode::PAYLOAD_TOO_LARGE,
+            ))
+        } else {
+            crate::quota::check(&self.usage, self.total + written, self.tracks + 1)
+        };
+        if let Err(err) = ret {
+            std::fs::remove_file(&temp)?;
+            return Err(err);
+        }
+        self.total += written;
+        self.tracks += 1;
+        Ok(temp)
+    }
+}
+
+// take every audio file out of the archive. this blocks.
+#[allow(clippy::type_complexity)]
+fn extract(
+    archive: &Path,
+    scratch: PathBuf,
+    kind: Kind,
+    usage: retstructs::StorageUsage,
+) -> Result<Vec<(String, Result<Extracted, MioInnerError>)>, MioInnerError> {
+    let bad_archive = |err: &dyn std::fmt::Display| {
+        MioInnerError::ExternalIoError(
+            anyhow!("could not read archive: {err}"),
+            StatusCode::BAD_REQUEST,
+        )
+    };
+    let file = std::fs::File::open(archive)?;
+    let mut extractor = Extractor {
+        scratch,
+        usage,
+        tracks: 0,
+        total: 0,
+        files: vec![],
+    };
+    match kind {
+        Kind::Zip => {
+            let mut zip = zip::ZipArchive::new(file).map_err(|err| bad_archive(&err))?;
+            for i in 0..zip.len() {
+                let mut entry = zip.by_index(i).map_err(|err| bad_archive(&err))?;
+                if entry.is_file() {
+                    let name = PathBuf::from(entry.name());
+                    extractor.take(&name, &mut entry);
+                }
+            }
+        }
+        Kind::Tar => {
+            let mut tar = tar::Archive::new(file);
+            for entry in tar.entries().map_err(|err| bad_archive(&err))? {
+                let mut entry = entry.map_err(|err| bad_archive(&err))?;
+                if entry.header().entry_type().is_file() {
+                    let name = entry.path().map_err(|err| bad_archive(&err))?.into_owned();
+                    extractor.take(&name, &mut entry);
+                }
+            }
+        }
+    }
+    Ok(extractor.files)
+}
+
+// move an extracted file into place and hand it off to be processed
+async fn queue(
+    state: &MioState,
+    userid: Uuid,
+    dir: &str,
+    extracted: Extracted,
+    codec: Option<Codec>,
+    bitrate: Option<u32>,
+    duplicates: Option<DuplicatePolicy>,
+) -> Result<Uuid, MioInnerError> {
+    let format = upload_format(codec, bitrate, Some(&extracted.fname))?;
+    let dir = Path::new(dir)
+        .join(&extracted.dir)
+        .to_string_lossy()
+        .into_owned();
+    check_dir_in_data_dir(&dir, userid)?;
+    create_dir_all(DATA_DIR.get().unwrap().join(format!("{userid}")).join(&dir)).await?;
+    let (id, real_fname, file) = create_upload_file(userid, &dir).await?;
+    drop(file);
+    let ret = async {
+        rename(&extracted.temp, &real_fname).await?;
+        let mut conn = state.db.acquire().await?;
+        crate::subtasks::jobs::enqueue(
+            &mut conn,
+            crate::subtasks::jobs::NewJob {
+                id,
+                owner: userid,
+                dir,
+                orig_fname: sanitize_fname(Some(extracted.fname), id),
+                format,
+                duplicates: duplicates.unwrap_or_default(),
+                inbox: None,
+            },
+        )
+        .await
+    }
+    .await;
+    if let Err(err) = ret {
+        remove_file(&real_fname).await?;
+        return Err(err);
+    }
+    Ok(id)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::test::*;
+    use axum::http::{Method, StatusCode};
+    use mio_protocol::*;
+    use std::io::Write;
+
+    #[tokio::test]
+    async fn archive_zip_good() {
+        let cli = client().await;
+        let jwt = gen_user(&cli, "archive_zip_good").await;
+        let mut zip = zip::ZipWriter::new(std::io::Cursor::new(vec![]));
+        let options = zip::write::SimpleFileOptions::default()
+            .compression_method(zip::CompressionMethod::Stored);
+        for (name, data) in [
+            ("Album/01 Intro.flac", &b"not really audio"[..]),
+            ("Album/cover.jpg", b"not a track"),
+            ("__MACOSX/Album/._01 Intro.flac", b"mac junk"),
+            ("../escape.mp3", b"not allowed"),
+            ("Album/Disc 2/01 Outro.MP3", b"also not audio"),
+        ] {
+            zip.start_file(name, options).unwrap();
+            zip.write_all(data).unwrap();
+        }
+        let data = zip.finish().unwrap().into_inner();
+        let resp = jwt_header(&cli, Method::POST, "/api/archive?dir=", &jwt)
+            .bytes(data.into())
+            .await;
+        resp.assert_status(StatusCode::ACCEPTED);
+        let report = resp.json::<retstructs::ArchiveReport>();
+
+        // only the audio is reported, in archive order
+        let paths = report
+            .files
+            .iter()
+            .map(|x| x.path.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            paths,
+            [
+                "Album/01 Intro.flac",
+                "../escape.mp3",
+                "Album/Disc 2/01 Outro.MP3"
+            ]
+        );
+        assert!(report.files[1].uuid.is_none() && report.files[1].error.is_some());
+        let userdir = crate::DATA_DIR
+            .get()
+            .unwrap()
+            .join(format!("{}", jwt.whois().unwrap().userid));
+        for (file, dir, data) in [
+            (&report.files[0], "Album", &b"not really audio"[..]),
+            (&report.files[2], "Album/Disc 2", b"also not audio"),
+        ] {
+            let id = file.uuid.unwrap();
+            assert_eq!(
+                tokio::fs::read(userdir.join(dir).join(format!("{id}")))
+                    .await
+                    .unwrap(),
+                data
+            );
+            let job = jwt_header(
+                &cli,
+                Method::GET,
+                &format!("/api/jobs/status?id={id}"),
+                &jwt,
+            )
+            .await
+            .json::<retstructs::Job>();
+            assert_eq!(job.state, JobState::Queued);
+        }
+    }
+
+    #[tokio::test]
+    async fn archive_tar_good() {
+        let cli = client().await;
+        let jwt = gen_user(&cli, "archive_tar_good").await;
+        let mut tar = tar::Builder::new(vec![]);
+        let data = b"not really audio";
+        let mut header = tar::Header::new_gnu();
+        header.set_size(data.len() as u64);
+        header.set_cksum();
+        tar.append_data(&mut header, "Some Album/track.ogg", &data[..])
+            .unwrap();
+        let data = tar.into_inner().unwrap();
+        let resp = jwt_header(&cli, Method::POST, "/api/archive?dir=", &jwt)
+            .bytes(data.into())
+            .await;
+        resp.assert_status(StatusCode::ACCEPTED);
+        let report = resp.json::<retstructs::ArchiveReport>();
+        assert_eq!(report.files.len(), 1);
+        assert_eq!(report.files[0].error, None);
+        let id = report.files[0].uuid.unwrap();
+        assert!(crate::DATA_DIR
+            .get()
+            .unwrap()
+            .join(format!("{}", jwt.whois().unwrap().userid))
+            .join("Some Album")
+            .join(format!("{id}"))
+            .exists());
+    }
+
+    #[tokio::test]
+    async fn archive_bad() {
+        let cli = client().await;
+        let jwt = gen_user(&cli, "archive_bad").await;
+        jwt_header(&cli, Method::POST, "/api/archive?dir=", &jwt)
+            .bytes(b"this is not an archive"[..].into())
+            .expect_failure()
+            .await
+            .assert_status(StatusCode::UNSUPPORTED_MEDIA_TYPE);
+        jwt_header(&cli, Method::POST, "/api/archive?dir=missing", &jwt)
+            .bytes(b"PK\x05\x06"[..].into())
+            .expect_failure()
+            .await
+            .assert_status(StatusCode::NOT_FOUND);
+        jwt_header(&cli, Method::POST, "/api/archive?dir=..", &jwt)
+            .bytes(b"PK\x05\x06"[..].into())
+            .expect_failure()
+            .await
+            .assert_status(StatusCode::BAD_REQUEST);
+
+        // the archive itself has to fit in the quota
+        let admin = gen_admin(&cli, "archive_bad_admin").await;
+        jwt_header(&cli, Method::PATCH, "/admin/users/quota", &admin)
+            .json(&msgstructs::AdminQuotaSet {
+                id: jwt.whois().unwrap().userid,
+                bytes: Some(10),
+                tracks: None,
+            })
+            .await;
+        jwt_header(&cli, Method::POST, "/api/archive?dir=", &jwt)
+            .bytes(vec![0; 1024].into())
+            .expect_failure()
+            .await
+            .assert_status(StatusCode::PAYLOAD_TOO_LARGE);
+    }
+}
@@ -1,5 +1,6 @@
 use super::check_dir_in_data_dir;
 use crate::error::MioInnerError;
+use crate::subtasks::track_upload::{ENCODING_EXT, ORIGINAL_EXT};
 use crate::MioState;
 use crate::DATA_DIR;
 use anyhow::anyhow;
@@ -163,6 +164,14 @@ async fn folder_query(
             if ftype.is_file() {
                 trace!("GET /api/folder branch is file {logfile}");
 
+                // originals and in progress encodes sit next to their track
+                if std::path::Path::new(&loghold)
+                    .extension()
+                    .is_some_and(|x| x == ORIGINAL_EXT || x == ENCODING_EXT)
+                {
+                    continue;
+                }
+
                 // check if fname is valid uuid
                 Uuid::try_parse(
                     x.file_name().into_string().map_err(|err| {
@@ -0,0 +1,137 @@
+use crate::{db::uuid_serialize, *};
+use anyhow::anyhow;
+use axum::extract::{Query, State};
+use mio_protocol::*;
+
+pub fn routes() -> Router<MioState> {
+    Router::new()
+        .route("/", get(list_jobs))
+        .route("/status", get(job_status))
+}
+
+fn to_job(
+    id: Vec<u8>,
+    state: String,
+    orig_fname: String,
+    error: Option<String>,
+    track: Option<Vec<u8>>,
+    created: i64,
+    updated: i64,
+) -> Result<retstructs::Job, MioInnerError> {
+    Ok(retstructs::Job {
+        id: uuid_serialize(&id)?,
+        state: state
+            .parse()
+            .map_err(|err| MioInnerError::DbError(anyhow!("could not parse job state {err}")))?,
+        fname: orig_fname,
+        error,
+        track: track.map(|x| uuid_serialize(&x)).transpose()?,
+        created,
+        updated,
+    })
+}
+
+#[tracing::instrument]
+async fn list_jobs(
+    State(state): State<MioState>,
+    Extension(auth::JWTInner { userid, .. }): Extension<auth::JWTInner>,
+) -> Result<impl IntoResponse, MioInnerError> {
+    let mut conn = state.db.acquire().await?;
+    let jobs = sqlx::query!(
+        "SELECT id, state, orig_fname, error, track, created, updated FROM upload_job
+        WHERE owner = ? ORDER BY created DESC;",
+        userid
+    )
+    .fetch_all(&mut *conn)
+    .await?
+    .into_iter()
+    .map(|x| {
+        to_job(
+            x.id,
+            x.state,
+            x.orig_fname,
+            x.error,
+            x.track,
+            x.created,
+            x.updated,
+        )
+    })
+    .collect::<Result<_, _>>()?;
+    Ok((StatusCode::OK, Json(retstructs::Jobs { jobs })))
+}
+
+#[tracing::instrument]
+async fn job_status(
+    State(state): State<MioState>,
+    Extension(auth::JWTInner { userid, .. }): Extension<auth::JWTInner>,
+    Query(msgstructs::IdInfoQuery { id }): Query<msgstructs::IdInfoQuery>,
+) -> Result<impl IntoResponse, MioInnerError> {
+    let mut conn = state.db.acquire().await?;
+    let x = sqlx::query!(
+        "SELECT id, state, orig_fname, error, track, created, updated FROM upload_job
+        WHERE id = ? AND owner = ?;",
+        id,
+        userid
+    )
+    .fetch_optional(&mut *conn)
+    .await?
+    .ok_or_else(|| MioInnerError::NotFound(anyhow!("could not find job {id}")))?;
+    Ok((
+        StatusCode::OK,
+        Json(to_job(
+            x.id,
+            x.state,
+            x.orig_fname,
+            x.error,
+            x.track,
+            x.created,
+            x.updated,
+        )?),
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use crate::test::*;
+    use axum::http::{Method, StatusCode};
+    use mio_protocol::*;
+
+    #[tokio::test]
+    async fn jobs_bad_processing() {
+        let cli = client().await;
+        let jwt = gen_user(&cli, "jobs_bad_processing").await;
+        let userid = jwt.whois().unwrap().userid;
+        let other = gen_user(&cli, "jobs_bad_processing_2").await;
+
+        // uploads come back right away
+        let resp = jwt_header(&cli, Method::POST, "/api/track?dir=&fname=a.flac", &jwt)
+            .bytes(b"not really audio".to_vec().into())
+            .await;
+        resp.assert_status(StatusCode::ACCEPTED);
+        let id = resp.json::<retstructs::UploadReturn>().uuid;
+        let status = |jwt| jwt_header(&cli, Method::GET, &format!("/api/jobs/status?id={id}"), jwt);
+        let job = status(&jwt).await.json::<retstructs::Job>();
+        assert_eq!(job.state, JobState::Queued);
+        assert_eq!(job.fname, "a.flac");
+        status(&other)
+            .expect_failure()
+            .await
+            .assert_status(StatusCode::NOT_FOUND);
+
+        // which isn't audio, so it fails and is cleaned up
+        assert!(crate::subtasks::jobs::process(&STATE, id).await.unwrap());
+        let job = status(&jwt).await.json::<retstructs::Job>();
+        assert_eq!(job.state, JobState::Failed);
+        assert!(job.error.is_some());
+        assert!(!crate::DATA_DIR
+            .get()
+            .unwrap()
+            .join(format!("{userid}"))
+            .join(format!("{id}"))
+            .exists());
+        let jobs = jwt_header(&cli, Method::GET, "/api/jobs", &jwt)
+            .await
+            .json::<retstructs::Jobs>();
+        assert_eq!(jobs.jobs, vec![job]);
+    }
+}
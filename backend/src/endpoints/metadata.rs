@@ -0,0 +1,657 @@
+use crate::db::{uuid_serialize, write_transaction};
+use crate::error::MioInnerError;
+use crate::MioState;
+use anyhow::anyhow;
+use axum::extract::*;
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use axum::routing::*;
+#[allow(unused)]
+use log::*;
+use mio_protocol::*;
+use sqlx::SqliteConnection;
+use std::collections::HashMap;
+use uuid::Uuid;
+
+pub fn routes() -> Router<MioState> {
+    Router::new().route("/track", patch(track_edit))
+}
+
+fn bad_edit(err: anyhow::Error) -> MioInnerError {
+    MioInnerError::TrackProcessingError(err, StatusCode::BAD_REQUEST)
+}
+
+// catch anything that would leave the track with a blank tag
+fn check_edit(edit: &msgstructs::TrackEdit) -> Result<(), MioInnerError> {
+    if edit.title.as_ref().is_some_and(|x| x.trim().is_empty()) {
+        return Err(bad_edit(anyhow!("title cannot be empty")));
+    }
+    for (name, x) in [
+        ("artist", &edit.artist),
+        ("artist sort name", &edit.artist_sort),
+        ("album artist", &edit.album_artist),
+        ("album", &edit.album),
+        ("album sort name", &edit.album_sort),
+    ] {
+        if let Some(Some(x)) = x {
+            if x.trim().is_empty() {
+                return Err(bad_edit(anyhow!(
+                    "{name} cannot be empty, use null to clear it"
+                )));
+            }
+        }
+    }
+    if let Some(artists) = &edit.artists {
+        if edit.artist.is_some() {
+            return Err(bad_edit(anyhow!(
+                "artist and artists cannot both be changed"
+            )));
+        }
+        if artists.iter().any(|x| x.name.trim().is_empty()) {
+            return Err(bad_edit(anyhow!("artist names cannot be empty")));
+        }
+    }
+    for (name, x) in [("disk", edit.disk), ("track", edit.track)] {
+        if let Some(Some(x)) = x {
+            if x < 0 {
+                return Err(bad_edit(anyhow!("{name} number cannot be negative")));
+            }
+        }
+    }
+    if edit.tags.keys().any(|x| x.trim().is_empty()) {
+        return Err(bad_edit(anyhow!("tag names cannot be empty")));
+    }
+    Ok(())
+}
+
+// change a track's tags. artists and albums are shared between every user, so they're
+// linked to by name, and forgotten about when no track is on them anymore. the first
+// primary artist is also kept on the track.
+#[tracing::instrument]
+async fn track_edit(
+    State(state): State<MioState>,
+    Extension(auth::JWTInner { userid, .. }): Extension<auth::JWTInner>,
+    Json(edit): Json<msgstructs::TrackEdit>,
+) -> Result<impl IntoResponse, MioInnerError> {
+    check_edit(&edit)?;
+    let msgstructs::TrackEdit {
+        id,
+        title,
+        artist,
+        artist_sort,
+        artists,
+        album_artist,
+        album,
+        album_sort,
+        disk,
+        track,
+        tags,
+    } = edit;
+    let mut conn = state.db.acquire().await?;
+    write_transaction(&mut conn, |txn| {
+        Box::pin(async move {
+            let x = sqlx::query!(
+                "SELECT title, disk, track, tags, album, album_artist FROM track
+                WHERE id = ? AND owner = ?;",
+                id,
+                userid
+            )
+            .fetch_optional(&mut *txn)
+            .await?
+            .ok_or_else(|| MioInnerError::NotFound(anyhow!("could not find track {id}")))?;
+            let old_credits = track_credits(&mut *txn, id).await?;
+            let old_album = x.album.map(|x| uuid_serialize(&x)).transpose()?;
+            let old_album_artist = x.album_artist.map(|x| uuid_serialize(&x)).transpose()?;
+
+            // artists, where setting the artist replaces only the primary ones
+            let new_credits = match (artist, artists) {
+                (_, Some(artists)) => {
+                    let mut ret = vec![];
+                    for x in artists {
+                        ret.push((link_artist(&mut *txn, x.name.trim(), None).await?, x.role));
+                    }
+                    ret
+                }
+                (Some(artist), None) => {
+                    let mut ret = vec![];
+                    if let Some(name) = artist {
+                        let artist = link_artist(&mut *txn, name.trim(), None).await?;
+                        ret.push((artist, ArtistRole::Primary));
+                    }
+                    ret.extend(
+                        old_credits
+                            .iter()
+                            .filter(|x| x.1 != ArtistRole::Primary)
+                            .copied(),
+                    );
+                    ret
+                }
+                (None, None) => old_credits.clone(),
+            };
+            let new_artist = new_credits
+                .iter()
+                .find(|x| x.1 == ArtistRole::Primary)
+                .map(|x| x.0);
+            if let Some(sort) = artist_sort {
+                let Some(artist) = new_artist else {
+                    return Err(bad_edit(anyhow!("track has no artist to sort by")));
+                };
+                set_artist_sort(&mut *txn, userid, artist, sort).await?;
+            }
+            let new_album_artist = match album_artist {
+                None => old_album_artist,
+                Some(None) => None,
+                Some(Some(name)) => Some(link_artist(&mut *txn, name.trim(), None).await?),
+            };
+
+            // album
+            let new_album = match album {
+                None => old_album,
+                Some(None) => None,
+                Some(Some(title)) => Some(link_album(&mut *txn, title.trim()).await?),
+            };
+            if let Some(sort) = album_sort {
+                let Some(album) = new_album else {
+                    return Err(bad_edit(anyhow!("track has no album to sort by")));
+                };
+                set_album_sort(&mut *txn, userid, album, sort).await?;
+            }
+
+            // everything else is on the track itself
+            let mut all_tags: HashMap<String, String> = serde_json::from_str(&x.tags)
+                .map_err(|err| MioInnerError::DbError(anyhow!("could not serialize tags {err}")))?;
+            for (name, value) in tags {
+                match value {
+                    Some(value) => all_tags.insert(name, value),
+                    None => all_tags.remove(&name),
+                };
+            }
+            let all_tags = serde_json::to_string(&all_tags)
+                .map_err(|err| MioInnerError::DbError(anyhow!("could not serialize tags {err}")))?;
+            let title = title.map(|x| x.trim().to_owned()).unwrap_or(x.title);
+            let disk = disk.unwrap_or(x.disk);
+            let track = track.unwrap_or(x.track);
+            sqlx::query!(
+                "UPDATE track SET title = ?, disk = ?, track = ?, tags = ?, artist = ?, album = ?,
+                album_artist = ?
+                WHERE id = ? AND owner = ?;",
+                title,
+                disk,
+                track,
+                all_tags,
+                new_artist,
+                new_album,
+                new_album_artist,
+                id,
+                userid
+            )
+            .execute(&mut *txn)
+            .await?;
+            set_credits(&mut *txn, id, &new_credits).await?;
+            let old_artists = old_credits
+                .iter()
+                .map(|x| x.0)
+                .chain(old_album_artist)
+                .collect::<Vec<_>>();
+            forget_orphans(
+                &mut *txn,
+                &old_artists,
+                old_album.filter(|x| Some(*x) != new_album),
+            )
+            .await?;
+            info!("PATCH /metadata/track {userid} edited {id}");
+            Ok(StatusCode::OK)
+        })
+    })
+    .await
+}
+
+// the artist with this name, which is created with the sort name if there isn't one
+pub(crate) async fn link_artist(
+    conn: &mut SqliteConnection,
+    name: &str,
+    sort: Option<&str>,
+) -> Result<Uuid, MioInnerError> {
+    if let Some(x) = sqlx::query!("SELECT id FROM artist WHERE artist_name = ?;", name)
+        .fetch_optional(&mut *conn)
+        .await?
+    {
+        return uuid_serialize(&x.id);
+    }
+    let id = Uuid::new_v4();
+    sqlx::query!(
+        "INSERT INTO artist (id, artist_name, sort_name) VALUES (?, ?, ?);",
+        id,
+        name,
+        sort
+    )
+    .execute(&mut *conn)
+    .await?;
+    trace!("new artist generated for \"{name}\": {id}");
+    Ok(id)
+}
+
+// everyone credited on a track, in order
+pub(crate) async fn track_credits(
+    conn: &mut SqliteConnection,
+    track: Uuid,
+) -> Result<Vec<(Uuid, ArtistRole)>, MioInnerError> {
+    sqlx::query!(
+        "SELECT artist, role FROM JOIN_track_artist WHERE track = ? ORDER BY position;",
+        track
+    )
+    .fetch_all(&mut *conn)
+    .await?
+    .into_iter()
+    .map(|x| {
+        Ok((
+            uuid_serialize(&x.artist)?,
+            x.role
+                .parse()
+                .map_err(|err| MioInnerError::DbError(anyhow!("could not parse role {err}")))?,
+        ))
+    })
+    .collect()
+}
+
+// replace everyone credited on a track. an artist is only credited once for each role.
+pub(crate) async fn set_credits(
+    conn: &mut SqliteConnection,
+    track: Uuid,
+    credits: &[(Uuid, ArtistRole)],
+) -> Result<(), MioInnerError> {
+    sqlx::query!("DELETE FROM JOIN_track_artist WHERE track = ?;", track)
+        .execute(&mut *conn)
+        .await?;
+    for (position, (artist, role)) in credits.iter().enumerate() {
+        let role = role.as_str();
+        let position = position as i64;
+        sqlx::query!(
+            "INSERT OR IGNORE INTO JOIN_track_artist (track, artist, role, position)
+            VALUES (?, ?, ?, ?);",
+            track,
+            artist,
+            role,
+            position
+        )
+        .execute(&mut *conn)
+        .await?;
+    }
+    Ok(())
+}
+
+// the album with this title, which is created if there isn't one
+async fn link_album(conn: &mut SqliteConnection, title: &str) -> Result<Uuid, MioInnerError> {
+    if let Some(x) = sqlx::query!("SELECT id FROM album WHERE title = ?;", title)
+        .fetch_optional(&mut *conn)
+        .await?
+    {
+        return uuid_serialize(&x.id);
+    }
+    let id = Uuid::new_v4();
+    sqlx::query!(
+        "INSERT INTO album (id, title, sort_title) VALUES (?, ?, NULL);",
+        id,
+        title
+    )
+    .execute(&mut *conn)
+    .await?;
+    trace!("PATCH /metadata/track new album generated: {id}");
+    Ok(id)
+}
+
+// the sort name belongs to the artist, so it can't be changed out from under another
+// user that has tracks on it
+async fn set_artist_sort(
+    conn: &mut SqliteConnection,
+    userid: Uuid,
+    id: Uuid,
+    sort: Option<String>,
+) -> Result<(), MioInnerError> {
+    let sort = sort.map(|x| x.trim().to_owned());
+    let x = sqlx::query!(
+        "SELECT sort_name,
+        (SELECT COUNT(*) FROM track WHERE owner != ? AND (album_artist = ? OR id IN
+            (SELECT track FROM JOIN_track_artist WHERE artist = ?))) AS \"shared!: i64\"
+        FROM artist WHERE id = ?;",
+        userid,
+        id,
+        id,
+        id
+    )
+    .fetch_one(&mut *conn)
+    .await?;
+    if x.sort_name == sort {
+        return Ok(());
+    }
+    if x.shared > 0 {
+        return Err(MioInnerError::Conflict(anyhow!(
+            "artist {id} has tracks from other users, so its sort name cannot be changed"
+        )));
+    }
+    sqlx::query!("UPDATE artist SET sort_name = ? WHERE id = ?;", sort, id)
+        .execute(&mut *conn)
+        .await?;
+    Ok(())
+}
+
+// same as for artists
+async fn set_album_sort(
+    conn: &mut SqliteConnection,
+    userid: Uuid,
+    id: Uuid,
+    sort: Option<String>,
+) -> Result<(), MioInnerError> {
+    let sort = sort.map(|x| x.trim().to_owned());
+    let x = sqlx::query!(
+        "SELECT sort_title,
+        (SELECT COUNT(*) FROM track WHERE album = ? AND owner != ?) AS \"shared!: i64\"
+        FROM album WHERE id = ?;",
+        id,
+        userid,
+        id
+    )
+    .fetch_one(&mut *conn)
+    .await?;
+    if x.sort_title == sort {
+        return Ok(());
+    }
+    if x.shared > 0 {
+        return Err(MioInnerError::Conflict(anyhow!(
+            "album {id} has tracks from other users, so its sort title cannot be changed"
+        )));
+    }
+    sqlx::query!("UPDATE album SET sort_title = ? WHERE id = ?;", sort, id)
+        .execute(&mut *conn)
+        .await?;
+    Ok(())
+}
+
+// remove artists and an album if no track is on them anymore
+pub(crate) async fn forget_orphans(
+    conn: &mut SqliteConnection,
+    artists: &[Uuid],
+    album: Option<Uuid>,
+) -> Result<(), MioInnerError> {
+    for artist in artists {
+        sqlx::query!(
+            "DELETE FROM artist WHERE id = ?
+            AND NOT EXISTS (SELECT 1 FROM track WHERE artist = ? OR album_artist = ?)
+            AND NOT EXISTS (SELECT 1 FROM JOIN_track_artist WHERE artist = ?);",
+            artist,
+            artist,
+            artist,
+            artist
+        )
+        .execute(&mut *conn)
+        .await?;
+    }
+    if let Some(album) = album {
+        sqlx::query!(
+            "DELETE FROM album WHERE id = ? AND NOT EXISTS
+            (SELECT 1 FROM track WHERE album = ?);",
+            album,
+            album
+        )
+        .execute(&mut *conn)
+        .await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use crate::test::*;
+    use axum::http::{Method, StatusCode};
+    use mio_protocol::*;
+
+    #[tokio::test]
+    async fn metadata_edit_good() {
+        let cli = client().await;
+        let jwt = gen_user(&cli, "metadata_edit_good").await;
+        let id = gen_track(jwt.whois().unwrap().userid, b"not really audio").await;
+        let edit = |edit: msgstructs::TrackEdit| {
+            jwt_header(&cli, Method::PATCH, "/api/metadata/track", &jwt).json(&edit)
+        };
+        let info = || {
+            jwt_header(
+                &cli,
+                Method::GET,
+                &format!("/api/query/track?id={id}"),
+                &jwt,
+            )
+        };
+        edit(msgstructs::TrackEdit {
+            id,
+            title: Some("Fixed Title".to_owned()),
+            artist: Some(Some("metadata_edit_good artist".to_owned())),
+            artist_sort: Some(Some("artist, metadata_edit_good".to_owned())),
+            album: Some(Some("metadata_edit_good album".to_owned())),
+            disk: Some(Some(1)),
+            track: Some(Some(3)),
+            tags: [("genre".to_owned(), Some("Ambient".to_owned()))].into(),
+            ..Default::default()
+        })
+        .await;
+        let track = info().await.json::<retstructs::Track>();
+        assert_eq!(track.title, "Fixed Title");
+        assert_eq!((track.disk, track.track), (Some(1), Some(3)));
+        assert_eq!(track.tags.get("genre").map(String::as_str), Some("Ambient"));
+        let artist = track.artist.unwrap();
+        let album = track.album.unwrap();
+        let resp = jwt_header(
+            &cli,
+            Method::GET,
+            &format!("/api/query/artist?id={artist}"),
+            &jwt,
+        )
+        .await
+        .json::<retstructs::Artist>();
+        assert_eq!(resp.name, "metadata_edit_good artist");
+        assert_eq!(
+            resp.sort_name.as_deref(),
+            Some("artist, metadata_edit_good")
+        );
+
+        // clearing things leaves the rest alone, and the old artist and album go away
+        edit(msgstructs::TrackEdit {
+            id,
+            artist: Some(Some("metadata_edit_good artist 2".to_owned())),
+            album: Some(None),
+            track: Some(None),
+            tags: [("genre".to_owned(), None)].into(),
+            ..Default::default()
+        })
+        .await;
+        let track = info().await.json::<retstructs::Track>();
+        assert_eq!(track.title, "Fixed Title");
+        assert_eq!((track.disk, track.track), (Some(1), None));
+        assert!(track.tags.is_empty());
+        assert_eq!(track.album, None);
+        assert_ne!(track.artist, Some(artist));
+        assert!(sqlx::query!("SELECT id FROM artist WHERE id = ?;", artist)
+            .fetch_optional(&STATE.db)
+            .await
+            .unwrap()
+            .is_none());
+        assert!(sqlx::query!("SELECT id FROM album WHERE id = ?;", album)
+            .fetch_optional(&STATE.db)
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn metadata_edit_artists() {
+        let cli = client().await;
+        let jwt = gen_user(&cli, "metadata_edit_artists").await;
+        let id = gen_track(jwt.whois().unwrap().userid, b"not really audio").await;
+        let edit = |edit: msgstructs::TrackEdit| {
+            jwt_header(&cli, Method::PATCH, "/api/metadata/track", &jwt).json(&edit)
+        };
+        let info = || {
+            jwt_header(
+                &cli,
+                Method::GET,
+                &format!("/api/query/track?id={id}"),
+                &jwt,
+            )
+        };
+        let artist_name = |id| {
+            let (cli, jwt) = (&cli, &jwt);
+            async move {
+                jwt_header(cli, Method::GET, &format!("/api/query/artist?id={id}"), jwt)
+                    .await
+                    .json::<retstructs::Artist>()
+                    .name
+            }
+        };
+        let credit = |name: &str, role| msgstructs::TrackCredit {
+            name: format!("metadata_edit_artists {name}"),
+            role,
+        };
+        edit(msgstructs::TrackEdit {
+            id,
+            artists: Some(vec![
+                credit("a", ArtistRole::Primary),
+                credit("b", ArtistRole::Featured),
+                credit("c", ArtistRole::Primary),
+                credit("d", ArtistRole::Composer),
+            ]),
+            album_artist: Some(Some("metadata_edit_artists various".to_owned())),
+            ..Default::default()
+        })
+        .await
+        .assert_status(StatusCode::OK);
+        let track = info().await.json::<retstructs::Track>();
+        let roles = track.artists.iter().map(|x| x.role).collect::<Vec<_>>();
+        assert_eq!(
+            roles,
+            [
+                ArtistRole::Primary,
+                ArtistRole::Featured,
+                ArtistRole::Primary,
+                ArtistRole::Composer
+            ]
+        );
+        assert_eq!(track.artist, Some(track.artists[0].id));
+        assert_eq!(
+            artist_name(track.artists[1].id).await,
+            "metadata_edit_artists b"
+        );
+        assert_eq!(
+            artist_name(track.album_artist.unwrap()).await,
+            "metadata_edit_artists various"
+        );
+
+        // setting the artist only replaces the primary ones
+        edit(msgstructs::TrackEdit {
+            id,
+            artist: Some(Some("metadata_edit_artists e".to_owned())),
+            album_artist: Some(None),
+            ..Default::default()
+        })
+        .await
+        .assert_status(StatusCode::OK);
+        let after = info().await.json::<retstructs::Track>();
+        assert_eq!(after.artists.len(), 3);
+        assert_eq!(after.artist, Some(after.artists[0].id));
+        assert_eq!(after.artists[0].role, ArtistRole::Primary);
+        assert_eq!(after.artists[1], track.artists[1]);
+        assert_eq!(after.artists[2], track.artists[3]);
+        assert_eq!(after.album_artist, None);
+        for gone in [
+            track.artists[0].id,
+            track.artists[2].id,
+            track.album_artist.unwrap(),
+        ] {
+            assert!(sqlx::query!("SELECT id FROM artist WHERE id = ?;", gone)
+                .fetch_optional(&STATE.db)
+                .await
+                .unwrap()
+                .is_none());
+        }
+
+        // and everyone goes along with the track
+        jwt_header(&cli, Method::DELETE, &format!("/api/track?id={id}"), &jwt)
+            .await
+            .assert_status(StatusCode::OK);
+        for gone in &after.artists {
+            assert!(sqlx::query!("SELECT id FROM artist WHERE id = ?;", gone.id)
+                .fetch_optional(&STATE.db)
+                .await
+                .unwrap()
+                .is_none());
+        }
+    }
+
+    #[tokio::test]
+    async fn metadata_edit_bad() {
+        let cli = client().await;
+        let jwt = gen_user(&cli, "metadata_edit_bad").await;
+        let other = gen_user(&cli, "metadata_edit_bad_2").await;
+        let id = gen_track(jwt.whois().unwrap().userid, b"not really audio").await;
+        let other_id = gen_track(other.whois().unwrap().userid, b"not really audio").await;
+        for (jwt, edit, status) in [
+            (
+                &jwt,
+                msgstructs::TrackEdit {
+                    id,
+                    title: Some(" ".to_owned()),
+                    ..Default::default()
+                },
+                StatusCode::BAD_REQUEST,
+            ),
+            (
+                &jwt,
+                msgstructs::TrackEdit {
+                    id,
+                    artist_sort: Some(Some("no artist".to_owned())),
+                    ..Default::default()
+                },
+                StatusCode::BAD_REQUEST,
+            ),
+            (
+                &jwt,
+                msgstructs::TrackEdit {
+                    id,
+                    artist: Some(None),
+                    artists: Some(vec![]),
+                    ..Default::default()
+                },
+                StatusCode::BAD_REQUEST,
+            ),
+            (
+                &other,
+                msgstructs::TrackEdit {
+                    id,
+                    title: Some("not theirs".to_owned()),
+                    ..Default::default()
+                },
+                StatusCode::NOT_FOUND,
+            ),
+        ] {
+            jwt_header(&cli, Method::PATCH, "/api/metadata/track", jwt)
+                .json(&edit)
+                .expect_failure()
+                .await
+                .assert_status(status);
+        }
+
+        // a sort name can't be changed for an artist that someone else is using
+        let shared = |id, sort: &str| msgstructs::TrackEdit {
+            id,
+            artist: Some(Some("metadata_edit_bad artist".to_owned())),
+            artist_sort: Some(Some(sort.to_owned())),
+            ..Default::default()
+        };
+        jwt_header(&cli, Method::PATCH, "/api/metadata/track", &other)
+            .json(&shared(other_id, "first"))
+            .await;
+        jwt_header(&cli, Method::PATCH, "/api/metadata/track", &jwt)
+            .json(&shared(id, "second"))
+            .expect_failure()
+            .await
+            .assert_status(StatusCode::CONFLICT);
+    }
+}
@@ -7,13 +7,20 @@ use path_absolutize::*;
 use std::path::Path;
 use uuid::Uuid;
 
+pub mod archive;
 pub mod folders;
 pub mod idquery;
+pub mod jobs;
+pub mod metadata;
 pub mod query;
 pub mod track_manage;
+pub mod upload;
 
 // util function to check if path is in user path
-fn check_dir_in_data_dir(path: impl AsRef<Path>, userid: Uuid) -> Result<(), MioInnerError> {
+pub(crate) fn check_dir_in_data_dir(
+    path: impl AsRef<Path>,
+    userid: Uuid,
+) -> Result<(), MioInnerError> {
     debug!("CD_IN_DD checking {:?}", path.as_ref());
 
     // get user dir for data
@@ -1,4 +1,5 @@
 use crate::db::uuid_serialize;
+use crate::subtasks::transcode::parse_codec;
 use crate::*;
 use anyhow::anyhow;
 use axum::extract::*;
@@ -19,6 +20,7 @@ pub fn routes() -> Router<MioState> {
         .route("/coverart", get(cover_art))
         .route("/artist", get(artist_info))
         .route("/closest", get(closest_track))
+        .route("/duplicates", get(duplicates))
 }
 
 fn uuid_map_back(x: Option<Vec<u8>>) -> Result<Option<Uuid>, MioInnerError> {
@@ -46,17 +48,34 @@ async fn track_info(
             .fetch_optional(&mut *conn)
             .await?
             .ok_or_else(|| MioInnerError::NotFound(anyhow!("could not find track {id}")))?;
+            let artists = crate::endpoints::metadata::track_credits(&mut conn, id)
+                .await?
+                .into_iter()
+                .map(|(id, role)| retstructs::TrackArtist { id, role })
+                .collect();
             retstructs::Track {
                 id,
                 album: uuid_map_back(x.album)?,
                 cover_art: uuid_map_back(x.cover_art)?,
                 artist: uuid_map_back(x.artist)?,
+                artists,
+                album_artist: uuid_map_back(x.album_artist)?,
                 title: x.title,
                 disk: x.disk,
                 track: x.track,
                 tags: serde_json::from_str(&x.tags).map_err(|err| {
                     MioInnerError::DbError(anyhow!("could not serialize tags {err}"))
                 })?,
+                codec: parse_codec(&x.codec)?,
+                bitrate: x.bitrate.map(|x| x as u32),
+                container: x.container,
+                original: x.orig_size.zip(x.orig_mime).map(|(size, mime)| {
+                    retstructs::OriginalFile {
+                        fname: x.orig_fname,
+                        size: size as u64,
+                        mime,
+                    }
+                }),
             }
         }),
     ))
@@ -199,9 +218,11 @@ async fn artist_info(
         Json({
             let mut conn = state.db.acquire().await?;
             let x = sqlx::query!(
-                "SELECT artist_name, artist.sort_name FROM artist 
-                JOIN track ON track.artist = artist.id 
-                WHERE artist.id = ? AND track.owner = ?;",
+                "SELECT artist_name, sort_name FROM artist
+                WHERE id = ? AND EXISTS (SELECT 1 FROM track WHERE owner = ? AND (
+                    album_artist = artist.id OR track.id IN
+                    (SELECT track FROM JOIN_track_artist WHERE artist = artist.id)
+                ));",
                 id,
                 userid
             )
@@ -217,6 +238,37 @@ async fn artist_info(
     ))
 }
 
+// tracks that have the same audio as another track
+#[tracing::instrument]
+async fn duplicates(
+    State(state): State<MioState>,
+    Extension(auth::JWTInner { userid, .. }): Extension<auth::JWTInner>,
+) -> Result<impl IntoResponse, MioInnerError> {
+    let mut conn = state.db.acquire().await?;
+    let tracks = sqlx::query!(
+        "SELECT id, audio_hash AS \"audio_hash!\" FROM track
+        WHERE owner = ? AND audio_hash IN (
+            SELECT audio_hash FROM track WHERE owner = ? AND audio_hash IS NOT NULL
+            GROUP BY audio_hash HAVING COUNT(*) > 1
+        )
+        ORDER BY audio_hash, rowid;",
+        userid,
+        userid
+    )
+    .fetch_all(&mut *conn)
+    .await?;
+    let mut groups: Vec<Vec<Uuid>> = vec![];
+    let mut last = None;
+    for x in tracks {
+        if last.as_ref() != Some(&x.audio_hash) {
+            groups.push(vec![]);
+            last = Some(x.audio_hash);
+        }
+        groups.last_mut().unwrap().push(uuid_serialize(&x.id)?);
+    }
+    Ok((StatusCode::OK, Json(retstructs::DuplicateGroups { groups })))
+}
+
 #[tracing::instrument]
 async fn closest_track(
     State(state): State<MioState>,
@@ -1,17 +1,24 @@
-use crate::db::write_transaction;
+use crate::db::{uuid_serialize, write_transaction};
 use crate::endpoints::check_dir_in_data_dir;
+use crate::endpoints::metadata::{forget_orphans, set_credits, track_credits};
 use crate::error::MioInnerError;
+use crate::subtasks::tagging::tag_track;
+use crate::subtasks::track_upload::original_path;
+use crate::subtasks::transcode::{parse_codec, StorageFormat};
 use crate::MioState;
 use anyhow::anyhow;
 use axum::body::Body;
 use axum::extract::*;
 use axum::http::StatusCode;
-use axum::response::IntoResponse;
+use axum::response::{IntoResponse, Response};
 use axum::routing::*;
+use base64::prelude::*;
 use futures::StreamExt;
+use hmac::{Hmac, Mac};
 #[allow(unused)]
 use log::*;
 use mio_protocol::*;
+use sha2::{Digest, Sha256};
 use std::path::{Path, PathBuf};
 use tokio::fs::{remove_file, rename, File, OpenOptions};
 use tokio::io::{AsyncWriteExt, ErrorKind};
@@ -20,41 +27,59 @@ use uuid::Uuid;
 
 // TODO: tests with actual files.
 pub fn routes() -> Router<MioState> {
-    Router::new().route(
-        "/",
-        post(track_upload)
-            .get(track_stream)
-            .patch(track_move)
-            .delete(track_delete),
-    )
+    Router::new().route("/", post(track_upload))
 }
 
-#[tracing::instrument]
-async fn track_upload(
-    State(state): State<MioState>,
-    Extension(auth::JWTInner { userid, .. }): Extension<auth::JWTInner>,
-    Query(msgstructs::TrackUploadQuery { fname, dir }): Query<msgstructs::TrackUploadQuery>,
-    payload: Body,
-) -> impl IntoResponse {
-    let mut payload = payload.into_data_stream();
-    trace!("/track/upload acquiring directory lock");
-    let _lock_hold = state.lock_files.clone();
-    let _hold = _lock_hold.read().await;
+// moving and deleting change what is already in the library, so keys need more than
+// the upload scope for them
+pub fn manage_routes() -> Router<MioState> {
+    Router::new().route("/", patch(track_move).delete(track_delete))
+}
 
-    // find a unique id for the track
-    debug!("/track/upload generating UUID");
-    let mut track_id;
-    let mut file: File;
-    let mut real_fname;
+// split out so that streaming can be allowed separately from managing tracks
+pub fn stream_routes() -> Router<MioState> {
+    Router::new()
+        .route("/", get(track_stream))
+        .route("/link", get(stream_link))
+        .route("/original", get(track_original))
+        .route("/download", get(track_download))
+}
 
-    // generate filename
+// how long stream links are valid for, in seconds
+const DEFAULT_LINK_LIFETIME: i64 = 60 * 60 * 6;
+const MAX_LINK_LIFETIME: i64 = 60 * 60 * 24;
+
+// how long an upload can go without sending anything
+pub(crate) const CHUNK_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
+// how an upload will be stored, falling back to the server settings
+pub(crate) fn upload_format(
+    codec: Option<Codec>,
+    bitrate: Option<u32>,
+    fname: Option<&str>,
+) -> Result<StorageFormat, MioInnerError> {
+    StorageFormat::new(
+        codec.unwrap_or(*crate::STORAGE_CODEC.get().unwrap()),
+        bitrate,
+        fname.unwrap_or_default(),
+    )
+    .map_err(|err| MioInnerError::TrackProcessingError(err, StatusCode::BAD_REQUEST))
+}
+
+// create a new file for an upload to go into, under a fresh track id. the caller
+// must be holding `lock_files`.
+pub(crate) async fn create_upload_file(
+    userid: Uuid,
+    dir: &str,
+) -> Result<(Uuid, PathBuf, File), MioInnerError> {
+    debug!("/track/upload generating UUID");
     loop {
-        track_id = Uuid::new_v4();
-        real_fname = crate::DATA_DIR
+        let track_id = Uuid::new_v4();
+        let real_fname = crate::DATA_DIR
             .get()
             .unwrap()
             .join(format!("{userid}"))
-            .join(&dir)
+            .join(dir)
             .join(format!("{track_id}"));
         check_dir_in_data_dir(real_fname.clone(), userid)?;
 
@@ -71,8 +96,7 @@ async fn track_upload(
                     "/track/upload opened file {}",
                     real_fname.as_os_str().to_string_lossy()
                 );
-                file = opened_file;
-                break;
+                return Ok((track_id, real_fname, opened_file));
             }
             Err(err) => {
                 if err.kind() == ErrorKind::AlreadyExists {
@@ -84,9 +108,35 @@ async fn track_upload(
             }
         }
     }
+}
 
-    // get original filename
-    let orig_filename = sanitize_filename::sanitize_with_options(
+// a content disposition that has the client save a file under the given name
+pub(crate) fn attachment(fname: &str) -> String {
+    // the plain filename is for clients that don't understand filename*
+    let fallback = fname
+        .chars()
+        .map(|x| match x {
+            '"' | '\\' => '_',
+            x if x == ' ' || x.is_ascii_graphic() => x,
+            _ => '_',
+        })
+        .collect::<String>();
+    let encoded = fname
+        .bytes()
+        .map(|x| {
+            if x.is_ascii_alphanumeric() || b"!#$&+-.^_`|~".contains(&x) {
+                (x as char).to_string()
+            } else {
+                format!("%{x:02X}")
+            }
+        })
+        .collect::<String>();
+    format!("attachment; filename=\"{fallback}\"; filename*=UTF-8''{encoded}")
+}
+
+// the name the track was uploaded with, made safe
+pub(crate) fn sanitize_fname(fname: Option<String>, track_id: Uuid) -> String {
+    sanitize_filename::sanitize_with_options(
         fname.unwrap_or_else(|| {
             trace!("PUT track_upload generated fname with uuid");
             track_id.to_string()
@@ -95,19 +145,70 @@ async fn track_upload(
             windows: true,
             ..Default::default()
         },
-    );
+    )
+}
+
+#[tracing::instrument]
+async fn track_upload(
+    State(state): State<MioState>,
+    Extension(auth::JWTInner { userid, .. }): Extension<auth::JWTInner>,
+    Query(msgstructs::TrackUploadQuery {
+        fname,
+        dir,
+        codec,
+        bitrate,
+        duplicates,
+    }): Query<msgstructs::TrackUploadQuery>,
+    payload: Body,
+) -> impl IntoResponse {
+    let mut payload = payload.into_data_stream();
+
+    // figure out how the track will be stored
+    let format = upload_format(codec, bitrate, fname.as_deref())?;
+    trace!("/track/upload acquiring directory lock");
+    let _lock_hold = state.lock_files.clone();
+    let _hold = _lock_hold.read().await;
+
+    // check quotas before taking any data. uploads that are still being processed
+    // count as tracks too.
+    let mut conn = state.db.acquire().await?;
+    let usage = crate::quota::check_new_upload(&mut conn, userid, 0, 1).await?;
+    drop(conn);
+
+    // find a unique id for the track
+    let (track_id, real_fname, mut file) = create_upload_file(userid, &dir).await?;
+    let orig_filename = sanitize_fname(fname, track_id);
     debug!(
         "/track/upload filename and uuid used: \"{orig_filename}\" -> \"{}\": {track_id}",
         real_fname.as_os_str().to_string_lossy()
     );
 
-    // TODO: filesize limits
-    //
-    // download the file
+    // download the file, hashing it on the way in
+    let mut written = 0;
+    let mut sha = Sha256::new();
     loop {
-        let chunk = timeout(core::time::Duration::from_secs(10), payload.next()).await;
+        let chunk = timeout(CHUNK_TIMEOUT, payload.next()).await;
         match chunk {
             Ok(Some(Ok(chunk))) => {
+                // stop as soon as the upload goes over the size limit or quota
+                written += chunk.len() as u64;
+                let max_size = *crate::MAX_UPLOAD_SIZE.get().unwrap();
+                if written > max_size {
+                    debug!("/track/upload {track_id} went over the max upload size");
+                    drop(file);
+                    remove_file(real_fname).await?;
+                    return Err(MioInnerError::TrackProcessingError(
+                        anyhow!("upload is larger than the max of {max_size} bytes"),
+                        StatusCode::PAYLOAD_TOO_LARGE,
+                    ));
+                }
+                if let Err(err) = crate::quota::check(&usage, written, 1) {
+                    debug!("/track/upload {track_id} went over quota");
+                    drop(file);
+                    remove_file(real_fname).await?;
+                    return Err(err);
+                }
+                sha.update(&chunk);
                 if let Err(err) = file.write_all(&chunk).await {
                     error!("/track/upload failed to write to file: {err}");
                     file.flush().await?;
@@ -149,18 +250,51 @@ async fn track_upload(
     trace!("/track/upload out of chunks, final flushing {track_id}");
     file.shutdown().await?;
 
-    // set off task to process files
-    crate::subtasks::track_upload::track_upload_process(
-        state,
-        track_id,
-        real_fname,
-        dir,
-        userid,
-        orig_filename,
+    // an exact copy can be caught now, anything else is caught while processing
+    let duplicates = duplicates.unwrap_or_default();
+    let mut conn = state.db.acquire().await?;
+    if duplicates != DuplicatePolicy::Allow {
+        let file_hash = sha.finalize();
+        let existing = crate::subtasks::track_upload::find_duplicate(
+            &mut conn,
+            userid,
+            Some(file_hash.as_slice()),
+            None,
+        )
+        .await?;
+        if let Some(existing) = existing {
+            remove_file(real_fname).await?;
+            if duplicates == DuplicatePolicy::Reject {
+                return Err(crate::subtasks::track_upload::duplicate_error(existing));
+            }
+            debug!("/track/upload {track_id} is a copy of {existing}, skipping");
+            return Ok((
+                StatusCode::OK,
+                Json(retstructs::UploadReturn { uuid: existing }),
+            ));
+        }
+    }
+
+    // hand off to be processed
+    if let Err(err) = crate::subtasks::jobs::enqueue(
+        &mut conn,
+        crate::subtasks::jobs::NewJob {
+            id: track_id,
+            owner: userid,
+            dir,
+            orig_fname: orig_filename,
+            format,
+            duplicates,
+            inbox: None,
+        },
     )
-    .await?;
+    .await
+    {
+        remove_file(real_fname).await?;
+        return Err(err);
+    }
     Ok((
-        StatusCode::OK,
+        StatusCode::ACCEPTED,
         Json(retstructs::UploadReturn { uuid: track_id }),
     ))
 }
@@ -168,23 +302,254 @@ async fn track_upload(
 #[tracing::instrument]
 async fn track_stream(
     State(state): State<MioState>,
-    Query(msgstructs::IdInfoQuery { id }): Query<msgstructs::IdInfoQuery>,
+    Query(msgstructs::TrackStreamQuery {
+        id,
+        format,
+        bitrate,
+    }): Query<msgstructs::TrackStreamQuery>,
     Extension(auth::JWTInner { userid, .. }): Extension<auth::JWTInner>,
-) -> impl IntoResponse {
+) -> Result<Response, MioInnerError> {
+    stream_track(state, userid, id, format, bitrate).await
+}
+
+// stream a track, transcoding it if it's asked for in something else
+async fn stream_track(
+    state: MioState,
+    userid: Uuid,
+    id: Uuid,
+    format: Option<Codec>,
+    bitrate: Option<u32>,
+) -> Result<Response, MioInnerError> {
+    let Some(format) = format else {
+        return Ok(load_track(state, userid, id).await?.into_response());
+    };
+    let format = StorageFormat::new(format, bitrate, "")
+        .map_err(|err| MioInnerError::TrackProcessingError(err, StatusCode::BAD_REQUEST))?;
+
+    // find out what it's stored as, there's no need to transcode if it's already right
+    let mut conn = state.db.acquire().await?;
+    let stored = sqlx::query!(
+        "SELECT codec, bitrate FROM track WHERE id = ? AND owner = ?;",
+        id,
+        userid
+    )
+    .fetch_optional(&mut *conn)
+    .await?
+    .ok_or_else(|| MioInnerError::NotFound(anyhow!("{id} under {userid} does not exist")))?;
+    drop(conn);
+    let stored_codec = parse_codec(&stored.codec)?;
+    if format.codec == Codec::Original
+        || (format.codec == stored_codec
+            && (bitrate.is_none() || stored.bitrate.map(|x| x as u32) == bitrate))
+    {
+        trace!("/track/stream {id} is already in the right format");
+        return Ok(load_track(state, userid, id).await?.into_response());
+    }
+    if format.codec == Codec::Qoa {
+        return Err(MioInnerError::TrackProcessingError(
+            anyhow!("qoa cannot be transcoded to while streaming"),
+            StatusCode::BAD_REQUEST,
+        ));
+    }
+
+    let content_type = crate::subtasks::transcode::content_type(&format.container);
+    if let Some(data) = crate::cache::fetch(&state, id, &format).await? {
+        trace!("/track/stream {id} was already transcoded to {format:?}");
+        return Ok((
+            StatusCode::OK,
+            [(axum::http::header::CONTENT_TYPE, content_type)],
+            data,
+        )
+            .into_response());
+    }
+
+    // grab the file and send it off to be transcoded
+    let (data, _) = read_track(&state, userid, id).await?;
+    debug!("/track/stream transcoding {id} from {stored_codec:?} to {format:?}");
+    let mut rx = crate::subtasks::transcode::stream(data, stored_codec, format.clone())
+        .await
+        .map_err(|err| {
+            MioInnerError::TrackProcessingError(err, StatusCode::INTERNAL_SERVER_ERROR)
+        })?;
+    if *crate::TRANSCODE_CACHE_SIZE.get().unwrap() > 0 {
+        rx = crate::cache::tee(state, id, format, rx);
+    }
+    Ok((
+        StatusCode::OK,
+        [(axum::http::header::CONTENT_TYPE, content_type)],
+        Body::from_stream(futures::stream::unfold(rx, |mut rx| async move {
+            rx.recv().await.map(|x| (x, rx))
+        })),
+    )
+        .into_response())
+}
+
+// give out a link that streams a track without needing the Authorization header,
+// for players that cannot set it
+#[tracing::instrument]
+async fn stream_link(
+    State(state): State<MioState>,
+    Query(msgstructs::StreamLinkQuery {
+        id,
+        lifetime,
+        format,
+        bitrate,
+    }): Query<msgstructs::StreamLinkQuery>,
+    Extension(auth::JWTInner { userid, .. }): Extension<auth::JWTInner>,
+) -> Result<impl IntoResponse, MioInnerError> {
+    let lifetime = lifetime.unwrap_or(DEFAULT_LINK_LIFETIME);
+    if !(1..=MAX_LINK_LIFETIME).contains(&lifetime) {
+        return Err(MioInnerError::ExternalIoError(
+            anyhow!("link lifetime must be between 1 and {MAX_LINK_LIFETIME} seconds"),
+            StatusCode::BAD_REQUEST,
+        ));
+    }
+    if let Some(format) = format {
+        StorageFormat::new(format, bitrate, "")
+            .map_err(|err| MioInnerError::TrackProcessingError(err, StatusCode::BAD_REQUEST))?;
+    }
+    let mut conn = state.db.acquire().await?;
+    sqlx::query!(
+        "SELECT id FROM track WHERE id = ? AND owner = ?;",
+        id,
+        userid
+    )
+    .fetch_optional(&mut *conn)
+    .await?
+    .ok_or_else(|| MioInnerError::NotFound(anyhow!("{id} under {userid} does not exist")))?;
+    let exp = chrono::Utc::now().timestamp().saturating_add(lifetime);
+    let sig = BASE64_URL_SAFE_NO_PAD.encode(
+        stream_mac(&mut conn, id, userid, exp, format, bitrate)
+            .await?
+            .finalize()
+            .into_bytes(),
+    );
+    trace!("/track/link signed {id} under {userid} until {exp}");
+    let mut url = format!("/stream?id={id}&user={userid}&exp={exp}");
+    if let Some(format) = format {
+        url.push_str(&format!("&format={}", format.as_str()));
+    }
+    if let Some(bitrate) = bitrate {
+        url.push_str(&format!("&bitrate={bitrate}"));
+    }
+    url.push_str(&format!("&sig={sig}"));
+    Ok((
+        StatusCode::OK,
+        Json(retstructs::StreamLink { url, expiry: exp }),
+    ))
+}
+
+// stream from a signed link. this is not behind the auth handler.
+#[tracing::instrument]
+pub async fn signed_stream(
+    State(state): State<MioState>,
+    Query(msgstructs::SignedStreamQuery {
+        id,
+        user,
+        exp,
+        format,
+        bitrate,
+        sig,
+    }): Query<msgstructs::SignedStreamQuery>,
+) -> Result<Response, MioInnerError> {
+    let bad_link = || {
+        MioInnerError::UserChallengedFail(
+            anyhow!("Invalid or expired stream link"),
+            StatusCode::UNAUTHORIZED,
+        )
+    };
+    let sig = BASE64_URL_SAFE_NO_PAD.decode(sig).map_err(|_| bad_link())?;
+    let mut conn = state.db.acquire().await?;
+    stream_mac(&mut conn, id, user, exp, format, bitrate)
+        .await?
+        .verify_slice(&sig)
+        .map_err(|_| bad_link())?;
+    if exp <= chrono::Utc::now().timestamp() {
+        return Err(bad_link());
+    }
+
+    // links stop working when the owner is disabled
+    if sqlx::query!(
+        "SELECT id FROM user WHERE id = ? AND disabled = FALSE;",
+        user
+    )
+    .fetch_optional(&mut *conn)
+    .await?
+    .is_none()
+    {
+        return Err(bad_link());
+    }
+    drop(conn);
+    stream_track(state, user, id, format, bitrate).await
+}
+
+// the mac of a stream link, which covers the track, owner, expiry and what it's
+// streamed as
+async fn stream_mac(
+    conn: &mut sqlx::SqliteConnection,
+    id: Uuid,
+    userid: Uuid,
+    exp: i64,
+    format: Option<Codec>,
+    bitrate: Option<u32>,
+) -> Result<Hmac<Sha256>, MioInnerError> {
+    let secret = sqlx::query!("SELECT secret FROM signing_key WHERE name = 'stream';")
+        .fetch_one(&mut *conn)
+        .await?
+        .secret;
+    let mut mac = Hmac::<Sha256>::new_from_slice(&secret)
+        .map_err(|err| MioInnerError::Panicked(anyhow!("could not create hmac: {err}")))?;
+    mac.update(id.as_bytes());
+    mac.update(userid.as_bytes());
+    mac.update(&exp.to_be_bytes());
+    // bitrates are never 0, and codec names never have a nul in them
+    mac.update(format.map_or("", |x| x.as_str()).as_bytes());
+    mac.update(&[0]);
+    mac.update(&bitrate.unwrap_or(0).to_be_bytes());
+    Ok(mac)
+}
+
+// read a track off disk for streaming
+async fn load_track(
+    state: MioState,
+    userid: Uuid,
+    id: Uuid,
+) -> Result<impl IntoResponse, MioInnerError> {
+    let (bytes, container) = read_track(&state, userid, id).await?;
+    trace!(
+        "/track/stream sending back {:?} bytes from {id}",
+        bytes.len()
+    );
+    Ok((
+        StatusCode::OK,
+        [(
+            axum::http::header::CONTENT_TYPE,
+            crate::subtasks::transcode::content_type(&container),
+        )],
+        bytes,
+    ))
+}
+
+// read a stored track and the container it's stored in
+async fn read_track(
+    state: &MioState,
+    userid: Uuid,
+    id: Uuid,
+) -> Result<(Vec<u8>, String), MioInnerError> {
     trace!("/track/stream locking read dir");
     let _hold = state.lock_files.read().await;
 
     // get dir
     trace!("/track/stream grabbing dir");
     let mut conn = state.db.acquire().await?;
-    let dir = sqlx::query!(
-        "SELECT path FROM track WHERE id = ? AND owner = ?;",
+    let (dir, container) = sqlx::query!(
+        "SELECT path, container FROM track WHERE id = ? AND owner = ?;",
         id,
         userid
     )
     .fetch_optional(&mut *conn)
     .await?
-    .map(|x| x.path)
+    .map(|x| (x.path, x.container))
     .ok_or_else(|| MioInnerError::NotFound(anyhow!("{id} under {userid} does not exist")))?;
     drop(conn);
 
@@ -212,16 +577,121 @@ async fn track_stream(
                 Err(MioInnerError::from(err))
             }
         }
-        Ok(bytes) => {
-            trace!(
-                "/track/stream sending back {:?} bytes from {id}",
-                bytes.len()
-            );
-            Ok((StatusCode::OK, bytes))
-        }
+        Ok(bytes) => Ok((bytes, container)),
     }
 }
 
+// download the stored copy of a track, named after the upload
+#[tracing::instrument]
+async fn track_download(
+    State(state): State<MioState>,
+    Query(msgstructs::TrackDownloadQuery { id, tags }): Query<msgstructs::TrackDownloadQuery>,
+    Extension(auth::JWTInner { userid, .. }): Extension<auth::JWTInner>,
+) -> Result<impl IntoResponse, MioInnerError> {
+    let (data, container) = read_track(&state, userid, id).await?;
+    let mut conn = state.db.acquire().await?;
+    let orig_fname = sqlx::query!(
+        "SELECT orig_fname FROM track WHERE id = ? AND owner = ?;",
+        id,
+        userid
+    )
+    .fetch_optional(&mut *conn)
+    .await?
+    .ok_or_else(|| MioInnerError::NotFound(anyhow!("{id} under {userid} does not exist")))?
+    .orig_fname;
+    let data = if tags.unwrap_or(true) {
+        tag_track(&mut conn, id, userid, data, container.clone()).await?
+    } else {
+        data
+    };
+    let fname = Path::new(&orig_fname).with_extension(&container);
+    Ok((
+        StatusCode::OK,
+        [
+            (
+                axum::http::header::CONTENT_TYPE,
+                crate::subtasks::transcode::content_type(&container).to_owned(),
+            ),
+            (
+                axum::http::header::CONTENT_DISPOSITION,
+                attachment(&fname.to_string_lossy()),
+            ),
+        ],
+        data,
+    ))
+}
+
+// download the file as it was uploaded, under the name it was uploaded with
+#[tracing::instrument]
+async fn track_original(
+    State(state): State<MioState>,
+    Query(msgstructs::TrackDownloadQuery { id, tags }): Query<msgstructs::TrackDownloadQuery>,
+    Extension(auth::JWTInner { userid, .. }): Extension<auth::JWTInner>,
+) -> Result<impl IntoResponse, MioInnerError> {
+    let _hold = state.lock_files.read().await;
+    let mut conn = state.db.acquire().await?;
+    let x = sqlx::query!(
+        "SELECT path, orig_fname, orig_mime, codec, container
+        FROM track WHERE id = ? AND owner = ?;",
+        id,
+        userid
+    )
+    .fetch_optional(&mut *conn)
+    .await?
+    .ok_or_else(|| MioInnerError::NotFound(anyhow!("{id} under {userid} does not exist")))?;
+    drop(conn);
+    let mime = x
+        .orig_mime
+        .ok_or_else(|| MioInnerError::NotFound(anyhow!("the original of {id} was not kept")))?;
+
+    // tracks stored as-is are their own original
+    let path = crate::DATA_DIR
+        .get()
+        .unwrap()
+        .join(format!("{userid}"))
+        .join(&x.path)
+        .join(format!("{id}"));
+    let path = if x.codec == Codec::Original.as_str() {
+        path
+    } else {
+        original_path(&path)
+    };
+    let data = tokio::fs::read(&path).await.map_err(|err| {
+        if err.kind() == ErrorKind::NotFound {
+            warn!("/track/original {path:?} doesn't exist, despite the db saying it does");
+            MioInnerError::NotFound(anyhow!("the original of {id} is missing"))
+        } else {
+            err.into()
+        }
+    })?;
+    let data = if tags.unwrap_or(false) {
+        // the original is in whatever it was uploaded as
+        let container = if x.codec == Codec::Original.as_str() {
+            x.container
+        } else {
+            Path::new(&x.orig_fname)
+                .extension()
+                .map(|x| x.to_string_lossy().to_lowercase())
+                .unwrap_or_default()
+        };
+        let mut conn = state.db.acquire().await?;
+        tag_track(&mut conn, id, userid, data, container).await?
+    } else {
+        data
+    };
+    Ok((
+        StatusCode::OK,
+        [
+            (axum::http::header::CONTENT_TYPE, mime),
+            (
+                axum::http::header::CONTENT_DISPOSITION,
+                attachment(&x.orig_fname),
+            ),
+        ],
+        data,
+    ))
+}
+
 #[tracing::instrument]
 async fn track_move(
     State(state): State<MioState>,
@@ -261,6 +731,8 @@ async fn track_move(
                 .join(&new_path)
                 .join(format!("{id}"));
             check_dir_in_data_dir(next_fname.clone(), userid)?;
+            let curr_orig = original_path(&curr_fname);
+            let next_orig = original_path(&next_fname);
 
             // note: no collision check is needed because every id is almost certainly
             // guaranteed to be unique. begin the actual meat of the transaction
@@ -273,6 +745,11 @@ async fn track_move(
             .execute(&mut *txn)
             .await?;
             rename(curr_fname, next_fname).await?;
+
+            // the original goes wherever the track does
+            if tokio::fs::try_exists(&curr_orig).await? {
+                rename(curr_orig, next_orig).await?;
+            }
             Ok::<_, MioInnerError>(StatusCode::OK)
         })
     })
@@ -292,32 +769,432 @@ async fn track_delete(
         Box::pin(async move {
             // fetch path and delete from db
             trace!("/track/delete finding path to remove");
-            let path = sqlx::query!(
-                "SELECT path FROM track WHERE id = ? AND owner = ?;",
+            let x = sqlx::query!(
+                "SELECT path, album, album_artist FROM track WHERE id = ? AND owner = ?;",
                 id,
                 userid
             )
             .fetch_optional(&mut *txn)
             .await?
-            .map(|x| x.path)
             .ok_or_else(|| {
                 MioInnerError::NotFound(anyhow!("track {id} for owner {userid} does not exist"))
             })?;
+            crate::cache::invalidate(&mut *txn, id).await?;
+            let mut artists = track_credits(&mut *txn, id)
+                .await?
+                .into_iter()
+                .map(|x| x.0)
+                .collect::<Vec<_>>();
+            artists.extend(x.album_artist.map(|x| uuid_serialize(&x)).transpose()?);
+            set_credits(&mut *txn, id, &[]).await?;
             sqlx::query!("DELETE FROM track WHERE id = ? AND owner = ?;", id, userid)
                 .execute(&mut *txn)
                 .await?;
+            forget_orphans(
+                &mut *txn,
+                &artists,
+                x.album.map(|x| uuid_serialize(&x)).transpose()?,
+            )
+            .await?;
 
             // delete realspace file
             let path = crate::DATA_DIR
                 .get()
                 .unwrap()
                 .join(format!("{userid}"))
-                .join(&path)
+                .join(&x.path)
                 .join(format!("{id}"));
             trace!("/track/delete path to delete is {path:?}");
+            let orig = original_path(&path);
             remove_file(path).await?;
+            if let Err(err) = remove_file(orig).await {
+                if err.kind() != ErrorKind::NotFound {
+                    return Err(err.into());
+                }
+            }
             Ok::<_, MioInnerError>(StatusCode::OK)
         })
     })
     .await
 }
+
+#[cfg(test)]
+mod test {
+    use crate::test::*;
+    use axum::http::{Method, StatusCode};
+    use mio_protocol::*;
+
+    #[tokio::test]
+    async fn track_stream_link_good() {
+        let cli = client().await;
+        let jwt = gen_user(&cli, "track_stream_link_good").await;
+        let id = gen_track(jwt.whois().unwrap().userid, b"not really audio").await;
+        let link = jwt_header(&cli, Method::GET, &format!("/api/track/link?id={id}"), &jwt)
+            .await
+            .json::<retstructs::StreamLink>();
+
+        // no auth header needed
+        let resp = cli.get(&link.url).await;
+        assert_eq!(resp.as_bytes().as_ref(), b"not really audio");
+        assert_eq!(
+            resp.headers()[axum::http::header::CONTENT_TYPE],
+            crate::subtasks::qoa::CONTENT_TYPE
+        );
+
+        // links can ask for a format, like a normal stream
+        let link = jwt_header(
+            &cli,
+            Method::GET,
+            &format!("/api/track/link?id={id}&format=original"),
+            &jwt,
+        )
+        .await
+        .json::<retstructs::StreamLink>();
+        assert!(link.url.contains("format=original"));
+        let resp = cli.get(&link.url).await;
+        assert_eq!(resp.as_bytes().as_ref(), b"not really audio");
+    }
+
+    #[tokio::test]
+    async fn track_stream_codec_good() {
+        let cli = client().await;
+        let jwt = gen_user(&cli, "track_stream_codec_good").await;
+        let id = gen_track(jwt.whois().unwrap().userid, b"not really audio").await;
+        sqlx::query!(
+            "UPDATE track SET codec = 'original', container = 'flac' WHERE id = ?;",
+            id
+        )
+        .execute(&STATE.db)
+        .await
+        .unwrap();
+
+        // stored codec is reported back
+        let track = jwt_header(
+            &cli,
+            Method::GET,
+            &format!("/api/query/track?id={id}"),
+            &jwt,
+        )
+        .await
+        .json::<retstructs::Track>();
+        assert_eq!(track.codec, Codec::Original);
+        assert_eq!(track.bitrate, None);
+        assert_eq!(track.container, "flac");
+
+        // and is served as what it is
+        let resp = jwt_header(&cli, Method::GET, &format!("/api/track?id={id}"), &jwt).await;
+        assert_eq!(
+            resp.headers()[axum::http::header::CONTENT_TYPE],
+            "audio/flac"
+        );
+    }
+
+    #[tokio::test]
+    async fn track_stream_format_good() {
+        let cli = client().await;
+        let jwt = gen_user(&cli, "track_stream_format_good").await;
+        let id = gen_track(jwt.whois().unwrap().userid, b"not really audio").await;
+
+        // asking for what's already stored skips transcoding
+        for format in ["qoa", "original"] {
+            let resp = jwt_header(
+                &cli,
+                Method::GET,
+                &format!("/api/track?id={id}&format={format}"),
+                &jwt,
+            )
+            .await;
+            assert_eq!(resp.as_bytes().as_ref(), b"not really audio");
+        }
+    }
+
+    #[tokio::test]
+    async fn track_stream_format_bad() {
+        let cli = client().await;
+        let jwt = gen_user(&cli, "track_stream_format_bad").await;
+        let id = gen_track(jwt.whois().unwrap().userid, b"not really audio").await;
+        jwt_header(
+            &cli,
+            Method::GET,
+            &format!("/api/track?id={id}&format=opus&bitrate=1000"),
+            &jwt,
+        )
+        .expect_failure()
+        .await
+        .assert_status(StatusCode::BAD_REQUEST);
+
+        // qoa can only be made from the whole track
+        sqlx::query!(
+            "UPDATE track SET codec = 'flac', container = 'flac' WHERE id = ?;",
+            id
+        )
+        .execute(&STATE.db)
+        .await
+        .unwrap();
+        jwt_header(
+            &cli,
+            Method::GET,
+            &format!("/api/track?id={id}&format=qoa"),
+            &jwt,
+        )
+        .expect_failure()
+        .await
+        .assert_status(StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn track_upload_bad_too_large() {
+        let cli = client().await;
+        let jwt = gen_user(&cli, "track_upload_bad_too_large").await;
+        jwt_header(&cli, Method::POST, "/api/track?dir=", &jwt)
+            .bytes(vec![0; TEST_MAX_UPLOAD_SIZE as usize + 1].into())
+            .expect_failure()
+            .await
+            .assert_status(StatusCode::PAYLOAD_TOO_LARGE);
+
+        // nothing is left behind
+        let usage = jwt_header(&cli, Method::GET, "/user/storage", &jwt)
+            .await
+            .json::<retstructs::StorageUsage>();
+        assert_eq!(usage.bytes, 0);
+    }
+
+    #[tokio::test]
+    async fn track_upload_duplicates() {
+        use sha2::{Digest, Sha256};
+
+        let cli = client().await;
+        let jwt = gen_user(&cli, "track_upload_duplicates").await;
+        let userid = jwt.whois().unwrap().userid;
+        let data = b"already uploaded";
+        let orig = gen_track(userid, data).await;
+        let copy = gen_track(userid, data).await;
+        let other = gen_track(userid, b"something else").await;
+        let file_hash = Sha256::digest(data).to_vec();
+        let audio_hash = Sha256::digest(b"the same audio").to_vec();
+        for (id, file_hash) in [(orig, Some(&file_hash)), (copy, None), (other, None)] {
+            let audio_hash = (id != other).then_some(&audio_hash);
+            sqlx::query!(
+                "UPDATE track SET file_hash = ?, audio_hash = ? WHERE id = ?;",
+                file_hash,
+                audio_hash,
+                id
+            )
+            .execute(&STATE.db)
+            .await
+            .unwrap();
+        }
+
+        // the same file again is caught straight away
+        let upload = |policy: &str| {
+            jwt_header(
+                &cli,
+                Method::POST,
+                &format!("/api/track?dir=&duplicates={policy}"),
+                &jwt,
+            )
+            .bytes(data[..].into())
+        };
+        upload("reject")
+            .expect_failure()
+            .await
+            .assert_status(StatusCode::CONFLICT);
+        let resp = upload("skip").await;
+        resp.assert_status(StatusCode::OK);
+        assert_eq!(resp.json::<retstructs::UploadReturn>().uuid, orig);
+        upload("allow").await.assert_status(StatusCode::ACCEPTED);
+
+        // tracks with the same audio are grouped, oldest first
+        let groups = jwt_header(&cli, Method::GET, "/api/query/duplicates", &jwt)
+            .await
+            .json::<retstructs::DuplicateGroups>();
+        assert_eq!(groups.groups, vec![vec![orig, copy]]);
+    }
+
+    #[tokio::test]
+    async fn track_original_good() {
+        let cli = client().await;
+        let jwt = gen_user(&cli, "track_original_good").await;
+        let userid = jwt.whois().unwrap().userid;
+        let id = gen_track(userid, b"the storage copy").await;
+        let url = format!("/api/track/original?id={id}");
+        jwt_header(&cli, Method::GET, &url, &jwt)
+            .expect_failure()
+            .await
+            .assert_status(StatusCode::NOT_FOUND);
+
+        // pretend that the original was kept while processing
+        let path = crate::DATA_DIR
+            .get()
+            .unwrap()
+            .join(format!("{userid}"))
+            .join(format!("{id}"));
+        tokio::fs::write(super::original_path(&path), b"the original")
+            .await
+            .unwrap();
+        sqlx::query!(
+            "UPDATE track SET orig_fname = 'Ünïcode \"song\".flac', orig_size = 12,
+            orig_mime = 'audio/flac' WHERE id = ?;",
+            id
+        )
+        .execute(&STATE.db)
+        .await
+        .unwrap();
+        let resp = jwt_header(&cli, Method::GET, &url, &jwt).await;
+        assert_eq!(resp.as_bytes().as_ref(), b"the original");
+        assert_eq!(
+            resp.headers()[axum::http::header::CONTENT_TYPE],
+            "audio/flac"
+        );
+        assert_eq!(
+            resp.headers()[axum::http::header::CONTENT_DISPOSITION],
+            "attachment; filename=\"_n_code _song_.flac\"; \
+            filename*=UTF-8''%C3%9Cn%C3%AFcode%20%22song%22.flac"
+        );
+        let info = jwt_header(
+            &cli,
+            Method::GET,
+            &format!("/api/query/track?id={id}"),
+            &jwt,
+        )
+        .await
+        .json::<retstructs::Track>();
+        assert_eq!(info.original.unwrap().size, 12);
+
+        // both go when the track does
+        jwt_header(&cli, Method::DELETE, &format!("/api/track?id={id}"), &jwt)
+            .await
+            .assert_status(StatusCode::OK);
+        assert!(!path.exists());
+        assert!(!super::original_path(&path).exists());
+    }
+
+    #[tokio::test]
+    async fn track_download_good() {
+        let cli = client().await;
+        let jwt = gen_user(&cli, "track_download_good").await;
+        let userid = jwt.whois().unwrap().userid;
+        // just enough of a flac file to be tagged
+        let stored = [&b"fLaC\x80\0\0\x22"[..], &[0; 34], b"frames"].concat();
+        let id = gen_track(userid, &stored).await;
+        sqlx::query!("UPDATE track SET container = 'flac' WHERE id = ?;", id)
+            .execute(&STATE.db)
+            .await
+            .unwrap();
+        jwt_header(&cli, Method::PATCH, "/api/metadata/track", &jwt)
+            .json(&msgstructs::TrackEdit {
+                id,
+                artist: Some(Some("track_download_good artist".to_owned())),
+                tags: [("genre".to_owned(), Some("Rock".to_owned()))].into(),
+                ..Default::default()
+            })
+            .await
+            .assert_status(StatusCode::OK);
+
+        // the edits are written into the file
+        let url = format!("/api/track/download?id={id}");
+        let resp = jwt_header(&cli, Method::GET, &url, &jwt).await;
+        assert_eq!(
+            resp.headers()[axum::http::header::CONTENT_DISPOSITION],
+            "attachment; filename=\"test.flac\"; filename*=UTF-8''test.flac"
+        );
+        assert_eq!(
+            resp.headers()[axum::http::header::CONTENT_TYPE],
+            "audio/flac"
+        );
+        let data = resp.as_bytes();
+        assert!(data.ends_with(b"frames"));
+        for comment in [
+            &b"TITLE=test track"[..],
+            b"ARTIST=track_download_good artist",
+            b"GENRE=Rock",
+        ] {
+            assert!(data.windows(comment.len()).any(|x| x == comment));
+        }
+
+        // unless they aren't wanted
+        let resp = jwt_header(&cli, Method::GET, &format!("{url}&tags=false"), &jwt).await;
+        assert_eq!(resp.as_bytes().as_ref(), stored);
+        jwt_header(
+            &cli,
+            Method::GET,
+            &format!("/api/track/download?id={}", uuid::Uuid::nil()),
+            &jwt,
+        )
+        .expect_failure()
+        .await
+        .assert_status(StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn track_stream_link_bad_tampered() {
+        let cli = client().await;
+        let jwt = gen_user(&cli, "track_stream_link_bad_tampered").await;
+        let other_jwt = gen_user(&cli, "track_stream_link_bad_tampered_2").await;
+        let id = gen_track(jwt.whois().unwrap().userid, b"not really audio").await;
+        let link = jwt_header(&cli, Method::GET, &format!("/api/track/link?id={id}"), &jwt)
+            .await
+            .json::<retstructs::StreamLink>();
+
+        // extending the expiry breaks the signature
+        let extended = link.url.replace(
+            &format!("exp={}", link.expiry),
+            &format!("exp={}", link.expiry + 1),
+        );
+        cli.get(&extended)
+            .expect_failure()
+            .await
+            .assert_status(StatusCode::UNAUTHORIZED);
+
+        // so does pointing it at someone else
+        let other = link.url.replace(
+            &jwt.whois().unwrap().userid.to_string(),
+            &other_jwt.whois().unwrap().userid.to_string(),
+        );
+        cli.get(&other)
+            .expect_failure()
+            .await
+            .assert_status(StatusCode::UNAUTHORIZED);
+
+        // or changing what it streams as
+        let link = jwt_header(
+            &cli,
+            Method::GET,
+            &format!("/api/track/link?id={id}&format=opus&bitrate=96"),
+            &jwt,
+        )
+        .await
+        .json::<retstructs::StreamLink>();
+        for tampered in [
+            link.url.replace("bitrate=96", "bitrate=320"),
+            link.url.replace("format=opus", "format=flac"),
+            link.url.replace("&format=opus&bitrate=96", ""),
+        ] {
+            cli.get(&tampered)
+                .expect_failure()
+                .await
+                .assert_status(StatusCode::UNAUTHORIZED);
+        }
+
+        // formats that can't be streamed can't be signed
+        jwt_header(
+            &cli,
+            Method::GET,
+            &format!("/api/track/link?id={id}&format=opus&bitrate=1000"),
+            &jwt,
+        )
+        .expect_failure()
+        .await
+        .assert_status(StatusCode::BAD_REQUEST);
+
+        // and links can't be made for tracks that aren't yours
+        jwt_header(
+            &cli,
+            Method::GET,
+            &format!("/api/track/link?id={id}"),
+            &other_jwt,
+        )
+        .expect_failure()
+        .await;
+    }
+}
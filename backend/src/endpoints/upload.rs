@@ -0,0 +1,513 @@
+use crate::db::{uuid_serialize, write_transaction};
+use crate::endpoints::track_manage::{
+    create_upload_file, sanitize_fname, upload_format, CHUNK_TIMEOUT,
+};
+use crate::subtasks::transcode::StorageFormat;
+use crate::*;
+use anyhow::anyhow;
+use axum::body::Body;
+use axum::extract::{Query, State};
+use chrono::Utc;
+use futures::StreamExt;
+use mio_protocol::*;
+use once_cell::sync::Lazy;
+use sqlx::SqliteConnection;
+use std::collections::HashSet;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use tokio::io::AsyncWriteExt;
+use tokio::time::timeout;
+use uuid::Uuid;
+
+// sessions that haven't been touched in this long are thrown away, in seconds
+pub(crate) const SESSION_LIFETIME: i64 = 60 * 60 * 24;
+
+// sessions that a request is currently working on
+static ACTIVE: Lazy<Mutex<HashSet<Uuid>>> = Lazy::new(Default::default);
+
+// only one request can work on a session at a time, and this is held while it does
+struct Active(Uuid);
+
+impl Active {
+    fn claim(id: Uuid) -> Result<Self, MioInnerError> {
+        if !ACTIVE.lock().unwrap().insert(id) {
+            return Err(MioInnerError::Conflict(anyhow!(
+                "upload {id} is already being worked on"
+            )));
+        }
+        Ok(Self(id))
+    }
+}
+
+impl Drop for Active {
+    fn drop(&mut self) {
+        ACTIVE.lock().unwrap().remove(&self.0);
+    }
+}
+
+pub fn routes() -> Router<MioState> {
+    Router::new()
+        .route(
+            "/",
+            post(create_session)
+                .get(session_status)
+                .put(upload_chunk)
+                .delete(abort_session),
+        )
+        .route("/finish", post(finish_session))
+}
+
+struct Session {
+    dir: String,
+    orig_fname: String,
+    format: StorageFormat,
+    duplicates: DuplicatePolicy,
+    size: Option<u64>,
+    received: u64,
+    updated: i64,
+}
+
+impl Session {
+    fn path(&self, userid: Uuid, id: Uuid) -> PathBuf {
+        crate::DATA_DIR
+            .get()
+            .unwrap()
+            .join(format!("{userid}"))
+            .join(&self.dir)
+            .join(format!("{id}"))
+    }
+
+    fn to_ret(&self, id: Uuid) -> retstructs::UploadSession {
+        retstructs::UploadSession {
+            id,
+            received: self.received,
+            size: self.size,
+            expiry: self.updated + SESSION_LIFETIME,
+        }
+    }
+}
+
+async fn load(
+    conn: &mut SqliteConnection,
+    id: Uuid,
+    userid: Uuid,
+) -> Result<Session, MioInnerError> {
+    let x = sqlx::query!(
+        "SELECT dir, orig_fname, codec, bitrate, container, duplicates, size, received, updated
+        FROM upload_session WHERE id = ? AND owner = ?;",
+        id,
+        userid
+    )
+    .fetch_optional(&mut *conn)
+    .await?
+    .ok_or_else(|| MioInnerError::NotFound(anyhow!("upload {id} does not exist")))?;
+    Ok(Session {
+        dir: x.dir,
+        orig_fname: x.orig_fname,
+        format: StorageFormat::from_row(&x.codec, x.bitrate, x.container)?,
+        duplicates: x.duplicates.parse().map_err(|err| {
+            MioInnerError::DbError(anyhow!("could not parse duplicate policy {err}"))
+        })?,
+        size: x.size.map(|x| x as u64),
+        received: x.received as u64,
+        updated: x.updated,
+    })
+}
+
+#[tracing::instrument]
+async fn create_session(
+    State(state): State<MioState>,
+    Extension(auth::JWTInner { userid, .. }): Extension<auth::JWTInner>,
+    Query(msgstructs::UploadSessionCreate {
+        dir,
+        fname,
+        codec,
+        bitrate,
+        duplicates,
+        size,
+    }): Query<msgstructs::UploadSessionCreate>,
+) -> Result<impl IntoResponse, MioInnerError> {
+    let format = upload_format(codec, bitrate, fname.as_deref())?;
+    let max_size = *crate::MAX_UPLOAD_SIZE.get().unwrap();
+    if size.is_some_and(|x| x > max_size) {
+        return Err(MioInnerError::TrackProcessingError(
+            anyhow!("upload is larger than the max of {max_size} bytes"),
+            StatusCode::PAYLOAD_TOO_LARGE,
+        ));
+    }
+    trace!("POST /upload acquiring directory lock");
+    let _hold = state.lock_files.read().await;
+    let mut conn = state.db.acquire().await?;
+    crate::quota::check_new_upload(&mut conn, userid, size.unwrap_or(0), 1).await?;
+
+    // the file is made up front, so that it's on the layout like any other upload
+    let (id, path, file) = create_upload_file(userid, &dir).await?;
+    drop(file);
+    let session = Session {
+        dir,
+        orig_fname: sanitize_fname(fname, id),
+        format,
+        duplicates: duplicates.unwrap_or_default(),
+        size,
+        received: 0,
+        updated: Utc::now().timestamp(),
+    };
+    let codec = session.format.codec.as_str();
+    let duplicates = session.duplicates.as_str();
+    let size = session.size.map(|x| x as i64);
+    if let Err(err) = sqlx::query!(
+        "INSERT INTO upload_session
+        (id, owner, dir, orig_fname, codec, bitrate, container, duplicates, size, received,
+        created, updated)
+        VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, 0, ?, ?);",
+        id,
+        userid,
+        session.dir,
+        session.orig_fname,
+        codec,
+        session.format.bitrate,
+        session.format.container,
+        duplicates,
+        size,
+        session.updated,
+        session.updated
+    )
+    .execute(&mut *conn)
+    .await
+    {
+        tokio::fs::remove_file(path).await?;
+        return Err(err.into());
+    }
+    debug!("POST /upload created {id} for \"{}\"", session.orig_fname);
+    Ok((StatusCode::OK, Json(session.to_ret(id))))
+}
+
+#[tracing::instrument]
+async fn session_status(
+    State(state): State<MioState>,
+    Extension(auth::JWTInner { userid, .. }): Extension<auth::JWTInner>,
+    Query(msgstructs::IdInfoQuery { id }): Query<msgstructs::IdInfoQuery>,
+) -> Result<impl IntoResponse, MioInnerError> {
+    let mut conn = state.db.acquire().await?;
+    Ok((
+        StatusCode::OK,
+        Json(load(&mut conn, id, userid).await?.to_ret(id)),
+    ))
+}
+
+#[tracing::instrument(skip(payload))]
+async fn upload_chunk(
+    State(state): State<MioState>,
+    Extension(auth::JWTInner { userid, .. }): Extension<auth::JWTInner>,
+    Query(msgstructs::UploadChunkQuery { id, offset }): Query<msgstructs::UploadChunkQuery>,
+    payload: Body,
+) -> Result<impl IntoResponse, MioInnerError> {
+    let _active = Active::claim(id)?;
+    trace!("PUT /upload acquiring directory lock");
+    let _hold = state.lock_files.read().await;
+    let mut conn = state.db.acquire().await?;
+    let mut session = load(&mut conn, id, userid).await?;
+    if offset != session.received {
+        return Err(MioInnerError::Conflict(anyhow!(
+            "upload {id} is at offset {}, not {offset}",
+            session.received
+        )));
+    }
+    let usage = crate::quota::usage(&mut conn, userid).await?;
+    drop(conn);
+    let limit = session
+        .size
+        .unwrap_or(u64::MAX)
+        .min(*crate::MAX_UPLOAD_SIZE.get().unwrap());
+
+    // anything past what was recorded is from a request that died partway through
+    let path = session.path(userid, id);
+    let mut file = tokio::fs::OpenOptions::new()
+        .append(true)
+        .open(&path)
+        .await
+        .map_err(|err| {
+            if err.kind() == std::io::ErrorKind::NotFound {
+                MioInnerError::NotFound(anyhow!("the file for upload {id} is gone"))
+            } else {
+                err.into()
+            }
+        })?;
+    file.set_len(session.received).await?;
+
+    // take chunks until the client is done or something goes wrong
+    let mut payload = payload.into_data_stream();
+    let mut written = 0u64;
+    let ret = loop {
+        match timeout(CHUNK_TIMEOUT, payload.next()).await {
+            Ok(Some(Ok(chunk))) => {
+                let next = written + chunk.len() as u64;
+                if session.received + next > limit {
+                    break Err(MioInnerError::TrackProcessingError(
+                        anyhow!("upload is larger than {limit} bytes"),
+                        StatusCode::PAYLOAD_TOO_LARGE,
+                    ));
+                }
+                if let Err(err) = crate::quota::check(&usage, next, 0) {
+                    break Err(err);
+                }
+                if let Err(err) = file.write_all(&chunk).await {
+                    break Err(err.into());
+                }
+                written = next;
+            }
+            Ok(None) => break Ok(()),
+            Ok(Some(Err(err))) => {
+                break Err(MioInnerError::TrackProcessingError(
+                    anyhow!("failed to stream chunk: {err}"),
+                    StatusCode::BAD_REQUEST,
+                ))
+            }
+            Err(_) => {
+                break Err(MioInnerError::TrackProcessingError(
+                    anyhow!("upload timeout hit"),
+                    StatusCode::REQUEST_TIMEOUT,
+                ))
+            }
+        }
+    };
+
+    // whatever made it to disk is kept, so the client can pick up from there
+    file.flush().await?;
+    session.received = file.metadata().await?.len();
+    session.updated = Utc::now().timestamp();
+    drop(file);
+    let received = session.received as i64;
+    let mut conn = state.db.acquire().await?;
+    sqlx::query!(
+        "UPDATE upload_session SET received = ?, updated = ? WHERE id = ?;",
+        received,
+        session.updated,
+        id
+    )
+    .execute(&mut *conn)
+    .await?;
+    trace!("PUT /upload {id} is now at {received} bytes");
+    ret?;
+    Ok((StatusCode::OK, Json(session.to_ret(id))))
+}
+
+#[tracing::instrument]
+async fn finish_session(
+    State(state): State<MioState>,
+    Extension(auth::JWTInner { userid, .. }): Extension<auth::JWTInner>,
+    Query(msgstructs::IdInfoQuery { id }): Query<msgstructs::IdInfoQuery>,
+) -> Result<impl IntoResponse, MioInnerError> {
+    let _active = Active::claim(id)?;
+    let _hold = state.lock_files.read().await;
+    let mut conn = state.db.acquire().await?;
+    let session = load(&mut conn, id, userid).await?;
+    if session.received == 0 {
+        return Err(MioInnerError::Conflict(anyhow!(
+            "nothing has been uploaded to {id}"
+        )));
+    }
+    if let Some(size) = session.size {
+        if session.received != size {
+            return Err(MioInnerError::Conflict(anyhow!(
+                "upload {id} has {} of {size} bytes",
+                session.received
+            )));
+        }
+    }
+
+    // the file is already in place, so it just needs to be processed
+    write_transaction(&mut conn, |txn| {
+        Box::pin(async move {
+            sqlx::query!("DELETE FROM upload_session WHERE id = ?;", id)
+                .execute(&mut *txn)
+                .await?;
+            crate::subtasks::jobs::enqueue(
+                &mut *txn,
+                crate::subtasks::jobs::NewJob {
+                    id,
+                    owner: userid,
+                    dir: session.dir,
+                    orig_fname: session.orig_fname,
+                    format: session.format,
+                    duplicates: session.duplicates,
+                    inbox: None,
+                },
+            )
+            .await
+        })
+    })
+    .await?;
+    debug!("POST /upload/finish queued {id}");
+    Ok((
+        StatusCode::ACCEPTED,
+        Json(retstructs::UploadReturn { uuid: id }),
+    ))
+}
+
+#[tracing::instrument]
+async fn abort_session(
+    State(state): State<MioState>,
+    Extension(auth::JWTInner { userid, .. }): Extension<auth::JWTInner>,
+    Query(msgstructs::IdInfoQuery { id }): Query<msgstructs::IdInfoQuery>,
+) -> Result<impl IntoResponse, MioInnerError> {
+    let _active = Active::claim(id)?;
+    let _hold = state.lock_files.read().await;
+    let mut conn = state.db.acquire().await?;
+    let session = load(&mut conn, id, userid).await?;
+    sqlx::query!("DELETE FROM upload_session WHERE id = ?;", id)
+        .execute(&mut *conn)
+        .await?;
+    remove_upload(session.path(userid, id)).await?;
+    Ok(StatusCode::OK)
+}
+
+async fn remove_upload(path: PathBuf) -> Result<(), MioInnerError> {
+    if let Err(err) = tokio::fs::remove_file(path).await {
+        if err.kind() != std::io::ErrorKind::NotFound {
+            return Err(err.into());
+        }
+    }
+    Ok(())
+}
+
+// throw away sessions that have been abandoned, along with what they uploaded
+pub(crate) async fn expire(state: MioState) -> Result<(), MioInnerError> {
+    let _hold = state.lock_files.read().await;
+    let mut conn = state.db.acquire().await?;
+    let cutoff = Utc::now().timestamp() - SESSION_LIFETIME;
+    let stale = sqlx::query!(
+        "SELECT id, owner FROM upload_session WHERE updated < ?;",
+        cutoff
+    )
+    .fetch_all(&mut *conn)
+    .await?;
+    let mut removed = 0;
+    for x in stale {
+        let id = uuid_serialize(&x.id)?;
+        let owner = uuid_serialize(&x.owner)?;
+
+        // a slow upload can still be going
+        let Ok(_active) = Active::claim(id) else {
+            continue;
+        };
+        let session = load(&mut conn, id, owner).await?;
+        sqlx::query!("DELETE FROM upload_session WHERE id = ?;", id)
+            .execute(&mut *conn)
+            .await?;
+        remove_upload(session.path(owner, id)).await?;
+        removed += 1;
+    }
+    debug!("UPLOAD expired {removed} abandoned upload sessions");
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use crate::test::*;
+    use axum::http::{Method, StatusCode};
+    use mio_protocol::*;
+
+    #[tokio::test]
+    async fn upload_resume_good() {
+        let cli = client().await;
+        let jwt = gen_user(&cli, "upload_resume_good").await;
+        let session = jwt_header(&cli, Method::POST, "/api/upload?dir=&size=10", &jwt)
+            .await
+            .json::<retstructs::UploadSession>();
+        let id = session.id;
+        assert_eq!(session.received, 0);
+        let put = |offset: u64, data: &'static [u8]| {
+            jwt_header(
+                &cli,
+                Method::PUT,
+                &format!("/api/upload?id={id}&offset={offset}"),
+                &jwt,
+            )
+            .bytes(data.into())
+        };
+        put(0, b"hello").await.assert_status(StatusCode::OK);
+
+        // the client can ask where to pick back up from
+        let session = jwt_header(&cli, Method::GET, &format!("/api/upload?id={id}"), &jwt)
+            .await
+            .json::<retstructs::UploadSession>();
+        assert_eq!(session.received, 5);
+        put(0, b"hello")
+            .expect_failure()
+            .await
+            .assert_status(StatusCode::CONFLICT);
+
+        // can't finish early, or go past the size
+        jwt_header(
+            &cli,
+            Method::POST,
+            &format!("/api/upload/finish?id={id}"),
+            &jwt,
+        )
+        .expect_failure()
+        .await
+        .assert_status(StatusCode::CONFLICT);
+        put(5, b"world!")
+            .expect_failure()
+            .await
+            .assert_status(StatusCode::PAYLOAD_TOO_LARGE);
+        put(5, b"world").await.assert_status(StatusCode::OK);
+        let resp = jwt_header(
+            &cli,
+            Method::POST,
+            &format!("/api/upload/finish?id={id}"),
+            &jwt,
+        )
+        .await;
+        resp.assert_status(StatusCode::ACCEPTED);
+        assert_eq!(resp.json::<retstructs::UploadReturn>().uuid, id);
+        let job = jwt_header(
+            &cli,
+            Method::GET,
+            &format!("/api/jobs/status?id={id}"),
+            &jwt,
+        )
+        .await
+        .json::<retstructs::Job>();
+        assert_eq!(job.state, JobState::Queued);
+        let path = crate::DATA_DIR
+            .get()
+            .unwrap()
+            .join(format!("{}", jwt.whois().unwrap().userid))
+            .join(format!("{id}"));
+        assert_eq!(tokio::fs::read(path).await.unwrap(), b"helloworld");
+    }
+
+    #[tokio::test]
+    async fn upload_bad_abandoned() {
+        let cli = client().await;
+        let jwt = gen_user(&cli, "upload_bad_abandoned").await;
+        let id = jwt_header(&cli, Method::POST, "/api/upload?dir=", &jwt)
+            .await
+            .json::<retstructs::UploadSession>()
+            .id;
+        jwt_header(
+            &cli,
+            Method::PUT,
+            &format!("/api/upload?id={id}&offset=0"),
+            &jwt,
+        )
+        .bytes(b"hello"[..].into())
+        .await
+        .assert_status(StatusCode::OK);
+        sqlx::query!("UPDATE upload_session SET updated = 0 WHERE id = ?;", id)
+            .execute(&STATE.db)
+            .await
+            .unwrap();
+        super::expire(STATE.clone()).await.unwrap();
+        jwt_header(&cli, Method::GET, &format!("/api/upload?id={id}"), &jwt)
+            .expect_failure()
+            .await
+            .assert_status(StatusCode::NOT_FOUND);
+        let usage = jwt_header(&cli, Method::GET, "/user/storage", &jwt)
+            .await
+            .json::<retstructs::StorageUsage>();
+        assert_eq!(usage.bytes, 0);
+    }
+}
@@ -16,6 +16,79 @@ pub static PORT: OnceLock<u16> = OnceLock::new();
 // enable or disable signup
 pub static SIGNUP_ENABLED: OnceLock<bool> = OnceLock::new();
 
+// Username and password of an admin that is made at startup if they don't exist, from
+// ADMIN_USERNAME and ADMIN_PASSWORD. An existing user with the name is made an admin,
+// and keeps their password. Optional, but both must be set together.
+pub static ADMIN_ACCOUNT: OnceLock<Option<(String, String)>> = OnceLock::new();
+
+// How long a newly issued token is valid for, in seconds. Defaults to a week.
+pub static TOKEN_LIFETIME: OnceLock<i64> = OnceLock::new();
+pub const DEFAULT_TOKEN_LIFETIME: i64 = 60 * 60 * 24 * 7;
+
+// How long a session can be kept alive via refreshing since the user logged in, in
+// seconds. Defaults to 90 days.
+pub static SESSION_MAX_AGE: OnceLock<i64> = OnceLock::new();
+pub const DEFAULT_SESSION_MAX_AGE: i64 = 60 * 60 * 24 * 90;
+
+// If /user/refresh extends the current session (invalidating the old token), or
+// issues a new session alongside it. Defaults to issuing a new session.
+pub static REFRESH_EXTENDS_SESSION: OnceLock<bool> = OnceLock::new();
+
+// Codec that uploads are stored in when the upload doesn't ask for one. Defaults to
+// QOA.
+pub static STORAGE_CODEC: OnceLock<mio_protocol::Codec> = OnceLock::new();
+
+// If the uploaded file is kept next to the storage copy when it's transcoded.
+// Defaults to false.
+pub static KEEP_ORIGINALS: OnceLock<bool> = OnceLock::new();
+
+// Bitrate used for lossy storage codecs, in kbps. Defaults to 128.
+pub static STORAGE_BITRATE: OnceLock<u32> = OnceLock::new();
+pub const DEFAULT_STORAGE_BITRATE: u32 = 128;
+
+// How many uploads can be processed at once. Defaults to 2.
+pub static UPLOAD_WORKERS: OnceLock<usize> = OnceLock::new();
+pub const DEFAULT_UPLOAD_WORKERS: usize = 2;
+
+// How many streams can be transcoded at once. Defaults to the number of cpus.
+pub static MAX_TRANSCODES: OnceLock<usize> = OnceLock::new();
+
+// How many bytes of transcoded streams are kept on disk. Setting this to 0 turns off
+// caching. Defaults to 1 GiB.
+pub static TRANSCODE_CACHE_SIZE: OnceLock<u64> = OnceLock::new();
+pub const DEFAULT_TRANSCODE_CACHE_SIZE: u64 = 1 << 30;
+
+// Largest upload that will be accepted, in bytes. Defaults to 1 GiB.
+pub static MAX_UPLOAD_SIZE: OnceLock<u64> = OnceLock::new();
+pub const DEFAULT_MAX_UPLOAD_SIZE: u64 = 1 << 30;
+
+// Largest archive that will be accepted for a bulk upload, in bytes. Defaults to 4
+// GiB.
+pub static MAX_ARCHIVE_SIZE: OnceLock<u64> = OnceLock::new();
+pub const DEFAULT_MAX_ARCHIVE_SIZE: u64 = 1 << 32;
+
+// If every user gets an inbox folder at DATA_DIR/inbox/<user id>, where audio that is
+// dropped in is uploaded. Defaults to false.
+pub static INBOX_ENABLED: OnceLock<bool> = OnceLock::new();
+
+// How long a file in an inbox has to go unchanged before it's taken, in seconds.
+// Defaults to 10 seconds.
+pub static INBOX_SETTLE: OnceLock<i64> = OnceLock::new();
+pub const DEFAULT_INBOX_SETTLE: i64 = 10;
+
+// Longest track that will be accepted, in seconds. Defaults to 2 hours.
+pub static MAX_TRACK_DURATION: OnceLock<u64> = OnceLock::new();
+pub const DEFAULT_MAX_TRACK_DURATION: u64 = 60 * 60 * 2;
+
+// How long each step of processing an upload can take, in seconds. Defaults to 5
+// minutes.
+pub static PROCESSING_TIMEOUT: OnceLock<u64> = OnceLock::new();
+pub const DEFAULT_PROCESSING_TIMEOUT: u64 = 60 * 5;
+
+// How often database maintenance is run, in seconds. Defaults to an hour.
+pub static MAINTENANCE_INTERVAL: OnceLock<i64> = OnceLock::new();
+pub const DEFAULT_MAINTENANCE_INTERVAL: i64 = 60 * 60;
+
 pub async fn init_from_env() {
     // TODO: dotenvy
     //
@@ -82,6 +155,204 @@ pub async fn init_from_env() {
                 .unwrap_or(false),
         )
         .unwrap();
+
+    // admin
+    let utf8 = |name| {
+        env::var_os(name).map(|x: OsString| {
+            x.into_string()
+                .unwrap_or_else(|_| panic!("{name} must be valid UTF-8"))
+        })
+    };
+    ADMIN_ACCOUNT
+        .set(match (utf8("ADMIN_USERNAME"), utf8("ADMIN_PASSWORD")) {
+            (Some(username), Some(password)) => Some((username, password)),
+            (None, None) => None,
+            _ => panic!("ADMIN_USERNAME and ADMIN_PASSWORD must be set together"),
+        })
+        .unwrap();
+
+    // token lifetimes
+    TOKEN_LIFETIME
+        .set(
+            env::var_os("TOKEN_LIFETIME")
+                .map(|x| var_to_secs(x, "TOKEN_LIFETIME"))
+                .unwrap_or(DEFAULT_TOKEN_LIFETIME),
+        )
+        .unwrap();
+    SESSION_MAX_AGE
+        .set(
+            env::var_os("SESSION_MAX_AGE")
+                .map(|x| var_to_secs(x, "SESSION_MAX_AGE"))
+                .unwrap_or(DEFAULT_SESSION_MAX_AGE),
+        )
+        .unwrap();
+    if SESSION_MAX_AGE.get().unwrap() < TOKEN_LIFETIME.get().unwrap() {
+        log::warn!("SESSION_MAX_AGE is shorter than TOKEN_LIFETIME, tokens will expire early");
+    }
+    REFRESH_EXTENDS_SESSION
+        .set(
+            env::var_os("REFRESH_EXTENDS_SESSION")
+                .and_then(var_to_bool)
+                .unwrap_or(false),
+        )
+        .unwrap();
+
+    // storage
+    STORAGE_CODEC
+        .set(
+            env::var_os("STORAGE_CODEC")
+                .map(|x| {
+                    x.to_str()
+                        .expect("STORAGE_CODEC must be valid UTF-8")
+                        .parse()
+                        .expect("STORAGE_CODEC must be one of original, flac, opus, or qoa")
+                })
+                .unwrap_or(mio_protocol::Codec::Qoa),
+        )
+        .unwrap();
+    STORAGE_BITRATE
+        .set(
+            env::var_os("STORAGE_BITRATE")
+                .map(|x| {
+                    x.to_str()
+                        .expect("STORAGE_BITRATE must be valid UTF-8")
+                        .parse()
+                        .expect("STORAGE_BITRATE is not a valid bitrate in kbps")
+                })
+                .unwrap_or(DEFAULT_STORAGE_BITRATE),
+        )
+        .unwrap();
+    if let Err(err) = crate::subtasks::transcode::check_bitrate(*STORAGE_BITRATE.get().unwrap()) {
+        panic!("STORAGE_BITRATE is invalid: {err}");
+    }
+    KEEP_ORIGINALS
+        .set(
+            env::var_os("KEEP_ORIGINALS")
+                .and_then(var_to_bool)
+                .unwrap_or(false),
+        )
+        .unwrap();
+
+    UPLOAD_WORKERS
+        .set(
+            env::var_os("UPLOAD_WORKERS")
+                .map(|x| {
+                    x.to_str()
+                        .expect("UPLOAD_WORKERS must be valid UTF-8")
+                        .parse()
+                        .expect("UPLOAD_WORKERS is not a valid number")
+                })
+                .unwrap_or(DEFAULT_UPLOAD_WORKERS),
+        )
+        .unwrap();
+    if *UPLOAD_WORKERS.get().unwrap() == 0 {
+        panic!("UPLOAD_WORKERS must be greater than 0");
+    }
+    MAX_TRANSCODES
+        .set(
+            env::var_os("MAX_TRANSCODES")
+                .map(|x| {
+                    x.to_str()
+                        .expect("MAX_TRANSCODES must be valid UTF-8")
+                        .parse()
+                        .expect("MAX_TRANSCODES is not a valid number")
+                })
+                .unwrap_or_else(num_cpus::get),
+        )
+        .unwrap();
+    if *MAX_TRANSCODES.get().unwrap() == 0 {
+        panic!("MAX_TRANSCODES must be greater than 0");
+    }
+
+    TRANSCODE_CACHE_SIZE
+        .set(
+            env::var_os("TRANSCODE_CACHE_SIZE")
+                .map(|x| {
+                    x.to_str()
+                        .expect("TRANSCODE_CACHE_SIZE must be valid UTF-8")
+                        .parse()
+                        .expect("TRANSCODE_CACHE_SIZE is not a valid number of bytes")
+                })
+                .unwrap_or(DEFAULT_TRANSCODE_CACHE_SIZE),
+        )
+        .unwrap();
+
+    // upload limits
+    MAX_UPLOAD_SIZE
+        .set(
+            env::var_os("MAX_UPLOAD_SIZE")
+                .map(|x| {
+                    x.to_str()
+                        .expect("MAX_UPLOAD_SIZE must be valid UTF-8")
+                        .parse()
+                        .expect("MAX_UPLOAD_SIZE is not a valid number of bytes")
+                })
+                .unwrap_or(DEFAULT_MAX_UPLOAD_SIZE),
+        )
+        .unwrap();
+    MAX_ARCHIVE_SIZE
+        .set(
+            env::var_os("MAX_ARCHIVE_SIZE")
+                .map(|x| {
+                    x.to_str()
+                        .expect("MAX_ARCHIVE_SIZE must be valid UTF-8")
+                        .parse()
+                        .expect("MAX_ARCHIVE_SIZE is not a valid number of bytes")
+                })
+                .unwrap_or(DEFAULT_MAX_ARCHIVE_SIZE),
+        )
+        .unwrap();
+    MAX_TRACK_DURATION
+        .set(
+            env::var_os("MAX_TRACK_DURATION")
+                .map(|x| var_to_secs(x, "MAX_TRACK_DURATION") as u64)
+                .unwrap_or(DEFAULT_MAX_TRACK_DURATION),
+        )
+        .unwrap();
+    PROCESSING_TIMEOUT
+        .set(
+            env::var_os("PROCESSING_TIMEOUT")
+                .map(|x| var_to_secs(x, "PROCESSING_TIMEOUT") as u64)
+                .unwrap_or(DEFAULT_PROCESSING_TIMEOUT),
+        )
+        .unwrap();
+
+    // inboxes
+    INBOX_ENABLED
+        .set(
+            env::var_os("INBOX_ENABLED")
+                .and_then(var_to_bool)
+                .unwrap_or(false),
+        )
+        .unwrap();
+    INBOX_SETTLE
+        .set(
+            env::var_os("INBOX_SETTLE")
+                .map(|x| var_to_secs(x, "INBOX_SETTLE"))
+                .unwrap_or(DEFAULT_INBOX_SETTLE),
+        )
+        .unwrap();
+
+    // background jobs
+    MAINTENANCE_INTERVAL
+        .set(
+            env::var_os("MAINTENANCE_INTERVAL")
+                .map(|x| var_to_secs(x, "MAINTENANCE_INTERVAL"))
+                .unwrap_or(DEFAULT_MAINTENANCE_INTERVAL),
+        )
+        .unwrap();
+}
+
+fn var_to_secs(x: OsString, name: &str) -> i64 {
+    let secs = x
+        .to_str()
+        .unwrap_or_else(|| panic!("{name} must be valid UTF-8"))
+        .parse()
+        .unwrap_or_else(|_| panic!("{name} is not a valid number of seconds"));
+    if secs <= 0 {
+        panic!("{name} must be greater than 0 seconds");
+    }
+    secs
 }
 
 fn var_to_bool(x: OsString) -> Option<bool> {
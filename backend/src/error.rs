@@ -26,6 +26,11 @@ pub enum MioInnerError {
     InternalIoError(anyhow::Error),
     #[error("conflict found: `{0}`. \nTRACE:\n{}", .0.backtrace())]
     Conflict(anyhow::Error),
+    // the i64 is how many seconds until the client can try again
+    #[error("rate limited: `{0}`. \nTRACE:\n{}", .0.backtrace())]
+    RateLimited(anyhow::Error, i64),
+    #[error("quota exceeded: `{0}`. \nTRACE:\n{}", .0.backtrace())]
+    QuotaExceeded(anyhow::Error),
 }
 
 impl MioInnerError {
@@ -36,7 +41,9 @@ impl MioInnerError {
             match self {
                 NotFound(e)
                 | Conflict(e)
+                | QuotaExceeded(e)
                 | UserChallengedFail(e, _)
+                | RateLimited(e, _)
                 | UserCreationFail(e, _)
                 | TrackProcessingError(e, _)
                 | ExternalIoError(e, _) => e,
@@ -79,7 +86,7 @@ impl IntoResponse for MioInnerError {
         log::log!(
             match self {
                 NotFound(_) | Conflict(_) | ExternalIoError(_, _) => Level::Debug,
-                UserChallengedFail(_, _) => Level::Info,
+                UserChallengedFail(_, _) | RateLimited(_, _) | QuotaExceeded(_) => Level::Info,
                 TrackProcessingError(_, _) => Level::Warn,
                 DbError(_) | UserCreationFail(_, _) | InternalIoError(_) | Panicked(_) =>
                     Level::Error,
@@ -87,19 +94,32 @@ impl IntoResponse for MioInnerError {
             "{self}"
         );
 
+        // tell the client when to come back
+        let retry_after = match self {
+            RateLimited(_, secs) => Some(secs.max(1)),
+            _ => None,
+        };
+
         // return
-        (
+        let mut ret = (
             match self {
                 NotFound(_) => StatusCode::NOT_FOUND,
                 Conflict(_) => StatusCode::CONFLICT,
+                QuotaExceeded(_) => StatusCode::PAYLOAD_TOO_LARGE,
                 InternalIoError(_) | DbError(_) | Panicked(_) => StatusCode::INTERNAL_SERVER_ERROR,
                 UserChallengedFail(_, c)
                 | UserCreationFail(_, c)
                 | TrackProcessingError(_, c)
                 | ExternalIoError(_, c) => c,
+                RateLimited(_, _) => StatusCode::TOO_MANY_REQUESTS,
             },
             Json(retstructs::ErrorMsg { error: self.msg() }),
         )
-            .into_response()
+            .into_response();
+        if let Some(secs) = retry_after {
+            ret.headers_mut()
+                .insert(axum::http::header::RETRY_AFTER, secs.into());
+        }
+        ret
     }
 }
@@ -10,10 +10,15 @@ use std::str::FromStr;
 use std::sync::Arc;
 use tokio::net::TcpListener;
 
+mod admin;
+mod apikey;
+mod cache;
 mod db;
 mod endpoints;
 mod env;
 mod error;
+mod quota;
+mod ratelimit;
 mod subtasks;
 mod user;
 
@@ -58,7 +63,18 @@ async fn main() -> anyhow::Result<()> {
     gstreamer::init()?;
 
     // create the main passing state
-    let state = gen_state().await;
+    let state = gen_state(db_settings()).await;
+    if let Some((username, password)) = ADMIN_ACCOUNT.get().unwrap().clone() {
+        admin::bootstrap(&state, username, password)
+            .await
+            .expect("failed to set up the admin account: {}");
+    }
+    quota::fill_sizes(&state)
+        .await
+        .expect("failed to fill in track sizes: {}");
+    archive::sweep_scratch()
+        .await
+        .expect("failed to clean up archive scratch space: {}");
 
     // setup the router
     trace!("main: building router");
@@ -68,32 +84,50 @@ async fn main() -> anyhow::Result<()> {
     let socket = TcpListener::bind(addr)
         .await
         .expect("failed to bind to addr : {}");
+
+    // background jobs
+    let (tx_shutdown, rx_shutdown) = tokio::sync::watch::channel(false);
+    let mut scheduled = subtasks::start_scheduled(state.clone(), rx_shutdown.clone());
+    let mut workers = subtasks::jobs::start_workers(state.clone(), rx_shutdown.clone()).await?;
+    workers.spawn(subtasks::import::worker(state.clone(), rx_shutdown.clone()));
+    if *INBOX_ENABLED.get().unwrap() {
+        workers.spawn(subtasks::inbox::watcher(state.clone(), rx_shutdown));
+    }
     let (tx_die, mut rx_die) = tokio::sync::mpsc::unbounded_channel();
     ctrlc::set_handler(move || tx_die.send(()).unwrap())
         .expect("failed to setup graceful shutdown: {}");
-    axum::serve(socket, router)
-        .with_graceful_shutdown(async move {
-            rx_die.recv().await;
-        })
-        .await
-        .expect("server exited improperly: {}");
+    // the peer address is needed for rate limiting
+    axum::serve(
+        socket,
+        router.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .with_graceful_shutdown(async move {
+        rx_die.recv().await;
+    })
+    .await
+    .expect("server exited improperly: {}");
     trace!("main: cleaning up nicely");
+    tx_shutdown.send_replace(true);
+    while scheduled.join_next().await.is_some() {}
+    while workers.join_next().await.is_some() {}
     state.db.close().await;
     Ok(())
 }
 
-async fn gen_state() -> MioState {
+// where the database lives. during testing it's only in memory.
+fn db_settings() -> SqliteConnectOptions {
+    if cfg!(test) {
+        SqliteConnectOptions::from_str(":memory:").unwrap()
+    } else {
+        SqliteConnectOptions::new()
+            .filename(DATA_DIR.get().unwrap().join("music.db"))
+            .create_if_missing(true)
+            .optimize_on_close(true, Some(400))
+    }
+}
+
+async fn gen_state(settings: SqliteConnectOptions) -> MioState {
     trace!("main: creating state");
-    let settings = {
-        if cfg!(test) {
-            SqliteConnectOptions::from_str(":memory:").unwrap()
-        } else {
-            SqliteConnectOptions::new()
-                .filename(DATA_DIR.get().unwrap().join("music.db"))
-                .create_if_missing(true)
-                .optimize_on_close(true, Some(400))
-        }
-    };
     let db = SqlitePool::connect_with(settings)
         .await
         .expect("Could not load database: {}");
@@ -117,12 +151,29 @@ fn gen_public_router(state: MioState) -> Router<()> {
             Router::new()
                 // general api stuff, like streaming and querying
                 .nest("/api", {
+                    use mio_protocol::auth::Scope;
+
+                    // api keys are only let into the groups they have scopes for
+                    let scoped = |router: Router<MioState>, scope: Scope| {
+                        router
+                            .route_layer(middleware::from_fn_with_state(scope, apikey::check_scope))
+                    };
+
                     #[allow(clippy::let_and_return)]
                     let api = Router::new()
-                        .nest("/track", track_manage::routes())
-                        .nest("/query", query::routes())
-                        .nest("/load", idquery::routes())
-                        .nest("/folder", folders::routes());
+                        .nest(
+                            "/track",
+                            scoped(track_manage::routes(), Scope::Upload)
+                                .merge(scoped(track_manage::manage_routes(), Scope::ManageFolders))
+                                .merge(scoped(track_manage::stream_routes(), Scope::Stream)),
+                        )
+                        .nest("/query", scoped(query::routes(), Scope::ReadLibrary))
+                        .nest("/metadata", scoped(metadata::routes(), Scope::Upload))
+                        .nest("/load", scoped(idquery::routes(), Scope::ReadLibrary))
+                        .nest("/jobs", scoped(jobs::routes(), Scope::Upload))
+                        .nest("/upload", scoped(upload::routes(), Scope::Upload))
+                        .nest("/archive", scoped(archive::routes(), Scope::Upload))
+                        .nest("/folder", scoped(folders::routes(), Scope::ManageFolders));
 
                     // this is used during testing as a quick method to test for if the auth works
                     if cfg!(test) {
@@ -131,8 +182,31 @@ fn gen_public_router(state: MioState) -> Router<()> {
                         api
                     }
                 })
-                // this is here because it needs the user id from the auth handler
-                .route("/user/refresh", patch(user::new_token)),
+                // account management, which api keys cannot do
+                .merge(
+                    Router::new()
+                        // this is here because it needs the user id from the auth handler
+                        .route("/user/refresh", patch(user::new_token))
+                        .route(
+                            "/user/sessions",
+                            get(user::list_sessions)
+                                .patch(user::rename_session)
+                                .delete(user::revoke_session),
+                        )
+                        .route("/user/sessions/all", delete(user::revoke_all_sessions))
+                        .route("/user/password", patch(user::change_password))
+                        .route("/user/account", delete(user::delete_account))
+                        .route("/user/storage", get(quota::get_usage))
+                        .route(
+                            "/user/keys",
+                            get(apikey::list_keys)
+                                .post(apikey::create_key)
+                                .delete(apikey::revoke_key),
+                        )
+                        // user administration, which also needs the auth handler
+                        .nest("/admin", admin::routes(state.clone()))
+                        .route_layer(middleware::from_fn(apikey::require_session)),
+                ),
         )
         // auth handler
         .route_layer(middleware::from_extractor_with_state::<user::Authenticate, _>(state.clone()))
@@ -143,6 +217,8 @@ fn gen_public_router(state: MioState) -> Router<()> {
                 .route("/login", get(user::login))
                 .route("/signup", post(user::signup)),
         )
+        // signed stream links, which carry their own auth
+        .route("/stream", get(track_manage::signed_stream))
         // get ver
         .route("/ver", get(get_version))
         // on any panic, dont just leave the client hanging
@@ -170,12 +246,55 @@ pub mod test {
     use axum_extra::headers::authorization::{Authorization, Credentials};
     use axum_test::{TestRequest, TestServer, TestServerConfig};
     use mio_protocol::auth;
-    use once_cell::sync::Lazy;
+    use once_cell::sync::{Lazy, OnceCell};
+
+    // small enough that going over it is quick
+    pub const TEST_MAX_UPLOAD_SIZE: u64 = 1 << 20;
 
-    pub static STATE: Lazy<MioState> = Lazy::new(|| futures::executor::block_on(gen_state()));
+    // in memory databases disappear when the last connection to them closes, which the
+    // pool can do between tests. one is held here for the whole run.
+    static HELD_DB: OnceCell<std::sync::Mutex<sqlx::SqliteConnection>> = OnceCell::new();
+
+    pub static STATE: Lazy<MioState> = Lazy::new(|| {
+        futures::executor::block_on(async {
+            use sqlx::Connection;
+
+            let settings = db_settings();
+            let held = sqlx::SqliteConnection::connect_with(&settings)
+                .await
+                .expect("Could not load database: {}");
+            HELD_DB.get_or_init(|| std::sync::Mutex::new(held));
+            gen_state(settings).await
+        })
+    });
 
     // create client
     pub async fn client() -> TestServer {
+        init_test_env();
+        TestServer::new_with_config(
+            gen_public_router(STATE.clone()).into_make_service(),
+            TestServerConfig {
+                ..Default::default()
+            },
+        )
+        .unwrap()
+    }
+
+    // a client that goes over a real socket, for things that need the peer address
+    pub async fn http_client() -> TestServer {
+        init_test_env();
+        TestServer::new_with_config(
+            gen_public_router(STATE.clone())
+                .into_make_service_with_connect_info::<std::net::SocketAddr>(),
+            TestServerConfig {
+                transport: Some(axum_test::Transport::HttpRandomPort),
+                ..Default::default()
+            },
+        )
+        .unwrap()
+    }
+
+    fn init_test_env() {
         // Try to init the logger each time just to make sure stuff is working
         drop(
             env_logger::builder()
@@ -184,13 +303,27 @@ pub mod test {
                 .filter_level(LevelFilter::Debug)
                 .try_init(),
         );
-        TestServer::new_with_config(
-            gen_public_router(STATE.clone()).into_make_service(),
-            TestServerConfig {
-                ..Default::default()
-            },
-        )
-        .unwrap()
+
+        // init_from_env is never called during testing, so set up the bits that the
+        // server needs here
+        DATA_DIR.get_or_init(|| std::path::PathBuf::from("test_files"));
+        SIGNUP_ENABLED.get_or_init(|| true);
+        ADMIN_ACCOUNT.get_or_init(|| None);
+        TOKEN_LIFETIME.get_or_init(|| DEFAULT_TOKEN_LIFETIME);
+        SESSION_MAX_AGE.get_or_init(|| DEFAULT_SESSION_MAX_AGE);
+        MAINTENANCE_INTERVAL.get_or_init(|| DEFAULT_MAINTENANCE_INTERVAL);
+        STORAGE_CODEC.get_or_init(|| mio_protocol::Codec::Qoa);
+        STORAGE_BITRATE.get_or_init(|| DEFAULT_STORAGE_BITRATE);
+        KEEP_ORIGINALS.get_or_init(|| false);
+        MAX_TRANSCODES.get_or_init(|| 2);
+        TRANSCODE_CACHE_SIZE.get_or_init(|| DEFAULT_TRANSCODE_CACHE_SIZE);
+        MAX_UPLOAD_SIZE.get_or_init(|| TEST_MAX_UPLOAD_SIZE);
+        MAX_ARCHIVE_SIZE.get_or_init(|| TEST_MAX_UPLOAD_SIZE * 4);
+        MAX_TRACK_DURATION.get_or_init(|| DEFAULT_MAX_TRACK_DURATION);
+        PROCESSING_TIMEOUT.get_or_init(|| DEFAULT_PROCESSING_TIMEOUT);
+        INBOX_ENABLED.get_or_init(|| true);
+        INBOX_SETTLE.get_or_init(|| DEFAULT_INBOX_SETTLE);
+        REFRESH_EXTENDS_SESSION.get_or_init(|| false);
     }
 
     pub async fn gen_user(client: &TestServer, username: &str) -> auth::JWT {
@@ -220,6 +353,38 @@ pub mod test {
         jwt
     }
 
+    pub async fn gen_admin(client: &TestServer, username: &str) -> auth::JWT {
+        let jwt = gen_user(client, username).await;
+        let userid = jwt.whois().unwrap().userid;
+        sqlx::query!("UPDATE user SET admin = TRUE WHERE id = ?;", userid)
+            .execute(&STATE.db)
+            .await
+            .unwrap();
+        jwt
+    }
+
+    // put a fake track straight into the db and onto disk, as uploading needs gstreamer
+    pub async fn gen_track(userid: uuid::Uuid, data: &[u8]) -> uuid::Uuid {
+        let id = uuid::Uuid::new_v4();
+        let dir = DATA_DIR.get().unwrap().join(format!("{userid}"));
+        tokio::fs::create_dir_all(&dir).await.unwrap();
+        tokio::fs::write(dir.join(format!("{id}")), data)
+            .await
+            .unwrap();
+        let size = data.len() as i64;
+        sqlx::query!(
+            "INSERT INTO track (id, title, path, owner, orig_fname, tags, track_vec, size) 
+            VALUES (?, 'test track', '', ?, 'test.flac', '{}', zeroblob(400), ?);",
+            id,
+            userid,
+            size
+        )
+        .execute(&STATE.db)
+        .await
+        .unwrap();
+        id
+    }
+
     pub fn jwt_header(
         client: &TestServer,
         method: Method,
@@ -0,0 +1,217 @@
+use crate::db::{uuid_serialize, write_transaction};
+use crate::{MioInnerError, MioState};
+use anyhow::anyhow;
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use axum::{Extension, Json};
+#[allow(unused)]
+use log::*;
+use mio_protocol::*;
+use sqlx::SqliteConnection;
+use uuid::Uuid;
+
+// how much a user is using and is allowed to use. uploads that haven't been made into
+// tracks yet are counted in with the bytes.
+pub(crate) async fn usage(
+    conn: &mut SqliteConnection,
+    id: Uuid,
+) -> Result<retstructs::StorageUsage, MioInnerError> {
+    let limits = sqlx::query!(
+        "SELECT quota_bytes, quota_tracks FROM user WHERE id = ?;",
+        id
+    )
+    .fetch_optional(&mut *conn)
+    .await?
+    .ok_or_else(|| MioInnerError::NotFound(anyhow!("user {id} does not exist")))?;
+
+    // kept originals are next to the storage copy, unless the storage copy is the original
+    let used = sqlx::query!(
+        "SELECT
+        (SELECT COUNT(*) FROM track WHERE owner = ?) AS \"tracks!: i64\",
+        (SELECT COALESCE(SUM(size), 0)
+            + COALESCE(SUM(CASE WHEN codec != 'original' THEN orig_size END), 0)
+            FROM track WHERE owner = ?)
+        + (SELECT COALESCE(SUM(size), 0) FROM upload_job
+            WHERE owner = ? AND state NOT IN ('done', 'failed'))
+        + (SELECT COALESCE(SUM(received), 0) FROM upload_session WHERE owner = ?)
+        AS \"bytes!: i64\";",
+        id,
+        id,
+        id,
+        id
+    )
+    .fetch_one(&mut *conn)
+    .await?;
+    Ok(retstructs::StorageUsage {
+        id,
+        bytes: used.bytes as u64,
+        tracks: used.tracks,
+        bytes_limit: limits.quota_bytes.map(|x| x as u64),
+        tracks_limit: limits.quota_tracks,
+    })
+}
+
+// fill in the sizes of tracks that were stored before sizes were recorded
+pub(crate) async fn fill_sizes(state: &MioState) -> Result<(), MioInnerError> {
+    let mut conn = state.db.acquire().await?;
+    let missing = sqlx::query!("SELECT id, owner, path FROM track WHERE size IS NULL;")
+        .fetch_all(&mut *conn)
+        .await?;
+    if missing.is_empty() {
+        return Ok(());
+    }
+    let mut sizes = vec![];
+    for x in missing {
+        let path = crate::DATA_DIR
+            .get()
+            .unwrap()
+            .join(format!("{}", uuid_serialize(&x.owner)?))
+            .join(&x.path)
+            .join(format!("{}", uuid_serialize(&x.id)?));
+        match tokio::fs::metadata(&path).await {
+            Ok(meta) => sizes.push((x.id, meta.len() as i64)),
+            Err(err) => warn!("QUOTA could not get the size of {path:?}: {err}"),
+        }
+    }
+    let filled = sizes.len();
+    write_transaction(&mut conn, |txn| {
+        Box::pin(async move {
+            for (id, size) in sizes {
+                sqlx::query!("UPDATE track SET size = ? WHERE id = ?;", size, id)
+                    .execute(&mut *txn)
+                    .await?;
+            }
+            Ok(())
+        })
+    })
+    .await?;
+    info!("QUOTA filled in the sizes of {filled} tracks");
+    Ok(())
+}
+
+// fail if adding the extra bytes and tracks would go over quota
+pub(crate) fn check(
+    usage: &retstructs::StorageUsage,
+    extra_bytes: u64,
+    extra_tracks: i64,
+) -> Result<(), MioInnerError> {
+    if let Some(limit) = usage.bytes_limit {
+        if usage.bytes.saturating_add(extra_bytes) > limit {
+            return Err(MioInnerError::QuotaExceeded(anyhow!(
+                "storage quota of {limit} bytes exceeded"
+            )));
+        }
+    }
+    if let Some(limit) = usage.tracks_limit {
+        if usage.tracks.saturating_add(extra_tracks) > limit {
+            return Err(MioInnerError::QuotaExceeded(anyhow!(
+                "track quota of {limit} tracks exceeded"
+            )));
+        }
+    }
+    Ok(())
+}
+
+// fail if an upload of this many bytes and tracks wouldn't fit. uploads that are still
+// waiting to be processed are counted in with the tracks that are given back, so that
+// the upload can be checked again as it comes in.
+pub(crate) async fn check_new_upload(
+    conn: &mut SqliteConnection,
+    owner: Uuid,
+    bytes: u64,
+    tracks: i64,
+) -> Result<retstructs::StorageUsage, MioInnerError> {
+    let mut usage = usage(&mut *conn, owner).await?;
+    usage.tracks += crate::subtasks::jobs::pending(&mut *conn, owner).await?;
+    check(&usage, bytes, tracks)?;
+    Ok(usage)
+}
+
+#[tracing::instrument]
+pub async fn get_usage(
+    State(state): State<MioState>,
+    Extension(auth::JWTInner { userid, .. }): Extension<auth::JWTInner>,
+) -> Result<impl IntoResponse, MioInnerError> {
+    let mut conn = state.db.acquire().await?;
+    Ok((StatusCode::OK, Json(usage(&mut conn, userid).await?)))
+}
+
+#[cfg(test)]
+mod test {
+    use crate::test::*;
+    use axum::http::{Method, StatusCode};
+    use mio_protocol::*;
+
+    #[tokio::test]
+    async fn quota_usage_good() {
+        let cli = client().await;
+        let jwt = gen_user(&cli, "quota_usage_good").await;
+        let userid = jwt.whois().unwrap().userid;
+        let id = gen_track(userid, b"not really audio").await;
+        let usage = || async {
+            jwt_header(&cli, Method::GET, "/user/storage", &jwt)
+                .await
+                .json::<retstructs::StorageUsage>()
+        };
+        assert_eq!(usage().await.bytes, 16);
+        assert_eq!(usage().await.tracks, 1);
+
+        // tracks from before sizes were kept get them on startup
+        sqlx::query!("UPDATE track SET size = NULL WHERE id = ?;", id)
+            .execute(&STATE.db)
+            .await
+            .unwrap();
+        assert_eq!(usage().await.bytes, 0);
+        super::fill_sizes(&STATE).await.unwrap();
+        assert_eq!(usage().await.bytes, 16);
+    }
+
+    #[tokio::test]
+    async fn quota_upload_bad_bytes() {
+        let cli = client().await;
+        let admin = gen_admin(&cli, "quota_upload_bad_bytes_admin").await;
+        let jwt = gen_user(&cli, "quota_upload_bad_bytes").await;
+        let userid = jwt.whois().unwrap().userid;
+        jwt_header(&cli, Method::PATCH, "/admin/users/quota", &admin)
+            .json(&msgstructs::AdminQuotaSet {
+                id: userid,
+                bytes: Some(10),
+                tracks: None,
+            })
+            .await;
+
+        // rejected while streaming, and nothing is left behind
+        jwt_header(&cli, Method::POST, "/api/track?dir=", &jwt)
+            .bytes(vec![0; 1024].into())
+            .expect_failure()
+            .await
+            .assert_status(StatusCode::PAYLOAD_TOO_LARGE);
+        let usage = jwt_header(&cli, Method::GET, "/user/storage", &jwt)
+            .await
+            .json::<retstructs::StorageUsage>();
+        assert_eq!(usage.bytes, 0);
+        assert_eq!(usage.bytes_limit, Some(10));
+    }
+
+    #[tokio::test]
+    async fn quota_upload_bad_tracks() {
+        let cli = client().await;
+        let admin = gen_admin(&cli, "quota_upload_bad_tracks_admin").await;
+        let jwt = gen_user(&cli, "quota_upload_bad_tracks").await;
+        let userid = jwt.whois().unwrap().userid;
+        gen_track(userid, b"not really audio").await;
+        jwt_header(&cli, Method::PATCH, "/admin/users/quota", &admin)
+            .json(&msgstructs::AdminQuotaSet {
+                id: userid,
+                bytes: None,
+                tracks: Some(1),
+            })
+            .await;
+        jwt_header(&cli, Method::POST, "/api/track?dir=", &jwt)
+            .bytes(vec![0; 16].into())
+            .expect_failure()
+            .await
+            .assert_status(StatusCode::PAYLOAD_TOO_LARGE);
+    }
+}
@@ -0,0 +1,162 @@
+use crate::db::write_transaction;
+use crate::MioInnerError;
+use anyhow::anyhow;
+use chrono::Utc;
+#[allow(unused)]
+use log::*;
+use sqlx::SqliteConnection;
+use std::net::IpAddr;
+
+// failures allowed before lockouts start
+const FREE_ATTEMPTS: i64 = 5;
+// the first lockout, which doubles on every failure after that
+const BASE_LOCKOUT: i64 = 2;
+const MAX_LOCKOUT: i64 = 60 * 60;
+// failures older than this are forgotten about
+pub(crate) const FORGET_AFTER: i64 = 24 * 60 * 60;
+
+// counts failed attempts against the ip and username of a request. the counters are
+// stored in the database so that restarting the server does not reset them.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Limiter {
+    ip: Option<String>,
+    username: Option<String>,
+}
+
+impl Limiter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn ip(mut self, ip: Option<IpAddr>) -> Self {
+        self.ip = ip.map(|x| x.to_string());
+        self
+    }
+
+    pub fn username(mut self, username: &str) -> Self {
+        self.username = Some(username.to_owned());
+        self
+    }
+
+    fn keys(&self) -> impl Iterator<Item = (&'static str, &str)> {
+        self.ip
+            .iter()
+            .map(|x| ("ip", x.as_str()))
+            .chain(self.username.iter().map(|x| ("username", x.as_str())))
+    }
+
+    // fails if any of the keys are locked out
+    pub async fn check(&self, conn: &mut SqliteConnection) -> Result<(), MioInnerError> {
+        let now = Utc::now().timestamp();
+        let mut locked_until = None;
+        for (kind, key) in self.keys() {
+            let row = sqlx::query!(
+                "SELECT locked_until FROM login_attempt 
+                WHERE kind = ? AND key = ? AND locked_until > ?;",
+                kind,
+                key,
+                now
+            )
+            .fetch_optional(&mut *conn)
+            .await?;
+            if let Some(row) = row {
+                debug!(
+                    "RATELIMIT {kind} {key} is locked until {}",
+                    row.locked_until
+                );
+                locked_until = locked_until.max(Some(row.locked_until));
+            }
+        }
+        match locked_until {
+            Some(until) => Err(MioInnerError::RateLimited(
+                anyhow!("Too many failed attempts, try again later"),
+                until - now,
+            )),
+            None => Ok(()),
+        }
+    }
+
+    // record a failure, locking out the keys if there have been too many
+    pub async fn fail(&self, conn: &mut SqliteConnection) -> Result<(), MioInnerError> {
+        let this = self.clone();
+        write_transaction(conn, |txn| {
+            Box::pin(async move {
+                let now = Utc::now().timestamp();
+                let forget = now - FORGET_AFTER;
+                for (kind, key) in this.keys() {
+                    let failures = sqlx::query!(
+                        "INSERT INTO login_attempt (kind, key, failures, last_failure, locked_until) 
+                        VALUES (?, ?, 1, ?, 0)
+                        ON CONFLICT (kind, key) DO UPDATE SET 
+                            failures = CASE WHEN last_failure < ? THEN 1 ELSE failures + 1 END,
+                            last_failure = excluded.last_failure
+                        RETURNING failures;",
+                        kind,
+                        key,
+                        now,
+                        forget
+                    )
+                    .fetch_one(&mut *txn)
+                    .await?
+                    .failures;
+                    if let Some(lockout) = lockout_for(failures) {
+                        let until = now + lockout;
+                        info!("RATELIMIT {kind} {key} failed {failures} times, locked for {lockout}s");
+                        sqlx::query!(
+                            "UPDATE login_attempt SET locked_until = ? WHERE kind = ? AND key = ?;",
+                            until,
+                            kind,
+                            key
+                        )
+                        .execute(&mut *txn)
+                        .await?;
+                    }
+                }
+                Ok(())
+            })
+        })
+        .await
+    }
+
+    // forget failures after a success. ips are not cleared, as that would let anyone
+    // with an account reset the counter for their ip.
+    pub async fn succeed(&self, conn: &mut SqliteConnection) -> Result<(), MioInnerError> {
+        if let Some(username) = self.username.as_ref() {
+            sqlx::query!(
+                "DELETE FROM login_attempt WHERE kind = 'username' AND key = ?;",
+                username
+            )
+            .execute(&mut *conn)
+            .await?;
+        }
+        Ok(())
+    }
+}
+
+// exponential backoff, in seconds
+fn lockout_for(failures: i64) -> Option<i64> {
+    let over = failures - FREE_ATTEMPTS;
+    if over <= 0 {
+        return None;
+    }
+    Some(
+        2_i64
+            .checked_pow((over - 1).min(u32::MAX as i64) as u32)
+            .and_then(|x| x.checked_mul(BASE_LOCKOUT))
+            .map_or(MAX_LOCKOUT, |x| x.min(MAX_LOCKOUT)),
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn ratelimit_lockout_backoff() {
+        assert_eq!(lockout_for(FREE_ATTEMPTS), None);
+        assert_eq!(lockout_for(FREE_ATTEMPTS + 1), Some(BASE_LOCKOUT));
+        assert_eq!(lockout_for(FREE_ATTEMPTS + 2), Some(BASE_LOCKOUT * 2));
+        assert_eq!(lockout_for(FREE_ATTEMPTS + 3), Some(BASE_LOCKOUT * 4));
+        assert_eq!(lockout_for(i64::MAX), Some(MAX_LOCKOUT));
+    }
+}
@@ -0,0 +1,580 @@
+use crate::db::{uuid_serialize, write_transaction};
+use crate::endpoints::archive::audio_target;
+use crate::endpoints::check_dir_in_data_dir;
+use crate::endpoints::track_manage::{create_upload_file, sanitize_fname, upload_format};
+use crate::subtasks::jobs::{self, NewJob};
+use crate::subtasks::transcode::parse_codec;
+use crate::{MioInnerError, MioState, DATA_DIR};
+use anyhow::anyhow;
+use chrono::Utc;
+#[allow(unused)]
+use log::*;
+use mio_protocol::*;
+use once_cell::sync::Lazy;
+use sqlx::{SqliteConnection, SqlitePool};
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::fs::{create_dir_all, remove_file};
+use tokio::sync::{watch, Notify};
+use uuid::Uuid;
+
+// wakes up the import worker when an import is started
+static STARTED: Lazy<Notify> = Lazy::new(Notify::new);
+
+// a folder on the server that is being imported
+#[derive(Debug)]
+pub(crate) struct NewImport {
+    pub id: Uuid,
+    pub owner: Uuid,
+    pub source: PathBuf,
+    pub dir: String,
+    pub codec: Codec,
+    pub bitrate: Option<u32>,
+    pub duplicates: DuplicatePolicy,
+    // hard link files in rather than copying them
+    pub link: bool,
+}
+
+// a file under the source, and where it goes under the import's dir
+type Found = (PathBuf, Result<(PathBuf, String), MioInnerError>);
+
+// record an import to be run by the worker
+pub(crate) async fn start(
+    conn: &mut SqliteConnection,
+    import: NewImport,
+) -> Result<(), MioInnerError> {
+    let now = Utc::now().timestamp();
+    let source = import.source.to_string_lossy().into_owned();
+    let codec = import.codec.as_str();
+    let duplicates = import.duplicates.as_str();
+    sqlx::query!(
+        "INSERT INTO import
+        (id, owner, source, dir, codec, bitrate, duplicates, link, state, created, updated)
+        VALUES (?, ?, ?, ?, ?, ?, ?, ?, 'scanning', ?, ?);",
+        import.id,
+        import.owner,
+        source,
+        import.dir,
+        codec,
+        import.bitrate,
+        duplicates,
+        import.link,
+        now,
+        now
+    )
+    .execute(&mut *conn)
+    .await?;
+    trace!("IMPORT started {}", import.id);
+    STARTED.notify_one();
+    Ok(())
+}
+
+// imports are run one at a time until true is sent on the shutdown channel. the file
+// being copied is finished first, and the import is left scanning so that it's picked
+// back up on the next start.
+pub(crate) async fn worker(state: MioState, mut shutdown: watch::Receiver<bool>) {
+    loop {
+        if *shutdown.borrow() {
+            debug!("IMPORT stopping worker");
+            return;
+        }
+        let next = match next_import(&state.db).await {
+            Ok(next) => next,
+            Err(err) => {
+                error!("IMPORT failed to get next import: {err}");
+                tokio::time::sleep(Duration::from_secs(1)).await;
+                continue;
+            }
+        };
+        match next {
+            Some(id) => run(&state, id, &shutdown).await,
+            None => tokio::select! {
+                _ = STARTED.notified() => (),
+                ret = shutdown.changed() => {
+                    if ret.is_err() || *shutdown.borrow() {
+                        debug!("IMPORT stopping worker");
+                        return;
+                    }
+                }
+            },
+        }
+    }
+}
+
+async fn next_import(db: &SqlitePool) -> Result<Option<Uuid>, MioInnerError> {
+    sqlx::query!("SELECT id FROM import WHERE state = 'scanning' ORDER BY created LIMIT 1;")
+        .fetch_optional(db)
+        .await?
+        .map(|x| uuid_serialize(&x.id))
+        .transpose()
+}
+
+// run an import through, recording how it went
+pub(crate) async fn run(state: &MioState, id: Uuid, shutdown: &watch::Receiver<bool>) {
+    let (new_state, error) = match scan(state, id, shutdown).await {
+        Ok(false) => {
+            debug!("IMPORT {id} stopped for shutdown");
+            return;
+        }
+        Ok(true) => (ImportState::Done, None),
+        Err(err) => {
+            warn!("IMPORT {id} failed: {err}");
+            (ImportState::Failed, Some(err.msg()))
+        }
+    };
+    let now = Utc::now().timestamp();
+    let state_str = new_state.as_str();
+    if let Err(err) = sqlx::query!(
+        "UPDATE import SET state = ?, error = ?, updated = ? WHERE id = ?;",
+        state_str,
+        error,
+        now,
+        id
+    )
+    .execute(&state.db)
+    .await
+    {
+        error!("IMPORT failed to record how {id} went: {err}");
+    }
+}
+
+// false if it was stopped part way through by a shutdown
+async fn scan(
+    state: &MioState,
+    id: Uuid,
+    shutdown: &watch::Receiver<bool>,
+) -> Result<bool, MioInnerError> {
+    let import = load(&state.db, id).await?;
+    debug!("IMPORT {id} walking {:?}", import.source);
+    let found = tokio::task::spawn_blocking({
+        let source = import.source.clone();
+        move || {
+            let mut found = vec![];
+            walk(&source, Path::new(""), &mut found)?;
+            Ok::<_, MioInnerError>(found)
+        }
+    })
+    .await??;
+    let total = found.iter().filter(|(_, x)| x.is_ok()).count() as i64;
+    let now = Utc::now().timestamp();
+    sqlx::query!(
+        "UPDATE import SET total = ?, updated = ? WHERE id = ?;",
+        total,
+        now,
+        id
+    )
+    .execute(&state.db)
+    .await?;
+
+    // anything already gotten to was done before the import was interrupted
+    let seen = sqlx::query!("SELECT path FROM import_file WHERE import = ?;", id)
+        .fetch_all(&state.db)
+        .await?
+        .into_iter()
+        .map(|x| x.path)
+        .collect::<HashSet<_>>();
+
+    for (path, target) in found {
+        let name = path.to_string_lossy().into_owned();
+        if seen.contains(&name) {
+            continue;
+        }
+        if *shutdown.borrow() {
+            return Ok(false);
+        }
+        let ret = match target {
+            Ok((dir, fname)) => queue(state, &import, &path, dir, fname).await,
+            Err(err) => Err(err),
+        };
+        if let Err(err) = ret {
+            debug!("IMPORT {id} could not take {name:?}: {err}");
+            let error = err.msg();
+            sqlx::query!(
+                "INSERT INTO import_file (import, path, job, error) VALUES (?, ?, NULL, ?);",
+                id,
+                name,
+                error
+            )
+            .execute(&state.db)
+            .await?;
+        }
+    }
+    info!("IMPORT {id} queued everything in {:?}", import.source);
+    Ok(true)
+}
+
+// find every audio file under a folder, in order. symlinks are not followed, and a
+// folder that can't be read is reported as it would be for a file. this blocks.
+fn walk(source: &Path, rel: &Path, found: &mut Vec<Found>) -> Result<(), MioInnerError> {
+    let mut entries = std::fs::read_dir(source.join(rel))?.collect::<Result<Vec<_>, _>>()?;
+    entries.sort_by_key(|x| x.file_name());
+    for entry in entries {
+        let path = rel.join(entry.file_name());
+        let ftype = entry.file_type()?;
+        if ftype.is_dir() {
+            if let Err(err) = walk(source, &path, found) {
+                found.push((path, Err(err)));
+            }
+        } else if ftype.is_file() {
+            if let Some(target) = audio_target(&path) {
+                found.push((path, target));
+            }
+        }
+    }
+    Ok(())
+}
+
+// put a file into the owner's library and hand it off to be processed, recording
+// that the import has gotten to it
+async fn queue(
+    state: &MioState,
+    import: &NewImport,
+    path: &Path,
+    dir: PathBuf,
+    fname: String,
+) -> Result<(), MioInnerError> {
+    let format = upload_format(Some(import.codec), import.bitrate, Some(&fname))?;
+    let source = import.source.join(path);
+    let size = tokio::fs::metadata(&source).await?.len();
+    let dir = Path::new(&import.dir)
+        .join(dir)
+        .to_string_lossy()
+        .into_owned();
+    let _hold = state.lock_files.read().await;
+    check_dir_in_data_dir(&dir, import.owner)?;
+    let mut conn = state.db.acquire().await?;
+    crate::quota::check_new_upload(&mut conn, import.owner, size, 1).await?;
+    create_dir_all(
+        DATA_DIR
+            .get()
+            .unwrap()
+            .join(format!("{}", import.owner))
+            .join(&dir),
+    )
+    .await?;
+    let (id, real_fname, file) = create_upload_file(import.owner, &dir).await?;
+    drop(file);
+    let ret = async {
+        // a link is the same file as the source, so it's only done when asked for.
+        // processing replaces the upload by renaming over it rather than writing into
+        // it, so the source is still left alone when it's linked.
+        if import.link {
+            remove_file(&real_fname).await?;
+            if tokio::fs::hard_link(&source, &real_fname).await.is_err() {
+                tokio::fs::copy(&source, &real_fname).await?;
+            }
+        } else {
+            tokio::fs::copy(&source, &real_fname).await?;
+        }
+        let name = path.to_string_lossy().into_owned();
+        let job = NewJob {
+            id,
+            owner: import.owner,
+            dir,
+            orig_fname: sanitize_fname(Some(fname), id),
+            format,
+            duplicates: import.duplicates,
+            inbox: None,
+        };
+        let import = import.id;
+        write_transaction(&mut conn, |txn| {
+            Box::pin(async move {
+                sqlx::query!(
+                    "INSERT INTO import_file (import, path, job, error) VALUES (?, ?, ?, NULL);",
+                    import,
+                    name,
+                    id
+                )
+                .execute(&mut *txn)
+                .await?;
+                jobs::enqueue(txn, job).await
+            })
+        })
+        .await
+    }
+    .await;
+    if let Err(err) = ret {
+        if let Err(err) = remove_file(&real_fname).await {
+            if err.kind() != std::io::ErrorKind::NotFound {
+                warn!("IMPORT failed to remove {real_fname:?}: {err}");
+            }
+        }
+        return Err(err);
+    }
+    Ok(())
+}
+
+async fn load(db: &SqlitePool, id: Uuid) -> Result<NewImport, MioInnerError> {
+    let import = sqlx::query!(
+        "SELECT owner, source, dir, codec, bitrate, duplicates, link FROM import WHERE id = ?;",
+        id
+    )
+    .fetch_optional(db)
+    .await?
+    .ok_or_else(|| MioInnerError::NotFound(anyhow!("import {id} does not exist")))?;
+    Ok(NewImport {
+        id,
+        owner: uuid_serialize(&import.owner)?,
+        source: import.source.into(),
+        dir: import.dir,
+        codec: parse_codec(&import.codec)?,
+        bitrate: import.bitrate.map(|x| x as u32),
+        duplicates: import.duplicates.parse().map_err(|err| {
+            MioInnerError::DbError(anyhow!("could not parse duplicate policy {err}"))
+        })?,
+        link: import.link != 0,
+    })
+}
+
+// how an import is going. jobs are forgotten after a while, after which the files
+// they were for only count as processed.
+pub(crate) async fn report(
+    conn: &mut SqliteConnection,
+    id: Uuid,
+) -> Result<retstructs::Import, MioInnerError> {
+    let import = sqlx::query!(
+        "SELECT owner, source, dir, state, error, total, created, updated
+        FROM import WHERE id = ?;",
+        id
+    )
+    .fetch_optional(&mut *conn)
+    .await?
+    .ok_or_else(|| MioInnerError::NotFound(anyhow!("import {id} does not exist")))?;
+    let counts = sqlx::query!(
+        "SELECT
+        COUNT(*) AS \"processed!: i64\",
+        COUNT(upload_job.id) - SUM(upload_job.state IN ('done', 'failed'))
+            AS \"queued: i64\",
+        SUM(upload_job.state = 'done') AS \"done: i64\",
+        SUM(import_file.error IS NOT NULL OR upload_job.state = 'failed') AS \"failed: i64\"
+        FROM import_file LEFT JOIN upload_job ON upload_job.id = import_file.job
+        WHERE import_file.import = ?;",
+        id
+    )
+    .fetch_one(&mut *conn)
+    .await?;
+    let errors = sqlx::query!(
+        "SELECT import_file.path, import_file.error, upload_job.error AS job_error
+        FROM import_file LEFT JOIN upload_job ON upload_job.id = import_file.job
+        WHERE import_file.import = ?
+        AND (import_file.error IS NOT NULL OR upload_job.state = 'failed')
+        ORDER BY import_file.path;",
+        id
+    )
+    .fetch_all(&mut *conn)
+    .await?
+    .into_iter()
+    .map(|x| retstructs::ImportError {
+        path: x.path,
+        error: x.error.or(x.job_error).unwrap_or_default(),
+    })
+    .collect();
+    Ok(retstructs::Import {
+        id,
+        user: uuid_serialize(&import.owner)?,
+        source: import.source,
+        dir: import.dir,
+        state: import
+            .state
+            .parse()
+            .map_err(|err| MioInnerError::DbError(anyhow!("could not parse state {err}")))?,
+        error: import.error,
+        total: import.total,
+        processed: counts.processed,
+        queued: counts.queued.unwrap_or(0),
+        done: counts.done.unwrap_or(0),
+        failed: counts.failed.unwrap_or(0),
+        errors,
+        created: import.created,
+        updated: import.updated,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use crate::test::*;
+    use axum::http::{Method, StatusCode};
+    use mio_protocol::*;
+
+    #[tokio::test]
+    async fn import_good() {
+        let cli = client().await;
+        let admin = gen_admin(&cli, "import_good").await;
+        let jwt = gen_user(&cli, "import_good_2").await;
+        let userid = jwt.whois().unwrap().userid;
+        let source = crate::DATA_DIR.get().unwrap().join("import_good_source");
+        for (path, data) in [
+            ("Artist/Album/01 Intro.flac", &b"not really audio"[..]),
+            ("Artist/Album/cover.jpg", b"not a track"),
+            ("Artist/.hidden.mp3", b"hidden"),
+            ("loose.OGG", b"also not audio"),
+        ] {
+            let path = source.join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, data).unwrap();
+        }
+        let source = source.canonicalize().unwrap();
+        let resp = jwt_header(&cli, Method::POST, "/admin/import", &admin)
+            .json(&msgstructs::AdminImport {
+                source: source.to_string_lossy().into_owned(),
+                user: userid,
+                dir: "Imported".to_owned(),
+                codec: None,
+                bitrate: None,
+                duplicates: None,
+                link: None,
+            })
+            .await;
+        resp.assert_status(StatusCode::ACCEPTED);
+        let import = resp.json::<retstructs::Import>();
+        assert_eq!(import.state, ImportState::Scanning);
+        assert_eq!(import.total, None);
+
+        // the worker isn't running, so this is done by hand. shutting down stops it
+        // before the next file, leaving it to be picked back up.
+        let (_tx, stopped) = tokio::sync::watch::channel(true);
+        super::run(&STATE, import.id, &stopped).await;
+        let resp = jwt_header(
+            &cli,
+            Method::GET,
+            &format!("/admin/import/status?id={}", import.id),
+            &admin,
+        )
+        .await
+        .json::<retstructs::Import>();
+        assert_eq!(resp.state, ImportState::Scanning);
+        assert_eq!(resp.processed, 0);
+
+        // running it again is what happens when it's picked back up, and should not
+        // queue anything twice
+        let (_tx, running) = tokio::sync::watch::channel(false);
+        for _ in 0..2 {
+            super::run(&STATE, import.id, &running).await;
+        }
+        let import = jwt_header(
+            &cli,
+            Method::GET,
+            &format!("/admin/import/status?id={}", import.id),
+            &admin,
+        )
+        .await
+        .json::<retstructs::Import>();
+        assert_eq!(import.state, ImportState::Done);
+        assert_eq!(import.total, Some(2));
+        assert_eq!(import.processed, 2);
+        assert_eq!(import.queued, 2);
+        assert_eq!(import.failed, 0);
+        let jobs = jwt_header(&cli, Method::GET, "/api/jobs", &jwt)
+            .await
+            .json::<retstructs::Jobs>()
+            .jobs;
+        assert_eq!(jobs.len(), 2);
+
+        // folders are mirrored, and the source is left where it was
+        let userdir = crate::DATA_DIR.get().unwrap().join(format!("{userid}"));
+        for (dir, fname, data) in [
+            (
+                "Imported/Artist/Album",
+                "01 Intro.flac",
+                &b"not really audio"[..],
+            ),
+            ("Imported", "loose.OGG", b"also not audio"),
+        ] {
+            let job = jobs.iter().find(|x| x.fname == fname).unwrap();
+            assert_eq!(
+                std::fs::read(userdir.join(dir).join(format!("{}", job.id))).unwrap(),
+                data
+            );
+        }
+        assert!(source.join("loose.OGG").exists());
+
+        // what's imported is a copy, so changing the source doesn't change the library
+        std::fs::write(source.join("loose.OGG"), b"retagged").unwrap();
+        let job = jobs.iter().find(|x| x.fname == "loose.OGG").unwrap();
+        assert_eq!(
+            std::fs::read(userdir.join("Imported").join(format!("{}", job.id))).unwrap(),
+            b"also not audio"
+        );
+        std::fs::remove_dir_all(source).unwrap();
+    }
+
+    #[tokio::test]
+    async fn import_link_good() {
+        let cli = client().await;
+        let admin = gen_admin(&cli, "import_link_good").await;
+        let jwt = gen_user(&cli, "import_link_good_2").await;
+        let userid = jwt.whois().unwrap().userid;
+        let source = crate::DATA_DIR
+            .get()
+            .unwrap()
+            .join("import_link_good_source");
+        std::fs::create_dir_all(&source).unwrap();
+        std::fs::write(source.join("track.flac"), b"not really audio").unwrap();
+        let source = source.canonicalize().unwrap();
+        let import = jwt_header(&cli, Method::POST, "/admin/import", &admin)
+            .json(&msgstructs::AdminImport {
+                source: source.to_string_lossy().into_owned(),
+                user: userid,
+                dir: "".to_owned(),
+                codec: None,
+                bitrate: None,
+                duplicates: None,
+                link: Some(true),
+            })
+            .await
+            .json::<retstructs::Import>();
+        super::run(&STATE, import.id, &tokio::sync::watch::channel(false).1).await;
+
+        // a linked file is the source, so changes to it show up in the library
+        let job = jwt_header(&cli, Method::GET, "/api/jobs", &jwt)
+            .await
+            .json::<retstructs::Jobs>()
+            .jobs
+            .remove(0);
+        std::fs::write(source.join("track.flac"), b"retagged").unwrap();
+        assert_eq!(
+            std::fs::read(
+                crate::DATA_DIR
+                    .get()
+                    .unwrap()
+                    .join(format!("{userid}"))
+                    .join(format!("{}", job.id))
+            )
+            .unwrap(),
+            b"retagged"
+        );
+        std::fs::remove_dir_all(source).unwrap();
+    }
+
+    #[tokio::test]
+    async fn import_bad() {
+        let cli = client().await;
+        let admin = gen_admin(&cli, "import_bad").await;
+        let jwt = gen_user(&cli, "import_bad_2").await;
+        let import = |source: &str, dir: &str| msgstructs::AdminImport {
+            source: source.to_owned(),
+            user: jwt.whois().unwrap().userid,
+            dir: dir.to_owned(),
+            codec: None,
+            bitrate: None,
+            duplicates: None,
+            link: None,
+        };
+        for (body, status) in [
+            (import("relative/path", ""), StatusCode::BAD_REQUEST),
+            (import("/does/not/exist", ""), StatusCode::NOT_FOUND),
+            (import("/", ".."), StatusCode::BAD_REQUEST),
+        ] {
+            jwt_header(&cli, Method::POST, "/admin/import", &admin)
+                .json(&body)
+                .expect_failure()
+                .await
+                .assert_status(status);
+        }
+        jwt_header(&cli, Method::POST, "/admin/import", &jwt)
+            .json(&import("/", ""))
+            .expect_failure()
+            .await;
+    }
+}
@@ -0,0 +1,385 @@
+use crate::endpoints::archive::audio_target;
+use crate::endpoints::check_dir_in_data_dir;
+use crate::endpoints::track_manage::{create_upload_file, sanitize_fname, upload_format};
+use crate::subtasks::jobs::{self, NewJob};
+use crate::{MioInnerError, MioState, DATA_DIR};
+use anyhow::anyhow;
+use axum::http::StatusCode;
+#[allow(unused)]
+use log::*;
+use notify::event::{AccessKind, AccessMode, ModifyKind, RenameMode};
+use notify::{EventKind, RecursiveMode, Watcher};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::fs::{create_dir_all, rename};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::watch;
+use tokio::time::Instant;
+use uuid::Uuid;
+
+// where audio is dropped in to be uploaded
+pub(crate) fn inbox_dir(owner: Uuid) -> PathBuf {
+    DATA_DIR
+        .get()
+        .unwrap()
+        .join("inbox")
+        .join(format!("{owner}"))
+}
+
+// where uploads from the inbox that failed end up
+pub(crate) fn quarantine_dir(owner: Uuid) -> PathBuf {
+    DATA_DIR
+        .get()
+        .unwrap()
+        .join("quarantine")
+        .join(format!("{owner}"))
+}
+
+// watch every inbox until true is sent on the shutdown channel
+pub(crate) async fn watcher(state: MioState, shutdown: watch::Receiver<bool>) {
+    if let Err(err) = watch_inboxes(state, shutdown).await {
+        error!("INBOX stopped watching: {err}");
+    }
+}
+
+async fn watch_inboxes(
+    state: MioState,
+    mut shutdown: watch::Receiver<bool>,
+) -> Result<(), MioInnerError> {
+    let root = DATA_DIR.get().unwrap().join("inbox");
+    let users = sqlx::query!("SELECT id FROM user;")
+        .fetch_all(&state.db)
+        .await?;
+    for user in users {
+        create_dir_all(inbox_dir(crate::db::uuid_serialize(&user.id)?)).await?;
+    }
+
+    // notify runs on its own thread, so events are passed over to here
+    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+    let notify_err = |err: notify::Error| {
+        MioInnerError::InternalIoError(anyhow!("failed to watch inboxes: {err}"))
+    };
+    let mut watcher =
+        notify::recommended_watcher(move |event| drop(tx.send(event))).map_err(notify_err)?;
+    watcher
+        .watch(&root, RecursiveMode::Recursive)
+        .map_err(notify_err)?;
+    info!("INBOX watching {root:?}");
+
+    // files are written in bits over a network, so anything that happens to a path
+    // pushes back when it's taken. the whole inbox is looked at first, for anything
+    // dropped in while the server was down.
+    let settle = Duration::from_secs(*crate::INBOX_SETTLE.get().unwrap() as u64);
+    let mut pending = HashMap::from([(root, Instant::now())]);
+    let mut tick = tokio::time::interval(Duration::from_secs(1));
+    loop {
+        tokio::select! {
+            Some(event) = rx.recv() => {
+                let event = match event {
+                    Ok(event) => event,
+                    Err(err) => {
+                        warn!("INBOX watch error: {err}");
+                        continue;
+                    }
+                };
+                match event.kind {
+                    EventKind::Remove(_)
+                    | EventKind::Modify(ModifyKind::Name(RenameMode::From)) => {
+                        for path in event.paths {
+                            pending.remove(&path);
+                        }
+                    }
+                    EventKind::Create(_)
+                    | EventKind::Modify(_)
+                    | EventKind::Access(AccessKind::Close(AccessMode::Write)) => {
+                        for path in event.paths {
+                            pending.insert(path, Instant::now() + settle);
+                        }
+                    }
+                    _ => (),
+                }
+            }
+            _ = tick.tick() => {
+                let now = Instant::now();
+                let ready = pending
+                    .iter()
+                    .filter(|(_, at)| **at <= now)
+                    .map(|(path, _)| path.clone())
+                    .collect::<Vec<_>>();
+                for path in ready {
+                    pending.remove(&path);
+                    if let Err(err) = settled(&state, path, settle, &mut pending).await {
+                        warn!("INBOX failed to take file: {err}");
+                    }
+                }
+            }
+            ret = shutdown.changed() => {
+                if ret.is_err() || *shutdown.borrow() {
+                    debug!("INBOX stopping watcher");
+                    return Ok(());
+                }
+            }
+        }
+    }
+}
+
+// look at a path that has had nothing happen to it for a while. the files in a folder
+// that was moved in all at once don't get their own events, so they're found here.
+async fn settled(
+    state: &MioState,
+    path: PathBuf,
+    settle: Duration,
+    pending: &mut HashMap<PathBuf, Instant>,
+) -> Result<(), MioInnerError> {
+    let Ok(meta) = tokio::fs::symlink_metadata(&path).await else {
+        return Ok(());
+    };
+    if meta.is_dir() {
+        let mut entries = tokio::fs::read_dir(&path).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            pending.insert(entry.path(), Instant::now());
+        }
+    } else if meta.is_file() {
+        // some ways of copying files in don't make events for every write
+        let age = meta.modified()?.elapsed().unwrap_or_default();
+        if age < settle {
+            pending.insert(path, Instant::now() + (settle - age));
+        } else {
+            ingest(state, &path).await?;
+        }
+    }
+    Ok(())
+}
+
+// move a file out of an inbox and queue it up. files that aren't audio are left alone,
+// and ones that can't be queued are quarantined.
+pub(crate) async fn ingest(state: &MioState, path: &Path) -> Result<(), MioInnerError> {
+    let root = DATA_DIR.get().unwrap().join("inbox");
+    let Ok(rel) = path.strip_prefix(root) else {
+        return Ok(());
+    };
+    let mut parts = rel.components();
+    let Some(Ok(owner)) = parts
+        .next()
+        .map(|x| x.as_os_str().to_string_lossy().parse::<Uuid>())
+    else {
+        return Ok(());
+    };
+    let rel = parts.as_path();
+    let Some(target) = audio_target(rel) else {
+        trace!("INBOX skipping {path:?}");
+        return Ok(());
+    };
+    if sqlx::query!("SELECT id FROM user WHERE id = ?;", owner)
+        .fetch_optional(&state.db)
+        .await?
+        .is_none()
+    {
+        debug!("INBOX skipping {path:?}, as {owner} does not exist");
+        return Ok(());
+    }
+    let ret = match target {
+        Ok((dir, fname)) => queue(state, owner, path, rel, dir, fname).await,
+        Err(err) => Err(err),
+    };
+    match ret {
+        Ok(id) => info!("INBOX queued {path:?} as {id}"),
+        Err(err) => {
+            info!("INBOX could not take {path:?}: {err}");
+            quarantine(owner, path, rel, &err.msg()).await?;
+        }
+    }
+    Ok(())
+}
+
+// move an inbox file into the library, mirroring the folders it was in
+async fn queue(
+    state: &MioState,
+    owner: Uuid,
+    path: &Path,
+    rel: &Path,
+    dir: PathBuf,
+    fname: String,
+) -> Result<Uuid, MioInnerError> {
+    let format = upload_format(None, None, Some(&fname))?;
+    let size = tokio::fs::metadata(path).await?.len();
+    let max_size = *crate::MAX_UPLOAD_SIZE.get().unwrap();
+    if size > max_size {
+        return Err(MioInnerError::TrackProcessingError(
+            anyhow!("file is larger than the max of {max_size} bytes"),
+            StatusCode::PAYLOAD_TOO_LARGE,
+        ));
+    }
+    let dir = dir.to_string_lossy().into_owned();
+    let _hold = state.lock_files.read().await;
+    check_dir_in_data_dir(&dir, owner)?;
+    let mut conn = state.db.acquire().await?;
+    crate::quota::check_new_upload(&mut conn, owner, size, 1).await?;
+    create_dir_all(DATA_DIR.get().unwrap().join(format!("{owner}")).join(&dir)).await?;
+    let (id, real_fname, file) = create_upload_file(owner, &dir).await?;
+    drop(file);
+    if let Err(err) = rename(path, &real_fname).await {
+        tokio::fs::remove_file(&real_fname).await?;
+        return Err(err.into());
+    }
+    let ret = jobs::enqueue(
+        &mut conn,
+        NewJob {
+            id,
+            owner,
+            dir,
+            orig_fname: sanitize_fname(Some(fname), id),
+            format,
+            duplicates: Default::default(),
+            inbox: Some(rel.to_string_lossy().into_owned()),
+        },
+    )
+    .await;
+    if let Err(err) = ret {
+        rename(&real_fname, path).await?;
+        return Err(err);
+    }
+    Ok(id)
+}
+
+// move a file that couldn't be uploaded out of the way, to where it was in the inbox
+// but under the quarantine folder. why is written next to it.
+pub(crate) async fn quarantine(
+    owner: Uuid,
+    from: &Path,
+    rel: &Path,
+    error: &str,
+) -> Result<(), MioInnerError> {
+    let to = quarantine_dir(owner).join(rel);
+    if let Some(parent) = to.parent() {
+        create_dir_all(parent).await?;
+    }
+
+    // anything already quarantined under the name is kept, so this gets a counter. the
+    // note is created first to claim the name, as jobs can quarantine at the same time.
+    let mut n = 0;
+    let (to, note_path, mut note) = loop {
+        let to = numbered(&to, n);
+        let mut note_path = to.clone().into_os_string();
+        note_path.push(".error.txt");
+        if !tokio::fs::try_exists(&to).await? {
+            match tokio::fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(&note_path)
+                .await
+            {
+                Ok(note) => break (to, note_path, note),
+                Err(err) if err.kind() == std::io::ErrorKind::AlreadyExists => (),
+                Err(err) => return Err(err.into()),
+            }
+        }
+        n += 1;
+    };
+    if let Err(err) = rename(from, &to).await {
+        drop(tokio::fs::remove_file(note_path).await);
+        return Err(err.into());
+    }
+    note.write_all(format!("{error}\n").as_bytes()).await?;
+    note.flush().await?;
+    Ok(())
+}
+
+// "name.ext" becomes "name (n).ext", with 0 leaving it as it is
+fn numbered(path: &Path, n: u32) -> PathBuf {
+    if n == 0 {
+        return path.to_owned();
+    }
+    let mut name = path.file_stem().unwrap_or_default().to_os_string();
+    name.push(format!(" ({n})"));
+    if let Some(ext) = path.extension() {
+        name.push(".");
+        name.push(ext);
+    }
+    path.with_file_name(name)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::test::*;
+    use axum::http::Method;
+    use mio_protocol::*;
+
+    #[tokio::test]
+    async fn inbox_good() {
+        let cli = client().await;
+        let jwt = gen_user(&cli, "inbox_good").await;
+        let userid = jwt.whois().unwrap().userid;
+        let inbox = super::inbox_dir(userid);
+        let path = inbox.join("Album").join("01 Intro.flac");
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(&path, b"not really audio").unwrap();
+        std::fs::write(inbox.join("cover.jpg"), b"not a track").unwrap();
+        super::ingest(&STATE, &path).await.unwrap();
+        super::ingest(&STATE, &inbox.join("cover.jpg"))
+            .await
+            .unwrap();
+
+        // the audio is moved into the library, and everything else is left alone
+        assert!(!path.exists());
+        assert!(inbox.join("cover.jpg").exists());
+        let jobs = jwt_header(&cli, Method::GET, "/api/jobs", &jwt)
+            .await
+            .json::<retstructs::Jobs>()
+            .jobs;
+        assert_eq!(jobs.len(), 1);
+        assert_eq!(jobs[0].fname, "01 Intro.flac");
+        let id = jobs[0].id;
+        assert!(crate::DATA_DIR
+            .get()
+            .unwrap()
+            .join(format!("{userid}"))
+            .join("Album")
+            .join(format!("{id}"))
+            .exists());
+
+        // it isn't audio, so it ends up in quarantine
+        assert!(crate::subtasks::jobs::process(&STATE, id).await.unwrap());
+        let quarantined = super::quarantine_dir(userid)
+            .join("Album")
+            .join("01 Intro.flac");
+        assert_eq!(std::fs::read(&quarantined).unwrap(), b"not really audio");
+        assert!(quarantined.with_extension("flac.error.txt").exists());
+    }
+
+    #[tokio::test]
+    async fn inbox_bad_quota() {
+        let cli = client().await;
+        let jwt = gen_user(&cli, "inbox_bad_quota").await;
+        let userid = jwt.whois().unwrap().userid;
+        sqlx::query!("UPDATE user SET quota_tracks = 0 WHERE id = ?;", userid)
+            .execute(&STATE.db)
+            .await
+            .unwrap();
+        let path = super::inbox_dir(userid).join("a.mp3");
+        std::fs::write(&path, b"not really audio").unwrap();
+        super::ingest(&STATE, &path).await.unwrap();
+        assert!(!path.exists());
+        let quarantined = super::quarantine_dir(userid).join("a.mp3");
+        assert!(quarantined.exists());
+        assert!(
+            std::fs::read_to_string(quarantined.with_extension("mp3.error.txt"))
+                .unwrap()
+                .contains("quota")
+        );
+
+        // a second file with the same name doesn't replace the first
+        std::fs::write(&path, b"another").unwrap();
+        super::ingest(&STATE, &path).await.unwrap();
+        assert_eq!(std::fs::read(&quarantined).unwrap(), b"not really audio");
+        let second = super::quarantine_dir(userid).join("a (1).mp3");
+        assert_eq!(std::fs::read(&second).unwrap(), b"another");
+        assert!(second.with_extension("mp3.error.txt").exists());
+        assert!(jwt_header(&cli, Method::GET, "/api/jobs", &jwt)
+            .await
+            .json::<retstructs::Jobs>()
+            .jobs
+            .is_empty());
+    }
+}
@@ -0,0 +1,297 @@
+use crate::db::uuid_serialize;
+use crate::subtasks::transcode::StorageFormat;
+use crate::{MioInnerError, MioState};
+use anyhow::anyhow;
+use chrono::Utc;
+#[allow(unused)]
+use log::*;
+use mio_protocol::*;
+use once_cell::sync::Lazy;
+use sqlx::{SqliteConnection, SqlitePool};
+use std::time::Duration;
+use tokio::sync::{watch, Notify};
+use tokio::task::JoinSet;
+use uuid::Uuid;
+
+// finished jobs are kept around for this long, in seconds, so that clients can see
+// how they went
+pub(crate) const FORGET_AFTER: i64 = 60 * 60 * 24 * 7;
+
+// wakes up a worker when something is queued
+static QUEUED: Lazy<Notify> = Lazy::new(Notify::new);
+
+// an upload that is waiting to be processed
+#[derive(Debug)]
+pub(crate) struct NewJob {
+    pub id: Uuid,
+    pub owner: Uuid,
+    pub dir: String,
+    pub orig_fname: String,
+    pub format: StorageFormat,
+    pub duplicates: DuplicatePolicy,
+    // where the upload was dropped into the owner's inbox, if it came from there
+    pub inbox: Option<String>,
+}
+
+// put an upload into the queue. the file must already be at DATA_DIR/owner/dir/id.
+pub(crate) async fn enqueue(conn: &mut SqliteConnection, job: NewJob) -> Result<(), MioInnerError> {
+    let now = Utc::now().timestamp();
+    let codec = job.format.codec.as_str();
+    let duplicates = job.duplicates.as_str();
+
+    // the upload counts against the owner's quota until it's processed
+    let size = tokio::fs::metadata(
+        crate::DATA_DIR
+            .get()
+            .unwrap()
+            .join(format!("{}", job.owner))
+            .join(&job.dir)
+            .join(format!("{}", job.id)),
+    )
+    .await?
+    .len() as i64;
+    sqlx::query!(
+        "INSERT INTO upload_job
+        (id, owner, state, dir, orig_fname, codec, bitrate, container, duplicates, inbox,
+        size, created, updated)
+        VALUES (?, ?, 'queued', ?, ?, ?, ?, ?, ?, ?, ?, ?, ?);",
+        job.id,
+        job.owner,
+        job.dir,
+        job.orig_fname,
+        codec,
+        job.format.bitrate,
+        job.format.container,
+        duplicates,
+        job.inbox,
+        size,
+        now,
+        now
+    )
+    .execute(&mut *conn)
+    .await?;
+    trace!("JOBS queued {}", job.id);
+    QUEUED.notify_one();
+    Ok(())
+}
+
+pub(crate) async fn set_state(
+    db: &SqlitePool,
+    id: Uuid,
+    state: JobState,
+    error: Option<String>,
+) -> Result<(), MioInnerError> {
+    let now = Utc::now().timestamp();
+    let state_str = state.as_str();
+    sqlx::query!(
+        "UPDATE upload_job SET state = ?, error = ?, updated = ? WHERE id = ?;",
+        state_str,
+        error,
+        now,
+        id
+    )
+    .execute(db)
+    .await?;
+    trace!("JOBS {id} is now {state:?}");
+    Ok(())
+}
+
+// record the track that a job ended up as
+async fn set_done(db: &SqlitePool, id: Uuid, track: Uuid) -> Result<(), MioInnerError> {
+    let now = Utc::now().timestamp();
+    sqlx::query!(
+        "UPDATE upload_job SET state = 'done', track = ?, updated = ? WHERE id = ?;",
+        track,
+        now,
+        id
+    )
+    .execute(db)
+    .await?;
+    trace!("JOBS {id} is done as {track}");
+    Ok(())
+}
+
+// jobs and upload sessions that will turn into tracks, which count against the
+// track quota
+pub(crate) async fn pending(
+    conn: &mut SqliteConnection,
+    owner: Uuid,
+) -> Result<i64, MioInnerError> {
+    Ok(sqlx::query!(
+        "SELECT
+        (SELECT COUNT(*) FROM upload_job WHERE owner = ? AND state NOT IN ('done', 'failed'))
+        + (SELECT COUNT(*) FROM upload_session WHERE owner = ?) AS \"pending!: i64\";",
+        owner,
+        owner
+    )
+    .fetch_one(&mut *conn)
+    .await?
+    .pending)
+}
+
+// start the upload workers. like the scheduled jobs, these run until true is sent on
+// the shutdown channel.
+pub(crate) async fn start_workers(
+    state: MioState,
+    shutdown: watch::Receiver<bool>,
+) -> Result<JoinSet<()>, MioInnerError> {
+    // anything that was running when the server stopped gets another go
+    let requeued = sqlx::query!(
+        "UPDATE upload_job SET state = 'queued' WHERE state IN ('decoding', 'embedding');"
+    )
+    .execute(&state.db)
+    .await?
+    .rows_affected();
+    if requeued > 0 {
+        info!("JOBS requeued {requeued} interrupted jobs");
+    }
+    let mut set = JoinSet::new();
+    for _ in 0..*crate::UPLOAD_WORKERS.get().unwrap() {
+        set.spawn(worker(state.clone(), shutdown.clone()));
+    }
+    Ok(set)
+}
+
+async fn worker(state: MioState, mut shutdown: watch::Receiver<bool>) {
+    loop {
+        let next = match claim_next(&state.db).await {
+            Ok(next) => next,
+            Err(err) => {
+                error!("JOBS failed to get next job: {err}");
+                tokio::time::sleep(Duration::from_secs(1)).await;
+                continue;
+            }
+        };
+        tokio::select! {
+            // an interrupted job is picked back up on the next start
+            _ = async {
+                match next {
+                    Some(id) => run(&state, id).await,
+                    None => QUEUED.notified().await,
+                }
+            } => (),
+            ret = shutdown.changed() => {
+                if ret.is_err() || *shutdown.borrow() {
+                    debug!("JOBS stopping worker");
+                    return;
+                }
+            }
+        }
+    }
+}
+
+// mark the oldest queued job as started
+async fn claim_next(db: &SqlitePool) -> Result<Option<Uuid>, MioInnerError> {
+    let now = Utc::now().timestamp();
+    sqlx::query!(
+        "UPDATE upload_job SET state = 'decoding', updated = ?
+        WHERE id = (SELECT id FROM upload_job WHERE state = 'queued' ORDER BY created LIMIT 1)
+        RETURNING id;",
+        now
+    )
+    .fetch_optional(db)
+    .await?
+    .map(|x| uuid_serialize(&x.id))
+    .transpose()
+}
+
+// run a specific job if it is still queued, without waiting on a worker
+#[cfg(test)]
+pub(crate) async fn process(state: &MioState, id: Uuid) -> Result<bool, MioInnerError> {
+    let now = Utc::now().timestamp();
+    let claimed = sqlx::query!(
+        "UPDATE upload_job SET state = 'decoding', updated = ?
+        WHERE id = ? AND state = 'queued';",
+        now,
+        id
+    )
+    .execute(&state.db)
+    .await?
+    .rows_affected()
+        > 0;
+    if claimed {
+        run(state, id).await;
+    }
+    Ok(claimed)
+}
+
+// process a claimed job, recording how it went
+async fn run(state: &MioState, id: Uuid) {
+    let ret = match load(&state.db, id).await {
+        Ok(job) => {
+            let path = crate::DATA_DIR
+                .get()
+                .unwrap()
+                .join(format!("{}", job.owner))
+                .join(&job.dir)
+                .join(format!("{id}"));
+            debug!("JOBS processing {id}: \"{}\"", job.orig_fname);
+            let (owner, inbox) = (job.owner, job.inbox.clone());
+            let ret = super::track_upload::track_upload_process(state.clone(), path.clone(), job)
+                .await
+                .map_err(|err| {
+                    info!("JOBS {id} failed: {err}");
+                    err.msg()
+                });
+
+            // the upload is of no use if it couldn't be processed, unless it came from an
+            // inbox, where it's set aside for the owner to look at
+            if let Err(msg) = &ret {
+                let _hold = state.lock_files.read().await;
+                let mut remove = vec![super::track_upload::original_path(&path)];
+                match inbox {
+                    Some(inbox) if path.exists() => {
+                        if let Err(err) =
+                            super::inbox::quarantine(owner, &path, inbox.as_ref(), msg).await
+                        {
+                            warn!("JOBS failed to quarantine {path:?} for {id}: {err}");
+                        }
+                    }
+                    _ => remove.push(path),
+                }
+                for path in remove {
+                    if let Err(err) = tokio::fs::remove_file(&path).await {
+                        if err.kind() != std::io::ErrorKind::NotFound {
+                            warn!("JOBS failed to remove {path:?} for {id}: {err}");
+                        }
+                    }
+                }
+            }
+            ret
+        }
+        Err(err) => {
+            info!("JOBS {id} failed: {err}");
+            Err(err.msg())
+        }
+    };
+    let ret = match ret {
+        Ok(track) => set_done(&state.db, id, track).await,
+        Err(msg) => set_state(&state.db, id, JobState::Failed, Some(msg)).await,
+    };
+    if let Err(err) = ret {
+        error!("JOBS failed to record how {id} went: {err}");
+    }
+}
+
+async fn load(db: &SqlitePool, id: Uuid) -> Result<NewJob, MioInnerError> {
+    let job = sqlx::query!(
+        "SELECT owner, dir, orig_fname, codec, bitrate, container, duplicates, inbox
+        FROM upload_job WHERE id = ?;",
+        id
+    )
+    .fetch_optional(db)
+    .await?
+    .ok_or_else(|| MioInnerError::NotFound(anyhow!("job {id} does not exist")))?;
+    let format = StorageFormat::from_row(&job.codec, job.bitrate, job.container)?;
+    Ok(NewJob {
+        id,
+        owner: uuid_serialize(&job.owner)?,
+        dir: job.dir,
+        orig_fname: job.orig_fname,
+        format,
+        duplicates: job.duplicates.parse().map_err(|err| {
+            MioInnerError::DbError(anyhow!("could not parse duplicate policy {err}"))
+        })?,
+        inbox: job.inbox,
+    })
+}
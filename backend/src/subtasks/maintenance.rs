@@ -0,0 +1,102 @@
+use crate::db::write_transaction;
+use crate::{MioInnerError, MioState};
+use chrono::Utc;
+#[allow(unused)]
+use log::*;
+
+// periodic cleanup of the database
+pub async fn run(state: MioState) -> Result<(), MioInnerError> {
+    let mut conn = state.db.acquire().await?;
+    write_transaction(&mut conn, |txn| {
+        Box::pin(async move {
+            let now = Utc::now().timestamp();
+
+            // tokens that can no longer be used
+            let keys = sqlx::query!("DELETE FROM auth_keys WHERE expiry <= ?;", now)
+                .execute(&mut *txn)
+                .await?
+                .rows_affected();
+
+            // failed logins that are not locked out and have been forgotten about
+            let forget = now - crate::ratelimit::FORGET_AFTER;
+            let attempts = sqlx::query!(
+                "DELETE FROM login_attempt WHERE last_failure < ? AND locked_until <= ?;",
+                forget,
+                now
+            )
+            .execute(&mut *txn)
+            .await?
+            .rows_affected();
+
+            // finished uploads that have been around for a while
+            let forget = now - crate::subtasks::jobs::FORGET_AFTER;
+            let jobs = sqlx::query!(
+                "DELETE FROM upload_job WHERE state IN ('done', 'failed') AND updated < ?;",
+                forget
+            )
+            .execute(&mut *txn)
+            .await?
+            .rows_affected();
+
+            // artists and albums whose tracks were all deleted along with their owner
+            let artists = sqlx::query!(
+                "DELETE FROM artist
+                WHERE NOT EXISTS (SELECT 1 FROM track
+                    WHERE track.artist = artist.id OR track.album_artist = artist.id)
+                AND NOT EXISTS (SELECT 1 FROM JOIN_track_artist
+                    WHERE JOIN_track_artist.artist = artist.id);"
+            )
+            .execute(&mut *txn)
+            .await?
+            .rows_affected();
+            let albums = sqlx::query!(
+                "DELETE FROM album WHERE NOT EXISTS
+                (SELECT 1 FROM track WHERE track.album = album.id);"
+            )
+            .execute(&mut *txn)
+            .await?
+            .rows_affected();
+            debug!(
+                "MAINTENANCE purged {keys} expired auth keys, {attempts} login attempts, {jobs} finished jobs, {artists} artists, and {albums} albums"
+            );
+            Ok(())
+        })
+    })
+    .await
+}
+
+#[cfg(test)]
+mod test {
+    use crate::test::*;
+    use chrono::Utc;
+
+    #[tokio::test]
+    async fn maintenance_purge_expired_keys() {
+        let cli = client().await;
+        let jwt = gen_user(&cli, "maintenance_purge_expired_keys").await;
+        let userid = jwt.whois().unwrap().userid;
+        let session = uuid::Uuid::new_v4();
+        let now = Utc::now().timestamp();
+        sqlx::query!(
+            "INSERT INTO auth_keys (session, id, expiry, secret, created, last_used) 
+            VALUES (?, ?, ?, x'00', ?, ?);",
+            session,
+            userid,
+            now,
+            now,
+            now
+        )
+        .execute(&STATE.db)
+        .await
+        .unwrap();
+        super::run(STATE.clone()).await.unwrap();
+
+        // only the expired key is gone
+        let left = sqlx::query!("SELECT session FROM auth_keys WHERE id = ?;", userid)
+            .fetch_all(&STATE.db)
+            .await
+            .unwrap();
+        assert_eq!(left.len(), 1);
+        assert_ne!(left[0].session, session.as_bytes());
+    }
+}
@@ -1,4 +1,112 @@
+use crate::{MioInnerError, MioState};
+use futures::future::BoxFuture;
+#[allow(unused)]
+use log::*;
+use std::time::Duration;
+use tokio::sync::watch;
+use tokio::task::JoinSet;
+
+pub mod import;
+pub mod inbox;
+pub mod jobs;
+pub mod maintenance;
+pub mod qoa;
+pub mod tagging;
 pub mod track_upload;
-// TODO: automatic db cleanup and maintenance
-//
+pub mod transcode;
 // TODO: automatic backup
+
+// a job that is run every interval in the background
+pub(crate) struct Scheduled {
+    pub name: &'static str,
+    pub interval: Duration,
+    pub job: fn(MioState) -> BoxFuture<'static, Result<(), MioInnerError>>,
+}
+
+// every job that the server runs in the background
+fn scheduled_jobs() -> Vec<Scheduled> {
+    let interval = Duration::from_secs(
+        (*crate::MAINTENANCE_INTERVAL.get().unwrap())
+            .try_into()
+            .unwrap(),
+    );
+    vec![
+        Scheduled {
+            name: "maintenance",
+            interval,
+            job: |state| Box::pin(maintenance::run(state)),
+        },
+        Scheduled {
+            name: "upload sessions",
+            interval,
+            job: |state| Box::pin(crate::endpoints::upload::expire(state)),
+        },
+    ]
+}
+
+// start all of the scheduled jobs. they run until true is sent on the shutdown
+// channel, and the set should be joined before closing the db.
+pub(crate) fn start_scheduled(state: MioState, shutdown: watch::Receiver<bool>) -> JoinSet<()> {
+    let mut set = JoinSet::new();
+    for job in scheduled_jobs() {
+        set.spawn(run_scheduled(job, state.clone(), shutdown.clone()));
+    }
+    set
+}
+
+async fn run_scheduled(job: Scheduled, state: MioState, mut shutdown: watch::Receiver<bool>) {
+    let mut interval = tokio::time::interval(job.interval);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {
+                trace!("SCHEDULED running {}", job.name);
+                if let Err(err) = (job.job)(state.clone()).await {
+                    error!("SCHEDULED {} failed: {err}", job.name);
+                }
+            }
+            // a closed channel also means the server is going down
+            ret = shutdown.changed() => {
+                if ret.is_err() || *shutdown.borrow() {
+                    debug!("SCHEDULED stopping {}", job.name);
+                    return;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::STATE;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    static RUNS: AtomicUsize = AtomicUsize::new(0);
+
+    #[tokio::test]
+    async fn scheduled_runs_and_stops() {
+        let (tx, rx) = watch::channel(false);
+        let handle = tokio::spawn(run_scheduled(
+            Scheduled {
+                name: "test",
+                interval: Duration::from_millis(10),
+                job: |_| {
+                    Box::pin(async {
+                        RUNS.fetch_add(1, Ordering::Relaxed);
+                        Ok(())
+                    })
+                },
+            },
+            STATE.clone(),
+            rx,
+        ));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        tx.send(true).unwrap();
+        tokio::time::timeout(Duration::from_secs(5), handle)
+            .await
+            .expect("scheduled job did not stop")
+            .unwrap();
+        assert!(RUNS.load(Ordering::Relaxed) > 1);
+    }
+}
@@ -0,0 +1,368 @@
+// Quite OK Audio, see https://qoaformat.org/qoa-specification.pdf
+//
+// this follows the reference encoder, including the weights penalty, so that files
+// play back the same in every decoder.
+use anyhow::{anyhow, bail};
+
+pub const CONTENT_TYPE: &str = "audio/x-qoa";
+pub const MAX_CHANNELS: u32 = 8;
+pub const MAX_SAMPLERATE: u32 = 0xffffff;
+
+const MAGIC: u32 = 0x716f6166; // 'qoaf'
+const SLICE_LEN: usize = 20;
+const SLICES_PER_FRAME: usize = 256;
+const FRAME_LEN: usize = SLICE_LEN * SLICES_PER_FRAME;
+const LMS_LEN: usize = 4;
+
+const RECIPROCAL_TAB: [i32; 16] = [
+    65536, 9363, 3121, 1457, 781, 475, 311, 216, 156, 117, 90, 71, 57, 47, 39, 32,
+];
+const QUANT_TAB: [u8; 17] = [
+    7, 7, 7, 5, 5, 3, 3, 1, // -8..-1
+    0, // 0
+    0, 2, 2, 4, 4, 6, 6, 6, // 1..8
+];
+const SCALEFACTOR_TAB: [i32; 16] = [
+    1, 7, 21, 45, 84, 138, 211, 304, 421, 562, 731, 928, 1157, 1419, 1715, 2048,
+];
+const DEQUANT_TAB: [[i32; 8]; 16] = dequant_tab();
+
+// round(sf * {0.75, 2.5, 4.5, 7}), rounding halves away from zero
+const fn dequant_tab() -> [[i32; 8]; 16] {
+    const QUARTERS: [i32; 4] = [3, 10, 18, 28];
+    let mut tab = [[0; 8]; 16];
+    let mut sf = 0;
+    while sf < 16 {
+        let mut q = 0;
+        while q < 4 {
+            let val = (SCALEFACTOR_TAB[sf] * QUARTERS[q] + 2) / 4;
+            tab[sf][q * 2] = val;
+            tab[sf][q * 2 + 1] = -val;
+            q += 1;
+        }
+        sf += 1;
+    }
+    tab
+}
+
+#[derive(Clone, Copy)]
+struct Lms {
+    history: [i32; LMS_LEN],
+    weights: [i32; LMS_LEN],
+}
+
+impl Lms {
+    fn new() -> Self {
+        Self {
+            history: [0; LMS_LEN],
+            weights: [0, 0, -(1 << 13), 1 << 14],
+        }
+    }
+
+    fn predict(&self) -> i32 {
+        self.weights
+            .iter()
+            .zip(self.history.iter())
+            .fold(0i32, |acc, (w, h)| acc.wrapping_add(w.wrapping_mul(*h)))
+            >> 13
+    }
+
+    fn update(&mut self, sample: i32, residual: i32) {
+        let delta = residual >> 4;
+        for (w, h) in self.weights.iter_mut().zip(self.history.iter()) {
+            *w = w.wrapping_add(if *h < 0 { -delta } else { delta });
+        }
+        self.history.rotate_left(1);
+        self.history[LMS_LEN - 1] = sample;
+    }
+
+    // both are packed as 4 big endian i16's
+    fn pack(vals: &[i32; LMS_LEN]) -> u64 {
+        vals.iter()
+            .fold(0, |acc, x| (acc << 16) | (*x as u16) as u64)
+    }
+
+    fn unpack(mut packed: u64) -> [i32; LMS_LEN] {
+        let mut ret = [0; LMS_LEN];
+        for x in ret.iter_mut() {
+            *x = (packed >> 48) as i16 as i32;
+            packed <<= 16;
+        }
+        ret
+    }
+}
+
+// divide, rounding away from zero
+fn div(v: i32, scalefactor: usize) -> i32 {
+    // this can overflow for the first scalefactor, which the reference lets wrap
+    let n = v
+        .wrapping_mul(RECIPROCAL_TAB[scalefactor])
+        .wrapping_add(1 << 15)
+        >> 16;
+    n + (v.signum() - n.signum())
+}
+
+fn clamp_s16(v: i32) -> i32 {
+    v.clamp(i16::MIN as i32, i16::MAX as i32)
+}
+
+fn frame_size(channels: usize, slices: usize) -> usize {
+    8 + LMS_LEN * 4 * channels + 8 * slices * channels
+}
+
+// encode interleaved samples into a complete qoa file
+pub fn encode(samples: &[i16], channels: u32, samplerate: u32) -> anyhow::Result<Vec<u8>> {
+    if channels == 0 || channels > MAX_CHANNELS {
+        bail!("qoa supports 1 to {MAX_CHANNELS} channels, got {channels}");
+    }
+    if samplerate == 0 || samplerate > MAX_SAMPLERATE {
+        bail!("qoa does not support a sample rate of {samplerate}");
+    }
+    let channels = channels as usize;
+    if !samples.len().is_multiple_of(channels) {
+        bail!("samples are not evenly split between {channels} channels");
+    }
+    let per_channel = samples.len() / channels;
+    let per_channel_u32 =
+        u32::try_from(per_channel).map_err(|_| anyhow!("too many samples to fit in a qoa file"))?;
+
+    let frames = per_channel.div_ceil(FRAME_LEN);
+    let mut out = Vec::with_capacity(8 + frames * frame_size(channels, SLICES_PER_FRAME));
+    out.extend_from_slice(&(((MAGIC as u64) << 32) | per_channel_u32 as u64).to_be_bytes());
+    let mut lms = vec![Lms::new(); channels];
+    for frame in samples.chunks(FRAME_LEN * channels) {
+        encode_frame(&mut out, frame, channels, samplerate, &mut lms);
+    }
+    Ok(out)
+}
+
+fn encode_frame(
+    out: &mut Vec<u8>,
+    frame: &[i16],
+    channels: usize,
+    samplerate: u32,
+    lms: &mut [Lms],
+) {
+    let frame_len = frame.len() / channels;
+    let slices = frame_len.div_ceil(SLICE_LEN);
+    out.extend_from_slice(
+        &((channels as u64) << 56
+            | (samplerate as u64) << 32
+            | (frame_len as u64) << 16
+            | frame_size(channels, slices) as u64)
+            .to_be_bytes(),
+    );
+    for state in lms.iter() {
+        out.extend_from_slice(&Lms::pack(&state.history).to_be_bytes());
+        out.extend_from_slice(&Lms::pack(&state.weights).to_be_bytes());
+    }
+
+    // slices are interleaved by channel
+    let mut prev_scalefactor = vec![0; channels];
+    for start in (0..frame_len).step_by(SLICE_LEN) {
+        let slice_len = SLICE_LEN.min(frame_len - start);
+        for c in 0..channels {
+            let slice_samples = frame[start * channels..(start + slice_len) * channels]
+                .iter()
+                .skip(c)
+                .step_by(channels);
+
+            // try every scalefactor and keep the one with the least error
+            let mut best_rank = u64::MAX;
+            let mut best = (0, lms[c], 0);
+            for sfi in 0..16 {
+                let scalefactor = (sfi + prev_scalefactor[c]) % 16;
+                let mut curr_lms = lms[c];
+                let mut slice = scalefactor as u64;
+                let mut rank = 0u64;
+                for sample in slice_samples.clone() {
+                    let sample = *sample as i32;
+                    let predicted = curr_lms.predict();
+                    let residual = sample - predicted;
+                    let scaled = div(residual, scalefactor).clamp(-8, 8);
+                    let quantized = QUANT_TAB[(scaled + 8) as usize];
+                    let dequantized = DEQUANT_TAB[scalefactor][quantized as usize];
+                    let reconstructed = clamp_s16(predicted + dequantized);
+
+                    // keep the weights from growing too large
+                    let weights_penalty = ((curr_lms
+                        .weights
+                        .iter()
+                        .map(|x| *x as i64 * *x as i64)
+                        .sum::<i64>()
+                        >> 18)
+                        - 0x8ff)
+                        .max(0) as u64;
+                    let error = (sample - reconstructed) as i64;
+                    rank = rank
+                        .saturating_add((error * error) as u64 + weights_penalty * weights_penalty);
+                    if rank > best_rank {
+                        break;
+                    }
+                    curr_lms.update(reconstructed, dequantized);
+                    slice = (slice << 3) | quantized as u64;
+                }
+                if rank < best_rank {
+                    best_rank = rank;
+                    best = (slice, curr_lms, scalefactor);
+                }
+            }
+            let (slice, best_lms, scalefactor) = best;
+            prev_scalefactor[c] = scalefactor;
+            lms[c] = best_lms;
+
+            // short slices are left aligned
+            out.extend_from_slice(&(slice << ((SLICE_LEN - slice_len) * 3)).to_be_bytes());
+        }
+    }
+}
+
+// decode a qoa file into (channels, samplerate, interleaved samples)
+pub fn decode(data: &[u8]) -> anyhow::Result<(u32, u32, Vec<i16>)> {
+    let mut reader = Reader { data, pos: 0 };
+    let header = reader.u64()?;
+    if (header >> 32) as u32 != MAGIC {
+        bail!("not a qoa file");
+    }
+    let per_channel = (header & 0xffffffff) as usize;
+    let mut desc = None;
+    let mut lms = vec![];
+    let mut out = vec![];
+    while !reader.done() {
+        let frame_header = reader.u64()?;
+        let channels = (frame_header >> 56) as usize;
+        let samplerate = ((frame_header >> 32) & 0xffffff) as u32;
+        let frame_len = ((frame_header >> 16) & 0xffff) as usize;
+        let size = (frame_header & 0xffff) as usize;
+        if channels == 0 || frame_len > FRAME_LEN {
+            bail!("invalid qoa frame");
+        }
+        if size != frame_size(channels, frame_len.div_ceil(SLICE_LEN)) {
+            bail!("qoa frame size does not match its sample count");
+        }
+        match desc {
+            None => {
+                desc = Some((channels, samplerate));
+                out.reserve(per_channel * channels);
+            }
+            Some(x) if x != (channels, samplerate) => {
+                bail!("channels or sample rate changed between qoa frames")
+            }
+            Some(_) => (),
+        }
+        lms.clear();
+        for _ in 0..channels {
+            lms.push(Lms {
+                history: Lms::unpack(reader.u64()?),
+                weights: Lms::unpack(reader.u64()?),
+            });
+        }
+        let base = out.len();
+        out.resize(base + frame_len * channels, 0);
+        for start in (0..frame_len).step_by(SLICE_LEN) {
+            for (c, state) in lms.iter_mut().enumerate() {
+                let mut slice = reader.u64()?;
+                let scalefactor = (slice >> 60) as usize;
+                slice <<= 4;
+                for si in start..(start + SLICE_LEN).min(frame_len) {
+                    let predicted = state.predict();
+                    let quantized = (slice >> 61) as usize;
+                    let dequantized = DEQUANT_TAB[scalefactor][quantized];
+                    let reconstructed = clamp_s16(predicted + dequantized);
+                    out[base + si * channels + c] = reconstructed as i16;
+                    slice <<= 3;
+                    state.update(reconstructed, dequantized);
+                }
+            }
+        }
+    }
+    let (channels, samplerate) = desc.unwrap_or((1, 0));
+    Ok((channels as u32, samplerate, out))
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl Reader<'_> {
+    fn u64(&mut self) -> anyhow::Result<u64> {
+        let bytes = self
+            .data
+            .get(self.pos..self.pos + 8)
+            .ok_or_else(|| anyhow!("qoa file ended early"))?;
+        self.pos += 8;
+        Ok(u64::from_be_bytes(bytes.try_into().unwrap()))
+    }
+
+    fn done(&self) -> bool {
+        self.pos >= self.data.len()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn sine(channels: usize, len: usize) -> Vec<i16> {
+        (0..len)
+            .flat_map(|x| {
+                (0..channels)
+                    .map(move |c| ((x as f64 * (0.01 + c as f64 * 0.003)).sin() * 12000.0) as i16)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn qoa_dequant_tab() {
+        // spot check against the tables in the reference implementation
+        assert_eq!(DEQUANT_TAB[0], [1, -1, 3, -3, 5, -5, 7, -7]);
+        assert_eq!(DEQUANT_TAB[1], [5, -5, 18, -18, 32, -32, 49, -49]);
+        assert_eq!(
+            DEQUANT_TAB[15],
+            [1536, -1536, 5120, -5120, 9216, -9216, 14336, -14336]
+        );
+    }
+
+    #[test]
+    fn qoa_roundtrip() {
+        // long enough to have a partial frame and a partial slice at the end
+        let len = FRAME_LEN * 2 + 37;
+        let orig = sine(2, len);
+        let encoded = encode(&orig, 2, 44100).unwrap();
+        assert_eq!(&encoded[0..4], b"qoaf");
+        assert_eq!(
+            encoded.len(),
+            8 + frame_size(2, SLICES_PER_FRAME) * 2 + frame_size(2, 2)
+        );
+        let (channels, samplerate, decoded) = decode(&encoded).unwrap();
+        assert_eq!((channels, samplerate), (2, 44100));
+        assert_eq!(decoded.len(), orig.len());
+
+        // lossy, but close
+        let max_err = orig
+            .iter()
+            .zip(decoded.iter())
+            .map(|(a, b)| (*a as i32 - *b as i32).abs())
+            .max()
+            .unwrap();
+        assert!(max_err < 600, "error of {max_err} is too high");
+    }
+
+    #[test]
+    fn qoa_empty() {
+        let encoded = encode(&[], 1, 8000).unwrap();
+        assert_eq!(encoded.len(), 8);
+        assert!(decode(&encoded).unwrap().2.is_empty());
+    }
+
+    #[test]
+    fn qoa_bad_input() {
+        assert!(encode(&[0; 9], 2, 44100).is_err());
+        assert!(encode(&[0; 8], 9, 44100).is_err());
+        assert!(decode(b"fLaC\0\0\0\0").is_err());
+        let mut truncated = encode(&sine(1, 100), 1, 44100).unwrap();
+        truncated.truncate(truncated.len() - 3);
+        assert!(decode(&truncated).is_err());
+    }
+}
@@ -0,0 +1,985 @@
+use crate::MioInnerError;
+use anyhow::{anyhow, bail};
+use base64::prelude::*;
+#[allow(unused)]
+use log::*;
+use mio_protocol::ArtistRole;
+use sqlx::SqliteConnection;
+use std::collections::BTreeMap;
+use std::io::Cursor;
+use uuid::Uuid;
+
+// tags that other formats have their own names for. the first is what gstreamer calls
+// it, which is what the extra tags are stored under.
+const KNOWN: &[(&str, &str, &str, &[u8; 4])] = &[
+    // gstreamer, vorbis, id3, mp4
+    ("genre", "GENRE", "TCON", b"\xa9gen"),
+    ("datetime", "DATE", "TDRC", b"\xa9day"),
+    ("copyright", "COPYRIGHT", "TCOP", b"cprt"),
+];
+
+// what the library has for a track, to be written into a copy of it
+#[derive(Debug, Default, Clone)]
+pub(crate) struct Tags {
+    pub title: String,
+    // everyone credited, in order
+    pub artists: Vec<(ArtistRole, String)>,
+    // of the first primary artist
+    pub artist_sort: Option<String>,
+    pub album: Option<String>,
+    pub album_sort: Option<String>,
+    pub album_artist: Option<String>,
+    pub disk: Option<u32>,
+    pub track: Option<u32>,
+    pub other: BTreeMap<String, String>,
+    // webp, as it is stored
+    pub cover: Option<Vec<u8>>,
+}
+
+impl Tags {
+    pub(crate) async fn load(
+        conn: &mut SqliteConnection,
+        id: Uuid,
+        owner: Uuid,
+    ) -> Result<Self, MioInnerError> {
+        let x = sqlx::query!(
+            "SELECT track.title, track.disk, track.track, track.tags,
+            artist.sort_name AS artist_sort,
+            album.title AS \"album?\", album.sort_title AS album_sort,
+            album_artist.artist_name AS \"album_artist?\",
+            cover_art.webm_blob AS \"cover?\"
+            FROM track
+            LEFT JOIN artist ON artist.id = track.artist
+            LEFT JOIN album ON album.id = track.album
+            LEFT JOIN artist AS album_artist ON album_artist.id = track.album_artist
+            LEFT JOIN cover_art ON cover_art.id = track.cover_art
+            WHERE track.id = ? AND track.owner = ?;",
+            id,
+            owner
+        )
+        .fetch_optional(&mut *conn)
+        .await?
+        .ok_or_else(|| MioInnerError::NotFound(anyhow!("{id} under {owner} does not exist")))?;
+        let other = serde_json::from_str::<BTreeMap<String, Option<String>>>(&x.tags)
+            .map_err(|err| MioInnerError::DbError(anyhow!("could not parse tags of {id}: {err}")))?
+            .into_iter()
+            .filter_map(|(k, v)| Some((k, v?)))
+            .collect();
+        let artists = sqlx::query!(
+            "SELECT artist_name, role FROM JOIN_track_artist
+            JOIN artist ON artist.id = JOIN_track_artist.artist
+            WHERE track = ? ORDER BY position;",
+            id
+        )
+        .fetch_all(&mut *conn)
+        .await?
+        .into_iter()
+        .map(|x| {
+            Ok((
+                x.role
+                    .parse()
+                    .map_err(|err| MioInnerError::DbError(anyhow!("could not parse role {err}")))?,
+                x.artist_name,
+            ))
+        })
+        .collect::<Result<_, MioInnerError>>()?;
+        Ok(Self {
+            title: x.title,
+            artists,
+            artist_sort: x.artist_sort,
+            album: x.album,
+            album_sort: x.album_sort,
+            album_artist: x.album_artist,
+            disk: x.disk.and_then(|x| x.try_into().ok()),
+            track: x.track.and_then(|x| x.try_into().ok()),
+            other,
+            cover: x.cover,
+        })
+    }
+}
+
+// write what the library has for a track into a copy of it. a file without tags is
+// still worth having, so it's given back as it was if they can't be written.
+pub(crate) async fn tag_track(
+    conn: &mut SqliteConnection,
+    id: Uuid,
+    owner: Uuid,
+    data: Vec<u8>,
+    container: String,
+) -> Result<Vec<u8>, MioInnerError> {
+    let tags = Tags::load(conn, id, owner).await?;
+    Ok(
+        tokio::task::spawn_blocking(move || match write(&data, &container, &tags) {
+            Ok(tagged) => tagged,
+            Err(err) => {
+                warn!("TAGGING could not tag {id} as {container}: {err}");
+                data
+            }
+        })
+        .await?,
+    )
+}
+
+// cover art, converted to something that every player understands
+struct Cover {
+    jpeg: Vec<u8>,
+    width: u32,
+    height: u32,
+}
+
+impl Cover {
+    fn new(webp: &[u8]) -> anyhow::Result<Self> {
+        let img = image::load_from_memory(webp)?.into_rgb8();
+        let mut jpeg = Cursor::new(vec![]);
+        img.write_to(&mut jpeg, image::ImageFormat::Jpeg)?;
+        Ok(Self {
+            jpeg: jpeg.into_inner(),
+            width: img.width(),
+            height: img.height(),
+        })
+    }
+}
+
+// replace the tags in a file with the given ones. containers that can't carry tags are
+// given back as they are.
+pub(crate) fn write(data: &[u8], container: &str, tags: &Tags) -> anyhow::Result<Vec<u8>> {
+    // a broken cover only loses the picture, the rest of the tags are still worth having
+    let cover = || match tags.cover.as_deref().map(Cover::new).transpose() {
+        Ok(cover) => cover,
+        Err(err) => {
+            warn!("TAGGING leaving out a cover that could not be read: {err}");
+            None
+        }
+    };
+    match container {
+        "mp3" => id3(data, tags, cover()),
+        "flac" => flac(data, tags, cover()),
+        "ogg" | "oga" | "opus" => ogg(data, tags, cover()),
+        "m4a" | "mp4" => mp4(data, tags, cover()),
+        _ => Ok(data.to_vec()),
+    }
+}
+
+impl Tags {
+    // the names of everyone credited with one of the roles
+    fn credited(&self, roles: &[ArtistRole]) -> Vec<&str> {
+        self.artists
+            .iter()
+            .filter(|x| roles.contains(&x.0))
+            .map(|x| x.1.as_str())
+            .collect()
+    }
+}
+
+// featured artists are written in with the main ones, since there's nowhere else for them
+const PERFORMERS: &[ArtistRole] = &[ArtistRole::Primary, ArtistRole::Featured];
+
+// how long the id3v2 tag at the start of some data is
+fn id3_len(data: &[u8]) -> usize {
+    match data {
+        [b'I', b'D', b'3', _, _, flags, size @ ..] if size.len() >= 4 => {
+            // the size is 7 bits per byte, and doesn't count the header or footer
+            let size = size[..4]
+                .iter()
+                .fold(0usize, |acc, x| (acc << 7) | (*x as usize & 0x7f));
+            let footer = if flags & 0x10 != 0 { 10 } else { 0 };
+            (10 + size + footer).min(data.len())
+        }
+        _ => 0,
+    }
+}
+
+fn id3(data: &[u8], tags: &Tags, cover: Option<Cover>) -> anyhow::Result<Vec<u8>> {
+    use id3::frame::{ExtendedText, Picture, PictureType};
+    use id3::TagLike;
+
+    let mut tag = id3::Tag::new();
+    tag.set_title(&tags.title);
+    // id3v2.4 can have more than one value in a frame
+    for (frame, roles) in [
+        ("TPE1", PERFORMERS),
+        ("TPE4", &[ArtistRole::Remixer]),
+        ("TCOM", &[ArtistRole::Composer]),
+    ] {
+        let names = tags.credited(roles);
+        if !names.is_empty() {
+            tag.set_text_values(frame, names);
+        }
+    }
+    if let Some(artist) = &tags.album_artist {
+        tag.set_album_artist(artist);
+    }
+    if let Some(sort) = &tags.artist_sort {
+        tag.set_text("TSOP", sort);
+    }
+    if let Some(album) = &tags.album {
+        tag.set_album(album);
+    }
+    if let Some(sort) = &tags.album_sort {
+        tag.set_text("TSOA", sort);
+    }
+    if let Some(disk) = tags.disk {
+        tag.set_disc(disk);
+    }
+    if let Some(track) = tags.track {
+        tag.set_track(track);
+    }
+    for (key, value) in &tags.other {
+        match KNOWN.iter().find(|x| x.0 == key.as_str()) {
+            Some((_, _, frame, _)) => tag.set_text(*frame, value),
+            None => {
+                tag.add_frame(ExtendedText {
+                    description: key.clone(),
+                    value: value.clone(),
+                });
+            }
+        }
+    }
+    if let Some(cover) = cover {
+        tag.add_frame(Picture {
+            mime_type: "image/jpeg".to_owned(),
+            picture_type: PictureType::CoverFront,
+            description: String::new(),
+            data: cover.jpeg,
+        });
+    }
+
+    // the old tag is dropped, rather than merged with
+    let mut ret = vec![];
+    tag.write_to(&mut ret, id3::Version::Id3v24)?;
+    ret.extend_from_slice(&data[id3_len(data)..]);
+    Ok(ret)
+}
+
+// the tags as vorbis comments, which is what flac and ogg use
+fn comments(tags: &Tags) -> Vec<String> {
+    let mut ret = vec![format!("TITLE={}", tags.title)];
+    // a field can be in there more than once
+    for (key, roles) in [
+        ("ARTIST", PERFORMERS),
+        ("REMIXER", &[ArtistRole::Remixer]),
+        ("COMPOSER", &[ArtistRole::Composer]),
+    ] {
+        for name in tags.credited(roles) {
+            ret.push(format!("{key}={name}"));
+        }
+    }
+    let named = [
+        ("ARTISTSORT", &tags.artist_sort),
+        ("ALBUMARTIST", &tags.album_artist),
+        ("ALBUM", &tags.album),
+        ("ALBUMSORT", &tags.album_sort),
+    ];
+    for (key, value) in named {
+        if let Some(value) = value {
+            ret.push(format!("{key}={value}"));
+        }
+    }
+    for (key, value) in [("DISCNUMBER", tags.disk), ("TRACKNUMBER", tags.track)] {
+        if let Some(value) = value {
+            ret.push(format!("{key}={value}"));
+        }
+    }
+    for (key, value) in &tags.other {
+        let key = match KNOWN.iter().find(|x| x.0 == key.as_str()) {
+            Some((_, vorbis, _, _)) => vorbis.to_string(),
+            None => key.to_ascii_uppercase(),
+        };
+        // field names are printable ascii without '='
+        if !key.is_empty() && key.bytes().all(|x| (0x20..=0x7d).contains(&x) && x != b'=') {
+            ret.push(format!("{key}={value}"));
+        }
+    }
+    ret
+}
+
+// a vorbis comment header, without the framing that vorbis puts around it
+fn comment_header(vendor: &[u8], comments: &[String]) -> anyhow::Result<Vec<u8>> {
+    let len = |x: usize| -> anyhow::Result<[u8; 4]> {
+        Ok(u32::try_from(x)
+            .map_err(|_| anyhow!("comment is too long"))?
+            .to_le_bytes())
+    };
+    let mut ret = vec![];
+    ret.extend(len(vendor.len())?);
+    ret.extend(vendor);
+    ret.extend(len(comments.len())?);
+    for comment in comments {
+        ret.extend(len(comment.len())?);
+        ret.extend(comment.as_bytes());
+    }
+    Ok(ret)
+}
+
+// the vendor string of a vorbis comment header, which is kept
+fn comment_vendor(header: &[u8]) -> anyhow::Result<&[u8]> {
+    let len = header
+        .get(..4)
+        .ok_or_else(|| anyhow!("comment header is cut off"))?;
+    let len = u32::from_le_bytes(len.try_into().unwrap()) as usize;
+    header
+        .get(4..4 + len)
+        .ok_or_else(|| anyhow!("comment header is cut off"))
+}
+
+// a flac picture block, which ogg also uses
+fn picture_block(cover: &Cover) -> Vec<u8> {
+    let mime = b"image/jpeg";
+    let mut ret = vec![];
+    // front cover
+    ret.extend(3u32.to_be_bytes());
+    ret.extend((mime.len() as u32).to_be_bytes());
+    ret.extend(mime);
+    // no description
+    ret.extend(0u32.to_be_bytes());
+    ret.extend(cover.width.to_be_bytes());
+    ret.extend(cover.height.to_be_bytes());
+    // bits per pixel, and no palette
+    ret.extend(24u32.to_be_bytes());
+    ret.extend(0u32.to_be_bytes());
+    ret.extend((cover.jpeg.len() as u32).to_be_bytes());
+    ret.extend(&cover.jpeg);
+    ret
+}
+
+fn flac(data: &[u8], tags: &Tags, cover: Option<Cover>) -> anyhow::Result<Vec<u8>> {
+    // some taggers put id3 in front of flac, which is dropped
+    let mut at = id3_len(data);
+    if data.get(at..at + 4) != Some(b"fLaC") {
+        bail!("not a flac file");
+    }
+    at += 4;
+    let mut blocks = vec![];
+    let mut vendor = b"mio".to_vec();
+    loop {
+        let header = data
+            .get(at..at + 4)
+            .ok_or_else(|| anyhow!("flac metadata is cut off"))?;
+        let last = header[0] & 0x80 != 0;
+        let kind = header[0] & 0x7f;
+        let len = u32::from_be_bytes([0, header[1], header[2], header[3]]) as usize;
+        let body = data
+            .get(at + 4..at + 4 + len)
+            .ok_or_else(|| anyhow!("flac metadata is cut off"))?;
+        match kind {
+            4 => vendor = comment_vendor(body)?.to_vec(),
+            // padding is for tagging in place, which isn't done here
+            1 | 6 => (),
+            _ => blocks.push((kind, body.to_vec())),
+        }
+        at += 4 + len;
+        if last {
+            break;
+        }
+    }
+    blocks.push((4, comment_header(&vendor, &comments(tags))?));
+    if let Some(cover) = cover {
+        blocks.push((6, picture_block(&cover)));
+    }
+
+    let mut ret = b"fLaC".to_vec();
+    for (idx, (kind, body)) in blocks.iter().enumerate() {
+        if body.len() >= 1 << 24 {
+            bail!("flac metadata block is too large");
+        }
+        let last = if idx + 1 == blocks.len() { 0x80 } else { 0 };
+        ret.push(last | kind);
+        ret.extend(&(body.len() as u32).to_be_bytes()[1..]);
+        ret.extend(body);
+    }
+    ret.extend(&data[at..]);
+    Ok(ret)
+}
+
+// ogg pages are checked with a crc32 that isn't the usual one
+const OGG_CRC: [u32; 256] = {
+    let mut table = [0; 256];
+    let mut idx = 0;
+    while idx < 256 {
+        let mut crc = (idx as u32) << 24;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 0x8000_0000 != 0 {
+                (crc << 1) ^ 0x04c1_1db7
+            } else {
+                crc << 1
+            };
+            bit += 1;
+        }
+        table[idx] = crc;
+        idx += 1;
+    }
+    table
+};
+
+fn ogg_crc(data: &[u8]) -> u32 {
+    data.iter().fold(0, |crc, x| {
+        (crc << 8) ^ OGG_CRC[((crc >> 24) as u8 ^ x) as usize]
+    })
+}
+
+struct Page<'a> {
+    serial: u32,
+    segments: &'a [u8],
+    body: &'a [u8],
+    // the whole page
+    raw: &'a [u8],
+}
+
+fn ogg_pages(mut data: &[u8]) -> anyhow::Result<Vec<Page<'_>>> {
+    let mut ret = vec![];
+    while !data.is_empty() {
+        let cut = || anyhow!("ogg page is cut off");
+        if data.get(..4) != Some(b"OggS") {
+            bail!("not an ogg page");
+        }
+        let count = *data.get(26).ok_or_else(cut)? as usize;
+        let segments = data.get(27..27 + count).ok_or_else(cut)?;
+        let len = 27 + count + segments.iter().map(|x| *x as usize).sum::<usize>();
+        let raw = data.get(..len).ok_or_else(cut)?;
+        ret.push(Page {
+            serial: u32::from_le_bytes(raw[14..18].try_into().unwrap()),
+            segments,
+            body: &raw[27 + count..],
+            raw,
+        });
+        data = &data[len..];
+    }
+    Ok(ret)
+}
+
+fn ogg_page(ret: &mut Vec<u8>, flags: u8, granule: u64, serial: u32, seq: u32, segments: &[u8]) {
+    ret.extend(b"OggS\0");
+    ret.push(flags);
+    ret.extend(granule.to_le_bytes());
+    ret.extend(serial.to_le_bytes());
+    ret.extend(seq.to_le_bytes());
+    ret.extend([0; 4]);
+    ret.push(segments.len() as u8);
+    ret.extend(segments);
+}
+
+// put a packet onto as many pages as it needs
+fn ogg_packet(ret: &mut Vec<u8>, serial: u32, seq: &mut u32, packet: &[u8]) {
+    let mut lacing = vec![255; packet.len() / 255];
+    lacing.push((packet.len() % 255) as u8);
+    let mut body = packet;
+    for (idx, segments) in lacing.chunks(255).enumerate() {
+        let start = ret.len();
+        let continued = if idx == 0 { 0 } else { 1 };
+        // pages that don't finish a packet have no position
+        let granule = if *segments.last().unwrap() < 255 {
+            0
+        } else {
+            u64::MAX
+        };
+        ogg_page(ret, continued, granule, serial, *seq, segments);
+        let len = segments.iter().map(|x| *x as usize).sum::<usize>();
+        ret.extend(&body[..len]);
+        body = &body[len..];
+        let crc = ogg_crc(&ret[start..]);
+        ret[start + 22..start + 26].copy_from_slice(&crc.to_le_bytes());
+        *seq += 1;
+    }
+}
+
+fn ogg(data: &[u8], tags: &Tags, cover: Option<Cover>) -> anyhow::Result<Vec<u8>> {
+    let pages = ogg_pages(data)?;
+    let serial = pages
+        .first()
+        .ok_or_else(|| anyhow!("ogg file is empty"))?
+        .serial;
+    if pages.iter().any(|x| x.serial != serial) {
+        bail!("ogg files with more than one stream can't be tagged");
+    }
+
+    // the headers are always on their own pages, so only those have to be redone
+    let mut headers = vec![];
+    let mut packet = vec![];
+    let mut wanted = None;
+    let mut audio = 0;
+    for (idx, page) in pages.iter().enumerate() {
+        let mut body = page.body;
+        for len in page.segments {
+            packet.extend(&body[..*len as usize]);
+            body = &body[*len as usize..];
+            if *len < 255 {
+                headers.push(std::mem::take(&mut packet));
+            }
+        }
+        if idx == 0 && (headers.len() != 1 || !packet.is_empty()) {
+            bail!("the first ogg page should only have the first header");
+        }
+        wanted = wanted.or_else(|| match headers.first() {
+            Some(x) if x.starts_with(b"OpusHead") => Some(2),
+            Some(x) if x.starts_with(b"\x01vorbis") => Some(3),
+            _ => None,
+        });
+        if wanted.is_none() {
+            bail!("only opus and vorbis can be tagged in ogg");
+        }
+        if Some(headers.len()) >= wanted {
+            if Some(headers.len()) > wanted || !packet.is_empty() {
+                bail!("ogg headers share a page with audio");
+            }
+            audio = idx + 1;
+            break;
+        }
+    }
+    let Some(wanted) = wanted.filter(|x| *x == headers.len()) else {
+        bail!("ogg headers are cut off");
+    };
+
+    let mut comments = comments(tags);
+    if let Some(cover) = cover {
+        comments.push(format!(
+            "METADATA_BLOCK_PICTURE={}",
+            BASE64_STANDARD.encode(picture_block(&cover))
+        ));
+    }
+    let comment = if wanted == 2 {
+        let vendor = headers[1]
+            .strip_prefix(b"OpusTags")
+            .ok_or_else(|| anyhow!("opus comment header is missing"))?;
+        [
+            &b"OpusTags"[..],
+            &comment_header(comment_vendor(vendor)?, &comments)?,
+        ]
+        .concat()
+    } else {
+        let vendor = headers[1]
+            .strip_prefix(b"\x03vorbis")
+            .ok_or_else(|| anyhow!("vorbis comment header is missing"))?;
+        // vorbis ends the header with a framing bit
+        let header = comment_header(comment_vendor(vendor)?, &comments)?;
+        [&b"\x03vorbis"[..], &header, &[1]].concat()
+    };
+
+    // the first page only ever has the first header on it, so it stays the same
+    let mut ret = pages[0].raw.to_vec();
+    let mut seq = 1;
+    ogg_packet(&mut ret, serial, &mut seq, &comment);
+    for packet in &headers[2..] {
+        ogg_packet(&mut ret, serial, &mut seq, packet);
+    }
+    for page in &pages[audio..] {
+        let start = ret.len();
+        ret.extend(page.raw);
+        ret[start + 18..start + 22].copy_from_slice(&seq.to_le_bytes());
+        ret[start + 22..start + 26].copy_from_slice(&[0; 4]);
+        let crc = ogg_crc(&ret[start..]);
+        ret[start + 22..start + 26].copy_from_slice(&crc.to_le_bytes());
+        seq += 1;
+    }
+    Ok(ret)
+}
+
+// an mp4 box, as its type, the whole box, and how long its header is
+type Mp4Box<'a> = ([u8; 4], &'a [u8], usize);
+
+// the boxes directly inside some mp4 data
+fn mp4_boxes(data: &[u8]) -> anyhow::Result<Vec<Mp4Box<'_>>> {
+    let mut ret = vec![];
+    let mut at = 0;
+    while at < data.len() {
+        let cut = || anyhow!("mp4 box is cut off");
+        let head = data.get(at..at + 8).ok_or_else(cut)?;
+        let kind = head[4..8].try_into().unwrap();
+        let (header, size) = match u32::from_be_bytes(head[..4].try_into().unwrap()) {
+            // the rest of the file
+            0 => (8, data.len() - at),
+            1 => {
+                let size = data.get(at + 8..at + 16).ok_or_else(cut)?;
+                (16, u64::from_be_bytes(size.try_into().unwrap()) as usize)
+            }
+            size => (8, size as usize),
+        };
+        if size < header || data.len() - at < size {
+            return Err(cut());
+        }
+        ret.push((kind, &data[at..at + size], header));
+        at += size;
+    }
+    Ok(ret)
+}
+
+fn mp4_box(kind: &[u8; 4], body: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let size = u32::try_from(body.len() + 8).map_err(|_| anyhow!("mp4 box is too large"))?;
+    Ok([&size.to_be_bytes(), kind, body].concat())
+}
+
+// an item of itunes metadata
+fn mp4_item(kind: &[u8; 4], data_type: u32, value: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let data = [&data_type.to_be_bytes()[..], &[0; 4], value].concat();
+    mp4_box(kind, &mp4_box(b"data", &data)?)
+}
+
+// an item of itunes metadata that isn't one of the usual ones
+fn mp4_freeform(name: &str, value: &str) -> anyhow::Result<Vec<u8>> {
+    let item = [
+        mp4_box(b"mean", &[&[0; 4], &b"com.apple.iTunes"[..]].concat())?,
+        mp4_box(b"name", &[&[0; 4], name.as_bytes()].concat())?,
+        mp4_box(
+            b"data",
+            &[&1u32.to_be_bytes()[..], &[0; 4], value.as_bytes()].concat(),
+        )?,
+    ]
+    .concat();
+    mp4_box(b"----", &item)
+}
+
+// the udta/meta box that itunes metadata goes in
+fn mp4_meta(tags: &Tags, cover: Option<Cover>) -> anyhow::Result<Vec<u8>> {
+    const UTF8: u32 = 1;
+    let mut items = mp4_item(b"\xa9nam", UTF8, tags.title.as_bytes())?;
+    // there's only one of each item, so more than one name is put into it
+    for (kind, roles) in [
+        (b"\xa9ART", PERFORMERS),
+        (b"\xa9wrt", &[ArtistRole::Composer]),
+    ] {
+        let names = tags.credited(roles);
+        if !names.is_empty() {
+            items.extend(mp4_item(kind, UTF8, names.join(", ").as_bytes())?);
+        }
+    }
+    let named = [
+        (b"soar", &tags.artist_sort),
+        (b"aART", &tags.album_artist),
+        (b"\xa9alb", &tags.album),
+        (b"soal", &tags.album_sort),
+    ];
+    for (kind, value) in named {
+        if let Some(value) = value {
+            items.extend(mp4_item(kind, UTF8, value.as_bytes())?);
+        }
+    }
+    // as index and total, which isn't known
+    if let Some(disk) = tags.disk.and_then(|x| u16::try_from(x).ok()) {
+        let value = [&[0; 2], &disk.to_be_bytes()[..], &[0; 2]].concat();
+        items.extend(mp4_item(b"disk", 0, &value)?);
+    }
+    if let Some(track) = tags.track.and_then(|x| u16::try_from(x).ok()) {
+        let value = [&[0; 2], &track.to_be_bytes()[..], &[0; 4]].concat();
+        items.extend(mp4_item(b"trkn", 0, &value)?);
+    }
+    for (key, value) in &tags.other {
+        match KNOWN.iter().find(|x| x.0 == key.as_str()) {
+            Some((_, _, _, kind)) => items.extend(mp4_item(kind, UTF8, value.as_bytes())?),
+            // anything else is a freeform item
+            None => items.extend(mp4_freeform(key, value)?),
+        }
+    }
+    let remixers = tags.credited(&[ArtistRole::Remixer]);
+    if !remixers.is_empty() {
+        items.extend(mp4_freeform("REMIXER", &remixers.join(", "))?);
+    }
+    if let Some(cover) = cover {
+        const JPEG: u32 = 13;
+        items.extend(mp4_item(b"covr", JPEG, &cover.jpeg)?);
+    }
+
+    let hdlr = [&[0; 8][..], &b"mdirappl"[..], &[0; 9]].concat();
+    let meta = [
+        &[0; 4][..],
+        &mp4_box(b"hdlr", &hdlr)?,
+        &mp4_box(b"ilst", &items)?,
+    ]
+    .concat();
+    mp4_box(b"meta", &meta)
+}
+
+// copy some boxes, moving the chunk offsets that point at or past `from` by `delta`
+fn mp4_shift(data: &[u8], from: u64, delta: i64) -> anyhow::Result<Vec<u8>> {
+    let mut ret = vec![];
+    for (kind, whole, header) in mp4_boxes(data)? {
+        let body = &whole[header..];
+        match &kind {
+            b"trak" | b"mdia" | b"minf" | b"stbl" => {
+                ret.extend(mp4_box(&kind, &mp4_shift(body, from, delta)?)?);
+            }
+            b"stco" | b"co64" => {
+                let wide = &kind == b"co64";
+                let width = if wide { 8 } else { 4 };
+                let mut body = body.to_vec();
+                let entries = body
+                    .get_mut(8..)
+                    .ok_or_else(|| anyhow!("chunk offsets are cut off"))?;
+                for entry in entries.chunks_exact_mut(width) {
+                    let offset = if wide {
+                        u64::from_be_bytes(entry.try_into().unwrap())
+                    } else {
+                        u32::from_be_bytes(entry.try_into().unwrap()) as u64
+                    };
+                    if offset < from {
+                        continue;
+                    }
+                    let offset = offset
+                        .checked_add_signed(delta)
+                        .ok_or_else(|| anyhow!("chunk offset is out of range"))?;
+                    if wide {
+                        entry.copy_from_slice(&offset.to_be_bytes());
+                    } else {
+                        let offset = u32::try_from(offset)
+                            .map_err(|_| anyhow!("chunk offset is out of range"))?;
+                        entry.copy_from_slice(&offset.to_be_bytes());
+                    }
+                }
+                ret.extend(mp4_box(&kind, &body)?);
+            }
+            _ => ret.extend(whole),
+        }
+    }
+    Ok(ret)
+}
+
+fn mp4(data: &[u8], tags: &Tags, cover: Option<Cover>) -> anyhow::Result<Vec<u8>> {
+    let top = mp4_boxes(data)?;
+    let idx = top
+        .iter()
+        .position(|x| &x.0 == b"moov")
+        .ok_or_else(|| anyhow!("mp4 file has no moov"))?;
+    let from = top[..idx].iter().map(|x| x.1.len() as u64).sum::<u64>();
+    let (_, moov, header) = top[idx];
+    let meta = mp4_meta(tags, cover)?;
+
+    // the old metadata is dropped, and anything else in udta kept
+    let moov_body = |delta| -> anyhow::Result<Vec<u8>> {
+        let mut ret = vec![];
+        let mut udta = false;
+        for (kind, whole, header) in mp4_boxes(&moov[header..])? {
+            match &kind {
+                b"udta" => {
+                    udta = true;
+                    let mut body = vec![];
+                    for (kind, whole, _) in mp4_boxes(&whole[header..])? {
+                        if &kind != b"meta" {
+                            body.extend(whole);
+                        }
+                    }
+                    body.extend(&meta);
+                    ret.extend(mp4_box(b"udta", &body)?);
+                }
+                b"trak" => ret.extend(mp4_shift(whole, from, delta)?),
+                _ => ret.extend(whole),
+            }
+        }
+        if !udta {
+            ret.extend(mp4_box(b"udta", &meta)?);
+        }
+        mp4_box(b"moov", &ret)
+    };
+    // moving the offsets doesn't change the size, so it's found out first
+    let delta = moov_body(0)?.len() as i64 - moov.len() as i64;
+    let new_moov = moov_body(delta)?;
+
+    let mut ret = vec![];
+    for (idx2, (_, whole, _)) in top.iter().enumerate() {
+        if idx2 == idx {
+            ret.extend(&new_moov);
+        } else {
+            ret.extend(*whole);
+        }
+    }
+    Ok(ret)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn tags() -> Tags {
+        Tags {
+            title: "Song".to_owned(),
+            artists: vec![(ArtistRole::Primary, "Artist".to_owned())],
+            album: Some("Album".to_owned()),
+            disk: Some(1),
+            track: Some(2),
+            other: BTreeMap::from([
+                ("genre".to_owned(), "Rock".to_owned()),
+                ("mood".to_owned(), "happy".to_owned()),
+            ]),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn tagging_id3() {
+        let audio = b"\xff\xfbnot really mpeg";
+        let tagged = write(audio, "mp3", &tags()).unwrap();
+        // tagging again replaces the tag instead of stacking them
+        let tagged = write(&tagged, "mp3", &tags()).unwrap();
+        assert!(tagged.ends_with(audio));
+        assert_eq!(id3_len(&tagged) + audio.len(), tagged.len());
+
+        use id3::TagLike;
+        let tag = id3::Tag::read_from2(Cursor::new(&tagged)).unwrap();
+        assert_eq!(tag.title(), Some("Song"));
+        assert_eq!(tag.artist(), Some("Artist"));
+        assert_eq!(tag.album(), Some("Album"));
+        assert_eq!((tag.disc(), tag.track()), (Some(1), Some(2)));
+        assert_eq!(tag.genre(), Some("Rock"));
+        assert!(tag
+            .extended_texts()
+            .any(|x| x.description == "mood" && x.value == "happy"));
+    }
+
+    #[test]
+    fn tagging_flac() {
+        let block = |kind: u8, body: &[u8]| {
+            [&[kind][..], &(body.len() as u32).to_be_bytes()[1..], body].concat()
+        };
+        let old = comment_header(b"encoder", &["TITLE=Old".to_owned()]).unwrap();
+        let file = [
+            &b"fLaC"[..],
+            &block(0, &[7; 34]),
+            &block(4, &old),
+            &block(0x80 | 1, &[0; 16]),
+            b"frames",
+        ]
+        .concat();
+        let tagged = write(&file, "flac", &tags()).unwrap();
+
+        // streaminfo is kept and comes first, the padding goes, and the frames are
+        // untouched
+        assert!(tagged.starts_with(&[&b"fLaC"[..], &block(0, &[7; 34])].concat()));
+        assert!(tagged.ends_with(b"frames"));
+        let comments = comment_header(
+            b"encoder",
+            &[
+                "TITLE=Song",
+                "ARTIST=Artist",
+                "ALBUM=Album",
+                "DISCNUMBER=1",
+                "TRACKNUMBER=2",
+                "GENRE=Rock",
+                "MOOD=happy",
+            ]
+            .map(String::from),
+        )
+        .unwrap();
+        assert_eq!(&tagged[42..tagged.len() - 6], block(0x80 | 4, &comments));
+    }
+
+    #[test]
+    fn tagging_ogg() {
+        let page = |flags: u8, granule: u64, seq: u32, packet: &[u8]| {
+            let mut ret = vec![];
+            let mut seq = seq;
+            ogg_packet(&mut ret, 1234, &mut seq, packet);
+            ret[5] = flags;
+            ret[6..14].copy_from_slice(&granule.to_le_bytes());
+            ret[22..26].copy_from_slice(&[0; 4]);
+            let crc = ogg_crc(&ret);
+            ret[22..26].copy_from_slice(&crc.to_le_bytes());
+            ret
+        };
+        let tags_packet = [
+            &b"OpusTags"[..],
+            &comment_header(b"libopus", &["TITLE=Old".to_owned()]).unwrap(),
+        ]
+        .concat();
+        let audio = page(4, 960, 2, &[9; 300]);
+        let file = [
+            page(2, 0, 0, b"OpusHead\x01\x02"),
+            page(0, 0, 1, &tags_packet),
+            audio.clone(),
+        ]
+        .concat();
+        let mut tags = tags();
+        tags.title = "A".repeat(600);
+        let tagged = write(&file, "opus", &tags).unwrap();
+
+        // the new comments are long enough to need more segments, but still fit on
+        // one page, so the audio page is the same
+        let pages = ogg_pages(&tagged).unwrap();
+        assert_eq!(pages.len(), 3);
+        assert_eq!(pages[0].raw, page(2, 0, 0, b"OpusHead\x01\x02"));
+        assert_eq!(pages[2].raw, audio);
+        for page in &pages {
+            let mut raw = page.raw.to_vec();
+            raw[22..26].copy_from_slice(&[0; 4]);
+            assert_eq!(ogg_crc(&raw).to_le_bytes(), page.raw[22..26]);
+        }
+        let comment = pages[1].body.strip_prefix(b"OpusTags").unwrap();
+        assert_eq!(comment_vendor(comment).unwrap(), b"libopus");
+        let title = format!("TITLE={}", "A".repeat(600));
+        assert!(comment.windows(title.len()).any(|x| x == title.as_bytes()));
+        assert!(!comment.windows(9).any(|x| x == b"TITLE=Old"));
+    }
+
+    // the body of the first box of a type
+    fn child<'a>(data: &'a [u8], kind: &[u8; 4]) -> &'a [u8] {
+        let (_, whole, header) = mp4_boxes(data)
+            .unwrap()
+            .into_iter()
+            .find(|x| &x.0 == kind)
+            .unwrap();
+        &whole[header..]
+    }
+
+    #[test]
+    fn tagging_mp4() {
+        let full = |kind: &[u8; 4], body: &[u8]| mp4_box(kind, &[&[0; 4][..], body].concat());
+        let ftyp = mp4_box(b"ftyp", b"M4A \0\0\0\0").unwrap();
+        let moov_for = |offset: u32| -> anyhow::Result<Vec<u8>> {
+            let stco = full(
+                b"stco",
+                &[1u32.to_be_bytes(), offset.to_be_bytes()].concat(),
+            )?;
+            let stbl = mp4_box(b"stbl", &stco)?;
+            let trak = mp4_box(b"trak", &mp4_box(b"mdia", &mp4_box(b"minf", &stbl)?)?)?;
+            mp4_box(b"moov", &[full(b"mvhd", &[0; 96])?, trak].concat())
+        };
+
+        // the moov is in front of the audio, so the offset has to move with it
+        let offset = ftyp.len() + moov_for(0).unwrap().len() + 8;
+        let file = [
+            ftyp.clone(),
+            moov_for(offset as u32).unwrap(),
+            mp4_box(b"mdat", b"audio").unwrap(),
+        ]
+        .concat();
+        let tagged = write(&file, "m4a", &tags()).unwrap();
+        assert_eq!(mp4_boxes(&tagged).unwrap().len(), 3);
+        let moov = child(&tagged, b"moov");
+        let stbl = child(
+            child(child(child(moov, b"trak"), b"mdia"), b"minf"),
+            b"stbl",
+        );
+        let offset = u32::from_be_bytes(child(stbl, b"stco")[8..12].try_into().unwrap());
+        let offset = offset as usize;
+        assert_eq!(&tagged[offset..offset + 5], b"audio");
+
+        // the metadata is in moov/udta/meta/ilst
+        let ilst = child(&child(child(moov, b"udta"), b"meta")[4..], b"ilst");
+        let items = mp4_boxes(ilst).unwrap();
+        let kinds = items.iter().map(|x| &x.0).collect::<Vec<_>>();
+        assert_eq!(
+            kinds,
+            [b"\xa9nam", b"\xa9ART", b"\xa9alb", b"disk", b"trkn", b"\xa9gen", b"----"]
+        );
+        assert!(items[0].1.ends_with(b"Song"));
+
+        // tagging again replaces the metadata
+        let again = write(&tagged, "m4a", &tags()).unwrap();
+        assert_eq!(again, tagged);
+    }
+
+    #[test]
+    fn tagging_bad() {
+        // files that don't look right are left for the caller to serve untagged
+        assert!(write(b"not flac", "flac", &tags()).is_err());
+        assert!(write(b"OggS", "ogg", &tags()).is_err());
+        assert!(write(b"\0\0\0\x10moov", "m4a", &tags()).is_err());
+        assert_eq!(write(b"qoaf", "qoa", &tags()).unwrap(), b"qoaf");
+
+        // a cover that isn't an image is left out, but the text is still written
+        let mut tags = tags();
+        tags.cover = Some(b"not an image".to_vec());
+        let tagged = write(b"\xff\xfbnot really mpeg", "mp3", &tags).unwrap();
+        use id3::TagLike;
+        let tag = id3::Tag::read_from2(Cursor::new(&tagged)).unwrap();
+        assert_eq!(tag.title(), Some("Song"));
+        assert_eq!(tag.pictures().count(), 0);
+    }
+}
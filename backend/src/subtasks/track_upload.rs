@@ -1,5 +1,7 @@
+use super::transcode::StorageFormat;
 use crate::db::uuid_serialize;
 use crate::db::write_transaction;
+use crate::endpoints::metadata::{link_artist, set_credits};
 use crate::*;
 use anyhow::anyhow;
 use axum::http::StatusCode;
@@ -9,14 +11,17 @@ use gstreamer_pbutils::prelude::*;
 use gstreamer_pbutils::DiscovererResult;
 #[allow(unused)]
 use log::*;
+use mio_protocol::{ArtistRole, Codec, DuplicatePolicy, JobState};
 use once_cell::sync::Lazy;
 use path_absolutize::Absolutize;
 use sha2::{Digest, Sha256};
+use sqlx::SqliteConnection;
 use std::collections::HashMap;
 use std::fmt::Debug;
 use std::io::Cursor;
 use std::path::Path;
 use std::path::PathBuf;
+use std::time::Instant;
 use symphonia::core::audio::Channels;
 use symphonia::core::audio::SampleBuffer;
 use symphonia::core::codecs::DecoderOptions;
@@ -32,10 +37,14 @@ use uuid::*;
 struct Metadata {
     title: String,
     other_tags: String,
-    artist: Option<String>,
+    // everyone credited, in order
+    artists: Vec<(ArtistRole, String)>,
+    // of the first primary artist
     artist_sort: Option<String>,
     album: Option<String>,
     album_sort: Option<String>,
+    album_artist: Option<String>,
+    album_artist_sort: Option<String>,
     img: Option<(Vec<u8>, [u8; 32])>,
     disk_track: (Option<i32>, Option<i32>),
 }
@@ -45,46 +54,158 @@ struct AudioDesc {
     sample_rate: u32,
 }
 
-// TODO: upload process time limits
-//
-// TODO: size limits
+// sha256 of the upload as it was sent, and of the decoded audio
+#[derive(Debug)]
+struct Hashes {
+    file: [u8; 32],
+    audio: [u8; 32],
+}
+
+// the upload as it was sent, when it's kept around
+#[derive(Debug)]
+struct Original {
+    size: i64,
+    mime: &'static str,
+}
+
+// extensions of files that sit next to a track
+pub(crate) const ORIGINAL_EXT: &str = "orig";
+pub(crate) const ENCODING_EXT: &str = "encoding";
+
+// where the original of the track at `path` is kept
+pub(crate) fn original_path(path: &Path) -> PathBuf {
+    path.with_extension(ORIGINAL_EXT)
+}
+
+// limits that processing can run into, which get their own status codes
+#[derive(Debug, thiserror::Error)]
+enum Limit {
+    #[error("processing took longer than {0} seconds")]
+    Timeout(u64),
+    #[error("track is longer than the max of {0} seconds")]
+    TooLong(u64),
+}
+
+// a wall clock limit for one step of processing, which is checked every so often
+struct Deadline {
+    started: Instant,
+    limit: u64,
+}
+
+impl Deadline {
+    fn new() -> Self {
+        Self {
+            started: Instant::now(),
+            limit: *crate::PROCESSING_TIMEOUT.get().unwrap(),
+        }
+    }
+
+    fn check(&self) -> anyhow::Result<()> {
+        if self.started.elapsed().as_secs() >= self.limit {
+            return Err(Limit::Timeout(self.limit).into());
+        }
+        Ok(())
+    }
+}
+
+// hitting a limit gets a more specific status than a generic failure
+fn processing_error(err: anyhow::Error, status: StatusCode) -> MioInnerError {
+    let status = match err.downcast_ref::<Limit>() {
+        Some(Limit::Timeout(_)) => StatusCode::REQUEST_TIMEOUT,
+        Some(Limit::TooLong(_)) => StatusCode::PAYLOAD_TOO_LARGE,
+        None => status,
+    };
+    MioInnerError::TrackProcessingError(err, status)
+}
+
+// run a blocking step that can't stop itself, giving up after limit seconds. the thread
+// is left to finish on its own, but the worker is freed.
+async fn with_timeout<T: Send + 'static>(
+    limit: u64,
+    f: impl FnOnce() -> anyhow::Result<T> + Send + 'static,
+) -> Result<T, MioInnerError> {
+    tokio::time::timeout(
+        std::time::Duration::from_secs(limit),
+        tokio::task::spawn_blocking(f),
+    )
+    .await
+    .map_err(|_| {
+        processing_error(
+            Limit::Timeout(limit).into(),
+            StatusCode::INTERNAL_SERVER_ERROR,
+        )
+    })?
+    .map_err(|err| {
+        MioInnerError::TrackProcessingError(
+            anyhow!("failed to run task: {err}"),
+            StatusCode::INTERNAL_SERVER_ERROR,
+        )
+    })?
+    .map_err(|err| processing_error(err, StatusCode::INTERNAL_SERVER_ERROR))
+}
+
+// find a track of the user's that the upload is a copy of. either hash matching is
+// enough.
+pub(crate) async fn find_duplicate(
+    conn: &mut SqliteConnection,
+    userid: Uuid,
+    file_hash: Option<&[u8]>,
+    audio_hash: Option<&[u8]>,
+) -> Result<Option<Uuid>, MioInnerError> {
+    sqlx::query!(
+        "SELECT id FROM track WHERE owner = ? AND (file_hash = ? OR audio_hash = ?)
+        ORDER BY rowid LIMIT 1;",
+        userid,
+        file_hash,
+        audio_hash
+    )
+    .fetch_optional(&mut *conn)
+    .await?
+    .map(|x| uuid_serialize(&x.id))
+    .transpose()
+}
+
+pub(crate) fn duplicate_error(existing: Uuid) -> MioInnerError {
+    MioInnerError::Conflict(anyhow!("upload is a duplicate of {existing}"))
+}
+
+// process an upload into a track. this returns the id of the track that the upload
+// ended up as, which is only different from the job id when it was skipped as a
+// duplicate.
 #[tracing::instrument]
 pub async fn track_upload_process(
     state: MioState,
-    id: Uuid,
     path: PathBuf,
-    dir: String,
-    userid: Uuid,
-    orig_filename: String,
-) -> Result<(), MioInnerError> {
+    job: super::jobs::NewJob,
+) -> Result<Uuid, MioInnerError> {
+    let super::jobs::NewJob {
+        id,
+        owner: userid,
+        dir,
+        orig_fname: orig_filename,
+        format,
+        duplicates,
+        inbox: _,
+    } = job;
+
     // process metadata
-    let (mdata, track_vec, encoded) = tokio::task::spawn_blocking({
+    let (mdata, desc, waveform, hashes, orig_size) = tokio::task::spawn_blocking({
         let orig_filename = orig_filename.clone();
         let path = path.clone();
         move || {
-            let mdata = get_metadata(path.clone(), orig_filename.clone())?;
+            let orig_size = std::fs::metadata(&path)?.len() as i64;
+            let file_hash = hash_file(&path)?;
+            let mdata = get_metadata(path.clone(), orig_filename.clone())
+                .map_err(|err| processing_error(err, StatusCode::BAD_REQUEST))?;
 
             // get waveform & desc
-            let (desc, waveform) = extract_waveform(path, orig_filename.clone())
-                .map_err(|err| MioInnerError::TrackProcessingError(err, StatusCode::BAD_REQUEST))?;
-
-            // generate vec
-            std::thread::scope(|s| {
-                let track_vec = s.spawn(|| {
-                    create_vec(&waveform, desc.channels, desc.sample_rate, orig_filename).map_err(
-                        |err| {
-                            MioInnerError::TrackProcessingError(
-                                err,
-                                StatusCode::INTERNAL_SERVER_ERROR,
-                            )
-                        },
-                    )
-                });
-
-                // conv into Quite Ok Audio
-                let encoded = s.spawn(|| todo!());
-                Ok((mdata, track_vec.join().unwrap()?, encoded.join().unwrap()))
-            })
+            let (desc, waveform) = extract_waveform(path, orig_filename)
+                .map_err(|err| processing_error(err, StatusCode::BAD_REQUEST))?;
+            let hashes = Hashes {
+                file: file_hash,
+                audio: hash_samples(&waveform),
+            };
+            Ok::<_, MioInnerError>((mdata, desc, waveform, hashes, orig_size))
         }
     })
     .await
@@ -93,27 +214,150 @@ pub async fn track_upload_process(
             anyhow!("failed to run task: {err}"),
             StatusCode::INTERNAL_SERVER_ERROR,
         )
-    })?
-    .map_err(|err| MioInnerError::TrackProcessingError(err, StatusCode::BAD_REQUEST))?;
+    })??;
 
-    // write out new encoded file
-    trace!("{orig_filename}: writing out encoding");
-    let mut file = tokio::fs::OpenOptions::new()
-        .write(true)
-        .truncate(true)
-        .open(path)
+    // no need to go any further if the user already has this
+    if duplicates != DuplicatePolicy::Allow {
+        let mut conn = state.db.acquire().await?;
+        let existing = find_duplicate(
+            &mut conn,
+            userid,
+            Some(hashes.file.as_slice()),
+            Some(hashes.audio.as_slice()),
+        )
         .await?;
-    file.write_all(todo!()).await?;
-    file.sync_all().await?;
-    drop(file);
+        drop(conn);
+        if let Some(existing) = existing {
+            if duplicates == DuplicatePolicy::Reject {
+                return Err(duplicate_error(existing));
+            }
+            debug!("{orig_filename}: skipping, already uploaded as {existing}");
+            let _hold = state.lock_files.read().await;
+            tokio::fs::remove_file(path).await?;
+            return Ok(existing);
+        }
+    }
+    super::jobs::set_state(&state.db, id, JobState::Embedding, None).await?;
+
+    // keep the upload around before it gets replaced by the storage copy. the link is
+    // left alone when the upload is replaced, as that's done by renaming over it.
+    let keep = *crate::KEEP_ORIGINALS.get().unwrap() && format.codec != Codec::Original;
+    let original = (keep || format.codec == Codec::Original).then(|| Original {
+        size: orig_size,
+        mime: super::transcode::content_type(
+            &StorageFormat::new(Codec::Original, None, &orig_filename)
+                .map(|x| x.container)
+                .unwrap_or_default(),
+        ),
+    });
+    if keep {
+        trace!("{orig_filename}: keeping original");
+        let orig_path = original_path(&path);
+        if tokio::fs::hard_link(&path, &orig_path).await.is_err() {
+            tokio::fs::copy(&path, &orig_path).await?;
+        }
+    }
+
+    // generate vec. the model can't check a deadline while it runs, so the job stops
+    // waiting on it instead.
+    let waveform = std::sync::Arc::new(waveform);
+    let AudioDesc {
+        channels,
+        sample_rate,
+    } = desc;
+    let track_vec = with_timeout(*crate::PROCESSING_TIMEOUT.get().unwrap(), {
+        let orig_filename = orig_filename.clone();
+        let waveform = waveform.clone();
+        move || create_vec(&waveform, channels, sample_rate, orig_filename)
+    });
+
+    // conv into the storage codec. only qoa is kept in memory, the gstreamer encoders
+    // write out to a temp file which replaces the upload.
+    let encoded = tokio::task::spawn_blocking({
+        let path = path.clone();
+        let format = format.clone();
+        move || -> anyhow::Result<Option<Vec<u8>>> {
+            match format.codec {
+                Codec::Original => Ok(None),
+                Codec::Qoa => super::qoa::encode(&waveform, channels, sample_rate).map(Some),
+                Codec::Flac | Codec::Opus => {
+                    let tmp = path.with_extension(ENCODING_EXT);
+                    let ret = super::transcode::encode_file(&path, &tmp, &format)
+                        .and_then(|_| Ok(std::fs::rename(&tmp, &path)?));
+                    if ret.is_err() {
+                        drop(std::fs::remove_file(&tmp));
+                    }
+                    ret.map(|_| None)
+                }
+            }
+        }
+    });
+
+    // the encode is always waited on, so that it can't replace the upload after the
+    // job has already failed
+    let (track_vec, encoded) = tokio::join!(track_vec, encoded);
+    let encoded = encoded
+        .map_err(|err| {
+            MioInnerError::TrackProcessingError(
+                anyhow!("failed to run task: {err}"),
+                StatusCode::INTERNAL_SERVER_ERROR,
+            )
+        })?
+        .map_err(|err| processing_error(err, StatusCode::BAD_REQUEST))?;
+    let track_vec = track_vec?;
+
+    // write out new encoded file
+    trace!("{orig_filename}: locking read dir");
+    let _hold = state.lock_files.read().await;
+    if let Some(encoded) = encoded {
+        trace!("{orig_filename}: writing out encoding");
+        let tmp = path.with_extension(ENCODING_EXT);
+        let mut file = tokio::fs::File::create(&tmp).await?;
+        file.write_all(&encoded).await?;
+        file.sync_all().await?;
+        drop(file);
+        tokio::fs::rename(&tmp, &path).await?;
+    }
+
+    // the encoded file can be bigger than what was uploaded, so check the quota again.
+    // the upload is still counted as this job, which is swapped for what's stored now.
+    let size = tokio::fs::metadata(&path).await?.len() as i64;
+    let mut conn = state.db.acquire().await?;
+    let mut usage = crate::quota::usage(&mut conn, userid).await?;
+    drop(conn);
+    usage.bytes = usage.bytes.saturating_sub(orig_size as u64);
+    let stored = size + original.as_ref().filter(|_| keep).map_or(0, |x| x.size);
+    if let Err(err) = crate::quota::check(&usage, stored as u64, 1) {
+        debug!("{orig_filename}: encoded file went over quota");
+        if keep {
+            tokio::fs::remove_file(original_path(&path)).await?;
+        }
+        tokio::fs::remove_file(path).await?;
+        return Err(err);
+    }
 
     // insert into the database
-    insert_into_db(state.db, id, userid, dir, mdata, orig_filename, track_vec).await
+    insert_into_db(
+        state.db,
+        id,
+        userid,
+        dir,
+        mdata,
+        orig_filename,
+        track_vec,
+        format,
+        hashes,
+        original,
+        size,
+    )
+    .await?;
+    Ok(id)
 }
 
 #[tracing::instrument]
 fn get_metadata(fname: PathBuf, orig_path: String) -> Result<Metadata, anyhow::Error> {
-    let discover = gstreamer_pbutils::Discoverer::new(gstreamer::ClockTime::from_seconds(10))?;
+    let timeout = *crate::PROCESSING_TIMEOUT.get().unwrap();
+    let discover = gstreamer_pbutils::Discoverer::new(gstreamer::ClockTime::from_seconds(timeout))?;
     let fname = glib::filename_to_uri(Path::new(&fname).absolutize()?, None)?;
     trace!("{orig_path}: new uri created: '{fname}'");
     let data = discover.discover_uri(&fname)?;
@@ -123,7 +367,7 @@ fn get_metadata(fname: PathBuf, orig_path: String) -> Result<Metadata, anyhow::E
             anyhow::bail!("Missing plugin needed for file {orig_path}");
         },
         DiscovererResult::Timeout => {
-            anyhow::bail!("Timeout reached for processing tags");
+            return Err(Limit::Timeout(timeout).into());
         },
         // these branches _shouldn't_ fail.
         //
@@ -139,10 +383,13 @@ fn get_metadata(fname: PathBuf, orig_path: String) -> Result<Metadata, anyhow::E
     // tag metadata, iterate through all tags
     debug!("{orig_path}: collecting tags");
     let mut title = None;
-    let mut artist = None;
+    let mut artists = vec![];
     let mut artist_sort = None;
     let mut album = None;
     let mut album_sort = None;
+    let mut album_artist = None;
+    let mut album_artist_sort = None;
+    let mut composers = vec![];
     let mut img = None;
     let mut disk_track = (None, None);
     let mut set = HashMap::new();
@@ -151,8 +398,12 @@ fn get_metadata(fname: PathBuf, orig_path: String) -> Result<Metadata, anyhow::E
         let mut ret = vec![];
         match tags {
             Some(tags) => {
-                for (tag, value) in tags.iter() {
-                    ret.push((tag.to_owned(), value));
+                // tags can have more than one value, such as when there's more than
+                // one artist
+                for (tag, values) in tags.iter_generic() {
+                    for value in values {
+                        ret.push((tag.to_owned(), value.clone()));
+                    }
                 }
             }
             None => debug!("{orig_path}: streaminfo.tags() produced a none"),
@@ -162,6 +413,10 @@ fn get_metadata(fname: PathBuf, orig_path: String) -> Result<Metadata, anyhow::E
         trace!("{orig_path}: tag \"{tag}\"");
         match tag.as_str() {
             "image" => {
+                // the first picture is usually the front cover
+                if img.is_some() {
+                    continue;
+                }
                 let samp = data.get::<gstreamer::Sample>()?;
                 if let Some(bufs) = samp.buffer() {
                     let img_raw = {
@@ -193,8 +448,8 @@ fn get_metadata(fname: PathBuf, orig_path: String) -> Result<Metadata, anyhow::E
                 trace!("{orig_path}: title is {:?}", title)
             }
             "artist" => {
-                artist = proc_tag(data);
-                trace!("{orig_path}: artist is {:?}", artist)
+                artists.extend(proc_tag(data));
+                trace!("{orig_path}: artists are {:?}", artists)
             }
             "artist-sortname" => {
                 artist_sort = proc_tag(data);
@@ -208,6 +463,21 @@ fn get_metadata(fname: PathBuf, orig_path: String) -> Result<Metadata, anyhow::E
                 album_sort = proc_tag(data);
                 trace!("{orig_path}: album sortname is {:?}", album_sort);
             }
+            "album-artist" => {
+                album_artist = proc_tag(data);
+                trace!("{orig_path}: album artist is {:?}", album_artist)
+            }
+            "album-artist-sortname" => {
+                album_artist_sort = proc_tag(data);
+                trace!(
+                    "{orig_path}: album artist sortname is {:?}",
+                    album_artist_sort
+                );
+            }
+            "composer" => {
+                composers.extend(proc_tag(data));
+                trace!("{orig_path}: composers are {:?}", composers)
+            }
             "album-disc-number" | "track-number" => {
                 let mut_info = if tag.as_str() == "album-disc-number" {
                     &mut disk_track.0
@@ -224,18 +494,17 @@ fn get_metadata(fname: PathBuf, orig_path: String) -> Result<Metadata, anyhow::E
                 trace!("{orig_path}: disk_track is {:?}", disk_track)
             }
             _ => {
-                // generic handler
-                let data = proc_tag(data);
-                if data.is_some() {
-                    let ret = set.insert(tag.clone(), data.clone());
-                    trace!(
-                        "{orig_path}: KV inserted on {tag}{}",
-                        if ret.is_some() {
-                            ", replaced prev KV"
-                        } else {
-                            ""
-                        }
-                    );
+                // generic handler, where the values of a tag are joined like gstreamer
+                // does with them
+                if let Some(data) = proc_tag(data) {
+                    let value: &mut String = set.entry(tag.clone()).or_default();
+                    if value.is_empty() {
+                        *value = data;
+                    } else if !value.split(", ").any(|x| x == data) {
+                        value.push_str(", ");
+                        value.push_str(&data);
+                    }
+                    trace!("{orig_path}: KV inserted on {tag}");
                 }
             }
         }
@@ -254,21 +523,47 @@ fn get_metadata(fname: PathBuf, orig_path: String) -> Result<Metadata, anyhow::E
         orig_path.to_string()
     });
     Ok(Metadata {
+        artists: credits(&artists, &composers, &title),
         title,
         other_tags,
-        artist,
         artist_sort,
         album,
         album_sort,
+        album_artist,
+        album_artist_sort,
         img,
         disk_track,
     })
 }
 
+fn hash(data: &[u8]) -> [u8; 32] {
+    digest_array(Sha256::digest(data).as_slice())
+}
+
+// hash a file without reading all of it into memory. this blocks.
+fn hash_file(path: &Path) -> std::io::Result<[u8; 32]> {
+    let mut sha = Sha256::new();
+    std::io::copy(&mut std::fs::File::open(path)?, &mut sha)?;
+    Ok(digest_array(sha.finalize().as_slice()))
+}
+
+// samples are hashed as little endian, so the hash is the same on every platform
+fn hash_samples(samples: &[i16]) -> [u8; 32] {
+    let mut sha = Sha256::new();
+    for chunk in samples.chunks(4096) {
+        sha.update(
+            chunk
+                .iter()
+                .flat_map(|x| x.to_le_bytes())
+                .collect::<Vec<_>>(),
+        );
+    }
+    digest_array(sha.finalize().as_slice())
+}
+
 // copy the hash of one value into a regular array since sha2 uses GenericArray's,
 // this is done via just manually iterating through it
-fn hash(data: &[u8]) -> [u8; 32] {
-    let sha = Sha256::digest(data);
+fn digest_array(sha: &[u8]) -> [u8; 32] {
     let mut actual_hash: [u8; 32] = Default::default();
     for (hasharr, digested) in actual_hash.iter_mut().zip(sha.iter()) {
         *hasharr = *digested;
@@ -276,6 +571,87 @@ fn hash(data: &[u8]) -> [u8; 32] {
     actual_hash
 }
 
+// separate the artists that are credited together. multiple artists should be in
+// separate values of the tag, but are often put into one with "feat." and the like.
+const FEAT: &[&str] = &["featuring ", "feat. ", "feat ", "ft. ", "ft "];
+
+// remixes that don't name who did them
+const NOT_REMIXERS: &[&str] = &["original", "extended", "radio", "club", "dub", "official"];
+
+// where the first "feat." that comes after one of `after` is, and how long it is
+fn find_feat(name: &str, after: &[u8]) -> Option<(usize, usize)> {
+    let lower = name.to_ascii_lowercase();
+    FEAT.iter()
+        .filter_map(|marker| {
+            lower
+                .match_indices(marker)
+                .find(|(at, _)| *at > 0 && after.contains(&lower.as_bytes()[at - 1]))
+                .map(|(at, _)| (at, marker.len()))
+        })
+        .min()
+}
+
+fn split_names<'a>(names: &'a str, seps: &[&str]) -> Vec<&'a str> {
+    let mut ret = vec![names];
+    for sep in seps {
+        ret = ret.into_iter().flat_map(|x| x.split(sep)).collect();
+    }
+    ret.into_iter()
+        .map(str::trim)
+        .filter(|x| !x.is_empty())
+        .collect()
+}
+
+// the artists after a "feat.", up to the end of the brackets it's in
+fn featured(names: &str) -> Vec<&str> {
+    let end = names.find([')', ']']).unwrap_or(names.len());
+    split_names(&names[..end], &[", ", " & ", ";"])
+}
+
+// who is credited on a track, from the values of the artist and composer tags. featured
+// artists and remixers are often only in the title.
+fn credits(artists: &[String], composers: &[String], title: &str) -> Vec<(ArtistRole, String)> {
+    let mut ret = vec![];
+    let mut add = |role, names: Vec<&str>| {
+        for name in names {
+            if !ret.iter().any(|(x, y)| *x == role && y == name) {
+                ret.push((role, name.to_owned()));
+            }
+        }
+    };
+    for artist in artists {
+        match find_feat(artist, b" ([") {
+            Some((at, len)) => {
+                let main = artist[..at].trim_end_matches([' ', '(', '[']);
+                add(ArtistRole::Primary, split_names(main, &[";"]));
+                add(ArtistRole::Featured, featured(&artist[at + len..]));
+            }
+            None => add(ArtistRole::Primary, split_names(artist, &[";"])),
+        }
+    }
+
+    // only what's in brackets in the title, as in "Song (feat. Someone)"
+    if let Some((at, len)) = find_feat(title, b"([") {
+        add(ArtistRole::Featured, featured(&title[at + len..]));
+    }
+    for group in title.split(['(', '[']).skip(1) {
+        let Some(end) = group.find([')', ']']) else {
+            continue;
+        };
+        let group = group[..end].trim();
+        if group.len() > 6 && group.to_ascii_lowercase().ends_with(" remix") {
+            let name = group[..group.len() - 6].trim();
+            if !NOT_REMIXERS.contains(&name.to_ascii_lowercase().as_str()) {
+                add(ArtistRole::Remixer, vec![name]);
+            }
+        }
+    }
+    for composer in composers {
+        add(ArtistRole::Composer, split_names(composer, &[";"]));
+    }
+    ret
+}
+
 fn proc_tag(data: SendValue) -> Option<String> {
     if let Ok(x) = data.get::<String>() {
         Some(x)
@@ -291,6 +667,8 @@ fn extract_waveform(file: PathBuf, fn_dis: String) -> anyhow::Result<(AudioDesc,
     use symphonia::core::errors::Error;
 
     info!("{fn_dis}: extracting waveforms");
+    let deadline = Deadline::new();
+    let max_duration = *crate::MAX_TRACK_DURATION.get().unwrap();
     let file = Box::new(std::fs::File::open(file)?);
     let mss = MediaSourceStream::new(file, Default::default());
     let fops: FormatOptions = Default::default();
@@ -340,6 +718,7 @@ fn extract_waveform(file: PathBuf, fn_dis: String) -> anyhow::Result<(AudioDesc,
         if packet.track_id() != track_id {
             continue;
         }
+        deadline.check()?;
         match decoder.decode(&packet) {
             Ok(buf) => {
                 let spec = *buf.spec();
@@ -357,6 +736,10 @@ fn extract_waveform(file: PathBuf, fn_dis: String) -> anyhow::Result<(AudioDesc,
                 }
                 sample_buf.copy_interleaved_ref(buf);
                 ret.extend(sample_buf.samples());
+                let frames = ret.len() as u64 / channel_num.unwrap() as u64;
+                if frames > max_duration * spec.rate as u64 {
+                    return Err(Limit::TooLong(max_duration).into());
+                }
             }
             Err(err) => {
                 return Err(anyhow::Error::from(err));
@@ -374,6 +757,7 @@ fn create_vec(
 ) -> anyhow::Result<Vec<f32>> {
     use ndarray::*;
 
+    let deadline = Deadline::new();
     // pad tracks shorter than 5 seconds
     let padded = {
         let full_len = sample_rate as usize * 5;
@@ -410,6 +794,7 @@ fn create_vec(
         let mut out_buf = vec![vec![0.0f32; resamp.output_frames_max()]; 1];
         let mut old_vec = vec![&floated[..]];
         while old_vec[0].len() >= resamp.input_frames_next() {
+            deadline.check()?;
             let (lin, lout) = resamp
                 .process_into_buffer(&old_vec, &mut out_buf, None)
                 .unwrap();
@@ -447,6 +832,7 @@ fn create_vec(
         pipeline.send_pcm(&floated[range]).unwrap();
         pipeline.close_ingress();
         let specs = pipeline.rx().into_iter().collect::<Vec<_>>();
+        deadline.check()?;
         trace!("{fn_dis}: joining spectrogram threads");
         handles.into_iter().for_each(|x| x.join().unwrap());
         let specs = specs.into_iter().map(|x| x.1).collect::<Vec<_>>();
@@ -492,6 +878,7 @@ fn create_vec(
         });
         let inp = vec![Value::from_array(SESSION.allocator(), &spec)?];
         let out = SESSION.run(inp)?;
+        deadline.check()?;
         out.get(0)
             .ok_or_else(|| anyhow!("when picking out output, index 0 does not exist"))?
             .try_extract::<f32>()?
@@ -502,6 +889,7 @@ fn create_vec(
     })
 }
 
+#[allow(clippy::too_many_arguments)]
 #[tracing::instrument]
 async fn insert_into_db(
     db: SqlitePool,
@@ -511,6 +899,10 @@ async fn insert_into_db(
     metadata: Metadata,
     orig_filename: String,
     track_vec: Vec<f32>,
+    format: StorageFormat,
+    hashes: Hashes,
+    original: Option<Original>,
+    size: i64,
 ) -> Result<(), MioInnerError> {
     let track_vec = track_vec
         .into_iter()
@@ -555,38 +947,23 @@ async fn insert_into_db(
                 }
             };
 
-            // insert artist, check on artist name
-            let artist_id = {
-                if let Some(q) = metadata.artist.as_ref() {
-                    match sqlx::query!(
-                        "SELECT id FROM artist
-                        WHERE artist_name = ?;",
-                        q
-                    )
-                    .fetch_optional(&mut *txn)
-                    .await?
-                    {
-                        Some(x) => Some(uuid_serialize(&x.id)?),
-                        None => {
-                            let id = Uuid::new_v4();
-                            let artist_name = metadata.artist.unwrap();
-                            sqlx::query!(
-                                "INSERT INTO artist
-                                (id, artist_name, sort_name)
-                                VALUES (?, ?, ?);",
-                                id,
-                                artist_name,
-                                metadata.artist_sort
-                            )
-                            .execute(&mut *txn)
-                            .await?;
-                            trace!("{orig_filename}: new artist generated: {id}");
-                            Some(id)
-                        }
-                    }
-                } else {
-                    None
+            // link everyone credited. the sort name is only for the first primary artist.
+            let mut credits: Vec<(Uuid, ArtistRole)> = vec![];
+            for (role, name) in &metadata.artists {
+                let first = *role == ArtistRole::Primary
+                    && !credits.iter().any(|x| x.1 == ArtistRole::Primary);
+                let sort = metadata.artist_sort.as_deref().filter(|_| first);
+                credits.push((link_artist(&mut *txn, name, sort).await?, *role));
+            }
+            let artist_id = credits
+                .iter()
+                .find(|x| x.1 == ArtistRole::Primary)
+                .map(|x| x.0);
+            let album_artist_id = match &metadata.album_artist {
+                Some(name) => {
+                    Some(link_artist(&mut *txn, name, metadata.album_artist_sort.as_deref()).await?)
                 }
+                None => None,
             };
 
             // insert album, check on album title
@@ -623,8 +1000,13 @@ async fn insert_into_db(
                 }
             };
 
-            // insert track, check on audiohash
+            // insert track
             let other_tags = metadata.other_tags;
+            let file_hash = hashes.file.as_slice();
+            let audio_hash = hashes.audio.as_slice();
+            let orig_size = original.as_ref().map(|x| x.size);
+            let orig_mime = original.as_ref().map(|x| x.mime);
+            let codec = format.codec.as_str();
             sqlx::query!(
                 "INSERT INTO track 
                     (id,
@@ -638,8 +1020,17 @@ async fn insert_into_db(
                     cover_art, 
                     owner,
                     path, 
-                    track_vec) 
-                VALUES (?,?,?,?,?,?,?,?,?,?,?,?);",
+                    track_vec,
+                    codec,
+                    bitrate,
+                    container,
+                    file_hash,
+                    audio_hash,
+                    orig_size,
+                    orig_mime,
+                    album_artist,
+                    size) 
+                VALUES (?,?,?,?,?,?,?,?,?,?,?,?,?,?,?,?,?,?,?,?,?);",
                 id,
                 metadata.title,
                 metadata.disk_track.0,
@@ -651,10 +1042,20 @@ async fn insert_into_db(
                 cover_art_id,
                 userid,
                 dir,
-                track_vec
+                track_vec,
+                codec,
+                format.bitrate,
+                format.container,
+                file_hash,
+                audio_hash,
+                orig_size,
+                orig_mime,
+                album_artist_id,
+                size
             )
             .execute(&mut *txn)
             .await?;
+            set_credits(&mut *txn, id, &credits).await?;
             trace!("{orig_filename}: new track created: {id}");
             Ok(())
         })
@@ -800,3 +1201,93 @@ static POSS_CHANNELS: Lazy<[(u32, Channels); 17]> = Lazy::new(|| {
         ),
     ]
 });
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn track_upload_bad_timeout() {
+        // a step that never checks in still gets cut off
+        let started = Instant::now();
+        let err = with_timeout(1, || {
+            std::thread::sleep(std::time::Duration::from_secs(5));
+            Ok(())
+        })
+        .await
+        .unwrap_err();
+        assert!(started.elapsed().as_secs() < 5);
+        assert!(matches!(
+            err,
+            MioInnerError::TrackProcessingError(_, StatusCode::REQUEST_TIMEOUT)
+        ));
+
+        // and ones that finish in time are passed through
+        assert_eq!(with_timeout(1, || Ok(1)).await.unwrap(), 1);
+        let err = with_timeout(1, || -> anyhow::Result<()> {
+            Err(Limit::TooLong(1).into())
+        })
+        .await
+        .unwrap_err();
+        assert!(matches!(
+            err,
+            MioInnerError::TrackProcessingError(_, StatusCode::PAYLOAD_TOO_LARGE)
+        ));
+    }
+
+    #[test]
+    fn track_upload_limit_status() {
+        let err = processing_error(Limit::Timeout(1).into(), StatusCode::BAD_REQUEST);
+        assert!(matches!(
+            err,
+            MioInnerError::TrackProcessingError(_, StatusCode::REQUEST_TIMEOUT)
+        ));
+        let err = processing_error(Limit::TooLong(1).into(), StatusCode::BAD_REQUEST);
+        assert!(matches!(
+            err,
+            MioInnerError::TrackProcessingError(_, StatusCode::PAYLOAD_TOO_LARGE)
+        ));
+        let err = processing_error(anyhow!("bad file"), StatusCode::BAD_REQUEST);
+        assert!(matches!(
+            err,
+            MioInnerError::TrackProcessingError(_, StatusCode::BAD_REQUEST)
+        ));
+    }
+
+    #[test]
+    fn track_upload_credits() {
+        use ArtistRole::*;
+        let names = |x: &[&str]| x.iter().map(|x| x.to_string()).collect::<Vec<_>>();
+        let credits = |artists: &[&str], composers: &[&str], title| {
+            credits(&names(artists), &names(composers), title)
+        };
+        assert_eq!(
+            credits(
+                &["A feat. B & C", "D; A"],
+                &["E;F"],
+                "Song (ft. G) [H Remix]"
+            ),
+            [
+                (Primary, "A"),
+                (Featured, "B"),
+                (Featured, "C"),
+                (Primary, "D"),
+                (Featured, "G"),
+                (Remixer, "H"),
+                (Composer, "E"),
+                (Composer, "F"),
+            ]
+            .map(|(role, name)| (role, name.to_owned()))
+        );
+
+        // things that only look like credits are left alone
+        assert_eq!(
+            credits(&["Left Featherweight"], &[], "Craft (Extended Remix)"),
+            [(Primary, "Left Featherweight".to_owned())]
+        );
+        assert_eq!(
+            credits(&["A (feat. B)"], &[], "Left ft Right"),
+            [(Primary, "A".to_owned()), (Featured, "B".to_owned())]
+        );
+    }
+}
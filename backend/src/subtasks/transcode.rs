@@ -0,0 +1,310 @@
+use crate::MioInnerError;
+use anyhow::{anyhow, bail};
+use axum::body::Bytes;
+use gstreamer::prelude::*;
+#[allow(unused)]
+use log::*;
+use mio_protocol::Codec;
+use once_cell::sync::Lazy;
+use std::path::Path;
+use std::sync::Arc;
+use tokio::sync::{mpsc, oneshot, Semaphore};
+
+// opus only supports bitrates in this range, in kbps
+const MIN_BITRATE: u32 = 6;
+const MAX_BITRATE: u32 = 510;
+
+// how many encoded chunks can be waiting to be sent out
+const STREAM_BUFFER: usize = 16;
+
+// limits how many transcodes can run at once, as each takes up a core
+static TRANSCODES: Lazy<Arc<Semaphore>> =
+    Lazy::new(|| Arc::new(Semaphore::new(*crate::MAX_TRANSCODES.get().unwrap())));
+
+// how a track is stored on disk
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StorageFormat {
+    pub codec: Codec,
+    // kbps, only for lossy codecs
+    pub bitrate: Option<u32>,
+    // file extension of the stored file
+    pub container: String,
+}
+
+impl StorageFormat {
+    pub fn new(codec: Codec, bitrate: Option<u32>, orig_filename: &str) -> anyhow::Result<Self> {
+        let bitrate = match codec {
+            Codec::Opus => {
+                let bitrate = bitrate.unwrap_or(*crate::STORAGE_BITRATE.get().unwrap());
+                check_bitrate(bitrate)?;
+                Some(bitrate)
+            }
+            _ => None,
+        };
+        let container = match codec {
+            Codec::Original => Path::new(orig_filename)
+                .extension()
+                .and_then(|x| x.to_str())
+                .filter(|x| x.chars().all(|x| x.is_ascii_alphanumeric()))
+                .map(|x| x.to_ascii_lowercase())
+                .unwrap_or_else(|| "bin".to_owned()),
+            Codec::Flac => "flac".to_owned(),
+            Codec::Opus => "ogg".to_owned(),
+            Codec::Qoa => "qoa".to_owned(),
+        };
+        Ok(Self {
+            codec,
+            bitrate,
+            container,
+        })
+    }
+
+    // a format as it's kept in the database
+    pub(crate) fn from_row(
+        codec: &str,
+        bitrate: Option<i64>,
+        container: String,
+    ) -> Result<Self, MioInnerError> {
+        Ok(Self {
+            codec: parse_codec(codec)?,
+            bitrate: bitrate.map(|x| x as u32),
+            container,
+        })
+    }
+}
+
+// a codec as it's kept in the database
+pub(crate) fn parse_codec(codec: &str) -> Result<Codec, MioInnerError> {
+    codec
+        .parse()
+        .map_err(|err| MioInnerError::DbError(anyhow!("could not parse codec {err}")))
+}
+
+pub fn check_bitrate(bitrate: u32) -> anyhow::Result<()> {
+    if !(MIN_BITRATE..=MAX_BITRATE).contains(&bitrate) {
+        bail!("bitrate must be between {MIN_BITRATE} and {MAX_BITRATE} kbps, got {bitrate}");
+    }
+    Ok(())
+}
+
+// mime type to serve a stored file with
+pub fn content_type(container: &str) -> &'static str {
+    match container {
+        "qoa" => super::qoa::CONTENT_TYPE,
+        "flac" => "audio/flac",
+        "ogg" | "oga" | "opus" => "audio/ogg",
+        "mp3" => "audio/mpeg",
+        "m4a" | "mp4" | "aac" => "audio/mp4",
+        "wav" => "audio/wav",
+        "webm" => "audio/webm",
+        _ => "application/octet-stream",
+    }
+}
+
+// the part of a gstreamer pipeline that encodes raw audio into a codec
+fn encoder_desc(codec: Codec, bitrate: Option<u32>) -> anyhow::Result<String> {
+    Ok(match codec {
+        Codec::Flac => "flacenc".to_owned(),
+        Codec::Opus => format!(
+            "opusenc bitrate={} ! oggmux",
+            bitrate.ok_or_else(|| anyhow!("opus needs a bitrate"))? * 1000
+        ),
+        Codec::Original | Codec::Qoa => bail!("{codec:?} is not encoded with gstreamer"),
+    })
+}
+
+// encode a file into another with gstreamer. this blocks until finished.
+pub fn encode_file(input: &Path, output: &Path, format: &StorageFormat) -> anyhow::Result<()> {
+    let desc = format!(
+        "filesrc name=src ! decodebin ! audioconvert ! audioresample ! {} ! filesink name=sink",
+        encoder_desc(format.codec, format.bitrate)?
+    );
+    let pipeline = launch(&desc)?;
+    for (name, path) in [("src", input), ("sink", output)] {
+        pipeline
+            .by_name(name)
+            .ok_or_else(|| anyhow!("pipeline is missing {name}"))?
+            .set_property(
+                "location",
+                path.to_str()
+                    .ok_or_else(|| anyhow!("path {path:?} is not valid UTF-8"))?,
+            );
+    }
+    run_pipeline(&pipeline)
+}
+
+// transcode a stored file, sending out the encoded output as it is produced. the
+// transcode is stopped when the receiver is dropped.
+pub async fn stream(
+    data: Vec<u8>,
+    stored: Codec,
+    format: StorageFormat,
+) -> anyhow::Result<mpsc::Receiver<anyhow::Result<Bytes>>> {
+    let permit = TRANSCODES.clone().acquire_owned().await?;
+    let (tx, rx) = mpsc::channel(STREAM_BUFFER);
+    let (tx_ready, rx_ready) = oneshot::channel();
+    tokio::task::spawn_blocking(move || {
+        let _permit = permit;
+        let (pipeline, sink) = match start_stream(data, stored, &format) {
+            Ok(x) => {
+                drop(tx_ready.send(Ok(())));
+                x
+            }
+            Err(err) => {
+                drop(tx_ready.send(Err(err)));
+                return;
+            }
+        };
+        if let Err(err) = pump(&pipeline, &sink, &tx) {
+            debug!("TRANSCODE stream failed: {err}");
+            drop(tx.blocking_send(Err(err)));
+        }
+        if let Err(err) = pipeline.set_state(gstreamer::State::Null) {
+            warn!("TRANSCODE failed to stop pipeline: {err}");
+        }
+    });
+    rx_ready
+        .await
+        .map_err(|_| anyhow!("transcode task died before starting"))??;
+    Ok(rx)
+}
+
+// build and start a pipeline that reads from memory and encodes into an appsink
+fn start_stream(
+    data: Vec<u8>,
+    stored: Codec,
+    format: &StorageFormat,
+) -> anyhow::Result<(gstreamer::Pipeline, gstreamer_app::AppSink)> {
+    // gstreamer doesn't know about qoa, so it's decoded here and fed in as raw audio
+    let (head, data, caps) = match stored {
+        Codec::Qoa => {
+            let (channels, samplerate, samples) = super::qoa::decode(&data)?;
+            let caps = gstreamer_audio::AudioInfo::builder(
+                gstreamer_audio::AudioFormat::S16le,
+                samplerate,
+                channels,
+            )
+            .build()?
+            .to_caps()?;
+            let data = samples
+                .into_iter()
+                .flat_map(|x| x.to_le_bytes())
+                .collect::<Vec<_>>();
+            ("appsrc name=src format=time", data, Some(caps))
+        }
+        _ => ("appsrc name=src ! decodebin", data, None),
+    };
+    let desc = format!(
+        "{head} ! audioconvert ! audioresample ! {} ! appsink name=sink sync=false",
+        encoder_desc(format.codec, format.bitrate)?
+    );
+    let pipeline = launch(&desc)?;
+    let src = pipeline
+        .by_name("src")
+        .ok_or_else(|| anyhow!("pipeline is missing src"))?
+        .downcast::<gstreamer_app::AppSrc>()
+        .map_err(|_| anyhow!("src is not an appsrc"))?;
+    let sink = pipeline
+        .by_name("sink")
+        .ok_or_else(|| anyhow!("pipeline is missing sink"))?
+        .downcast::<gstreamer_app::AppSink>()
+        .map_err(|_| anyhow!("sink is not an appsink"))?;
+    if let Some(caps) = caps {
+        src.set_caps(Some(&caps));
+    }
+    src.push_buffer(gstreamer::Buffer::from_slice(data))?;
+    src.end_of_stream()?;
+    pipeline.set_state(gstreamer::State::Playing)?;
+    Ok((pipeline, sink))
+}
+
+// move encoded output from the appsink to the receiver until either side is done
+fn pump(
+    pipeline: &gstreamer::Pipeline,
+    sink: &gstreamer_app::AppSink,
+    tx: &mpsc::Sender<anyhow::Result<Bytes>>,
+) -> anyhow::Result<()> {
+    while let Ok(sample) = sink.pull_sample() {
+        let buf = sample
+            .buffer()
+            .ok_or_else(|| anyhow!("sample did not have a buffer"))?;
+        let map = buf.map_readable()?;
+        if tx.blocking_send(Ok(Bytes::copy_from_slice(&map))).is_err() {
+            trace!("TRANSCODE receiver went away, stopping");
+            return Ok(());
+        }
+    }
+    if sink.is_eos() {
+        return Ok(());
+    }
+    Err(pipeline
+        .bus()
+        .and_then(|bus| bus.pop_filtered(&[gstreamer::MessageType::Error]))
+        .and_then(|msg| message_error(&msg))
+        .unwrap_or_else(|| anyhow!("pipeline stopped before finishing")))
+}
+
+fn launch(desc: &str) -> anyhow::Result<gstreamer::Pipeline> {
+    trace!("TRANSCODE launching \"{desc}\"");
+    gstreamer::parse::launch(desc)?
+        .downcast::<gstreamer::Pipeline>()
+        .map_err(|_| anyhow!("launched element was not a pipeline"))
+}
+
+fn message_error(msg: &gstreamer::Message) -> Option<anyhow::Error> {
+    match msg.view() {
+        gstreamer::MessageView::Error(err) => Some(anyhow!(
+            "error from {:?}: {} ({:?})",
+            err.src().map(|x| x.path_string()),
+            err.error(),
+            err.debug()
+        )),
+        _ => None,
+    }
+}
+
+// run a pipeline until it ends
+fn run_pipeline(pipeline: &gstreamer::Pipeline) -> anyhow::Result<()> {
+    pipeline.set_state(gstreamer::State::Playing)?;
+    let bus = pipeline
+        .bus()
+        .ok_or_else(|| anyhow!("pipeline does not have a bus"))?;
+    let mut ret = Err(anyhow!("pipeline stopped before finishing"));
+    for msg in bus.iter_timed(gstreamer::ClockTime::NONE) {
+        if let gstreamer::MessageView::Eos(..) = msg.view() {
+            ret = Ok(());
+            break;
+        }
+        if let Some(err) = message_error(&msg) {
+            ret = Err(err);
+            break;
+        }
+    }
+    pipeline.set_state(gstreamer::State::Null)?;
+    ret
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn transcode_storage_format() {
+        crate::STORAGE_BITRATE.get_or_init(|| crate::DEFAULT_STORAGE_BITRATE);
+        let opus = StorageFormat::new(Codec::Opus, None, "a.flac").unwrap();
+        assert_eq!(opus.bitrate, Some(crate::DEFAULT_STORAGE_BITRATE));
+        assert_eq!(opus.container, "ogg");
+        assert!(StorageFormat::new(Codec::Opus, Some(1000), "a.flac").is_err());
+
+        // bitrates only matter for lossy codecs
+        let flac = StorageFormat::new(Codec::Flac, Some(96), "a.mp3").unwrap();
+        assert_eq!(flac.bitrate, None);
+
+        // originals keep their extension, if it's sane
+        let orig = StorageFormat::new(Codec::Original, None, "Some Song.MP3").unwrap();
+        assert_eq!(orig.container, "mp3");
+        assert_eq!(content_type(&orig.container), "audio/mpeg");
+        let orig = StorageFormat::new(Codec::Original, None, "no extension").unwrap();
+        assert_eq!(orig.container, "bin");
+    }
+}
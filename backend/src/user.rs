@@ -1,8 +1,9 @@
 use crate::db::{uuid_serialize, write_transaction};
+use crate::ratelimit::Limiter;
 use crate::{MioInnerError, MioState, MioStateRegen};
 use anyhow::anyhow;
 use argon2::{Argon2, PasswordHash, PasswordHasher, PasswordVerifier};
-use axum::extract::{FromRequestParts, State};
+use axum::extract::{ConnectInfo, FromRequestParts, Query, State};
 use axum::http::request::Parts;
 use axum::http::StatusCode;
 use axum::response::IntoResponse;
@@ -15,12 +16,18 @@ use log::*;
 use mio_protocol::*;
 use sqlx::SqliteConnection;
 use std::fmt::Debug;
+use std::net::SocketAddr;
+use tokio::task::JoinHandle;
 use uuid::Uuid;
 
 const SECRET_SIZE: usize = 1024;
 
 pub(crate) struct Authenticate;
 
+// id of the session (auth key) that authenticated the request
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct CurrentSession(pub Uuid);
+
 #[async_trait]
 impl<S> FromRequestParts<S> for Authenticate
 where
@@ -45,6 +52,19 @@ where
                 StatusCode::BAD_REQUEST,
             ));
         };
+
+        // api keys are looked up directly
+        if raw_token.starts_with(crate::apikey::KEY_PREFIX) {
+            let mut conn = state.db.acquire().await?;
+            let (userid, key) = crate::apikey::authenticate(&mut conn, raw_token).await?;
+            req.extensions.insert(auth::JWTInner {
+                userid,
+                // api keys do not expire
+                exp: i64::MAX,
+            });
+            req.extensions.insert(key);
+            return Ok(Authenticate);
+        }
         let potent_token = auth::JWT::from_raw(raw_token.to_owned());
 
         // get secrets
@@ -61,9 +81,10 @@ where
         let mut conn = state.db.acquire().await?;
         let now = Utc::now().timestamp();
         let secrets = sqlx::query!(
-            "SELECT secret 
+            "SELECT session, secret, last_used 
             FROM auth_keys 
-            WHERE id = ? AND expiry > ? 
+            JOIN user ON user.id = auth_keys.id 
+            WHERE auth_keys.id = ? AND expiry > ? AND user.disabled = FALSE 
             ORDER BY expiry ASC;",
             potent_id,
             now
@@ -75,47 +96,116 @@ where
         let tasks = secrets
             .into_iter()
             .map(|x| {
-                tokio::task::spawn_blocking({
-                    let potent_token = potent_token.clone();
-                    move || potent_token.decode(&x.secret)
-                })
+                (
+                    (x.session, x.last_used),
+                    tokio::task::spawn_blocking({
+                        let potent_token = potent_token.clone();
+                        move || potent_token.decode(&x.secret)
+                    }),
+                )
             })
             .collect::<Vec<_>>();
         let mut auth = None;
-        for task in tasks {
+        for (session, task) in tasks {
             match task.await? {
                 Ok(ret) => {
-                    auth = Some(ret);
+                    auth = Some((session, ret));
                     break;
                 }
                 Err(err) => debug!("USER_INJ could not auth token: {err}"),
             }
         }
-        if auth.is_none() {
+        let Some(((session, last_used), auth)) = auth else {
             return Err(MioInnerError::UserChallengedFail(
                 anyhow!("Invalid auth token"),
                 StatusCode::UNAUTHORIZED,
             ));
+        };
+
+        // record usage of the session. this only needs to be roughly right, so most
+        // requests are left as reads
+        if last_used < now - 60 {
+            let session = session.clone();
+            write_transaction(&mut conn, |txn| {
+                Box::pin(async move {
+                    sqlx::query!(
+                        "UPDATE auth_keys SET last_used = ? WHERE session = ?;",
+                        now,
+                        session
+                    )
+                    .execute(&mut *txn)
+                    .await?;
+                    Ok(())
+                })
+            })
+            .await?;
         }
 
         // inject user
-        if let Some(item) = req.extensions.insert(auth.unwrap().claims) {
+        if let Some(item) = req.extensions.insert(auth.claims) {
             warn!(
                 "USER_INJ while injecting user: user of {} existed, replacing.",
                 item.userid
             );
         }
+        req.extensions
+            .insert(CurrentSession(uuid_serialize(&session)?));
         Ok(Authenticate)
     }
 }
 
+// only lets through users that are admins, must be layered under `Authenticate`
+pub(crate) struct RequireAdmin;
+
+#[async_trait]
+impl<S> FromRequestParts<S> for RequireAdmin
+where
+    S: Send + Sync + MioStateRegen + Debug,
+{
+    type Rejection = MioInnerError;
+
+    #[tracing::instrument(name = "check_admin")]
+    async fn from_request_parts(req: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let state = state.get_self();
+        let userid = req
+            .extensions
+            .get::<auth::JWTInner>()
+            .ok_or_else(|| {
+                MioInnerError::Panicked(anyhow!("admin check was run without authentication"))
+            })?
+            .userid;
+        let mut conn = state.db.acquire().await?;
+        let admin = sqlx::query!("SELECT admin FROM user WHERE id = ?;", userid)
+            .fetch_optional(&mut *conn)
+            .await?
+            .is_some_and(|x| x.admin != 0);
+        if !admin {
+            debug!("ADMIN_CHK {userid} is not an admin");
+            return Err(MioInnerError::UserChallengedFail(
+                anyhow!("This action requires an admin"),
+                StatusCode::FORBIDDEN,
+            ));
+        }
+        Ok(RequireAdmin)
+    }
+}
+
 #[tracing::instrument]
 pub async fn login(
     State(state): State<MioState>,
+    connect_info: Option<ConnectInfo<SocketAddr>>,
     TypedHeader(auth): TypedHeader<Authorization<Basic>>,
+    login_query: Option<Query<msgstructs::LoginQuery>>,
 ) -> Result<impl IntoResponse, MioInnerError> {
+    let device = login_query.and_then(|Query(x)| x.device);
     let mut conn = state.db.acquire().await?;
-    write_transaction(&mut conn, |txn| {
+
+    // check for lockouts before doing any expensive hashing
+    let limiter = Limiter::new()
+        .ip(connect_info.map(|ConnectInfo(x)| x.ip()))
+        .username(auth.username());
+    limiter.check(&mut conn).await?;
+    let ret = write_transaction(&mut conn, |txn| {
         Box::pin(async move {
             // get user
             let username = auth.username();
@@ -131,69 +221,173 @@ pub async fn login(
             let userid = uuid_serialize(&user.id)?;
 
             // check hash
-            let passwd = user.password.to_owned();
-            tokio::task::spawn_blocking({
-                move || {
-                    let parsed = PasswordHash::new(&passwd).map_err(|err| {
-                        MioInnerError::UserChallengedFail(
-                            anyhow!("Unable to extract phc string: {err}"),
-                            StatusCode::INTERNAL_SERVER_ERROR,
-                        )
-                    })?;
-                    Argon2::default()
-                        .verify_password(auth.password().to_owned().as_bytes(), &parsed)
-                        .map_err(|_| {
-                            MioInnerError::UserChallengedFail(
-                                anyhow!("Unable to verify user on server"),
-                                StatusCode::UNAUTHORIZED,
-                            )
-                        })
-                }
-            })
-            .await??;
+            verify_password(user.password, auth.password().to_owned()).await?;
+            if user.disabled != 0 {
+                return Err(MioInnerError::UserChallengedFail(
+                    anyhow!("This account has been disabled"),
+                    StatusCode::FORBIDDEN,
+                ));
+            }
 
             // generate new token
-            let token = create_new_token(&mut *txn, userid).await?;
+            let token = create_new_token(&mut *txn, userid, device, Utc::now().timestamp()).await?;
             debug!("GET /user/login new token generated for {userid:?}");
             Ok((StatusCode::OK, Json(token)))
         })
     })
-    .await
+    .await;
+    match ret {
+        Ok(_) => limiter.succeed(&mut conn).await?,
+        Err(MioInnerError::UserChallengedFail(_, _)) => limiter.fail(&mut conn).await?,
+        Err(_) => (),
+    }
+    ret
+}
+
+// util function to check a password against a phc string
+async fn verify_password(phc_string: String, passwd: String) -> Result<(), MioInnerError> {
+    tokio::task::spawn_blocking(move || {
+        let parsed = PasswordHash::new(&phc_string).map_err(|err| {
+            MioInnerError::UserChallengedFail(
+                anyhow!("Unable to extract phc string: {err}"),
+                StatusCode::INTERNAL_SERVER_ERROR,
+            )
+        })?;
+        Argon2::default()
+            .verify_password(passwd.as_bytes(), &parsed)
+            .map_err(|_| {
+                MioInnerError::UserChallengedFail(
+                    anyhow!("Unable to verify user on server"),
+                    StatusCode::UNAUTHORIZED,
+                )
+            })
+    })
+    .await?
+}
+
+// util function to argon2 a password into a phc string
+pub(crate) fn hash_password(passwd: String) -> JoinHandle<Result<String, MioInnerError>> {
+    tokio::task::spawn_blocking(move || {
+        let salt = argon2::password_hash::SaltString::generate(&mut rand::rngs::OsRng);
+        let ret = Argon2::default()
+            .hash_password(passwd.as_bytes(), &salt)
+            .map_err(|err| {
+                MioInnerError::UserCreationFail(
+                    anyhow!("could not generate phc string: {err}"),
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                )
+            })?;
+        Ok(ret.to_string())
+    })
 }
 
 // create new token
 pub async fn new_token(
     State(state): State<MioState>,
     Extension(auth::JWTInner { userid, .. }): Extension<auth::JWTInner>,
+    Extension(CurrentSession(session)): Extension<CurrentSession>,
 ) -> Result<(StatusCode, Json<auth::JWT>), MioInnerError> {
     let mut conn = state.db.acquire().await?;
-    Ok((
-        StatusCode::OK,
-        Json(create_new_token(&mut conn, userid).await?),
-    ))
+    write_transaction(&mut conn, |txn| {
+        Box::pin(async move {
+            let current = sqlx::query!(
+                "SELECT name, created FROM auth_keys WHERE session = ? AND id = ?;",
+                session,
+                userid
+            )
+            .fetch_optional(&mut *txn)
+            .await?
+            .ok_or_else(|| {
+                MioInnerError::UserChallengedFail(
+                    anyhow!("session was revoked during refresh"),
+                    StatusCode::UNAUTHORIZED,
+                )
+            })?;
+
+            // sessions cannot be refreshed forever
+            if Utc::now().timestamp() >= current.created + crate::SESSION_MAX_AGE.get().unwrap() {
+                return Err(MioInnerError::UserChallengedFail(
+                    anyhow!("session is too old to be refreshed, please log in again"),
+                    StatusCode::UNAUTHORIZED,
+                ));
+            }
+            let token = if *crate::REFRESH_EXTENDS_SESSION.get().unwrap() {
+                debug!("PATCH /user/refresh extending session {session}");
+                extend_token(&mut *txn, userid, session, current.created).await?
+            } else {
+                // carry over the device name and login time to the new session
+                debug!("PATCH /user/refresh creating new session from {session}");
+                create_new_token(&mut *txn, userid, current.name, current.created).await?
+            };
+            Ok((StatusCode::OK, Json(token)))
+        })
+    })
+    .await
+}
+
+// util function for when a token issued now should expire, given when the session
+// started
+fn token_expiry(now: i64, created: i64) -> i64 {
+    (now + crate::TOKEN_LIFETIME.get().unwrap())
+        .min(created + crate::SESSION_MAX_AGE.get().unwrap())
 }
 
 // util function for creating new secret in db
 async fn create_new_token(
     conn: &mut SqliteConnection,
     userid: Uuid,
+    name: Option<String>,
+    created: i64,
 ) -> Result<auth::JWT, MioInnerError> {
-    // TODO: let server host specify when the logout tokens expire
     let secret: [u8; SECRET_SIZE] = rand::random();
     let slice = secret.as_slice();
-    let exp = Utc::now()
-        .checked_add_days(chrono::Days::new(7))
-        .unwrap()
-        .timestamp();
+    let session = Uuid::new_v4();
+    let now = Utc::now().timestamp();
+    let exp = token_expiry(now, created);
     sqlx::query!(
-        "INSERT INTO auth_keys (id, expiry, secret) VALUES (?,?,?);",
+        "INSERT INTO auth_keys (session, id, expiry, secret, name, created, last_used) 
+        VALUES (?,?,?,?,?,?,?);",
+        session,
         userid,
         exp,
-        slice
+        slice,
+        name,
+        created,
+        now
+    )
+    .execute(conn)
+    .await?;
+    sign_token(userid, exp, &secret)
+}
+
+// util function for replacing the secret of an existing session, pushing back it's
+// expiry. the old token stops working.
+async fn extend_token(
+    conn: &mut SqliteConnection,
+    userid: Uuid,
+    session: Uuid,
+    created: i64,
+) -> Result<auth::JWT, MioInnerError> {
+    let secret: [u8; SECRET_SIZE] = rand::random();
+    let slice = secret.as_slice();
+    let now = Utc::now().timestamp();
+    let exp = token_expiry(now, created);
+    sqlx::query!(
+        "UPDATE auth_keys SET secret = ?, expiry = ?, last_used = ? 
+        WHERE session = ? AND id = ?;",
+        slice,
+        exp,
+        now,
+        session,
+        userid
     )
     .execute(conn)
     .await?;
-    auth::JWT::new(auth::JWTInner { userid, exp }, &secret).map_err(|err| {
+    sign_token(userid, exp, &secret)
+}
+
+fn sign_token(userid: Uuid, exp: i64, secret: &[u8]) -> Result<auth::JWT, MioInnerError> {
+    auth::JWT::new(auth::JWTInner { userid, exp }, secret).map_err(|err| {
         MioInnerError::UserChallengedFail(
             anyhow::anyhow!("failed to generate JWT for user: {err}"),
             StatusCode::INTERNAL_SERVER_ERROR,
@@ -201,13 +395,119 @@ async fn create_new_token(
     })
 }
 
+// list all sessions that are still valid
+#[tracing::instrument]
+pub async fn list_sessions(
+    State(state): State<MioState>,
+    Extension(auth::JWTInner { userid, .. }): Extension<auth::JWTInner>,
+    Extension(CurrentSession(current)): Extension<CurrentSession>,
+) -> Result<impl IntoResponse, MioInnerError> {
+    let mut conn = state.db.acquire().await?;
+    let now = Utc::now().timestamp();
+    let sessions = sqlx::query!(
+        "SELECT session, name, created, last_used, expiry 
+        FROM auth_keys 
+        WHERE id = ? AND expiry > ? 
+        ORDER BY created ASC;",
+        userid,
+        now
+    )
+    .fetch_all(&mut *conn)
+    .await?
+    .into_iter()
+    .map(|x| {
+        let id = uuid_serialize(&x.session)?;
+        Ok(retstructs::Session {
+            id,
+            name: x.name,
+            created: x.created,
+            last_used: x.last_used,
+            expiry: x.expiry,
+            current: id == current,
+        })
+    })
+    .collect::<Result<_, MioInnerError>>()?;
+    Ok((StatusCode::OK, Json(retstructs::Sessions { sessions })))
+}
+
+// give a session a new device name
+#[tracing::instrument]
+pub async fn rename_session(
+    State(state): State<MioState>,
+    Extension(auth::JWTInner { userid, .. }): Extension<auth::JWTInner>,
+    Query(msgstructs::SessionRename { id, name }): Query<msgstructs::SessionRename>,
+) -> Result<impl IntoResponse, MioInnerError> {
+    let mut conn = state.db.acquire().await?;
+    if sqlx::query!(
+        "UPDATE auth_keys SET name = ? WHERE session = ? AND id = ?;",
+        name,
+        id,
+        userid
+    )
+    .execute(&mut *conn)
+    .await?
+    .rows_affected()
+        == 0
+    {
+        return Err(MioInnerError::NotFound(anyhow!(
+            "session {id} does not exist for {userid}"
+        )));
+    }
+    Ok(StatusCode::OK)
+}
+
+// revoke a single session
+#[tracing::instrument]
+pub async fn revoke_session(
+    State(state): State<MioState>,
+    Extension(auth::JWTInner { userid, .. }): Extension<auth::JWTInner>,
+    Query(msgstructs::DeleteQuery { id }): Query<msgstructs::DeleteQuery>,
+) -> Result<impl IntoResponse, MioInnerError> {
+    let mut conn = state.db.acquire().await?;
+    if sqlx::query!(
+        "DELETE FROM auth_keys WHERE session = ? AND id = ?;",
+        id,
+        userid
+    )
+    .execute(&mut *conn)
+    .await?
+    .rows_affected()
+        == 0
+    {
+        return Err(MioInnerError::NotFound(anyhow!(
+            "session {id} does not exist for {userid}"
+        )));
+    }
+    debug!("DELETE /user/sessions revoked session {id} for {userid}");
+    Ok(StatusCode::OK)
+}
+
+// log out everywhere, including the session used for this request
+#[tracing::instrument]
+pub async fn revoke_all_sessions(
+    State(state): State<MioState>,
+    Extension(auth::JWTInner { userid, .. }): Extension<auth::JWTInner>,
+) -> Result<impl IntoResponse, MioInnerError> {
+    let mut conn = state.db.acquire().await?;
+    let revoked = sqlx::query!("DELETE FROM auth_keys WHERE id = ?;", userid)
+        .execute(&mut *conn)
+        .await?
+        .rows_affected();
+    debug!("DELETE /user/sessions/all revoked {revoked} sessions for {userid}");
+    Ok(StatusCode::OK)
+}
+
 // TODO: is this a good idea to be an endpoint or to have a private thing?
 #[tracing::instrument]
 pub async fn signup(
     State(state): State<MioState>,
+    connect_info: Option<ConnectInfo<SocketAddr>>,
     TypedHeader(auth): TypedHeader<Authorization<Basic>>,
+    signup_query: Option<Query<msgstructs::SignupQuery>>,
 ) -> Result<impl IntoResponse, MioInnerError> {
-    if !crate::env::SIGNUP_ENABLED.get().unwrap() {
+    // an invite lets users sign up even when it's disabled
+    let invite = signup_query.and_then(|Query(x)| x.invite);
+    if !crate::env::SIGNUP_ENABLED.get().unwrap() && invite.is_none() {
         return Err(MioInnerError::UserCreationFail(
             anyhow!("signing up is currently disabled"),
             StatusCode::FORBIDDEN,
@@ -216,70 +516,270 @@ pub async fn signup(
     let uname = auth.username().to_owned();
     let passwd = auth.password().to_owned();
 
+    // signups are only limited by ip, as failures here are things like guessing invites
+    let mut conn = state.db.acquire().await?;
+    let limiter = Limiter::new().ip(connect_info.map(|ConnectInfo(x)| x.ip()));
+    limiter.check(&mut conn).await?;
+
     // argon2 the password
-    let phc_string = tokio::task::spawn_blocking(move || {
-        debug!("POST /user/signup generating phc string");
-        let salt = argon2::password_hash::SaltString::generate(&mut rand::rngs::OsRng);
-        let ret = Argon2::default()
-            .hash_password(passwd.as_bytes(), &salt)
-            .map_err(|err| {
-                MioInnerError::UserCreationFail(
-                    anyhow!("could not generate phc string: {err}"),
-                    StatusCode::INTERNAL_SERVER_ERROR,
-                )
-            })?;
-        Ok::<_, MioInnerError>(ret.to_string())
-    });
+    debug!("POST /user/signup generating phc string");
+    let phc_string = hash_password(passwd);
 
     // then put into db and create dir
-    let mut conn = state.db.acquire().await?;
-    write_transaction(&mut conn, |txn| {
+    let ret = write_transaction(&mut conn, |txn| {
         Box::pin(async move {
-            // setup user
             debug!("POST /user/signup transaction begin");
-            if sqlx::query!("SELECT * FROM user WHERE username = ?;", uname)
-                .fetch_optional(&mut *txn)
-                .await?
-                .is_some()
-            {
-                phc_string.abort();
-                drop(phc_string);
-                return Err(MioInnerError::UserCreationFail(
-                    anyhow!("Username already taken."),
-                    StatusCode::CONFLICT,
-                ));
-            }
 
-            // generate uuid
-            let uid = loop {
-                let uid = Uuid::new_v4();
-                if sqlx::query!("SELECT * FROM user WHERE id = ?;", uid)
+            // use up the invite
+            let invite = match invite {
+                Some(code) => {
+                    let now = Utc::now().timestamp();
+                    let id = sqlx::query!(
+                        "SELECT id FROM invite WHERE code = ? AND uses_left > 0 AND expiry > ?;",
+                        code,
+                        now
+                    )
                     .fetch_optional(&mut *txn)
                     .await?
-                    .is_none()
-                {
-                    break uid;
+                    .ok_or_else(|| {
+                        MioInnerError::UserCreationFail(
+                            anyhow!("invite is invalid, used up, or expired"),
+                            StatusCode::FORBIDDEN,
+                        )
+                    })?
+                    .id;
+                    sqlx::query!(
+                        "UPDATE invite SET uses_left = uses_left - 1 WHERE id = ?;",
+                        id
+                    )
+                    .execute(&mut *txn)
+                    .await?;
+                    Some(uuid_serialize(&id)?)
                 }
+                None => None,
             };
+            create_user(&mut *txn, uname, phc_string, false, invite).await?;
+            Ok(StatusCode::OK)
+        })
+    })
+    .await;
+    if let Err(MioInnerError::UserCreationFail(_, code)) = ret.as_ref() {
+        if code.is_client_error() {
+            limiter.fail(&mut conn).await?;
+        }
+    }
+    ret
+}
+
+// util function to create a user in the db and it's directory
+pub(crate) async fn create_user(
+    txn: &mut SqliteConnection,
+    uname: String,
+    phc_string: JoinHandle<Result<String, MioInnerError>>,
+    admin: bool,
+    invite: Option<Uuid>,
+) -> Result<Uuid, MioInnerError> {
+    // setup user
+    if sqlx::query!("SELECT * FROM user WHERE username = ?;", uname)
+        .fetch_optional(&mut *txn)
+        .await?
+        .is_some()
+    {
+        phc_string.abort();
+        drop(phc_string);
+        return Err(MioInnerError::UserCreationFail(
+            anyhow!("Username already taken."),
+            StatusCode::CONFLICT,
+        ));
+    }
+
+    // generate uuid
+    let uid = loop {
+        let uid = Uuid::new_v4();
+        if sqlx::query!("SELECT * FROM user WHERE id = ?;", uid)
+            .fetch_optional(&mut *txn)
+            .await?
+            .is_none()
+        {
+            break uid;
+        }
+    };
+    let phc_string = phc_string.await??;
+    sqlx::query!(
+        "INSERT INTO user (id, username, password, admin, invite) VALUES (?,?,?,?,?);",
+        uid,
+        uname,
+        phc_string,
+        admin,
+        invite
+    )
+    .execute(&mut *txn)
+    .await?;
+
+    // create the user dir if not exists
+    if let Err(err) =
+        { tokio::fs::create_dir(crate::DATA_DIR.get().unwrap().join(format!("{uid}"))).await }
+    {
+        if err.kind() != std::io::ErrorKind::AlreadyExists {
+            error!("CREATE_USER failed to create user directory: {err}");
+            return Err(MioInnerError::InternalIoError(anyhow!(
+                "Failed to create user dir: {err}"
+            )));
+        }
+    }
+    if *crate::INBOX_ENABLED.get().unwrap() {
+        if let Err(err) = tokio::fs::create_dir_all(crate::subtasks::inbox::inbox_dir(uid)).await {
+            error!("CREATE_USER failed to create inbox: {err}");
+            return Err(MioInnerError::InternalIoError(anyhow!(
+                "Failed to create inbox: {err}"
+            )));
+        }
+    }
+    Ok(uid)
+}
+
+// change password, logging out every other session
+#[tracing::instrument]
+pub async fn change_password(
+    State(state): State<MioState>,
+    Extension(auth::JWTInner { userid, .. }): Extension<auth::JWTInner>,
+    Extension(CurrentSession(session)): Extension<CurrentSession>,
+    Json(msgstructs::PasswordChange {
+        old_password,
+        new_password,
+    }): Json<msgstructs::PasswordChange>,
+) -> Result<impl IntoResponse, MioInnerError> {
+    let phc_string = hash_password(new_password);
+    let mut conn = state.db.acquire().await?;
+    write_transaction(&mut conn, |txn| {
+        Box::pin(async move {
+            let user = sqlx::query!("SELECT password FROM user WHERE id = ?;", userid)
+                .fetch_one(&mut *txn)
+                .await?;
+            if let Err(err) = verify_password(user.password, old_password).await {
+                phc_string.abort();
+                return Err(err);
+            }
             let phc_string = phc_string.await??;
             sqlx::query!(
-                "INSERT INTO user (id, username, password) VALUES (?,?,?);",
-                uid,
-                uname,
-                phc_string
+                "UPDATE user SET password = ? WHERE id = ?;",
+                phc_string,
+                userid
             )
             .execute(&mut *txn)
             .await?;
+            let revoked = sqlx::query!(
+                "DELETE FROM auth_keys WHERE id = ? AND session != ?;",
+                userid,
+                session
+            )
+            .execute(&mut *txn)
+            .await?
+            .rows_affected();
+            debug!(
+                "PATCH /user/password changed password for {userid}, revoked {revoked} sessions"
+            );
+            Ok(StatusCode::OK)
+        })
+    })
+    .await
+}
 
-            // create the user dir if not exists
-            if let Err(err) = {
-                tokio::fs::create_dir(crate::DATA_DIR.get().unwrap().join(&format!("{uid}"))).await
-            } {
-                if err.kind() != std::io::ErrorKind::AlreadyExists {
-                    error!("POST /user/signup failed to create user directory: {err}");
-                    return Err(MioInnerError::InternalIoError(anyhow!(
-                        "Failed to create user dir: {err}"
-                    )));
+// delete the user, and everything that they own
+#[tracing::instrument]
+pub async fn delete_account(
+    State(state): State<MioState>,
+    connect_info: Option<ConnectInfo<SocketAddr>>,
+    Extension(auth::JWTInner { userid, .. }): Extension<auth::JWTInner>,
+    Json(msgstructs::AccountDelete { password }): Json<msgstructs::AccountDelete>,
+) -> Result<impl IntoResponse, MioInnerError> {
+    // the password is checked before anything is locked, as hashing is slow. wrong
+    // passwords count towards lockouts like they do for logging in.
+    let mut conn = state.db.acquire().await?;
+    let user = sqlx::query!("SELECT username, password FROM user WHERE id = ?;", userid)
+        .fetch_one(&mut *conn)
+        .await?;
+    let limiter = Limiter::new()
+        .ip(connect_info.map(|ConnectInfo(x)| x.ip()))
+        .username(&user.username);
+    limiter.check(&mut conn).await?;
+    if let Err(err) = verify_password(user.password, password).await {
+        limiter.fail(&mut conn).await?;
+        return Err(err);
+    }
+
+    trace!("DELETE /user/account locking write dir");
+    let _hold = state.lock_files.write().await;
+    write_transaction(&mut conn, |txn| {
+        Box::pin(async move {
+            // remove everything that references the user before the user itself
+            debug!("DELETE /user/account removing all data for {userid}");
+            sqlx::query!(
+                "DELETE FROM JOIN_playlist_track 
+                WHERE playlist IN (SELECT id FROM playlist WHERE owner = ?) 
+                OR track IN (SELECT id FROM track WHERE owner = ?);",
+                userid,
+                userid
+            )
+            .execute(&mut *txn)
+            .await?;
+            sqlx::query!("DELETE FROM playlist WHERE owner = ?;", userid)
+                .execute(&mut *txn)
+                .await?;
+            sqlx::query!(
+                "DELETE FROM JOIN_track_artist
+                WHERE track IN (SELECT id FROM track WHERE owner = ?);",
+                userid
+            )
+            .execute(&mut *txn)
+            .await?;
+            crate::cache::invalidate_owner(&mut *txn, userid).await?;
+            sqlx::query!("DELETE FROM track WHERE owner = ?;", userid)
+                .execute(&mut *txn)
+                .await?;
+            sqlx::query!("DELETE FROM auth_keys WHERE id = ?;", userid)
+                .execute(&mut *txn)
+                .await?;
+            sqlx::query!("DELETE FROM upload_job WHERE owner = ?;", userid)
+                .execute(&mut *txn)
+                .await?;
+            sqlx::query!("DELETE FROM upload_session WHERE owner = ?;", userid)
+                .execute(&mut *txn)
+                .await?;
+            sqlx::query!(
+                "DELETE FROM import_file WHERE import IN (SELECT id FROM import WHERE owner = ?);",
+                userid
+            )
+            .execute(&mut *txn)
+            .await?;
+            sqlx::query!("DELETE FROM import WHERE owner = ?;", userid)
+                .execute(&mut *txn)
+                .await?;
+            sqlx::query!("DELETE FROM api_key WHERE owner = ?;", userid)
+                .execute(&mut *txn)
+                .await?;
+            sqlx::query!(
+                "UPDATE invite SET creator = NULL WHERE creator = ?;",
+                userid
+            )
+            .execute(&mut *txn)
+            .await?;
+            sqlx::query!("DELETE FROM user WHERE id = ?;", userid)
+                .execute(&mut *txn)
+                .await?;
+
+            // and then their files. if this fails, the user still exists, so their
+            // library goes last to not leave them without it.
+            for dir in [
+                crate::subtasks::inbox::inbox_dir(userid),
+                crate::subtasks::inbox::quarantine_dir(userid),
+                crate::DATA_DIR.get().unwrap().join(format!("{userid}")),
+            ] {
+                if let Err(err) = tokio::fs::remove_dir_all(&dir).await {
+                    if err.kind() != std::io::ErrorKind::NotFound {
+                        error!("DELETE /user/account failed to remove {dir:?}: {err}");
+                        return Err(MioInnerError::from(err));
+                    }
                 }
             }
             Ok(StatusCode::OK)
@@ -381,4 +881,327 @@ mod test {
             .json::<auth::JWT>();
         jwt_header(&cli, Method::GET, "/api/auth_test", &new_jwt).await;
     }
+
+    #[tokio::test]
+    async fn user_refresh_new_session_good() {
+        let cli = client().await;
+        let jwt = gen_user(&cli, "user_refresh_new_session_good").await;
+        let new_jwt = jwt_header(&cli, Method::PATCH, "/user/refresh", &jwt)
+            .await
+            .json::<auth::JWT>();
+
+        // both sessions are usable, and the new one started when the old one did
+        jwt_header(&cli, Method::GET, "/api/auth_test", &jwt).await;
+        jwt_header(&cli, Method::GET, "/api/auth_test", &new_jwt).await;
+        let sessions = jwt_header(&cli, Method::GET, "/user/sessions", &new_jwt)
+            .await
+            .json::<retstructs::Sessions>()
+            .sessions;
+        assert_eq!(sessions.len(), 2);
+        assert_eq!(sessions[0].created, sessions[1].created);
+    }
+
+    #[tokio::test]
+    async fn user_refresh_extend_good() {
+        let cli = client().await;
+        let jwt = gen_user(&cli, "user_refresh_extend_good").await;
+        let session = jwt_header(&cli, Method::GET, "/user/sessions", &jwt)
+            .await
+            .json::<retstructs::Sessions>()
+            .sessions
+            .remove(0);
+        let new_jwt = super::extend_token(
+            &mut STATE.db.acquire().await.unwrap(),
+            jwt.whois().unwrap().userid,
+            session.id,
+            session.created,
+        )
+        .await
+        .unwrap();
+
+        // the old token is replaced by the new token in the same session
+        jwt_header(&cli, Method::GET, "/api/auth_test", &jwt)
+            .expect_failure()
+            .await;
+        let sessions = jwt_header(&cli, Method::GET, "/user/sessions", &new_jwt)
+            .await
+            .json::<retstructs::Sessions>()
+            .sessions;
+        assert_eq!(sessions.len(), 1);
+        assert_eq!(sessions[0].id, session.id);
+    }
+
+    #[tokio::test]
+    async fn user_password_change_good() {
+        let cli = client().await;
+        let jwt = gen_user(&cli, "user_password_change_good").await;
+        let phone_jwt = login_device(&cli, "user_password_change_good", "phone").await;
+        jwt_header(&cli, Method::PATCH, "/user/password", &jwt)
+            .json(&msgstructs::PasswordChange {
+                old_password: "password".to_owned(),
+                new_password: "hunter2".to_owned(),
+            })
+            .await;
+
+        // other sessions are logged out
+        jwt_header(&cli, Method::GET, "/api/auth_test", &jwt).await;
+        jwt_header(&cli, Method::GET, "/api/auth_test", &phone_jwt)
+            .expect_failure()
+            .await;
+
+        // and only the new password works
+        cli.get("/user/login")
+            .add_header(
+                HeaderName::from_static("authorization"),
+                Authorization::basic("user_password_change_good", "hunter2")
+                    .0
+                    .encode(),
+            )
+            .await;
+        cli.get("/user/login")
+            .add_header(
+                HeaderName::from_static("authorization"),
+                Authorization::basic("user_password_change_good", "password")
+                    .0
+                    .encode(),
+            )
+            .expect_failure()
+            .await;
+    }
+
+    #[tokio::test]
+    async fn user_password_change_bad_old_password() {
+        let cli = client().await;
+        let jwt = gen_user(&cli, "user_password_change_bad_old_password").await;
+        let phone_jwt = login_device(&cli, "user_password_change_bad_old_password", "phone").await;
+        jwt_header(&cli, Method::PATCH, "/user/password", &jwt)
+            .json(&msgstructs::PasswordChange {
+                old_password: "notpassword".to_owned(),
+                new_password: "hunter2".to_owned(),
+            })
+            .expect_failure()
+            .await;
+        jwt_header(&cli, Method::GET, "/api/auth_test", &phone_jwt).await;
+    }
+
+    #[tokio::test]
+    async fn user_delete_account_good() {
+        let cli = client().await;
+        let jwt = gen_user(&cli, "user_delete_account_good").await;
+        let userid = jwt.whois().unwrap().userid;
+        jwt_header(&cli, Method::DELETE, "/user/account", &jwt)
+            .json(&msgstructs::AccountDelete {
+                password: "password".to_owned(),
+            })
+            .await;
+        jwt_header(&cli, Method::GET, "/api/auth_test", &jwt)
+            .expect_failure()
+            .await;
+        cli.get("/user/login")
+            .add_header(
+                HeaderName::from_static("authorization"),
+                Authorization::basic("user_delete_account_good", "password")
+                    .0
+                    .encode(),
+            )
+            .expect_failure()
+            .await;
+        assert!(!crate::DATA_DIR
+            .get()
+            .unwrap()
+            .join(format!("{userid}"))
+            .exists());
+    }
+
+    #[tokio::test]
+    async fn user_delete_account_bad_password() {
+        let cli = client().await;
+        let jwt = gen_user(&cli, "user_delete_account_bad_password").await;
+        jwt_header(&cli, Method::DELETE, "/user/account", &jwt)
+            .json(&msgstructs::AccountDelete {
+                password: "notpassword".to_owned(),
+            })
+            .expect_failure()
+            .await;
+        jwt_header(&cli, Method::GET, "/api/auth_test", &jwt).await;
+
+        // guessing gets locked out like logging in does
+        for _ in 0..5 {
+            jwt_header(&cli, Method::DELETE, "/user/account", &jwt)
+                .json(&msgstructs::AccountDelete {
+                    password: "notpassword".to_owned(),
+                })
+                .expect_failure()
+                .await;
+        }
+        jwt_header(&cli, Method::DELETE, "/user/account", &jwt)
+            .json(&msgstructs::AccountDelete {
+                password: "password".to_owned(),
+            })
+            .expect_failure()
+            .await
+            .assert_status(axum::http::StatusCode::TOO_MANY_REQUESTS);
+    }
+
+    // util function to login again with a device name
+    async fn login_device(cli: &axum_test::TestServer, username: &str, device: &str) -> auth::JWT {
+        cli.get(&format!("/user/login?device={device}"))
+            .add_header(
+                HeaderName::from_static("authorization"),
+                Authorization::basic(username, "password").0.encode(),
+            )
+            .await
+            .json::<auth::JWT>()
+    }
+
+    #[tokio::test]
+    async fn user_sessions_good() {
+        let cli = client().await;
+        let jwt = gen_user(&cli, "user_sessions_good").await;
+        let phone_jwt = login_device(&cli, "user_sessions_good", "phone").await;
+        let sessions = jwt_header(&cli, Method::GET, "/user/sessions", &jwt)
+            .await
+            .json::<retstructs::Sessions>()
+            .sessions;
+        assert_eq!(sessions.len(), 2);
+        assert_eq!(sessions.iter().filter(|x| x.current).count(), 1);
+        let phone = sessions
+            .iter()
+            .find(|x| x.name.as_deref() == Some("phone"))
+            .unwrap();
+        assert!(!phone.current);
+
+        // revoke the phone session, and only the phone session
+        jwt_header(
+            &cli,
+            Method::DELETE,
+            &format!("/user/sessions?id={}", phone.id),
+            &jwt,
+        )
+        .await;
+        jwt_header(&cli, Method::GET, "/api/auth_test", &phone_jwt)
+            .expect_failure()
+            .await;
+        jwt_header(&cli, Method::GET, "/api/auth_test", &jwt).await;
+    }
+
+    #[tokio::test]
+    async fn user_sessions_rename_good() {
+        let cli = client().await;
+        let jwt = gen_user(&cli, "user_sessions_rename_good").await;
+        let sessions = jwt_header(&cli, Method::GET, "/user/sessions", &jwt)
+            .await
+            .json::<retstructs::Sessions>()
+            .sessions;
+        jwt_header(
+            &cli,
+            Method::PATCH,
+            &format!("/user/sessions?id={}&name=laptop", sessions[0].id),
+            &jwt,
+        )
+        .await;
+        let sessions = jwt_header(&cli, Method::GET, "/user/sessions", &jwt)
+            .await
+            .json::<retstructs::Sessions>()
+            .sessions;
+        assert_eq!(sessions[0].name.as_deref(), Some("laptop"));
+    }
+
+    #[tokio::test]
+    async fn user_sessions_revoke_all_good() {
+        let cli = client().await;
+        let jwt = gen_user(&cli, "user_sessions_revoke_all_good").await;
+        let phone_jwt = login_device(&cli, "user_sessions_revoke_all_good", "phone").await;
+        jwt_header(&cli, Method::DELETE, "/user/sessions/all", &jwt).await;
+        jwt_header(&cli, Method::GET, "/api/auth_test", &jwt)
+            .expect_failure()
+            .await;
+        jwt_header(&cli, Method::GET, "/api/auth_test", &phone_jwt)
+            .expect_failure()
+            .await;
+    }
+
+    #[tokio::test]
+    async fn user_sessions_bad_other_user() {
+        let cli = client().await;
+        let jwt = gen_user(&cli, "user_sessions_bad_other_user").await;
+        let other_jwt = gen_user(&cli, "user_sessions_bad_other_user_2").await;
+        let sessions = jwt_header(&cli, Method::GET, "/user/sessions", &other_jwt)
+            .await
+            .json::<retstructs::Sessions>()
+            .sessions;
+        jwt_header(
+            &cli,
+            Method::DELETE,
+            &format!("/user/sessions?id={}", sessions[0].id),
+            &jwt,
+        )
+        .expect_failure()
+        .await;
+        jwt_header(&cli, Method::GET, "/api/auth_test", &other_jwt).await;
+    }
+
+    #[tokio::test]
+    async fn user_login_bad_lockout() {
+        let cli = client().await;
+        let _ = gen_user(&cli, "user_login_bad_lockout").await;
+        let login = |password: &'static str| {
+            cli.get("/user/login").add_header(
+                HeaderName::from_static("authorization"),
+                Authorization::basic("user_login_bad_lockout", password)
+                    .0
+                    .encode(),
+            )
+        };
+
+        // after enough failures even the right password is turned away
+        for _ in 0..6 {
+            login("wrong")
+                .expect_failure()
+                .await
+                .assert_status(axum::http::StatusCode::UNAUTHORIZED);
+        }
+        let resp = login("password").expect_failure().await;
+        resp.assert_status(axum::http::StatusCode::TOO_MANY_REQUESTS);
+        assert!(resp
+            .headers()
+            .get(axum::http::header::RETRY_AFTER)
+            .is_some());
+
+        // other users are unaffected
+        let _ = gen_user(&cli, "user_login_bad_lockout_2").await;
+    }
+
+    #[tokio::test]
+    async fn user_login_bad_ip_lockout() {
+        let cli = client().await;
+        for name in ["a", "b", "c", "d"] {
+            let _ = gen_user(&cli, &format!("user_login_bad_ip_lockout_{name}")).await;
+        }
+
+        // the mock transport has no peer address, so this goes over a socket
+        let cli = http_client().await;
+        let login = |name: &str, password: &str| {
+            cli.get("/user/login").add_header(
+                HeaderName::from_static("authorization"),
+                Authorization::basic(&format!("user_login_bad_ip_lockout_{name}"), password)
+                    .0
+                    .encode(),
+            )
+        };
+
+        // no single username fails enough to be locked, but the ip does
+        for name in ["a", "a", "b", "b", "c", "c"] {
+            login(name, "wrong")
+                .expect_failure()
+                .await
+                .assert_status(axum::http::StatusCode::UNAUTHORIZED);
+        }
+        let resp = login("d", "password").expect_failure().await;
+        resp.assert_status(axum::http::StatusCode::TOO_MANY_REQUESTS);
+        assert!(resp
+            .headers()
+            .get(axum::http::header::RETRY_AFTER)
+            .is_some());
+    }
 }
@@ -62,3 +62,22 @@ pub struct JWTInner {
     pub userid: Uuid,
     pub exp: i64,
 }
+
+// what an api key is allowed to do. sessions made with a password can do everything.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "kebab-case")]
+pub enum Scope {
+    ReadLibrary,
+    Stream,
+    Upload,
+    ManageFolders,
+}
+
+impl Scope {
+    pub const ALL: [Scope; 4] = [
+        Scope::ReadLibrary,
+        Scope::Stream,
+        Scope::Upload,
+        Scope::ManageFolders,
+    ];
+}
@@ -25,3 +25,184 @@ impl Vers {
         }
     }
 }
+
+// formats that tracks can be stored or streamed in
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone, Copy, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum Codec {
+    // whatever was uploaded, untouched
+    Original,
+    Flac,
+    Opus,
+    Qoa,
+}
+
+impl Codec {
+    pub const fn as_str(&self) -> &'static str {
+        match self {
+            Codec::Original => "original",
+            Codec::Flac => "flac",
+            Codec::Opus => "opus",
+            Codec::Qoa => "qoa",
+        }
+    }
+}
+
+impl std::str::FromStr for Codec {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "original" => Codec::Original,
+            "flac" => Codec::Flac,
+            "opus" => Codec::Opus,
+            "qoa" => Codec::Qoa,
+            _ => anyhow::bail!("unknown codec {s}"),
+        })
+    }
+}
+
+// where an upload is in processing
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone, Copy, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum JobState {
+    Queued,
+    // reading tags and the waveform
+    Decoding,
+    // creating the track vector and encoding for storage
+    Embedding,
+    Done,
+    Failed,
+}
+
+impl JobState {
+    pub const fn as_str(&self) -> &'static str {
+        match self {
+            JobState::Queued => "queued",
+            JobState::Decoding => "decoding",
+            JobState::Embedding => "embedding",
+            JobState::Done => "done",
+            JobState::Failed => "failed",
+        }
+    }
+}
+
+impl std::str::FromStr for JobState {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "queued" => JobState::Queued,
+            "decoding" => JobState::Decoding,
+            "embedding" => JobState::Embedding,
+            "done" => JobState::Done,
+            "failed" => JobState::Failed,
+            _ => anyhow::bail!("unknown job state {s}"),
+        })
+    }
+}
+
+// what to do when an upload is already in the library
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone, Copy, Hash, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum DuplicatePolicy {
+    // fail the upload
+    Reject,
+    // throw away the upload and point to the existing track
+    Skip,
+    // keep both
+    #[default]
+    Allow,
+}
+
+impl DuplicatePolicy {
+    pub const fn as_str(&self) -> &'static str {
+        match self {
+            DuplicatePolicy::Reject => "reject",
+            DuplicatePolicy::Skip => "skip",
+            DuplicatePolicy::Allow => "allow",
+        }
+    }
+}
+
+impl std::str::FromStr for DuplicatePolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "reject" => DuplicatePolicy::Reject,
+            "skip" => DuplicatePolicy::Skip,
+            "allow" => DuplicatePolicy::Allow,
+            _ => anyhow::bail!("unknown duplicate policy {s}"),
+        })
+    }
+}
+
+// where a server side import is
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone, Copy, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum ImportState {
+    // walking the source folder and queueing what's in it
+    Scanning,
+    // everything has been queued, the jobs may still be running
+    Done,
+    Failed,
+}
+
+impl ImportState {
+    pub const fn as_str(&self) -> &'static str {
+        match self {
+            ImportState::Scanning => "scanning",
+            ImportState::Done => "done",
+            ImportState::Failed => "failed",
+        }
+    }
+}
+
+impl std::str::FromStr for ImportState {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "scanning" => ImportState::Scanning,
+            "done" => ImportState::Done,
+            "failed" => ImportState::Failed,
+            _ => anyhow::bail!("unknown import state {s}"),
+        })
+    }
+}
+
+// what an artist did on a track
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone, Copy, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum ArtistRole {
+    Primary,
+    Featured,
+    Remixer,
+    Composer,
+}
+
+impl ArtistRole {
+    pub const fn as_str(&self) -> &'static str {
+        match self {
+            ArtistRole::Primary => "primary",
+            ArtistRole::Featured => "featured",
+            ArtistRole::Remixer => "remixer",
+            ArtistRole::Composer => "composer",
+        }
+    }
+}
+
+impl std::str::FromStr for ArtistRole {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "primary" => ArtistRole::Primary,
+            "featured" => ArtistRole::Featured,
+            "remixer" => ArtistRole::Remixer,
+            "composer" => ArtistRole::Composer,
+            _ => anyhow::bail!("unknown artist role {s}"),
+        })
+    }
+}
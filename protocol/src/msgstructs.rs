@@ -20,6 +20,57 @@ pub struct DeleteQuery {
 pub struct TrackUploadQuery {
     pub dir: String,
     pub fname: Option<String>,
+    // how to store the track, defaults to the server setting
+    pub codec: Option<crate::Codec>,
+    // in kbps, only used for lossy codecs
+    pub bitrate: Option<u32>,
+    // what to do if the track is already in the library, defaults to allow
+    pub duplicates: Option<crate::DuplicatePolicy>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct ArchiveUploadQuery {
+    // folder that the archive is extracted into
+    pub dir: String,
+    // applies to every track in the archive
+    pub codec: Option<crate::Codec>,
+    pub bitrate: Option<u32>,
+    pub duplicates: Option<crate::DuplicatePolicy>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct UploadSessionCreate {
+    pub dir: String,
+    pub fname: Option<String>,
+    pub codec: Option<crate::Codec>,
+    pub bitrate: Option<u32>,
+    pub duplicates: Option<crate::DuplicatePolicy>,
+    // total size in bytes, if known. the upload can't be finished until it's all there.
+    pub size: Option<u64>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct UploadChunkQuery {
+    pub id: Uuid,
+    // where the chunk starts, which must be where the last one left off
+    pub offset: u64,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct TrackStreamQuery {
+    pub id: Uuid,
+    // transcode to this codec while streaming, defaults to the stored file
+    pub format: Option<crate::Codec>,
+    // in kbps, only used for lossy codecs
+    pub bitrate: Option<u32>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct TrackDownloadQuery {
+    pub id: Uuid,
+    // write the library's metadata and cover art into the file. defaults to on for
+    // downloads, and off for originals.
+    pub tags: Option<bool>,
 }
 
 #[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
@@ -45,8 +96,224 @@ pub struct TrackMove {
     pub new_path: Vec<String>,
 }
 
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct TrackCredit {
+    pub name: String,
+    pub role: crate::ArtistRole,
+}
+
+// changes to a track's metadata. anything that's left out is kept as it is, and null
+// clears it.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq, Default)]
+pub struct TrackEdit {
+    pub id: Uuid,
+    pub title: Option<String>,
+    #[serde(
+        default,
+        deserialize_with = "double_option",
+        skip_serializing_if = "Option::is_none"
+    )]
+    // replaces every primary artist
+    pub artist: Option<Option<String>>,
+    #[serde(
+        default,
+        deserialize_with = "double_option",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub artist_sort: Option<Option<String>>,
+    // replaces everyone credited on the track, and can't be used along with artist
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub artists: Option<Vec<TrackCredit>>,
+    #[serde(
+        default,
+        deserialize_with = "double_option",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub album_artist: Option<Option<String>>,
+    #[serde(
+        default,
+        deserialize_with = "double_option",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub album: Option<Option<String>>,
+    #[serde(
+        default,
+        deserialize_with = "double_option",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub album_sort: Option<Option<String>>,
+    #[serde(
+        default,
+        deserialize_with = "double_option",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub disk: Option<Option<i64>>,
+    #[serde(
+        default,
+        deserialize_with = "double_option",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub track: Option<Option<i64>>,
+    // only the tags named here are changed, and null removes the tag
+    #[serde(default)]
+    pub tags: std::collections::HashMap<String, Option<String>>,
+}
+
+// tells a field that is null apart from one that isn't there at all
+fn double_option<'de, T, D>(de: D) -> Result<Option<Option<T>>, D::Error>
+where
+    T: Deserialize<'de>,
+    D: serde::Deserializer<'de>,
+{
+    Option::<T>::deserialize(de).map(Some)
+}
+
 #[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
 pub struct ClosestTrack {
     pub id: Uuid,
     pub ignore_tracks: Vec<Uuid>,
 }
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct LoginQuery {
+    pub device: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct SessionRename {
+    pub id: Uuid,
+    pub name: String,
+}
+
+#[derive(Deserialize, Serialize, Clone, PartialEq, Eq)]
+pub struct PasswordChange {
+    pub old_password: String,
+    pub new_password: String,
+}
+
+impl std::fmt::Debug for PasswordChange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PasswordChange")
+            .field("old_password", &"**SCRUBBED**")
+            .field("new_password", &"**SCRUBBED**")
+            .finish()
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, PartialEq, Eq)]
+pub struct AccountDelete {
+    pub password: String,
+}
+
+impl std::fmt::Debug for AccountDelete {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AccountDelete")
+            .field("password", &"**SCRUBBED**")
+            .finish()
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, PartialEq, Eq)]
+pub struct AdminUserCreate {
+    pub username: String,
+    pub password: String,
+    pub admin: bool,
+}
+
+impl std::fmt::Debug for AdminUserCreate {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AdminUserCreate")
+            .field("username", &self.username)
+            .field("password", &"**SCRUBBED**")
+            .field("admin", &self.admin)
+            .finish()
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct AdminUserUpdate {
+    pub id: Uuid,
+    pub admin: Option<bool>,
+    pub disabled: Option<bool>,
+}
+
+// sets both quotas, None removes the limit
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct AdminQuotaSet {
+    pub id: Uuid,
+    pub bytes: Option<u64>,
+    pub tracks: Option<i64>,
+}
+
+// import a folder that's already on the server into a user's library
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct AdminImport {
+    // absolute path on the server
+    pub source: String,
+    pub user: Uuid,
+    // folder of the user's that source is mirrored into
+    pub dir: String,
+    pub codec: Option<crate::Codec>,
+    pub bitrate: Option<u32>,
+    pub duplicates: Option<crate::DuplicatePolicy>,
+    // hard link files into the library instead of copying them. this saves space, but
+    // the library then shares the source's files, so anything that changes the source
+    // afterwards changes the library too. defaults to false.
+    pub link: Option<bool>,
+}
+
+#[derive(Deserialize, Serialize, Clone, PartialEq, Eq)]
+pub struct AdminPasswordReset {
+    pub id: Uuid,
+    pub new_password: String,
+}
+
+impl std::fmt::Debug for AdminPasswordReset {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AdminPasswordReset")
+            .field("id", &self.id)
+            .field("new_password", &"**SCRUBBED**")
+            .finish()
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct SignupQuery {
+    pub invite: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct InviteCreate {
+    // how many accounts can be made with the invite
+    pub uses: i64,
+    // how long the invite is valid for, in seconds
+    pub lifetime: i64,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct ApiKeyCreate {
+    pub name: String,
+    pub scopes: Vec<crate::auth::Scope>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct StreamLinkQuery {
+    pub id: Uuid,
+    // how long the link is valid for, in seconds
+    pub lifetime: Option<i64>,
+    // what the link streams as, like in `TrackStreamQuery`
+    pub format: Option<crate::Codec>,
+    pub bitrate: Option<u32>,
+}
+
+// query of a signed stream link, clients should use the url as given instead of
+// building this
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct SignedStreamQuery {
+    pub id: Uuid,
+    pub user: Uuid,
+    pub exp: i64,
+    pub format: Option<crate::Codec>,
+    pub bitrate: Option<u32>,
+    pub sig: String,
+}
@@ -7,11 +7,34 @@ pub struct Track {
     pub id: Uuid,
     pub album: Option<Uuid>,
     pub cover_art: Option<Uuid>,
+    // the first primary artist
     pub artist: Option<Uuid>,
+    // everyone credited on the track, in the order they were credited
+    pub artists: Vec<TrackArtist>,
+    pub album_artist: Option<Uuid>,
     pub title: String,
     pub disk: Option<i64>,
     pub track: Option<i64>,
     pub tags: HashMap<String, String>,
+    // how the track is stored on the server
+    pub codec: crate::Codec,
+    pub bitrate: Option<u32>,
+    pub container: String,
+    // the file as it was uploaded, if it was kept
+    pub original: Option<OriginalFile>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct TrackArtist {
+    pub id: Uuid,
+    pub role: crate::ArtistRole,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct OriginalFile {
+    pub fname: String,
+    pub size: u64,
+    pub mime: String,
 }
 
 #[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
@@ -48,9 +71,60 @@ pub struct Playlists {
 
 #[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
 pub struct UploadReturn {
+    // id of the track once it's processed, which is also the id of the job
+    // processing it
     pub uuid: Uuid,
 }
 
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct ArchiveReport {
+    pub files: Vec<ArchiveFile>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct ArchiveFile {
+    // where the file was in the archive
+    pub path: String,
+    // the upload that the file was queued as, if it got that far
+    pub uuid: Option<Uuid>,
+    pub error: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct UploadSession {
+    pub id: Uuid,
+    // bytes received so far, which is where the next chunk should start
+    pub received: u64,
+    pub size: Option<u64>,
+    // the session is thrown away if nothing is sent before this
+    pub expiry: i64,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct Job {
+    pub id: Uuid,
+    pub state: crate::JobState,
+    pub fname: String,
+    // set when the job failed
+    pub error: Option<String>,
+    // the track that the upload ended up as. for a skipped duplicate, this is the
+    // track that was already there.
+    pub track: Option<Uuid>,
+    pub created: i64,
+    pub updated: i64,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct Jobs {
+    pub jobs: Vec<Job>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct DuplicateGroups {
+    // each group is tracks with the same audio, oldest first
+    pub groups: Vec<Vec<Uuid>>,
+}
+
 #[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
 pub struct Albums {
     pub albums: Vec<Uuid>,
@@ -90,3 +164,146 @@ pub struct ClosestId {
     pub id: Uuid,
     pub similarity: f32,
 }
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct Sessions {
+    pub sessions: Vec<Session>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct Session {
+    pub id: Uuid,
+    // device name given on login or rename
+    pub name: Option<String>,
+    // all times are unix timestamps
+    pub created: i64,
+    pub last_used: i64,
+    pub expiry: i64,
+    // if this is the session used to make the request
+    pub current: bool,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct Users {
+    pub users: Vec<User>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct User {
+    pub id: Uuid,
+    pub username: String,
+    pub admin: bool,
+    pub disabled: bool,
+    // invite used to create the account
+    pub invite: Option<Uuid>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct StorageUsage {
+    pub id: Uuid,
+    // bytes used on disk by the user's directory
+    pub bytes: u64,
+    pub tracks: i64,
+    // quotas, None if unlimited
+    pub bytes_limit: Option<u64>,
+    pub tracks_limit: Option<i64>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct CacheStats {
+    // counted since the server started
+    pub hits: u64,
+    pub misses: u64,
+    pub entries: i64,
+    pub bytes: u64,
+    pub limit: u64,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct Import {
+    pub id: Uuid,
+    pub user: Uuid,
+    pub source: String,
+    pub dir: String,
+    pub state: crate::ImportState,
+    // set when the import failed as a whole
+    pub error: Option<String>,
+    // audio files in source, once it has been walked
+    pub total: Option<i64>,
+    // files gotten to so far, and how the jobs for them are going
+    pub processed: i64,
+    pub queued: i64,
+    pub done: i64,
+    pub failed: i64,
+    // files that couldn't be queued or whose job failed
+    pub errors: Vec<ImportError>,
+    pub created: i64,
+    pub updated: i64,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct ImportError {
+    // where the file is under source
+    pub path: String,
+    pub error: String,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct Imports {
+    pub imports: Vec<Import>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct Invites {
+    pub invites: Vec<Invite>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct Invite {
+    pub id: Uuid,
+    pub code: String,
+    // None if the creator was deleted
+    pub creator: Option<Uuid>,
+    pub uses_left: i64,
+    // all times are unix timestamps
+    pub expiry: i64,
+    pub created: i64,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct ApiKeys {
+    pub keys: Vec<ApiKey>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct ApiKey {
+    pub id: Uuid,
+    pub name: String,
+    pub scopes: Vec<crate::auth::Scope>,
+    // all times are unix timestamps
+    pub created: i64,
+    pub last_used: Option<i64>,
+}
+
+// only returned once, when the key is created
+#[derive(Deserialize, Serialize, Clone, PartialEq, Eq)]
+pub struct NewApiKey {
+    pub info: ApiKey,
+    pub key: String,
+}
+
+impl std::fmt::Debug for NewApiKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("NewApiKey")
+            .field("info", &self.info)
+            .field("key", &"**SCRUBBED**")
+            .finish()
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct StreamLink {
+    // path and query relative to the server, which can be streamed from without auth
+    pub url: String,
+    pub expiry: i64,
+}